@@ -0,0 +1,513 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{num::NonZeroUsize, time::Duration};
+
+use lru::LruCache;
+
+use super::fuse::{FuseAttr, FuseEntryOut};
+
+/// The number of attributes and of entries cached if not configured otherwise.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// The number of bytes of a node that `StoredData` keeps at most.
+pub const MAX_STORED_BYTES: u64 = 1024 * 1024;
+
+struct CachedAttr {
+    attr: FuseAttr,
+    expires: Duration,
+}
+
+struct CachedEntry {
+    entry: FuseEntryOut,
+    expires: Duration,
+    /// The number of lookups of `entry.nodeid` answered while the entry was cached.
+    nlookup: u64,
+}
+
+/// Bounded caches of the attributes, directory entries and symlink targets
+/// returned by the server.
+///
+/// Each cache holds at most `capacity` items and evicts the least recently used
+/// one to make room. The validity of an item is checked against `now`, the time
+/// elapsed since boot.
+///
+/// Each lookup answered while an entry is cached holds a reference to its node,
+/// as counted by `NodeRefs`. Whenever an entry goes away, its `(nodeid, nlookup)`
+/// is returned so that the caller can drop those references.
+///
+/// The target of a symlink never changes, so it is kept until the node may be
+/// forgotten, after which its nodeid may be reused.
+pub struct NodeCache {
+    attrs: LruCache<u64, CachedAttr>,
+    entries: LruCache<(u64, Vec<u8>), CachedEntry>,
+    symlinks: LruCache<u64, Vec<u8>>,
+}
+
+impl NodeCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            attrs: LruCache::new(capacity),
+            entries: LruCache::new(capacity),
+            symlinks: LruCache::new(capacity),
+        }
+    }
+
+    pub fn capacity(&self) -> NonZeroUsize {
+        self.entries.cap()
+    }
+
+    /// Changes the capacity of both caches, returning the entries to forget.
+    pub fn set_capacity(&mut self, capacity: NonZeroUsize) -> Vec<(u64, u64)> {
+        let mut forgets = Vec::new();
+        while self.entries.len() > capacity.get() {
+            forgets.extend(self.evict_entry());
+        }
+        self.entries.resize(capacity);
+        self.attrs.resize(capacity);
+        self.symlinks.resize(capacity);
+        forgets
+    }
+
+    pub fn num_attrs(&self) -> usize {
+        self.attrs.len()
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Caches the attributes of node `nodeid` for `valid`.
+    pub fn insert_attr(&mut self, nodeid: u64, attr: FuseAttr, valid: Duration, now: Duration) {
+        let cached = CachedAttr {
+            attr: attr,
+            expires: now.saturating_add(valid),
+        };
+        self.attrs.put(nodeid, cached);
+    }
+
+    /// Returns the attributes of node `nodeid`, if they are cached and still valid.
+    pub fn attr(&mut self, nodeid: u64, now: Duration) -> Option<FuseAttr> {
+        let cached = self.attrs.get(&nodeid)?;
+        if cached.expires <= now {
+            self.attrs.pop(&nodeid);
+            return None;
+        }
+        Some(cached.attr)
+    }
+
+    /// Caches the reply to a lookup of `name` in directory `parent`, along with
+    /// the attributes it carries.
+    ///
+    /// Returns the entry to forget if one is evicted or replaced.
+    pub fn insert_entry(
+        &mut self,
+        parent: u64,
+        name: &[u8],
+        entry: &FuseEntryOut,
+        now: Duration,
+    ) -> Option<(u64, u64)> {
+        // A zero nodeid is a negative entry, which holds no lookup count.
+        if entry.nodeid == 0 {
+            return None;
+        }
+        self.insert_attr(
+            entry.nodeid,
+            entry.attr,
+            valid_duration(entry.attr_valid, entry.attr_valid_nsec),
+            now,
+        );
+
+        let expires = now.saturating_add(valid_duration(entry.entry_valid, entry.entry_valid_nsec));
+        let key = (parent, name.to_vec());
+        let mut forget = None;
+        if let Some(cached) = self.entries.get_mut(&key) {
+            if cached.entry.nodeid == entry.nodeid {
+                cached.entry = *entry;
+                cached.expires = expires;
+                cached.nlookup += 1;
+                return None;
+            }
+            // The name now refers to another node.
+            forget = Some((cached.entry.nodeid, cached.nlookup));
+            self.symlinks.pop(&cached.entry.nodeid);
+            self.entries.pop(&key);
+        } else if self.entries.len() == self.entries.cap().get() {
+            forget = self.evict_entry();
+        }
+
+        let cached = CachedEntry {
+            entry: *entry,
+            expires: expires,
+            nlookup: 1,
+        };
+        self.entries.put(key, cached);
+        forget
+    }
+
+    /// Returns the entry of `name` in directory `parent`, if it is cached and still valid.
+    ///
+    /// The entry carries the attributes cached for its node, which a setattr may
+    /// have changed since the lookup, and is not returned once they expire. An
+    /// expired entry is kept, since it still holds a lookup count.
+    pub fn entry(&mut self, parent: u64, name: &[u8], now: Duration) -> Option<FuseEntryOut> {
+        let cached = self.entries.get(&(parent, name.to_vec()))?;
+        if cached.expires <= now {
+            return None;
+        }
+        let mut entry = cached.entry;
+        entry.attr = self.attr(entry.nodeid, now)?;
+        Some(entry)
+    }
+
+    /// Drops the attributes of node `nodeid`, e.g. once the server invalidates them.
+    pub fn invalidate_attr(&mut self, nodeid: u64) {
+        self.attrs.pop(&nodeid);
+    }
+
+    /// Drops the entry of `name` in directory `parent` along with the attributes
+    /// of its node, e.g. once the name is removed or renamed.
+    ///
+    /// Returns the entry to forget if one was cached.
+    pub fn remove_entry(&mut self, parent: u64, name: &[u8]) -> Option<(u64, u64)> {
+        let cached = self.entries.pop(&(parent, name.to_vec()))?;
+        self.attrs.pop(&cached.entry.nodeid);
+        self.symlinks.pop(&cached.entry.nodeid);
+        Some((cached.entry.nodeid, cached.nlookup))
+    }
+
+    /// Caches `target`, the target of symlink `nodeid`.
+    pub fn insert_symlink(&mut self, nodeid: u64, target: &[u8]) {
+        self.symlinks.put(nodeid, target.to_vec());
+    }
+
+    /// Returns the target of symlink `nodeid`, if it is cached.
+    pub fn symlink(&mut self, nodeid: u64) -> Option<Vec<u8>> {
+        self.symlinks.get(&nodeid).cloned()
+    }
+
+    /// Drops the attributes and the symlink target of node `nodeid`, which is
+    /// forgotten, so that nothing is returned of whatever node reuses its nodeid.
+    pub fn forget_node(&mut self, nodeid: u64) {
+        self.attrs.pop(&nodeid);
+        self.symlinks.pop(&nodeid);
+    }
+
+    /// Evicts the least recently used entry along with the attributes of its node.
+    fn evict_entry(&mut self) -> Option<(u64, u64)> {
+        let (_, cached) = self.entries.pop_lru()?;
+        self.attrs.pop(&cached.entry.nodeid);
+        self.symlinks.pop(&cached.entry.nodeid);
+        Some((cached.entry.nodeid, cached.nlookup))
+    }
+}
+
+/// The lookup counts the server keeps for the nodes it returned, along with the
+/// references held to each node.
+///
+/// Each reply carrying an entry, e.g. to a lookup or a create, adds one to the
+/// lookup count of its node. The references are held by the callers the entry
+/// is returned to, and by the entries of `NodeCache`. Once the last reference
+/// to a node is dropped, its lookup count is to be sent back with `FUSE_FORGET`.
+pub struct NodeRefs {
+    nodes: BTreeMap<u64, NodeRef>,
+}
+
+struct NodeRef {
+    nlookup: u64,
+    refs: u64,
+}
+
+impl NodeRefs {
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a lookup of node `nodeid` by the server, which comes with `refs` references.
+    ///
+    /// A zero nodeid is a negative entry, which the server does not count.
+    pub fn lookup(&mut self, nodeid: u64, refs: u64) {
+        if nodeid == 0 {
+            return;
+        }
+        let node = self.nodes.entry(nodeid).or_insert(NodeRef {
+            nlookup: 0,
+            refs: 0,
+        });
+        node.nlookup += 1;
+        node.refs += refs;
+    }
+
+    /// Takes a reference to node `nodeid` without a lookup, e.g. for a cached entry.
+    pub fn get(&mut self, nodeid: u64) {
+        if let Some(node) = self.nodes.get_mut(&nodeid) {
+            node.refs += 1;
+        }
+    }
+
+    /// Returns whether node `nodeid` is referenced.
+    pub fn contains(&self, nodeid: u64) -> bool {
+        self.nodes.contains_key(&nodeid)
+    }
+
+    /// Drops `refs` references to node `nodeid`.
+    ///
+    /// Returns the lookup count to forget once no reference is left.
+    pub fn put(&mut self, nodeid: u64, refs: u64) -> Option<u64> {
+        let node = self.nodes.get_mut(&nodeid)?;
+        node.refs = node.refs.saturating_sub(refs);
+        if node.refs > 0 {
+            return None;
+        }
+        self.nodes.remove(&nodeid).map(|node| node.nlookup)
+    }
+}
+
+/// The file data pushed by the server with `FUSE_NOTIFY_STORE`.
+///
+/// This stands in for the page cache, and serves `FUSE_NOTIFY_RETRIEVE`. The
+/// data of a node is kept from offset 0 up to `MAX_STORED_BYTES`.
+pub struct StoredData {
+    nodes: BTreeMap<u64, Vec<u8>>,
+}
+
+impl StoredData {
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Stores `data` at `offset` in node `nodeid`.
+    ///
+    /// Returns `false` if the data does not fit below `MAX_STORED_BYTES`, in
+    /// which case it is dropped.
+    pub fn store(&mut self, nodeid: u64, offset: u64, data: &[u8]) -> bool {
+        let Some(end) = offset.checked_add(data.len() as u64) else {
+            return false;
+        };
+        if end > MAX_STORED_BYTES {
+            return false;
+        }
+        let stored = self.nodes.entry(nodeid).or_default();
+        if stored.len() < end as usize {
+            stored.resize(end as usize, 0);
+        }
+        stored[offset as usize..end as usize].copy_from_slice(data);
+        true
+    }
+
+    /// Overwrites the bytes stored at `offset` in node `nodeid` with `data`, e.g.
+    /// with the data of a read or a write, so that they do not go stale.
+    ///
+    /// Only the bytes already stored are overwritten, so `data` past them is left out.
+    pub fn update(&mut self, nodeid: u64, offset: u64, data: &[u8]) {
+        let Some(stored) = self.nodes.get_mut(&nodeid) else {
+            return;
+        };
+        let start = offset.min(stored.len() as u64) as usize;
+        let end = start.saturating_add(data.len()).min(stored.len());
+        stored[start..end].copy_from_slice(&data[..end - start]);
+    }
+
+    /// Returns up to `size` bytes stored at `offset` in node `nodeid`.
+    pub fn retrieve(&self, nodeid: u64, offset: u64, size: u32) -> &[u8] {
+        let Some(stored) = self.nodes.get(&nodeid) else {
+            return &[];
+        };
+        let start = offset.min(stored.len() as u64) as usize;
+        let end = start.saturating_add(size as usize).min(stored.len());
+        &stored[start..end]
+    }
+
+    /// Drops the data of node `nodeid`, e.g. once it is forgotten.
+    pub fn remove(&mut self, nodeid: u64) {
+        self.nodes.remove(&nodeid);
+    }
+}
+
+/// Returns the validity given by a pair of timeout fields in a reply.
+pub fn valid_duration(secs: u64, nsecs: u32) -> Duration {
+    Duration::from_secs(secs).saturating_add(Duration::from_nanos(nsecs as u64))
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    fn fake_entry(nodeid: u64) -> FuseEntryOut {
+        FuseEntryOut {
+            nodeid: nodeid,
+            entry_valid: 1,
+            attr_valid: 1,
+            ..Default::default()
+        }
+    }
+
+    #[ktest]
+    fn cache_stays_bounded_under_churn() {
+        const CAPACITY: usize = 16;
+        let mut cache = NodeCache::new(NonZeroUsize::new(CAPACITY).unwrap());
+        let now = Duration::ZERO;
+
+        let mut nlookup_forgotten = 0;
+        for i in 0..1000u64 {
+            // Names come back now and then, which bumps their lookup count.
+            let name = if i % 2 == 0 { 0 } else { (i % 40) as u8 };
+            let nodeid = 2 + name as u64;
+            if let Some((_, nlookup)) = cache.insert_entry(1, &[name], &fake_entry(nodeid), now) {
+                nlookup_forgotten += nlookup;
+            }
+            cache.insert_attr(1000 + i, FuseAttr::default(), Duration::from_secs(1), now);
+            assert!(cache.num_entries() <= CAPACITY);
+            assert!(cache.num_attrs() <= CAPACITY);
+        }
+
+        // Every lookup is either still cached or has been handed back.
+        let forgets = cache.set_capacity(NonZeroUsize::new(1).unwrap());
+        nlookup_forgotten += forgets.iter().map(|(_, nlookup)| nlookup).sum::<u64>();
+        assert_eq!(cache.num_entries(), 1);
+        let (_, last) = cache.evict_entry().unwrap();
+        assert_eq!(nlookup_forgotten + last, 1000);
+    }
+
+    #[ktest]
+    fn expired_items_are_not_returned() {
+        let mut cache = NodeCache::new(NonZeroUsize::new(4).unwrap());
+        let entry = fake_entry(2);
+        cache.insert_entry(1, b"a", &entry, Duration::ZERO);
+
+        let before = Duration::from_millis(999);
+        assert_eq!(cache.entry(1, b"a", before).unwrap().nodeid, 2);
+        assert!(cache.attr(2, before).is_some());
+
+        let after = Duration::from_secs(1);
+        assert!(cache.entry(1, b"a", after).is_none());
+        assert!(cache.attr(2, after).is_none());
+        // The entry still holds its lookup count.
+        assert_eq!(cache.num_entries(), 1);
+    }
+
+    #[ktest]
+    fn stored_data_is_retrieved() {
+        let mut stored = StoredData::new();
+        assert!(stored.store(2, 4, b"world"));
+        assert!(stored.store(2, 0, b"helo"));
+        assert_eq!(stored.retrieve(2, 0, 9), b"heloworld");
+        // A retrieve past the stored data is cut short.
+        assert_eq!(stored.retrieve(2, 4, 100), b"world");
+        assert_eq!(stored.retrieve(2, 100, 4), b"");
+        assert_eq!(stored.retrieve(3, 0, 4), b"");
+
+        assert!(!stored.store(2, MAX_STORED_BYTES - 1, b"xy"));
+        assert!(!stored.store(2, u64::MAX, b"x"));
+        stored.remove(2);
+        assert_eq!(stored.retrieve(2, 0, 9), b"");
+    }
+
+    #[ktest]
+    fn stored_data_is_updated_in_place() {
+        let mut stored = StoredData::new();
+        // Nothing is stored for a node the server never pushed data of.
+        stored.update(2, 0, b"data");
+        assert_eq!(stored.retrieve(2, 0, 4), b"");
+
+        assert!(stored.store(2, 0, b"hello"));
+        stored.update(2, 3, b"p me");
+        assert_eq!(stored.retrieve(2, 0, 9), b"help ");
+        stored.update(2, 100, b"far");
+        assert_eq!(stored.retrieve(2, 0, 9), b"help ");
+    }
+
+    #[ktest]
+    fn node_is_forgotten_with_its_last_reference() {
+        let mut refs = NodeRefs::new();
+        // A lookup whose entry is cached, and a create.
+        refs.lookup(2, 2);
+        refs.lookup(2, 1);
+        // The cached entry is returned again.
+        refs.get(2);
+        refs.lookup(0, 1);
+        assert!(!refs.contains(0));
+
+        assert_eq!(refs.put(2, 1), None);
+        assert_eq!(refs.put(2, 2), None);
+        // The lookups are forgotten together, whatever the references.
+        assert_eq!(refs.put(2, 1), Some(2));
+        assert!(!refs.contains(2));
+        assert_eq!(refs.put(2, 1), None);
+    }
+
+    #[ktest]
+    fn symlink_is_dropped_with_its_entry() {
+        let mut cache = NodeCache::new(NonZeroUsize::new(1).unwrap());
+        cache.insert_entry(1, b"link", &fake_entry(2), Duration::ZERO);
+        cache.insert_symlink(2, b"target");
+        assert_eq!(cache.symlink(2).unwrap(), b"target");
+
+        // Node 2 may be forgotten once its entry is evicted.
+        cache.insert_entry(1, b"other", &fake_entry(3), Duration::ZERO);
+        assert!(cache.symlink(2).is_none());
+    }
+
+    #[ktest]
+    fn removed_entry_is_forgotten() {
+        let mut cache = NodeCache::new(NonZeroUsize::new(4).unwrap());
+        cache.insert_entry(1, b"a", &fake_entry(2), Duration::ZERO);
+        cache.insert_entry(1, b"a", &fake_entry(2), Duration::ZERO);
+
+        assert_eq!(cache.remove_entry(1, b"a"), Some((2, 2)));
+        assert!(cache.entry(1, b"a", Duration::ZERO).is_none());
+        assert!(cache.attr(2, Duration::ZERO).is_none());
+        assert_eq!(cache.num_entries(), 0);
+        assert_eq!(cache.remove_entry(1, b"a"), None);
+    }
+
+    #[ktest]
+    fn entry_carries_updated_attr() {
+        let mut cache = NodeCache::new(NonZeroUsize::new(4).unwrap());
+        cache.insert_entry(1, b"a", &fake_entry(2), Duration::ZERO);
+
+        // A setattr changes the mode after the lookup.
+        let attr = FuseAttr {
+            ino: 2,
+            mode: 0o100600,
+            ..Default::default()
+        };
+        cache.insert_attr(2, attr, Duration::from_secs(1), Duration::ZERO);
+        let entry = cache.entry(1, b"a", Duration::ZERO).unwrap();
+        assert_eq!(entry.attr.mode, 0o100600);
+
+        // Once the server invalidates the attributes, the entry is looked up again.
+        cache.invalidate_attr(2);
+        assert!(cache.entry(1, b"a", Duration::ZERO).is_none());
+        assert_eq!(cache.num_entries(), 1);
+    }
+
+    #[ktest]
+    fn replaced_entry_is_forgotten() {
+        let mut cache = NodeCache::new(NonZeroUsize::new(4).unwrap());
+        assert_eq!(
+            cache.insert_entry(1, b"a", &fake_entry(2), Duration::ZERO),
+            None
+        );
+        assert_eq!(
+            cache.insert_entry(1, b"a", &fake_entry(2), Duration::ZERO),
+            None
+        );
+        assert_eq!(
+            cache.insert_entry(1, b"a", &fake_entry(3), Duration::ZERO),
+            Some((2, 2))
+        );
+        // Negative entries are not cached.
+        assert_eq!(
+            cache.insert_entry(1, b"b", &fake_entry(0), Duration::ZERO),
+            None
+        );
+        assert_eq!(cache.num_entries(), 1);
+    }
+}
@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: MPL-2.0
 
+use alloc::string::String;
 use core::mem::offset_of;
 
 use aster_util::safe_ptr::SafePtr;
@@ -30,6 +31,18 @@ pub struct VirtioFilesystemConfig {
 }
 
 impl VirtioFilesystemConfig {
+    /// Returns the tag the device is exported under.
+    ///
+    /// The tag is NUL-padded, and is not NUL-terminated if it uses all 36 bytes.
+    pub fn tag(&self) -> String {
+        let tag_len = self
+            .tag
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.tag.len());
+        String::from_utf8_lossy(&self.tag[..tag_len]).into_owned()
+    }
+
     pub(super) fn new_manager(transport: &dyn VirtioTransport) -> ConfigManager<Self> {
         let safe_ptr = transport
             .device_config_mem()
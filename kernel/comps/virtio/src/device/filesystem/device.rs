@@ -1,423 +1,1241 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
-use core::{fmt::Debug, iter::Fuse};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Debug,
+    hint::spin_loop,
+    iter::{self, Fuse},
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 
-use log::debug;
+use align_ext::AlignExt;
+use log::{debug, info, warn};
 use ostd::{
+    arch::{read_tsc, tsc_freq},
     early_print, early_println,
-    mm::{DmaDirection, DmaStream, DmaStreamSlice, FrameAllocOptions, VmReader, VmWriter},
-    sync::{RwLock, SpinLock},
-    trap::TrapFrame,
+    mm::{
+        DmaDirection, DmaStream, DmaStreamSlice, FrameAllocOptions, Infallible, VmReader, VmWriter,
+        PAGE_SIZE,
+    },
+    sync::{LocalIrqDisabled, RwLock, SpinLock, WaitQueue, Waiter},
+    timer::{self, Jiffies},
+    trap::{IrqCallbackFunction, TrapFrame},
     Pod,
 };
 
 use super::{
+    cache::{valid_duration, NodeCache, NodeRefs, StoredData, DEFAULT_CACHE_CAPACITY},
     config::{FilesystemFeatures, VirtioFilesystemConfig},
+    error::{
+        FilesystemError, EACCES, EAGAIN, ECONNABORTED, EINTR, EINVAL, EIO, ENOENT, ENOSYS, ENXIO,
+        EOPNOTSUPP, EOVERFLOW, EPROTO, ETIMEDOUT,
+    },
     fuse::*,
-    request::{fuse_pad_str, AnyFuseDevice, FuseReaddirOut},
+    metadata::Metadata,
+    request::{
+        build_extensions, fuse_pad_str, secctx_extension, supp_groups_extension, AnyFuseDevice,
+        CountingReader, FuseCreds, FuseDirentWithName, FuseReaddirOut, FuseReaddirplusOut,
+        SecurityContext, SetattrRequest, VirtioFsReq,
+    },
+    stats::{FuseStats, RequestStats},
 };
 use crate::{
-    device::VirtioDeviceError,
-    queue::VirtQueue,
-    transport::{ConfigManager, VirtioTransport},
+    device::{VirtioDeviceError, VirtioDeviceType},
+    driver_features,
+    queue::{QueueError, VirtQueue},
+    transport::{ConfigManager, DeviceStatus, VirtioTransport, VirtioTransportError},
 };
 
+const HIPRIO_QUEUE_INDEX: u16 = 0;
+/// The notification queue only exists if `VIRTIO_FS_F_NOTIFICATION` is negotiated,
+/// in which case the request queues follow it.
+const NOTIFICATION_QUEUE_INDEX: u16 = 1;
+/// `VIRTIO_F_VERSION_1`, as virtio-fs has no legacy interface.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// The feature bits a device must offer to be driven.
+const REQUIRED_FEATURES: u64 = VIRTIO_F_VERSION_1;
+/// The number of buffers kept posted on the notification queue.
+const NOTIFICATION_QUEUE_SIZE: u16 = 4;
+/// The number of descriptors of each request queue.
+const REQUEST_QUEUE_SIZE: u16 = 16;
+/// The number of requests of `send_sync` each request queue has in flight at most.
+const REQUEST_POOL_SLOTS: usize = 4;
+/// The most forgets sent in one `FUSE_BATCH_FORGET`, which fits in the hiprio buffer.
+const MAX_BATCH_FORGETS: usize = 256;
+/// The number of nodes whose forgets are held back before they are sent together.
+const FORGET_FLUSH_THRESHOLD: usize = 64;
+/// The most times `ioctl_sync` makes an ioctl again for the server asking to retry it.
+const FUSE_IOCTL_MAX_RETRIES: usize = 8;
+/// How long `shutdown` waits for the requests in flight, and then for `FUSE_DESTROY`.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the device is waited for to answer `FUSE_INIT` when it is probed.
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the device is waited for to return a request sent on the hiprio queue.
+const HIPRIO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The size of the reply buffer used for each `FUSE_READDIR` in `readdir_all`.
+const READDIR_BUFFER_SIZE: u32 = 4096;
+
+/// The number of pages of a read or write if the server does not set `max_pages`.
+const FUSE_DEFAULT_MAX_PAGES: usize = 32;
+/// The upper bound of `max_pages`, which keeps a misbehaving server from
+/// making the request buffers arbitrarily large.
+const FUSE_MAX_MAX_PAGES: usize = 256;
+/// The `max_readahead` offered in `FUSE_INIT`, which the server may lower.
+const FUSE_MAX_READAHEAD: u32 = 128 * 1024;
+
+/// `O_APPEND` of the open flags, which are passed on to the server as is.
+const O_APPEND: u32 = 0o2000;
+/// `O_TRUNC` of the open flags.
+const O_TRUNC: u32 = 0o1000;
+
+/// The `whence` of an lseek(2) from the start of the file.
+pub const SEEK_SET: u32 = 0;
+/// The `whence` of an lseek(2) from the current offset.
+pub const SEEK_CUR: u32 = 1;
+/// The `whence` of an lseek(2) from the end of the file.
+pub const SEEK_END: u32 = 2;
+/// The `whence` of an lseek(2) to the next data at or after the offset.
+pub const SEEK_DATA: u32 = 3;
+/// The `whence` of an lseek(2) to the next hole at or after the offset.
+pub const SEEK_HOLE: u32 = 4;
+
+/// The directory handle of an `opendir` answered without a round-trip, once
+/// `FUSE_NO_OPENDIR_SUPPORT` is negotiated.
+pub const NO_OPENDIR_FH: u64 = 0;
+
+/// The file handle of an `open` answered without a round-trip, once the server
+/// does without `FUSE_OPEN`.
+pub const NO_OPEN_FH: u64 = 0;
+
+/// The longest symlink target that `readlink` makes room for, as `PATH_MAX`.
+const FUSE_PATH_MAX: usize = 4096;
+
+/// The first minor version of the protocol with `FUSE_RENAME2`.
+const FUSE_RENAME2_MINOR: u32 = 23;
+
+/// A callback invoked with the nodeid of a submount that should be auto-mounted.
+pub type AutomountCallback = dyn Fn(u64) + Send + Sync;
+
+/// A receiver of the notifications by which the server invalidates what the
+/// client may have cached, e.g. the VFS layer.
+///
+/// The methods are invoked from the interrupt handler of the notification
+/// queue, so they must not block.
+pub trait FilesystemCallback: Send + Sync {
+    /// The data of node `ino` in `off..off + len` is stale. A negative `off`
+    /// also invalidates the attributes, and a zero `len` goes to the end.
+    fn invalidate_inode(&self, ino: u64, off: i64, len: i64);
+    /// The entry `name` of directory `parent` is stale.
+    fn invalidate_entry(&self, parent: u64, name: &[u8]);
+    /// The entry `name` of directory `parent`, which is node `child`, is gone.
+    fn delete(&self, parent: u64, child: u64, name: &[u8]);
+    /// The poll with the handle `kh` is ready.
+    fn poll_wakeup(&self, kh: u64);
+}
+
+/// A provider of the credentials of the current task, as seen by the server.
+///
+/// It returns `None` if the ids of the task cannot be mapped into those of the server.
+pub type CredsProvider = dyn Fn() -> Option<FuseCreds> + Send + Sync;
+
+/// The device as its interrupt handlers reach it, until `shutdown` takes it out.
+///
+/// The transport offers no way to unregister a handler, so the handlers hold
+/// the device through this slot rather than directly.
+type IrqTarget = RwLock<Option<Arc<FilesystemDevice>>, LocalIrqDisabled>;
+
 pub struct FilesystemDevice {
     config_manager: ConfigManager<VirtioFilesystemConfig>,
+    /// The config last read from the device, which is read again upon each change.
+    config: SpinLock<VirtioFilesystemConfig>,
     transport: SpinLock<Box<dyn VirtioTransport>>,
 
     hiprio_queue: SpinLock<VirtQueue>,
     request_queues: Vec<SpinLock<VirtQueue>>,
-    // notify_queue: SpinLock<VirtQueue>,
+    notify_queue: Option<SpinLock<NotifyQueue>>,
     hiprio_buffer: DmaStream,
-    request_buffers: Vec<DmaStream>,
-    // notify_buffer: DmaStream,
-    // callbacks: RwLock<Vec<&'static FilesystemCallback>, LocalIrqDisabled>,
+    /// The slots the requests are staged in, paired with `request_queues` and
+    /// enlarged once `FUSE_INIT` is answered.
+    request_pools: Vec<SpinLock<RequestPool>>,
+    next_queue: AtomicUsize,
+    callbacks: RwLock<Vec<Arc<dyn FilesystemCallback>>, LocalIrqDisabled>,
+    features: FilesystemFeatures,
+    connection: FuseConnection,
+    automount_callbacks: RwLock<Vec<Arc<AutomountCallback>>, LocalIrqDisabled>,
+    creds_provider: RwLock<Option<Arc<CredsProvider>>, LocalIrqDisabled>,
+    /// The attributes and entries returned by `lookup_sync` and `getattr_sync`.
+    cache: SpinLock<NodeCache>,
+    /// The file data stored by the server with `FUSE_NOTIFY_STORE`.
+    stored_data: SpinLock<StoredData>,
+    /// The file and directory handles opened on the server and not released yet.
+    open_handles: SpinLock<HandleTable>,
+    /// The lookup counts of the nodes returned by the server, and their references.
+    node_refs: SpinLock<NodeRefs>,
+    /// The forgets of the nodes left without references, to be sent with `flush_forgets`.
+    pending_forgets: SpinLock<ForgetBatch>,
+    is_shut_down: AtomicBool,
+    /// Whether the server does without `FUSE_OPEN`, as it negotiated
+    /// `FUSE_NO_OPEN_SUPPORT` or answered an open with `ENOSYS`.
+    no_open: AtomicBool,
+    poll_waiters: Arc<PollWaiters>,
+    stats: RequestStats,
+    irq_target: Arc<IrqTarget>,
 }
 
-impl AnyFuseDevice for FilesystemDevice {
-    fn init(&self) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInitIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseInit as u32,
-            unique: 0,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+/// The notification queue, along with the buffers posted on it.
+struct NotifyQueue {
+    queue: VirtQueue,
+    buffer: DmaStream,
+    slot_size: usize,
+    /// The offset in `buffer` of the slot posted under each token.
+    slots: BTreeMap<u16, usize>,
+}
 
-        let initin = FuseInitIn {
-            major: FUSE_KERNEL_VERSION,
-            minor: FUSE_KERNEL_MINOR_VERSION,
-            max_readahead: 0,
-            flags: FuseInitFlags::FUSE_INIT_EXT.bits() as u32,
-            flags2: 0,
-            unused: [0u32; 11],
+impl NotifyQueue {
+    /// Creates a notification queue with all its slots of `slot_size` bytes posted.
+    fn new(queue: VirtQueue, slot_size: usize) -> Result<Self, VirtioDeviceError> {
+        let nframes = (NOTIFICATION_QUEUE_SIZE as usize * slot_size).div_ceil(PAGE_SIZE);
+        let buffer = alloc_dma_stream(nframes, DmaDirection::FromDevice)?;
+        let mut notify_queue = Self {
+            queue: queue,
+            buffer: buffer,
+            slot_size: slot_size,
+            slots: BTreeMap::new(),
         };
+        for slot in 0..NOTIFICATION_QUEUE_SIZE as usize {
+            notify_queue.post(slot * slot_size)?;
+        }
+        if notify_queue.queue.should_notify() {
+            notify_queue.queue.notify();
+        }
+        Ok(notify_queue)
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let initin_bytes = initin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let initout_bytes = [0u8; 256];
-        let concat_req = [
-            headerin_bytes,
-            initin_bytes,
-            &headerout_buffer,
-            &initout_bytes,
-        ]
-        .concat();
+    /// Hands the slot at `offset` over to the device for the next notification.
+    fn post(&mut self, offset: usize) -> Result<(), QueueError> {
+        let slice = DmaStreamSlice::new(&self.buffer, offset, self.slot_size);
+        let token = self.queue.add_dma_buf(&[], &[&slice])?;
+        self.slots.insert(token, offset);
+        Ok(())
+    }
+}
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInitIn>() + size_of::<FuseInHeader>();
+/// DMA buffers split into slots of the same size, each holding a request and
+/// its reply while the request is in flight.
+///
+/// This lets several requests share a request queue, instead of all of them
+/// taking turns in a single request buffer.
+///
+/// A request and its reply are in the slot at the same offset of two buffers,
+/// mapped such that the device may only read the request and only write the reply.
+struct RequestPool {
+    /// The requests, read by the device.
+    requests: DmaStream,
+    /// The replies, written by the device.
+    replies: DmaStream,
+    slot_size: usize,
+    /// The offsets in `buffer` of the slots no request occupies.
+    free_slots: Vec<usize>,
+    /// The request sent under each token.
+    in_flight: BTreeMap<u16, PooledRequest>,
+}
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+/// A request of a `RequestPool` the device has yet to return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PooledRequest {
+    /// The offset of its slot.
+    offset: usize,
+    /// Whether a caller of `send_sync` waits for the reply, rather than
+    /// `handle_reply` decoding it.
+    awaited: bool,
+}
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+/// What a request carries past its `FuseInHeader`, and the room it leaves for
+/// its reply past the `FuseOutHeader`.
+#[derive(Default)]
+struct RequestParts<'a> {
+    /// The body, staged in the slot of the request.
+    datain: &'a [&'a [u8]],
+    /// The pages following the body, which the device reads in place.
+    in_pages: &'a [DmaStreamSlice<DmaStream>],
+    /// The extensions ending `datain`, in units of 8 bytes.
+    total_extlen: u16,
+    /// The room for the reply body in the slot.
+    out_len: usize,
+    /// The pages the rest of the reply body goes to.
+    out_pages: &'a [DmaStreamSlice<DmaStream>],
+}
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+impl RequestPool {
+    /// Allocates a pool of `num_slots` slots of `slot_size` bytes, in whole pages.
+    fn new(num_slots: usize, slot_size: usize) -> Result<Self, ostd::Error> {
+        let slot_size = slot_size.align_up(PAGE_SIZE);
+        let nframes = num_slots * slot_size / PAGE_SIZE;
+        Ok(Self {
+            requests: alloc_dma_stream(nframes, DmaDirection::ToDevice)?,
+            replies: alloc_dma_stream(nframes, DmaDirection::FromDevice)?,
+            slot_size: slot_size,
+            // The slots are handed out from the start of the buffer.
+            free_slots: (0..num_slots).rev().map(|slot| slot * slot_size).collect(),
+            in_flight: BTreeMap::new(),
+        })
     }
 
-    fn opendir(&self, nodeid: u64, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseOpenIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseOpendir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Takes a free slot, returning its offset, or `None` if all slots are occupied.
+    fn alloc(&mut self) -> Option<usize> {
+        self.free_slots.pop()
+    }
 
-        let openin = FuseOpenIn {
-            flags: flags,
-            open_flags: 0,
-        };
+    /// Gives back the slot at `offset`, once nothing of its request is left to read.
+    fn free(&mut self, offset: usize) {
+        debug_assert!(!self.free_slots.contains(&offset));
+        self.free_slots.push(offset);
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let openin_bytes = openin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let openout_bytes = [0u8; size_of::<FuseOpenOut>()];
-        let concat_req = [
-            headerin_bytes,
-            openin_bytes,
-            &headerout_buffer,
-            &openout_bytes,
-        ]
-        .concat();
+    /// Records that the request in the slot at `offset` is sent under `token`,
+    /// and whether its reply is `awaited`.
+    fn track(&mut self, token: u16, offset: usize, awaited: bool) {
+        self.in_flight.insert(
+            token,
+            PooledRequest {
+                offset: offset,
+                awaited: awaited,
+            },
+        );
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseOpenIn>() + size_of::<FuseInHeader>();
+    /// Returns the request completed under `token`, or `None` if the request
+    /// was not staged in this pool.
+    ///
+    /// The slot is left occupied until `free`, so that its reply can be copied out.
+    fn complete(&mut self, token: u16) -> Option<PooledRequest> {
+        self.in_flight.remove(&token)
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Replaces the buffer with one whose slots hold at least `slot_size` bytes.
+    ///
+    /// This is done once `FUSE_INIT` is answered, while no request of the pool
+    /// is in flight. The pool is left as is if the new buffers cannot be allocated.
+    fn resize(&mut self, slot_size: usize) -> Result<(), ostd::Error> {
+        debug_assert!(self.in_flight.is_empty());
+        if self.slot_size >= slot_size {
+            return Ok(());
+        }
+        let num_slots = self.requests.nbytes() / self.slot_size;
+        *self = Self::new(num_slots, slot_size)?;
+        Ok(())
+    }
+}
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+/// The connection parameters agreed on in the `FUSE_INIT` exchange.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NegotiatedInit {
+    pub major: u32,
+    pub minor: u32,
+    pub flags: u32,
+    pub flags2: u32,
+    pub max_write: u32,
+    pub max_readahead: u32,
+    /// The number of pages a request may carry, or 0 if `FUSE_MAX_PAGES` is
+    /// not agreed on.
+    pub max_pages: u16,
+    pub max_background: u16,
+    pub time_gran: u32,
+}
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+impl NegotiatedInit {
+    fn new(init_out: &FuseInitOut) -> Self {
+        Self {
+            major: init_out.major,
+            minor: init_out.minor,
+            flags: init_out.flags,
+            flags2: init_out.flags2,
+            max_write: init_out.max_write,
+            max_readahead: init_out.max_readahead,
+            max_pages: if init_out.flags as u64 & FUSE_MAX_PAGES != 0 {
+                init_out.max_pages
+            } else {
+                0
+            },
+            max_background: init_out.max_background,
+            time_gran: init_out.time_gran,
         }
     }
 
-    fn readdir(&self, nodeid: u64, fh: u64, offset: u64, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    /// Returns the init flags, with `flags2` in the upper 32 bits.
+    pub fn fuse_flags(&self) -> u64 {
+        self.flags as u64 | (self.flags2 as u64) << 32
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReadIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseReaddir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Returns whether the server agreed on the init flag `flag`, e.g. `FUSE_SETXATTR_EXT`.
+    pub fn has(&self, flag: u64) -> bool {
+        self.fuse_flags() & flag != 0
+    }
 
-        let readin = FuseReadIn {
-            fh: fh,
-            offset: offset,
-            size: size,
-            read_flags: 0,
-            lock_owner: 0,
-            flags: 0,
-            padding: 0,
+    /// Returns the largest payload of a single read or write the server accepts.
+    ///
+    /// Without `FUSE_MAX_PAGES`, the server takes `FUSE_DEFAULT_MAX_PAGES` pages.
+    pub fn max_transfer_size(&self) -> u32 {
+        let max_pages = match self.max_pages {
+            0 => FUSE_DEFAULT_MAX_PAGES,
+            max_pages => (max_pages as usize).min(FUSE_MAX_MAX_PAGES),
         };
+        (max_pages * PAGE_SIZE) as u32
+    }
+}
 
-        let headerin_bytes = headerin.as_bytes();
-        let readin_bytes = readin.as_bytes();
-        // let readin_bytes = [0u8; 36];
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let readout_bytes = [0u8; 1024];
-        let concat_req = [
-            headerin_bytes,
-            &readin_bytes,
-            &headerout_buffer,
-            &readout_bytes,
-        ]
-        .concat();
+/// The reply to a request sent by `send_sync`, or `None` while it is awaited.
+type ReplySlot = Option<Result<Vec<u8>, FilesystemError>>;
+
+/// The requests sent by `send_sync` whose callers wait for a reply, keyed by `unique`.
+///
+/// A request is completed at most once. A second reply with the same `unique`,
+/// e.g. from a buggy server, or a reply to a request already given up on is
+/// dropped, so that a waiter never sees a reply that is not its own.
+#[derive(Debug, Default)]
+struct InflightTable {
+    slots: BTreeMap<u64, ReplySlot>,
+}
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReadIn>() + size_of::<FuseInHeader>();
+impl InflightTable {
+    fn insert(&mut self, unique: u64) {
+        self.slots.insert(unique, None);
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Forgets request `unique`, e.g. because it could not be sent.
+    fn remove(&mut self, unique: u64) {
+        self.slots.remove(&unique);
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns whether request `unique` is still waiting for its reply.
+    fn is_waiting(&self, unique: u64) -> bool {
+        matches!(self.slots.get(&unique), Some(None))
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Returns the uniques of the requests still waiting for their reply.
+    fn waiting(&self) -> Vec<u64> {
+        self.slots
+            .iter()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(unique, _)| *unique)
+            .collect()
+    }
+
+    /// Completes request `unique` with `reply`.
+    ///
+    /// Returns whether the request was waiting for it. Otherwise, `reply` is dropped.
+    fn complete(&mut self, unique: u64, reply: Result<Vec<u8>, FilesystemError>) -> bool {
+        match self.slots.get_mut(&unique) {
+            Some(slot @ None) => {
+                *slot = Some(reply);
+                true
+            }
+            _ => false,
         }
     }
 
-    fn read(&self, nodeid: u64, fh: u64, offset: u64, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    /// Fails the requests still waiting for their reply with the errors of `error`.
+    fn fail_all<F: Fn() -> FilesystemError>(&mut self, error: F) {
+        for slot in self.slots.values_mut() {
+            if slot.is_none() {
+                *slot = Some(Err(error()));
+            }
+        }
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReadIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRead as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Takes the slot of request `unique` once it is completed, or once `cancel`
+    /// is cancelled, in which case the slot may still be empty.
+    ///
+    /// Returns `None` while the request is to be waited for.
+    fn take(&mut self, unique: u64, cancel: Option<&CancelToken>) -> Option<ReplySlot> {
+        let completed = self.slots.get(&unique)?.is_some();
+        if completed || cancel.is_some_and(CancelToken::is_cancelled) {
+            return self.slots.remove(&unique);
+        }
+        None
+    }
+}
 
-        let readin = FuseReadIn {
-            fh: fh,
-            offset: offset,
-            size: size,
-            read_flags: 0,
-            lock_owner: 0,
-            flags: 0,
-            padding: 0,
-        };
+/// The state of the FUSE session with the server, shared by all request queues.
+///
+/// It hands out the `unique` of each request, keeps the requests whose callers
+/// wait for a reply, and holds what the `FUSE_INIT` exchange settled.
+struct FuseConnection {
+    next_unique: AtomicU64,
+    /// Replies to the requests sent by `send_sync`, keyed by `unique`.
+    pending_replies: SpinLock<InflightTable>,
+    /// Always locked after `pending_replies`, if both are.
+    abort_state: SpinLock<AbortState>,
+    init_state: SpinLock<InitState>,
+    /// The parameters of the connection, set once `FUSE_INIT` is answered.
+    negotiated: SpinLock<Option<NegotiatedInit>>,
+    /// The deadlines of the waiters of `RequestHandle::wait_timeout`, along with
+    /// the unique of their request.
+    ///
+    /// It is shared with the timer callback, as is `reply_wait_queue`, so that
+    /// the callback does not keep the device alive.
+    deadlines: Arc<SpinLock<BTreeSet<(Duration, u64)>>>,
+    /// Woken up whenever a reply arrives or a request may be sent again.
+    reply_wait_queue: Arc<WaitQueue>,
+}
 
-        let headerin_bytes = headerin.as_bytes();
-        let readin_bytes = readin.as_bytes();
-        // let readin_bytes = [0u8; 36];
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let readout_bytes = [0u8; 1024];
-        let concat_req = [
-            headerin_bytes,
-            &readin_bytes,
-            &headerout_buffer,
-            &readout_bytes,
-        ]
-        .concat();
+impl FuseConnection {
+    fn new() -> Self {
+        Self {
+            // Unique 0 is left to the requests whose replies nobody waits for.
+            next_unique: AtomicU64::new(1),
+            pending_replies: SpinLock::new(InflightTable::default()),
+            abort_state: SpinLock::new(AbortState::default()),
+            init_state: SpinLock::new(InitState::default()),
+            negotiated: SpinLock::new(None),
+            deadlines: Arc::new(SpinLock::new(BTreeSet::new())),
+            reply_wait_queue: Arc::new(WaitQueue::new()),
+        }
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReadIn>() + size_of::<FuseInHeader>();
+    fn next_unique(&self) -> u64 {
+        self.next_unique.fetch_add(1, Ordering::Relaxed)
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    fn is_ready(&self) -> bool {
+        *self.init_state.disable_irq().lock() == InitState::Ready
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn negotiated(&self) -> Option<NegotiatedInit> {
+        *self.negotiated.disable_irq().lock()
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Returns whether a request may be sent, or `None` if it must wait for
+    /// the connection to be established or reestablished.
+    fn admit(&self) -> Option<Result<(), FilesystemError>> {
+        match self.abort_state.disable_irq().lock().admit() {
+            Some(Ok(())) => self.init_state.disable_irq().lock().admit(),
+            not_admitted => not_admitted,
         }
     }
 
-    fn open(&self, nodeid: u64, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseOpenIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseOpen as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    /// Records the outcome of `FUSE_INIT`, which is `None` if it failed, and
+    /// lets the requests held for it through.
+    fn finish_init(&self, negotiated: Option<NegotiatedInit>) {
+        if *self.init_state.disable_irq().lock() == InitState::ShutDown {
+            return;
+        }
+        let init_state = if negotiated.is_some() {
+            InitState::Ready
+        } else {
+            InitState::Failed
         };
+        if let Some(negotiated) = negotiated {
+            self.abort_state.disable_irq().lock().abort_error = negotiated.has(FUSE_ABORT_ERROR);
+        }
+        *self.negotiated.disable_irq().lock() = negotiated;
+        *self.init_state.disable_irq().lock() = init_state;
+        self.reply_wait_queue.wake_all();
+    }
 
-        let openin = FuseOpenIn {
-            flags: flags,
-            open_flags: 0,
-        };
+    fn abort(&self) {
+        let mut pending_replies = self.pending_replies.disable_irq().lock();
+        self.abort_state
+            .disable_irq()
+            .lock()
+            .abort(&mut pending_replies);
+        drop(pending_replies);
+        self.reply_wait_queue.wake_all();
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let openin_bytes = openin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let openout_bytes = [0u8; size_of::<FuseOpenOut>()];
-        let concat_req = [
-            headerin_bytes,
-            openin_bytes,
-            &headerout_buffer,
-            &openout_bytes,
-        ]
-        .concat();
+    fn reconnect(&self) {
+        self.abort_state.disable_irq().lock().reconnect();
+        self.reply_wait_queue.wake_all();
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseOpenIn>() + size_of::<FuseInHeader>();
+    /// Returns the error requests fail with if the connection is aborted.
+    fn abort_error(&self) -> Option<FilesystemError> {
+        let abort_state = self.abort_state.disable_irq().lock();
+        abort_state.aborted.then(|| abort_state.error())
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Lets no request through anymore, returning whether the connection was
+    /// established until now.
+    fn shut_down(&self) -> bool {
+        let init_state = core::mem::replace(
+            &mut *self.init_state.disable_irq().lock(),
+            InitState::ShutDown,
+        );
+        self.reply_wait_queue.wake_all();
+        init_state == InitState::Ready
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Waits until `cond` returns `Some`, or gives up with `None` once `timeout` passes.
+    ///
+    /// `key` tells the deadline apart from those of the other waiters, e.g. it is
+    /// the unique of the request waited for.
+    fn wait_timeout<R, F>(&self, key: u64, timeout: Duration, mut cond: F) -> Option<R>
+    where
+        F: FnMut() -> Option<R>,
+    {
+        let deadline = now().saturating_add(timeout);
+        self.deadlines.disable_irq().lock().insert((deadline, key));
+
+        let (waiter, _) = Waiter::new_pair();
+        let result = waiter.wait_until_or_cancelled(
+            || {
+                self.reply_wait_queue.enqueue(waiter.waker());
+                cond()
+            },
+            || if now() < deadline { Ok(()) } else { Err(()) },
+        );
+        self.deadlines.disable_irq().lock().remove(&(deadline, key));
+        result.ok()
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+    /// Wakes up the waiters whose deadline has passed.
+    fn wake_timed_out(&self) {
+        wake_timed_out(&self.deadlines, &self.reply_wait_queue);
     }
+}
 
-    fn flush(&self, nodeid: u64, fh: u64, lock_owner: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+/// Wakes up the waiters on `wait_queue` if the first of `deadlines` has
+/// passed, upon each timer tick.
+fn wake_timed_out(deadlines: &SpinLock<BTreeSet<(Duration, u64)>>, wait_queue: &WaitQueue) {
+    let expired = deadlines
+        .disable_irq()
+        .lock()
+        .first()
+        .is_some_and(|&(deadline, _)| deadline <= now());
+    if expired {
+        wait_queue.wake_all();
+    }
+}
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseFlushIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseFlush as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+/// How the device behaves once the connection is aborted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AbortPolicy {
+    /// Fails the requests with `ECONNABORTED`, as `FUSE_ABORT_ERROR` does, instead of `EIO`.
+    pub conn_aborted: bool,
+    /// Holds the requests made after the abort until `reconnect`, instead of failing them.
+    pub block_until_reconnect: bool,
+}
 
-        let flushin = FuseFlushIn {
-            fh: fh,
-            lock_owner: lock_owner,
-            padding: 0,
-            unused: 0,
-        };
+/// The progress of the `FUSE_INIT` handshake, which all other requests wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum InitState {
+    #[default]
+    Unsent,
+    Sent,
+    Ready,
+    Failed,
+    /// The connection is shut down with `shutdown`, for good.
+    ShutDown,
+}
 
-        let headerin_bytes = headerin.as_bytes();
-        let flushin_bytes = flushin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        // let flushout_bytes = [0u8; size_of::<FuseFlushOut>()];
-        let concat_req = [
-            headerin_bytes,
-            flushin_bytes,
-            &headerout_buffer,
-            // &flushout_bytes,
-        ]
-        .concat();
+impl InitState {
+    /// Returns whether a request may be sent, or `None` if it must wait for the reply to `FUSE_INIT`.
+    fn admit(self) -> Option<Result<(), FilesystemError>> {
+        match self {
+            Self::Ready => Some(Ok(())),
+            Self::Sent => None,
+            Self::Unsent | Self::Failed | Self::ShutDown => {
+                Some(Err(FilesystemError::InvalidOperation))
+            }
+        }
+    }
+}
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseFlushIn>() + size_of::<FuseInHeader>();
+#[derive(Debug, Default)]
+struct AbortState {
+    policy: AbortPolicy,
+    /// Whether `FUSE_ABORT_ERROR` is negotiated, which fails the requests with
+    /// `ECONNABORTED` whatever the policy.
+    abort_error: bool,
+    aborted: bool,
+}
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+impl AbortState {
+    /// Marks the connection aborted and fails the requests in `pending_replies`.
+    fn abort(&mut self, pending_replies: &mut InflightTable) {
+        self.aborted = true;
+        pending_replies.fail_all(|| self.error());
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn reconnect(&mut self) {
+        self.aborted = false;
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Returns whether a new request may be sent, or `None` if it must wait for `reconnect`.
+    fn admit(&self) -> Option<Result<(), FilesystemError>> {
+        if !self.aborted {
+            Some(Ok(()))
+        } else if self.policy.block_until_reconnect {
+            None
+        } else {
+            Some(Err(self.error()))
         }
     }
 
-    fn releasedir(&self, nodeid: u64, fh: u64, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn error(&self) -> FilesystemError {
+        if self.policy.conn_aborted || self.abort_error {
+            FilesystemError::Fuse {
+                errno: ECONNABORTED,
+            }
+        } else {
+            FilesystemError::Fuse { errno: EIO }
+        }
+    }
+}
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReleaseIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseReleasedir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+/// An open of a handle kept in `HandleTable`.
+#[derive(Debug, Clone, Copy)]
+struct OpenHandle {
+    open_flags: FopenFlags,
+    /// The number of opens the handle was returned by, as a server may return
+    /// the same one more than once, e.g. 0 if it keeps no handles.
+    opens: u32,
+}
 
-        let releasein = FuseReleaseIn {
-            fh: fh,
-            flags: flags,
-            release_flags: 0,
-            lock_owner: 0,
-        };
+/// The file and directory handles opened on the server and not released yet,
+/// keyed by the node they were opened on and by handle.
+///
+/// The handles passed to the requests are checked against it, which turns the
+/// use of a released handle into an error instead of a request about whatever
+/// the server reused the handle for.
+#[derive(Debug, Default)]
+struct HandleTable {
+    handles: BTreeMap<(u64, u64), OpenHandle>,
+}
 
-        let headerin_bytes = headerin.as_bytes();
-        let releasein_bytes = releasein.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        // let releaseout_bytes = [0u8; size_of::<FuseReleaseOut>()];
-        let concat_req = [
-            headerin_bytes,
-            releasein_bytes,
-            &headerout_buffer,
-            // &releaseout_bytes,
-        ]
-        .concat();
+impl HandleTable {
+    /// Records an open of node `nodeid` returning `fh`, answered with `open_flags`.
+    fn insert(&mut self, nodeid: u64, fh: u64, open_flags: FopenFlags) {
+        let handle = self.handles.entry((nodeid, fh)).or_insert(OpenHandle {
+            open_flags: open_flags,
+            opens: 0,
+        });
+        handle.open_flags = open_flags;
+        handle.opens += 1;
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReleaseIn>() + size_of::<FuseInHeader>();
+    /// Forgets an open of `fh`, returning whether it was open.
+    fn remove(&mut self, nodeid: u64, fh: u64) -> bool {
+        let Some(handle) = self.handles.get_mut(&(nodeid, fh)) else {
+            return false;
+        };
+        handle.opens -= 1;
+        if handle.opens == 0 {
+            self.handles.remove(&(nodeid, fh));
+        }
+        true
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Returns the flags `fh` was last opened with, or `None` if it is not open.
+    fn open_flags(&self, nodeid: u64, fh: u64) -> Option<FopenFlags> {
+        self.handles
+            .get(&(nodeid, fh))
+            .map(|handle| handle.open_flags)
+    }
+}
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+/// The forgets of the nodes left without references, which are sent together
+/// with `FUSE_BATCH_FORGET` rather than one `FUSE_FORGET` per node.
+///
+/// Forgets of the same node are coalesced into one, which forgets all their lookups.
+#[derive(Debug, Default)]
+struct ForgetBatch {
+    /// The `(nodeid, nlookup)` of each node to forget, in the order they came in.
+    forgets: Vec<(u64, u64)>,
+}
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+impl ForgetBatch {
+    /// Queues forgetting `nlookup` lookups of node `nodeid`.
+    ///
+    /// Returns whether `FORGET_FLUSH_THRESHOLD` nodes are queued, and so the
+    /// batch is due to be sent.
+    fn push(&mut self, nodeid: u64, nlookup: u64) -> bool {
+        match self.forgets.iter_mut().find(|(id, _)| *id == nodeid) {
+            Some((_, queued)) => *queued = queued.saturating_add(nlookup),
+            None => self.forgets.push((nodeid, nlookup)),
         }
+        self.forgets.len() >= FORGET_FLUSH_THRESHOLD
     }
 
-    fn getattr(&self, nodeid: u64, fh: u64, flags: u32, dummy: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    /// Takes the queued forgets out, to be sent.
+    fn take(&mut self) -> Vec<(u64, u64)> {
+        core::mem::take(&mut self.forgets)
+    }
+}
 
+/// The outcome of an ioctl made with `FilesystemDevice::ioctl_sync`.
+#[derive(Debug, Clone, Default)]
+pub struct IoctlReply {
+    /// The value the ioctl returned.
+    pub result: i32,
+    /// The data the ioctl wrote back.
+    pub out_data: Vec<u8>,
+    /// The buffers of the caller, one after the other, that `out_data` is to be
+    /// copied to, as asked for by the server in its last retry. It is empty
+    /// unless the ioctl was retried.
+    pub out_iovs: Vec<FuseIoctlIovec>,
+}
+
+/// A reply to `FUSE_IOCTL`, which either ends the ioctl or asks to make it again.
+#[derive(Debug)]
+enum IoctlStep {
+    Done {
+        result: i32,
+        out_data: Vec<u8>,
+    },
+    /// The server asks for the data of the caller in `in_iovs`, and for room for
+    /// the data it writes back to `out_iovs`.
+    Retry {
+        in_iovs: Vec<FuseIoctlIovec>,
+        out_iovs: Vec<FuseIoctlIovec>,
+    },
+}
+
+/// A file opened with `FilesystemDevice::open_sync`.
+#[derive(Debug, Clone, Copy)]
+pub struct OpenFileState {
+    pub nodeid: u64,
+    pub fh: u64,
+    /// The flags the file was opened with, e.g. `O_APPEND`.
+    pub flags: u32,
+    /// The flags the server answered the open with.
+    pub open_flags: FopenFlags,
+    /// The backing file the server set up for passthrough I/O of the file, if any.
+    pub backing_id: Option<i32>,
+}
+
+impl OpenFileState {
+    /// Builds the state of file `nodeid` opened with `flags`, from the reply `openout`.
+    ///
+    /// The backing file in `openout` is only taken once `FUSE_PASSTHROUGH` is
+    /// in `negotiated_flags` and the reply has `FOPEN_PASSTHROUGH`. A negative
+    /// `backing_id` means there is none.
+    fn new(nodeid: u64, flags: u32, openout: &FuseOpenOut, negotiated_flags: u64) -> Self {
+        let open_flags = FopenFlags::from_bits_truncate(openout.open_flags);
+        let passthrough = negotiated_flags & FUSE_PASSTHROUGH != 0
+            && open_flags.contains(FopenFlags::FOPEN_PASSTHROUGH);
+        Self {
+            nodeid: nodeid,
+            fh: openout.fh,
+            flags: flags,
+            open_flags: open_flags,
+            backing_id: Some(openout.backing_id).filter(|&id| passthrough && id >= 0),
+        }
+    }
+
+    /// Returns whether the writes go to the end of the file.
+    pub fn is_append(&self) -> bool {
+        self.flags & O_APPEND != 0
+    }
+
+    /// Returns whether the reads and writes of the file are to bypass the page
+    /// cache, as the server answered the open with `FOPEN_DIRECT_IO`.
+    pub fn is_direct_io(&self) -> bool {
+        self.open_flags.contains(FopenFlags::FOPEN_DIRECT_IO)
+    }
+}
+
+/// Lets a caller give up on the request it is blocked on, e.g. to handle a signal.
+///
+/// The token is passed to the requests with `FilesystemDevice::with_cancel`, and
+/// cancelled with `FilesystemDevice::cancel`. A cancelled request fails with
+/// `EINTR`, and the server is sent a `FUSE_INTERRUPT` if it already has the
+/// request. A token stays cancelled, so it is meant for a single operation.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+}
+
+/// The waiters of the polls scheduled with `FUSE_POLL_SCHEDULE_NOTIFY`.
+///
+/// It is registered as the first callback of the device, so that a
+/// `FUSE_NOTIFY_POLL` wakes up the waiters of its poll handle.
+struct PollWaiters {
+    /// The handles notified since their poll was last scheduled.
+    woken: SpinLock<BTreeSet<u64>>,
+    wait_queue: WaitQueue,
+}
+
+impl PollWaiters {
+    fn new() -> Self {
+        Self {
+            woken: SpinLock::new(BTreeSet::new()),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    /// Forgets the notifications of `kh` before its poll is scheduled again.
+    fn schedule(&self, kh: u64) {
+        self.woken.disable_irq().lock().remove(&kh);
+    }
+
+    /// Waits for `kh` to be notified since its poll was last scheduled.
+    fn wait(&self, kh: u64) {
+        self.wait_queue
+            .wait_until(|| self.woken.disable_irq().lock().remove(&kh).then_some(()));
+    }
+}
+
+impl FilesystemCallback for PollWaiters {
+    fn invalidate_inode(&self, _ino: u64, _off: i64, _len: i64) {}
+
+    fn invalidate_entry(&self, _parent: u64, _name: &[u8]) {}
+
+    fn delete(&self, _parent: u64, _child: u64, _name: &[u8]) {}
+
+    fn poll_wakeup(&self, kh: u64) {
+        self.woken.disable_irq().lock().insert(kh);
+        self.wait_queue.wake_all();
+    }
+}
+
+/// Drops what the device caches of the nodes and entries the server invalidates,
+/// before the registered callbacks hear of it.
+struct DriverCaches<'a>(&'a FilesystemDevice);
+
+impl FilesystemCallback for DriverCaches<'_> {
+    fn invalidate_inode(&self, ino: u64, off: i64, _len: i64) {
+        self.0.cache.disable_irq().lock().invalidate_attr(ino);
+        // A negative offset leaves the data alone.
+        if off >= 0 {
+            self.0.stored_data.disable_irq().lock().remove(ino);
+        }
+    }
+
+    fn invalidate_entry(&self, parent: u64, name: &[u8]) {
+        self.drop_entry(parent, name);
+    }
+
+    fn delete(&self, parent: u64, child: u64, name: &[u8]) {
+        self.drop_entry(parent, name);
+        // The link count of the node changed, whether or not its entry was cached.
+        self.0.cache.disable_irq().lock().invalidate_attr(child);
+    }
+
+    fn poll_wakeup(&self, _kh: u64) {}
+}
+
+impl DriverCaches<'_> {
+    /// Drops the entry of `name` in `parent` and the reference it holds.
+    ///
+    /// This runs in interrupt context, so the forget is left to the next flush.
+    fn drop_entry(&self, parent: u64, name: &[u8]) {
+        let forget = self.0.cache.disable_irq().lock().remove_entry(parent, name);
+        self.0.drop_node_refs(forget);
+    }
+}
+
+/// A snapshot of the identity and negotiated parameters of a virtio-fs device.
+#[derive(Debug, Clone)]
+pub struct FilesystemDeviceInfo {
+    /// The tag the device is exported under, used as the mount source.
+    pub tag: String,
+    /// The negotiated virtio feature bits.
+    pub features: FilesystemFeatures,
+    /// The negotiated FUSE init flags, with `flags2` in the upper 32 bits.
+    pub fuse_flags: u64,
+    /// The FUSE protocol major version, or 0 if INIT has not completed.
+    pub major: u32,
+    /// The FUSE protocol minor version, or 0 if INIT has not completed.
+    pub minor: u32,
+    pub max_write: u32,
+    pub max_readahead: u32,
+    pub num_request_queues: u16,
+    pub notify_buf_size: u32,
+    pub request_buffer_size: usize,
+    /// The number of attributes, and of entries, that may be cached.
+    pub cache_capacity: usize,
+}
+
+impl FilesystemDeviceInfo {
+    fn new(
+        config: &VirtioFilesystemConfig,
+        features: FilesystemFeatures,
+        negotiated: Option<&NegotiatedInit>,
+        num_request_queues: u16,
+        request_buffer_size: usize,
+        cache_capacity: usize,
+    ) -> Self {
+        let negotiated = negotiated.copied().unwrap_or_default();
+        Self {
+            tag: config.tag(),
+            features: features,
+            fuse_flags: negotiated.fuse_flags(),
+            major: negotiated.major,
+            minor: negotiated.minor,
+            max_write: negotiated.max_write,
+            max_readahead: negotiated.max_readahead,
+            num_request_queues: num_request_queues,
+            notify_buf_size: config.notify_buf_size,
+            request_buffer_size: request_buffer_size,
+            cache_capacity: cache_capacity,
+        }
+    }
+}
+
+impl AnyFuseDevice for FilesystemDevice {
+    fn init(&self) -> Result<(), FilesystemError> {
+        let queue_idx = self.select_queue();
+
+        let (headerin, initin) = build_init_request(init_flags().bits(), FUSE_MAX_READAHEAD);
+        let mut init_state = self.connection.init_state.disable_irq().lock();
+        if *init_state != InitState::Unsent {
+            return Err(FilesystemError::InvalidOperation);
+        }
+
+        let req = VirtioFsReq {
+            headerin: headerin,
+            datain: initin.as_bytes().to_vec(),
+            headerout: FuseOutHeader::new_zeroed(),
+            dataout: vec![0u8; 256],
+        };
+
+        self.send_unawaited(queue_idx, req.headerin, &[&req.datain], req.dataout.len())?;
+        // The reply cannot be handled before `init_state` is unlocked.
+        *init_state = InitState::Sent;
+
+        Ok(())
+    }
+
+    fn opendir(&self, nodeid: u64, flags: u32) -> Result<(), FilesystemError> {
+        // The directory is open right away, under the handle `readdir` accepts.
+        if self.has_negotiated(FUSE_NO_OPENDIR_SUPPORT) {
+            self.open_handles.disable_irq().lock().insert(
+                nodeid,
+                NO_OPENDIR_FH,
+                FopenFlags::empty(),
+            );
+            return Ok(());
+        }
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseOpenIn>() as u32 + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseOpendir as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let openin = FuseOpenIn {
+            flags: flags,
+            open_flags: 0,
+        };
+
+        let openin_bytes = openin.as_bytes();
+
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[openin_bytes],
+            size_of::<FuseOpenOut>(),
+        )
+    }
+
+    fn readdir(&self, nodeid: u64, fh: u64, offset: u64, size: u32) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseReadIn>() as u32 + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseReaddir as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let readin = FuseReadIn {
+            fh: fh,
+            offset: offset,
+            size: size,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+
+        let readin_bytes = readin.as_bytes();
+
+        self.send_unawaited(queue_idx, headerin, &[&readin_bytes], size as usize)
+    }
+
+    fn read(&self, nodeid: u64, fh: u64, offset: u64, size: u32) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseReadIn>() as u32 + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseRead as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let readin = FuseReadIn {
+            fh: fh,
+            offset: offset,
+            size: size,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+
+        let readin_bytes = readin.as_bytes();
+
+        self.send_unawaited(queue_idx, headerin, &[&readin_bytes], size as usize)
+    }
+
+    fn open(&self, nodeid: u64, flags: u32) -> Result<(), FilesystemError> {
+        if self.no_open.load(Ordering::Relaxed) {
+            self.record_open(nodeid, NO_OPEN_FH, FopenFlags::FOPEN_KEEP_CACHE);
+            return Ok(());
+        }
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseOpenIn>() as u32 + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseOpen as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let openin = FuseOpenIn {
+            flags: flags,
+            open_flags: kill_suidgid_open_flags(self.negotiated_flags(), flags, false),
+        };
+
+        let openin_bytes = openin.as_bytes();
+
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[openin_bytes],
+            size_of::<FuseOpenOut>(),
+        )
+    }
+
+    fn flush(&self, nodeid: u64, fh: u64, lock_owner: u64) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseFlushIn>() as u32 + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseFlush as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let flushin = FuseFlushIn {
+            fh: fh,
+            lock_owner: lock_owner,
+            padding: 0,
+            unused: 0,
+        };
+
+        let flushin_bytes = flushin.as_bytes();
+        // let flushout_bytes = [0u8; size_of::<FuseFlushOut>()];
+
+        self.send_unawaited(queue_idx, headerin, &[flushin_bytes], 0)
+    }
+
+    fn releasedir(&self, nodeid: u64, fh: u64, flags: u32) -> Result<(), FilesystemError> {
+        self.release_handle(nodeid, fh)?;
+        // The server never opened the directory, so there is nothing to release.
+        if fh == NO_OPENDIR_FH && self.has_negotiated(FUSE_NO_OPENDIR_SUPPORT) {
+            return Ok(());
+        }
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseReleaseIn>() as u32 + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseReleasedir as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
+
+        let releasein = FuseReleaseIn {
+            fh: fh,
+            flags: flags,
+            release_flags: 0,
+            lock_owner: 0,
+        };
+
+        let releasein_bytes = releasein.as_bytes();
+        // let releaseout_bytes = [0u8; size_of::<FuseReleaseOut>()];
+
+        self.send_unawaited(queue_idx, headerin, &[releasein_bytes], 0)
+    }
+
+    fn getattr(&self, nodeid: u64, fh: u64, flags: u32, dummy: u32) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseGetattrIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseGetattr as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -428,40 +1246,20 @@ impl AnyFuseDevice for FilesystemDevice {
             fh: fh,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let getattrin_bytes = getattrin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let getattrout_bytes = [0u8; size_of::<FuseAttrOut>()];
-        let concat_req = [
-            headerin_bytes,
-            getattrin_bytes,
-            &headerout_buffer,
-            &getattrout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseGetattrIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[getattrin_bytes],
+            size_of::<FuseAttrOut>(),
+        )
     }
 
     fn setattr(
         &self,
         nodeid: u64,
-        valid: u32,
+        valid: FattrFlags,
         fh: u64,
         size: u64,
         lock_owner: u64,
@@ -474,21 +1272,22 @@ impl AnyFuseDevice for FilesystemDevice {
         mode: u32,
         uid: u32,
         gid: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32 + size_of::<FuseSetattrIn>() as u32),
             opcode: FuseOpcode::FuseSetattr as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
         let setattrin = FuseSetattrIn {
-            valid: valid,
+            valid: valid.bits(),
             padding: 0,
             fh: fh,
             size: size,
@@ -506,104 +1305,76 @@ impl AnyFuseDevice for FilesystemDevice {
             unused5: 0,
         };
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let setattrout_buffer = [0u8; size_of::<FuseAttrOut>()];
-
-        let headerin_bytes = headerin.as_bytes();
         let setattrin_bytes = setattrin.as_bytes();
-        let concat_req = [
-            headerin_bytes,
-            setattrin_bytes,
-            &headerout_buffer,
-            &setattrout_buffer,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseSetattrIn>() + size_of::<FuseInHeader>();
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[setattrin_bytes],
+            size_of::<FuseAttrOut>(),
+        )
     }
 
-    fn lookup(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn lookup(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
         // // add terminating '\0' to the name
         // let mut name = name;
         // name.push(0);
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let prepared_name = fuse_pad_str(&name, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32 + prepared_name.len() as u32),
             opcode: FuseOpcode::FuseLookup as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let lookupin_bytes = prepared_name.as_slice();
 
         // early_println!("lookup name: {:?}", name);
         // early_println!("headerin_bytes: {:?}", headerin_bytes);
         // early_println!("lookupin_bytes: {:?}", lookupin_bytes);
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let lookupout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            lookupin_bytes,
-            &headerout_buffer,
-            &lookupout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[lookupin_bytes],
+            size_of::<FuseEntryOut>(),
+        )
     }
 
-    fn release(&self, nodeid: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn release(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<(), FilesystemError> {
+        self.release_handle(nodeid, fh)?;
+        // The server never opened the file, so there is nothing to release.
+        if fh == NO_OPEN_FH && self.no_open.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseReleaseIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseRelease as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -615,47 +1386,24 @@ impl AnyFuseDevice for FilesystemDevice {
             lock_owner: lock_owner,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let releasein_bytes = releasein.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
         // let releaseout_bytes = [0u8; size_of::<FuseReleaseOut>()];
-        let concat_req = [
-            headerin_bytes,
-            releasein_bytes,
-            &headerout_buffer,
-            // &releaseout_bytes,
-        ]
-        .concat();
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReleaseIn>() + size_of::<FuseInHeader>();
+        self.send_unawaited(queue_idx, headerin, &[releasein_bytes], 0)
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
-    }
-
-    fn access(&self, nodeid: u64, mask: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn access(&self, nodeid: u64, mask: u32) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseAccessIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseAccess as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -665,130 +1413,63 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let accessin_bytes = accessin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let accessout_bytes = [0u8; size_of::<FuseAttrOut>()];
-        let concat_req = [
-            headerin_bytes,
-            accessin_bytes,
-            &headerout_buffer,
-            &accessout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseAccessIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[accessin_bytes], 0)
     }
 
-    fn statfs(&self, nodeid: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn statfs(&self, nodeid: u64) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseStatfs as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let statfsout_bytes = [0u8; size_of::<FuseStatfsOut>()];
-        let concat_req = [headerin_bytes, &headerout_buffer, &statfsout_bytes].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[], size_of::<FuseStatfsOut>())
     }
 
-    fn interrupt(&self, unique: u64) {
-        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInterruptIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseInterrupt as u32,
-            unique: unique,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let interruptin = FuseInterruptIn { unique: unique };
-
-        let headerin_bytes = headerin.as_bytes();
-        let interruptin_bytes = interruptin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, interruptin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInterruptIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        hiprio_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if hiprio_queue.should_notify() {
-            hiprio_queue.notify();
-        }
+    fn interrupt(&self, unique: u64) -> Result<(), FilesystemError> {
+        self.send_interrupt(unique)?;
+        Ok(())
     }
 
-    fn mkdir(&self, nodeid: u64, mode: u32, umask: u32, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn mkdir(
+        &self,
+        nodeid: u64,
+        mode: u32,
+        umask: u32,
+        name: Vec<u8>,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+
+        let prepared_name = fuse_pad_str(&name, true);
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let (extensions, total_extlen) = self.create_extensions(secctx, supp_groups)?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseMkdirIn>() as u32
                 + prepared_name.len() as u32
+                + extensions.len() as u32
                 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseMkdir as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: total_extlen,
             padding: 0,
         };
 
@@ -797,55 +1478,46 @@ impl AnyFuseDevice for FilesystemDevice {
             umask: umask,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let mkdirin_bytes = mkdirin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let mkdirout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            mkdirin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &mkdirout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseMkdirIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[mkdirin_bytes, prepared_name_bytes, &extensions],
+            size_of::<FuseEntryOut>(),
+        )
     }
 
-    fn create(&self, nodeid: u64, name: Vec<u8>, mode: u32, umask: u32, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn create(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+
+        let prepared_name = fuse_pad_str(&name, true);
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let (extensions, total_extlen) = self.create_extensions(secctx, supp_groups)?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseCreateIn>() as u32
                 + prepared_name.len() as u32
+                + extensions.len() as u32
                 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseCreate as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: total_extlen,
             padding: 0,
         };
 
@@ -856,41 +1528,21 @@ impl AnyFuseDevice for FilesystemDevice {
             open_flags: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let createin_bytes = createin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let createout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            createin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &createout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseCreateIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        // The reply is a `FuseEntryOut` followed by a `FuseOpenOut`.
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[createin_bytes, prepared_name_bytes, &extensions],
+            size_of::<FuseEntryOut>() + size_of::<FuseOpenOut>(),
+        )
     }
 
-    fn destroy(&self) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn destroy(&self) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32),
@@ -904,41 +1556,25 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[], 0)
     }
 
-    fn rename(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn rename(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
         // combine the old and new names
 
-        let names = format!(
-            "{}\0{}",
-            String::from_utf8(name).unwrap(),
-            String::from_utf8(newname).unwrap()
-        );
+        let names = [name.as_slice(), b"\0", newname.as_slice()].concat();
 
         let prepared_names = fuse_pad_str(&names, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseRenameIn>() as u32
                 + prepared_names.len() as u32
@@ -946,59 +1582,45 @@ impl AnyFuseDevice for FilesystemDevice {
             opcode: FuseOpcode::FuseRename as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
         let renamein = FuseRenameIn { newdir: newdir };
 
-        let headerin_bytes = headerin.as_bytes();
         let renamein_bytes = renamein.as_bytes();
         let prepared_names_bytes = prepared_names.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let renameout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            renamein_bytes,
-            prepared_names_bytes,
-            &headerout_buffer,
-            &renameout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_names.len() + size_of::<FuseRenameIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[renamein_bytes, prepared_names_bytes],
+            0,
+        )
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    fn rename2(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+        flags: u32,
+    ) -> Result<(), FilesystemError> {
+        if let FuseOpcode::FuseRename = rename_opcode(flags, self.protocol_minor())? {
+            return self.rename(nodeid, name, newdir, newname);
         }
-    }
 
-    fn rename2(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+        let queue_idx = self.ready_queue()?;
 
-        let names = format!(
-            "{}\0{}",
-            String::from_utf8(name).unwrap(),
-            String::from_utf8(newname).unwrap()
-        );
+        let names = [name.as_slice(), b"\0", newname.as_slice()].concat();
 
         let prepared_names = fuse_pad_str(&names, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseRename2In>() as u32
                 + prepared_names.len() as u32
@@ -1006,9 +1628,9 @@ impl AnyFuseDevice for FilesystemDevice {
             opcode: FuseOpcode::FuseRename2 as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1019,50 +1641,32 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let rename2in_bytes = rename2in.as_bytes();
         let prepared_names_bytes = prepared_names.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let rename2out_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            rename2in_bytes,
-            prepared_names_bytes,
-            &headerout_buffer,
-            &rename2out_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_names.len() + size_of::<FuseRename2In>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[rename2in_bytes, prepared_names_bytes],
+            0,
+        )
     }
 
-    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let data = [data, vec![0u8; (8 - (data.len() & 0x7)) & 0x7].as_slice()].concat();
+    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
+        // The data is sent as is: padding it would have the server write the padding too.
+        let size = u32::try_from(data.len()).map_err(|_| FilesystemError::InvalidOperation)?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
-            len: size_of::<FuseInHeader>() as u32
-                + size_of::<FuseWriteIn>() as u32
-                + data.len() as u32,
+            len: size_of::<FuseInHeader>() as u32 + size_of::<FuseWriteIn>() as u32 + size,
             opcode: FuseOpcode::FuseWrite as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1070,136 +1674,75 @@ impl AnyFuseDevice for FilesystemDevice {
         let writein = FuseWriteIn {
             fh: fh,
             offset: offset,
-            size: data.len() as u32,
-            write_flags: FUSE_WRITE_LOCKOWNER,
+            size: size,
+            // No lock owner is known, so `lock_owner` is not claimed to be valid.
+            write_flags: kill_suidgid_write_flags(self.negotiated_flags(), false),
             lock_owner: 0,
             flags: 0,
             padding: 0,
         };
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let writeout_buffer = [0u8; size_of::<FuseWriteOut>()];
-
-        let data_bytes = data.as_slice();
         let writein_bytes = writein.as_bytes();
-        let headerin_bytes = headerin.as_bytes();
-        let concat_req = [
-            headerin_bytes,
-            writein_bytes,
-            data_bytes,
-            &headerout_buffer,
-            &writeout_buffer,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseWriteIn>() + size_of::<FuseInHeader>() + data.len() as usize;
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in as usize);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in as usize, len);
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[writein_bytes, data],
+            size_of::<FuseWriteOut>(),
+        )
     }
 
-    fn forget(&self, nodeid: u64, nlookup: u64) {
-        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseForgetIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseForget as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let forgetin = FuseForgetIn { nlookup: nlookup };
-
-        let headerin_bytes = headerin.as_bytes();
-        let forgetin_bytes = forgetin.as_bytes();
+    fn forget(&self, nodeid: u64, nlookup: u64) -> Result<(), FilesystemError> {
+        let forget_bytes = encode_forget(nodeid, nlookup);
         let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, forgetin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseForgetIn>() + size_of::<FuseInHeader>();
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        let len_in = forget_bytes.len();
 
-        hiprio_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if hiprio_queue.should_notify() {
-            hiprio_queue.notify();
-        }
+        self.submit_hiprio(
+            FuseOpcode::FuseForget,
+            &[forget_bytes.as_slice(), &headerout_buffer],
+            len_in,
+        )?;
+        Ok(())
     }
 
-    fn batch_forget(&self, forget_list: &[(u64, u64)]) {
-        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseBatchForgetIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseBatchForget as u32,
-            unique: 0,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let mut forgetin_bytes = Vec::new();
-        for (nodeid, nlookup) in forget_list {
-            let forgetin = FuseForgetOne {
-                nodeid: *nodeid,
-                nlookup: *nlookup,
-            };
-            forgetin_bytes.extend_from_slice(&forgetin.as_bytes());
-        }
-
-        let headerin_bytes = headerin.as_bytes();
+    fn batch_forget(&self, forget_list: &[(u64, u64)]) -> Result<(), FilesystemError> {
+        let batch_forget_bytes = encode_batch_forget(forget_list);
         let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &forgetin_bytes, &headerout_buffer].concat();
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = forget_list.len() * size_of::<FuseForgetOne>() + size_of::<FuseInHeader>();
+        let len_in = batch_forget_bytes.len();
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        self.submit_hiprio(
+            FuseOpcode::FuseBatchForget,
+            &[batch_forget_bytes.as_slice(), &headerout_buffer],
+            len_in,
+        )?;
+        Ok(())
+    }
 
-        hiprio_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn notify_reply(
+        &self,
+        notify_unique: u64,
+        nodeid: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
-        if hiprio_queue.should_notify() {
-            hiprio_queue.notify();
-        }
+        let notify_reply_bytes = encode_notify_reply(notify_unique, nodeid, offset, data);
+        let (headerin_bytes, datain) = notify_reply_bytes.split_at(size_of::<FuseInHeader>());
+        let headerin = FuseInHeader::from_bytes(headerin_bytes);
+
+        // This runs in the notification interrupt handler, which cannot wait for a slot.
+        self.try_send_unawaited(queue_idx, headerin, &[datain], 0)
     }
-    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+
+        let prepared_name = fuse_pad_str(&name, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseLinkIn>() as u32
                 + prepared_name.len() as u32
@@ -1207,9 +1750,9 @@ impl AnyFuseDevice for FilesystemDevice {
             opcode: FuseOpcode::FuseLink as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1218,97 +1761,51 @@ impl AnyFuseDevice for FilesystemDevice {
             oldnodeid: oldnodeid,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let linkin_bytes = linkin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let linkout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            linkin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &linkout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseLinkIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[linkin_bytes, prepared_name_bytes],
+            size_of::<FuseEntryOut>(),
+        )
     }
-    fn unlink(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn unlink(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let prepared_name = fuse_pad_str(&name, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (prepared_name.len() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseUnlink as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let unlinkout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &unlinkout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[prepared_name_bytes], 0)
     }
 
-    fn bmap(&self, nodeid: u64, blocksize: u32, index: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn bmap(&self, nodeid: u64, blocksize: u32, index: u64) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseBmapIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseBmap as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1319,47 +1816,37 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let bmapin_bytes = bmapin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let bmapout_bytes = [0u8; size_of::<FuseBmapOut>()];
-        let concat_req = [
-            headerin_bytes,
-            bmapin_bytes,
-            &headerout_buffer,
-            &bmapout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseBmapIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[bmapin_bytes],
+            size_of::<FuseBmapOut>(),
+        )
     }
 
-    fn fallocate(&self, nodeid: u64, fh: u64, offset: u64, length: u64, mode: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn fallocate(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<(), FilesystemError> {
+        check_fallocate_mode(mode)?;
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseFallocateIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseFallocate as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1372,31 +1859,14 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let fallocatein_bytes = fallocatein.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, fallocatein_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseFallocateIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[fallocatein_bytes], 0)
     }
 
-    fn fsync(&self, nodeid: u64, fh: u64, fsync_flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn fsync(&self, nodeid: u64, fh: u64, fsync_flags: u32) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
 
         let fsyncin = FuseFsyncIn {
             fh: fh,
@@ -1404,77 +1874,50 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32 + size_of::<FuseFsyncIn>() as u32),
-            opcode: FuseOpcode::FuseFsyncdir as u32,
+            opcode: FuseOpcode::FuseFsync as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
 
-        let headerin_bytes = headerin.as_bytes();
         let fsyncin_bytes = fsyncin.as_bytes();
 
-        let concat_req = [headerin_bytes, fsyncin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseFsyncIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[fsyncin_bytes], 0)
     }
 
-    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: u32) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
+
+        let fsyncin = FuseFsyncIn {
+            fh: fh,
+            fsync_flags: datasync,
+            padding: 0,
+        };
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
+            len: (size_of::<FuseInHeader>() as u32 + size_of::<FuseFsyncIn>() as u32),
             opcode: FuseOpcode::FuseFsyncdir as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        let fsyncin_bytes = fsyncin.as_bytes();
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[fsyncin_bytes], 0)
     }
 
     fn getlk(
@@ -1486,49 +1929,52 @@ impl AnyFuseDevice for FilesystemDevice {
         end: u64,
         typ: u32,
         pid: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    ) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
+            len: (size_of::<FuseLkIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseGetlk as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let getlkout_bytes = [0u8; size_of::<FuseLkOut>()];
-        let concat_req = [headerin_bytes, &headerout_buffer, &getlkout_bytes].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        let getlkin = fuse_lk_in(
+            fh,
+            lock_owner,
+            FuseFileLock {
+                start: start,
+                end: end,
+                type_: typ,
+                pid: pid,
+            },
+            false,
+        );
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        let getlkin_bytes = getlkin.as_bytes();
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[getlkin_bytes],
+            size_of::<FuseLkOut>(),
+        )
     }
 
-    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32) -> Result<(), FilesystemError> {
+        check_xattr_name(&name)?;
+        let queue_idx = self.ready_queue()?;
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let prepared_name = fuse_pad_str(&name, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseGetxattrIn>() as u32
                 + prepared_name.len() as u32
@@ -1536,9 +1982,9 @@ impl AnyFuseDevice for FilesystemDevice {
             opcode: FuseOpcode::FuseGetxattr as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1548,42 +1994,29 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let getxattrin_bytes = getxattrin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let getxattrout_bytes = [0u8; size_of::<FuseGetxattrOut>()];
-        let concat_req = [
-            headerin_bytes,
-            getxattrin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &getxattrout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseGetxattrIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[getxattrin_bytes, prepared_name_bytes],
+            size_of::<FuseGetxattrOut>(),
+        )
     }
 
-    fn ioctl(&self, nodeid: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8]) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
+    fn ioctl(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+    ) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
+
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseIoctlIn>() as u32
                 + in_data.len() as u32
@@ -1591,9 +2024,9 @@ impl AnyFuseDevice for FilesystemDevice {
             opcode: FuseOpcode::FuseIoctl as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1607,88 +2040,50 @@ impl AnyFuseDevice for FilesystemDevice {
             out_size: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let ioctlin_bytes = ioctlin.as_bytes();
         let in_data_bytes = in_data;
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let ioctlout_bytes = [0u8; size_of::<FuseIoctlOut>()];
-        let concat_req = [
-            headerin_bytes,
-            ioctlin_bytes,
-            in_data_bytes,
-            &headerout_buffer,
-            &ioctlout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = in_data.len() + size_of::<FuseIoctlIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[ioctlin_bytes, in_data_bytes],
+            size_of::<FuseIoctlOut>(),
+        )
     }
 
-    fn listxattr(&self, nodeid: u64, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn listxattr(&self, nodeid: u64, size: u32) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseListxattr as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let listxattrout_bytes = [0u8; size_of::<FuseGetxattrOut>()];
-        let concat_req = [headerin_bytes, &headerout_buffer, &listxattrout_bytes].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[], size_of::<FuseGetxattrOut>())
     }
 
-    fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        check_whence(whence)?;
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseLseekIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseLseek as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1700,52 +2095,44 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let lseekin_bytes = lseekin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let lseekout_bytes = [0u8; size_of::<FuseLseekOut>()];
-        let concat_req = [
-            headerin_bytes,
-            lseekin_bytes,
-            &headerout_buffer,
-            &lseekout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseLseekIn>() + size_of::<FuseInHeader>();
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[lseekin_bytes],
+            size_of::<FuseLseekOut>(),
+        )
     }
 
-    fn mknod(&self, nodeid: u64, name: Vec<u8>, mode: u32, rdev: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn mknod(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        rdev: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+
+        let prepared_name = fuse_pad_str(&name, true);
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let (extensions, total_extlen) = self.create_extensions(secctx, supp_groups)?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseMknodIn>() as u32
                 + prepared_name.len() as u32
+                + extensions.len() as u32
                 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseMknod as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: total_extlen,
             padding: 0,
         };
 
@@ -1756,50 +2143,30 @@ impl AnyFuseDevice for FilesystemDevice {
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let mknodin_bytes = mknodin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let mknodout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            mknodin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &mknodout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseMknodIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[mknodin_bytes, prepared_name_bytes, &extensions],
+            size_of::<FuseEntryOut>(),
+        )
     }
 
-    fn poll(&self, nodeid: u64, fh: u64, events: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn poll(&self, nodeid: u64, fh: u64, events: u32) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FusePollIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FusePoll as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
@@ -1811,34 +2178,14 @@ impl AnyFuseDevice for FilesystemDevice {
             events: events,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let pollin_bytes = pollin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let pollout_bytes = [0u8; size_of::<FusePollOut>()];
-        let concat_req = [
-            headerin_bytes,
-            pollin_bytes,
-            &headerout_buffer,
-            &pollout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FusePollIn>() + size_of::<FuseInHeader>();
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[pollin_bytes],
+            size_of::<FusePollOut>(),
+        )
     }
 
     // // todo: readdirplus
@@ -1894,130 +2241,120 @@ impl AnyFuseDevice for FilesystemDevice {
     //     }
     // }
 
-    fn readlink(&self, nodeid: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn readlink(&self, nodeid: u64) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseReadlink as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
+        // The target follows the `FuseOutHeader`.
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[], FUSE_PATH_MAX)
     }
 
-    fn removexattr(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn removexattr(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError> {
+        check_xattr_name(&name)?;
+        let queue_idx = self.ready_queue()?;
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let prepared_name = fuse_pad_str(&name, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (prepared_name.len() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseRemovexattr as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, prepared_name_bytes, &headerout_buffer].concat();
+        self.send_unawaited(queue_idx, headerin, &[prepared_name_bytes], 0)
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
+    fn setxattr(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), FilesystemError> {
+        check_xattr_name(&name)?;
+        let queue_idx = self.ready_queue()?;
+
+        // The value directly follows the NUL terminator of the name, so the
+        // name must not be padded.
+        let prepared_name = [name.as_slice(), b"\0"].concat();
+        let setxattrin = FuseSetxattrIn {
+            size: value.len() as u32,
+            flags: flags,
+            setxattr_flags: 0,
+            padding: 0,
+        };
+        let setxattrin_bytes =
+            setxattr_in_bytes(&setxattrin, self.has_negotiated(FUSE_SETXATTR_EXT));
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        let creds = self.current_creds()?;
+        let headerin = FuseInHeader {
+            len: (setxattrin_bytes.len() as u32
+                + prepared_name.len() as u32
+                + value.len() as u32
+                + size_of::<FuseInHeader>() as u32),
+            opcode: FuseOpcode::FuseSetxattr as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: 0,
+            padding: 0,
+        };
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        let prepared_name_bytes = prepared_name.as_slice();
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[setxattrin_bytes, prepared_name_bytes, value],
+            0,
+        )
     }
 
-    fn rmdir(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn rmdir(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        let prepared_name = fuse_pad_str(&name, true);
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (prepared_name.len() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseRmdir as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let rmdirout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &rmdirout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[prepared_name_bytes], 0)
     }
 
     fn setlk(
@@ -2029,42 +2366,39 @@ impl AnyFuseDevice for FilesystemDevice {
         end: u64,
         typ: u32,
         pid: u32,
-        sleep: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+        flock: bool,
+    ) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
+            len: (size_of::<FuseLkIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseSetlk as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        let setlkin = fuse_lk_in(
+            fh,
+            lock_owner,
+            FuseFileLock {
+                start: start,
+                end: end,
+                type_: typ,
+                pid: pid,
+            },
+            flock,
+        );
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        let setlkin_bytes = setlkin.as_bytes();
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[setlkin_bytes], 0)
     }
 
     fn setlkw(
@@ -2076,103 +2410,81 @@ impl AnyFuseDevice for FilesystemDevice {
         end: u64,
         typ: u32,
         pid: u32,
-        sleep: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+        flock: bool,
+    ) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let queue_idx = self.ready_queue()?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
+            len: (size_of::<FuseLkIn>() as u32 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseSetlkw as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
             total_extlen: 0,
             padding: 0,
         };
 
-        let lk = FuseFileLock {
-            start: start,
-            end: end,
-            type_: typ,
-            pid: pid,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let setlkin_bytes = lk.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, setlkin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        let setlkwin = fuse_lk_in(
+            fh,
+            lock_owner,
+            FuseFileLock {
+                start: start,
+                end: end,
+                type_: typ,
+                pid: pid,
+            },
+            flock,
+        );
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        let setlkwin_bytes = setlkwin.as_bytes();
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(queue_idx, headerin, &[setlkwin_bytes], 0)
     }
 
-    fn symlink(&self, nodeid: u64, name: Vec<u8>, link: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn symlink(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        link: Vec<u8>,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError> {
+        let queue_idx = self.ready_queue()?;
+
+        let prepared_name = fuse_pad_str(&name, true);
+        let prepared_link = fuse_pad_str(&link, true);
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-        let prepared_link = fuse_pad_str(&String::from_utf8(link).unwrap(), true);
+        let (extensions, total_extlen) = self.create_extensions(secctx, supp_groups)?;
 
+        let creds = self.current_creds()?;
         let headerin = FuseInHeader {
             len: (prepared_name.len() as u32
                 + prepared_link.len() as u32
+                + extensions.len() as u32
                 + size_of::<FuseInHeader>() as u32),
             opcode: FuseOpcode::FuseSymlink as u32,
             unique: 0,
             nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
+            uid: creds.uid,
+            gid: creds.gid,
+            pid: creds.pid,
+            total_extlen: total_extlen,
             padding: 0,
         };
 
-        let headerin_bytes = headerin.as_bytes();
         let prepared_name_bytes = prepared_name.as_slice();
         let prepared_link_bytes = prepared_link.as_slice();
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let symlinkout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            prepared_name_bytes,
-            prepared_link_bytes,
-            &headerout_buffer,
-            &symlinkout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + prepared_link.len() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_unawaited(
+            queue_idx,
+            headerin,
+            &[prepared_name_bytes, prepared_link_bytes, &extensions],
+            size_of::<FuseEntryOut>(),
+        )
     }
 }
 
@@ -2182,11 +2494,10 @@ impl FilesystemDevice {
         let device_features = FilesystemFeatures::from_bits_truncate(features);
         let supported_features = FilesystemFeatures::supported_features();
         let filesystem_features = device_features & supported_features;
-        debug!("features negotiated: {:?}", filesystem_features);
-
-        early_println!("device features: {:?}", device_features);
-        early_println!("supported features: {:?}", supported_features);
-        early_println!("features negotiated: {:?}", filesystem_features);
+        debug!(
+            "virtio-fs features: device {:?}, supported {:?}, negotiated {:?}",
+            device_features, supported_features, filesystem_features
+        );
 
         filesystem_features.bits()
     }
@@ -2194,376 +2505,2670 @@ impl FilesystemDevice {
     pub fn init(mut transport: Box<dyn VirtioTransport>) -> Result<(), VirtioDeviceError> {
         let config_manager = VirtioFilesystemConfig::new_manager(transport.as_ref());
         let fs_config: VirtioFilesystemConfig = config_manager.read_config();
-        early_print!(
-            "virtio_filesystem_config_notify_buf_size = {:?}\n",
-            fs_config.notify_buf_size
+        debug!(
+            "virtio-fs config: notify_buf_size = {}, num_request_queues = {}",
+            fs_config.notify_buf_size, fs_config.num_request_queues
         );
-        early_print!(
-            "virtio_filesystem_config_num_request_queues = {:?}\n",
-            fs_config.num_request_queues
-        );
-        early_print!("virtio_filesystem_config_tag = {:?}\n", fs_config.tag);
 
-        const HIPRIO_QUEUE_INDEX: u16 = 0;
-        // const NOTIFICATION_QUEUE_INDEX: u16 = 1;
-        const REQUEST_QUEUE_BASE_INDEX: u16 = 1;
+        // The negotiated bits were written back before `FEATURES_OK`.
+        let features = accepted_features(driver_features(
+            VirtioDeviceType::Filesystem,
+            transport.read_device_features(),
+        ))?;
+        let request_queue_base = request_queue_base_index(features);
+
         let hiprio_queue =
-            SpinLock::new(VirtQueue::new(HIPRIO_QUEUE_INDEX, 2, transport.as_mut()).unwrap());
-        // let notification_queue= SpinLock::new(VirtQueue::new(NOTIFICATION_QUEUE_INDEX, 2, transport.as_mut()).unwrap());
-        let mut request_queues = Vec::new();
-        for i in 0..fs_config.num_request_queues {
-            request_queues.push(SpinLock::new(
-                VirtQueue::new(REQUEST_QUEUE_BASE_INDEX + (i as u16), 4, transport.as_mut())
-                    .unwrap(),
-            ))
-        }
-
-        let hiprio_buffer = {
-            let vm_segment = FrameAllocOptions::new().alloc_segment(3).unwrap();
-            DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap()
-        };
-
-        let mut request_buffers = Vec::new();
-        for _ in 0..fs_config.num_request_queues {
-            let request_buffer = {
-                let vm_segment = FrameAllocOptions::new().alloc_segment(3).unwrap();
-                DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap()
-            };
-            request_buffers.push(request_buffer);
-        }
+            SpinLock::new(VirtQueue::new(HIPRIO_QUEUE_INDEX, 2, transport.as_mut())?);
+        let notify_queue = if features.contains(FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION) {
+            let queue = VirtQueue::new(
+                NOTIFICATION_QUEUE_INDEX,
+                NOTIFICATION_QUEUE_SIZE,
+                transport.as_mut(),
+            )?;
+            // Each buffer must hold at least `notify_buf_size` bytes.
+            let slot_size = (fs_config.notify_buf_size as usize)
+                .max(PAGE_SIZE)
+                .align_up(PAGE_SIZE);
+            Some(SpinLock::new(NotifyQueue::new(queue, slot_size)?))
+        } else {
+            None
+        };
+        let transport_queues = transport.num_queues();
+        let (request_queues, request_pools) = setup_request_queues(
+            fs_config.num_request_queues,
+            transport_queues,
+            request_queue_base,
+            |idx| VirtQueue::new(idx, REQUEST_QUEUE_SIZE, transport.as_mut()),
+        )?;
+
+        let hiprio_buffer = alloc_request_buffer()?;
 
+        let poll_waiters = Arc::new(PollWaiters::new());
         let device = Arc::new(Self {
             config_manager: config_manager,
+            config: SpinLock::new(fs_config),
             transport: SpinLock::new(transport),
             hiprio_queue: hiprio_queue,
-            // notification_queue: notification_queue,
             request_queues: request_queues,
+            notify_queue: notify_queue,
             hiprio_buffer: hiprio_buffer,
-            request_buffers: request_buffers,
+            request_pools: request_pools,
+            next_queue: AtomicUsize::new(0),
+            features: features,
+            connection: FuseConnection::new(),
+            callbacks: RwLock::new(vec![poll_waiters.clone() as Arc<dyn FilesystemCallback>]),
+            automount_callbacks: RwLock::new(Vec::new()),
+            creds_provider: RwLock::new(None),
+            cache: SpinLock::new(NodeCache::new(
+                NonZeroUsize::new(DEFAULT_CACHE_CAPACITY).unwrap(),
+            )),
+            stored_data: SpinLock::new(StoredData::new()),
+            open_handles: SpinLock::new(HandleTable::default()),
+            node_refs: SpinLock::new(NodeRefs::new()),
+            pending_forgets: SpinLock::new(ForgetBatch::default()),
+            is_shut_down: AtomicBool::new(false),
+            no_open: AtomicBool::new(false),
+            poll_waiters: poll_waiters,
+            stats: RequestStats::new(),
+            irq_target: Arc::new(RwLock::new(None)),
+        });
+        *device.irq_target.write() = Some(device.clone());
+        // A timer callback cannot be unregistered either, so it only holds weak
+        // references to the deadlines and the wait queue, which go with the device.
+        timer::register_callback({
+            let deadlines = Arc::downgrade(&device.connection.deadlines);
+            let reply_wait_queue = Arc::downgrade(&device.connection.reply_wait_queue);
+            move || {
+                if let (Some(deadlines), Some(reply_wait_queue)) =
+                    (deadlines.upgrade(), reply_wait_queue.upgrade())
+                {
+                    wake_timed_out(&deadlines, &reply_wait_queue);
+                }
+            }
         });
-        let handle_request = {
-            let device = device.clone();
-            move |_: &TrapFrame| device.handle_recv_irq()
-        };
-        let config_space_change = |_: &TrapFrame| early_print!("Config Changed\n");
         let mut transport = device.transport.disable_irq().lock();
-        transport
-            .register_queue_callback(
-                REQUEST_QUEUE_BASE_INDEX + 0,
-                Box::new(handle_request),
-                false,
-            )
-            .unwrap();
-        transport
-            .register_cfg_callback(Box::new(config_space_change))
-            .unwrap();
+        if let Err(err) = register_callbacks(&device, &mut **transport, request_queue_base) {
+            // The handlers registered so far must not keep the device alive.
+            drop(transport);
+            device.irq_target.write().take();
+            return Err(err.into());
+        }
         transport.finish_init();
         drop(transport);
 
-        // device.init();
-        test_device(&device);
+        device.init().map_err(init_error)?;
+        device.wait_init().map_err(init_error)?;
 
         Ok(())
     }
 
-    fn handle_recv_irq(&self) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-        let Ok((_, len)) = request_queue.pop_used() else {
-            return;
-        };
-        self.request_buffers[0].sync(0..len as usize).unwrap();
-        let mut reader = self.request_buffers[0].reader().unwrap();
-        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+    /// Returns the tag the device is exported under, which names the share to mount.
+    pub fn tag(&self) -> String {
+        self.config.disable_irq().lock().tag()
+    }
 
-        match FuseOpcode::try_from(headerin.opcode).unwrap() {
-            FuseOpcode::FuseInit => {
-                let _datain = reader.read_val::<FuseInitIn>().unwrap();
-                let _headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseInitOut>().unwrap();
-                early_print!("Received Init Msg\n");
-                early_print!("major:{:?}\n", dataout.major);
-                early_print!("minor:{:?}\n", dataout.minor);
-                early_print!("flags:{:?}\n", dataout.flags);
-                early_println!();
-            }
-            FuseOpcode::FuseReaddir => {
-                // 这里的datain千万不要注释，注释掉会出bug！！！！
-                let _datain = reader.read_val::<FuseReadIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout);
+    /// Reads the config again once the device signals that it changed.
+    ///
+    /// The request queues are set up once and for all, so a change of their
+    /// number only takes effect once the device is probed again.
+    fn handle_config_change(&self) {
+        let fs_config = self.config_manager.read_config();
+        let mut config = self.config.disable_irq().lock();
+        if fs_config.num_request_queues != config.num_request_queues {
+            warn!(
+                "virtio-fs number of request queues changed from {} to {}, keeping {}",
+                config.num_request_queues,
+                fs_config.num_request_queues,
+                self.request_queues.len()
+            );
+        }
+        if fs_config.tag != config.tag {
+            info!("virtio-fs tag changed to {:?}", fs_config.tag());
+        }
+        *config = fs_config;
+    }
 
-                early_print!(
-                    "Readdir response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                for dirent_name in readdir_out.dirents {
-                    let dirent = dirent_name.dirent;
-                    let name = String::from_utf8(dirent_name.name).unwrap();
-                    early_print!("Readdir response received: inode={:?}, off={:?}, namelen={:?}, type:{:?}, filename={:?}\n", 
-                        dirent.ino, dirent.off, dirent.namelen, dirent.type_, name);
+    /// Returns a snapshot of the device tag, features and negotiated FUSE parameters.
+    ///
+    /// The FUSE fields are zero until the reply to `FUSE_INIT` has been received.
+    pub fn info(&self) -> FilesystemDeviceInfo {
+        let fs_config = *self.config.disable_irq().lock();
+        let negotiated = self.negotiated();
+        FilesystemDeviceInfo::new(
+            &fs_config,
+            self.features,
+            negotiated.as_ref(),
+            self.request_queues.len() as u16,
+            self.slot_size(),
+            self.cache.disable_irq().lock().capacity().get(),
+        )
+    }
+
+    /// Returns the number of requests made so far of each opcode, along with
+    /// the time the device took to answer those waited for.
+    pub fn stats(&self) -> FuseStats {
+        self.stats.snapshot()
+    }
+
+    /// Sets the number of attributes, and of entries, that may be cached.
+    ///
+    /// The entries evicted to shrink the cache are forgotten.
+    pub fn set_cache_capacity(&self, capacity: NonZeroUsize) {
+        let forgets = self.cache.disable_irq().lock().set_capacity(capacity);
+        self.forget_evicted(forgets);
+    }
+
+    /// Drops the cached entries of the `(parent, name)` in `entries`, which no
+    /// longer refer to the same nodes, along with the references they hold.
+    fn remove_entries(&self, entries: &[(u64, &[u8])]) {
+        let forgets: Vec<_> = {
+            let mut cache = self.cache.disable_irq().lock();
+            entries
+                .iter()
+                .filter_map(|&(parent, name)| cache.remove_entry(parent, name))
+                .collect()
+        };
+        self.forget_evicted(forgets);
+    }
+
+    /// Drops the references held by the `(nodeid, nlookup)` evicted from the cache.
+    fn forget_evicted(&self, forgets: impl IntoIterator<Item = (u64, u64)>) {
+        self.put_nodes(forgets);
+    }
+
+    /// Drops a reference to node `nodeid`, e.g. once the inode of an entry
+    /// returned by `lookup_sync` or `create_sync` goes away.
+    ///
+    /// The node is forgotten along with all of its lookups once no reference is
+    /// left, including those of the cache. Fails with
+    /// `FilesystemError::InvalidOperation` if the node is not referenced.
+    pub fn forget_node(&self, nodeid: u64) -> Result<(), FilesystemError> {
+        if !self.node_refs.disable_irq().lock().contains(nodeid) {
+            return Err(FilesystemError::InvalidOperation);
+        }
+        self.put_nodes([(nodeid, 1)]);
+        Ok(())
+    }
+
+    /// Counts a lookup of node `nodeid`, whose reply comes with `refs` references.
+    fn count_lookup(&self, nodeid: u64, refs: u64) {
+        self.node_refs.disable_irq().lock().lookup(nodeid, refs);
+    }
+
+    /// Same as `drop_node_refs`, but also sends the forgets once
+    /// `FORGET_FLUSH_THRESHOLD` nodes are queued. Otherwise they are sent on
+    /// `flush_forgets`.
+    fn put_nodes(&self, puts: impl IntoIterator<Item = (u64, u64)>) {
+        if !self.drop_node_refs(puts) {
+            return;
+        }
+        if let Err(err) = self.flush_forgets() {
+            warn!("virtio-fs failed to forget nodes: {}", err);
+        }
+    }
+
+    /// Drops the references of each `(nodeid, refs)` of `puts`, and queues the
+    /// forgets of the nodes left without references.
+    ///
+    /// Returns whether `FORGET_FLUSH_THRESHOLD` nodes are queued.
+    fn drop_node_refs(&self, puts: impl IntoIterator<Item = (u64, u64)>) -> bool {
+        let mut forgets = Vec::new();
+        {
+            let mut node_refs = self.node_refs.disable_irq().lock();
+            for (nodeid, refs) in puts {
+                if let Some(nlookup) = node_refs.put(nodeid, refs) {
+                    forgets.push((nodeid, nlookup));
                 }
-                early_println!();
-            }
-            FuseOpcode::FuseOpendir => {
-                let _datain = reader.read_val::<FuseOpenIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseOpenOut>().unwrap();
-                early_print!(
-                    "Opendir response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("fh:{:?}\n", dataout.fh);
-                early_print!("open_flags:{:?}\n", dataout.open_flags);
-                early_print!("backing_id:{:?}\n", dataout.backing_id);
-                early_println!();
-            }
-            FuseOpcode::FuseOpen => {
-                let _datain = reader.read_val::<FuseOpenIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseOpenOut>().unwrap();
-                early_print!(
-                    "Open response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("fh:{:?}\n", dataout.fh);
-                early_print!("open_flags:{:?}\n", dataout.open_flags);
-                early_print!("backing_id:{:?}\n", dataout.backing_id);
             }
-            FuseOpcode::FuseRead => {
-                let _datain = reader.read_val::<FuseReadIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                //The requested action is to read up to size bytes of the file or directory, starting at offset. The bytes should be returned directly following the usual reply header.
-                // let dataout = reader.read_val::<Vec<u8>>().unwrap();
-                early_print!(
-                    "Read response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                // early_println!();
-                // if the file is not empty
-                if headerout.len > size_of::<FuseOutHeader>() as u32 {
-                    let data_len = headerout.len - size_of::<FuseOutHeader>() as u32;
-                    let mut dataout_buf = vec![0u8; data_len as usize];
-                    let mut writer = VmWriter::from(dataout_buf.as_mut_slice());
-                    writer.write(&mut reader);
-                    let data_utf8 = String::from_utf8(dataout_buf).unwrap();
-                    early_print!("Read response received: data={:?}\n", data_utf8);
+        }
+        for (nodeid, _) in forgets.iter() {
+            self.cache.disable_irq().lock().forget_node(*nodeid);
+            self.stored_data.disable_irq().lock().remove(*nodeid);
+        }
+        let mut is_due = false;
+        let mut pending_forgets = self.pending_forgets.disable_irq().lock();
+        for (nodeid, nlookup) in forgets {
+            is_due |= pending_forgets.push(nodeid, nlookup);
+        }
+        is_due
+    }
+
+    /// Sends the queued forgets of the nodes left without references, in
+    /// batches of `FUSE_BATCH_FORGET` on the hiprio queue if there are several.
+    ///
+    /// The forgets that cannot be sent are queued again for the next flush.
+    pub fn flush_forgets(&self) -> Result<(), FilesystemError> {
+        let mut forgets = self.pending_forgets.disable_irq().lock().take();
+        while !forgets.is_empty() {
+            let len = forgets.len().min(MAX_BATCH_FORGETS);
+            let sent = match &forgets[..len] {
+                [(nodeid, nlookup)] => self.forget(*nodeid, *nlookup),
+                batch => self.batch_forget(batch),
+            };
+            if let Err(err) = sent {
+                let mut pending_forgets = self.pending_forgets.disable_irq().lock();
+                for (nodeid, nlookup) in forgets {
+                    pending_forgets.push(nodeid, nlookup);
                 }
-                // early_print!("Read data: {:?}", dataout);
-            }
-            FuseOpcode::FuseFlush => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                early_print!(
-                    "Flush response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
-            }
-            FuseOpcode::FuseReleasedir => {
-                let _datain = reader.read_val::<FuseReleaseIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                // let dataout = reader.read_val::<FuseReleaseOut>().unwrap();
-                early_print!(
-                    "Releasedir response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
-                // early_print!("fh:{:?}\n", dataout.fh);
-            }
-            FuseOpcode::FuseGetattr => {
-                let _datain = reader.read_val::<FuseGetattrIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseAttrOut>().unwrap();
-                early_print!(
-                    "Getattr response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
+                return Err(err);
             }
-            FuseOpcode::FuseSetattr => {
-                let _datain = reader.read_val::<FuseSetattrIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseAttrOut>().unwrap();
-                early_print!(
-                    "Setattr response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
+            forgets.drain(..len);
+        }
+        Ok(())
+    }
+
+    /// Shuts the connection down, e.g. to unmount the filesystem.
+    ///
+    /// The pending forgets are flushed, and no request is let through afterwards.
+    /// The requests in flight are interrupted and given `SHUTDOWN_TIMEOUT` to
+    /// finish, after which they fail as on `abort`. The server is then sent
+    /// `FUSE_DESTROY`.
+    ///
+    /// At last the device is reset, so that it no longer uses the buffers of the
+    /// requests, and the interrupt handlers let go of it, whether or not the
+    /// reset succeeds. The queues and the buffers are not freed here but along
+    /// with the last reference to the device, as callers holding it, e.g. with a
+    /// `RequestHandle`, may still reach the request pools until then.
+    ///
+    /// Only the first call does anything.
+    pub fn shutdown(&self) {
+        if self.is_shut_down.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Err(err) = self.flush_forgets() {
+            warn!("virtio-fs failed to flush forgets on shutdown: {}", err);
+        }
+        let was_ready = self.connection.shut_down();
+
+        let waiting = self
+            .connection
+            .pending_replies
+            .disable_irq()
+            .lock()
+            .waiting();
+        for unique in waiting {
+            if let Err(err) = self.send_interrupt(unique) {
+                warn!("virtio-fs failed to interrupt request {}: {}", unique, err);
             }
-            FuseOpcode::FuseLookup => {
-                let _name = reader.read_val::<FuseInHeader>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
-                early_print!(
-                    "Lookup response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!("test for lookup");
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
+        }
+        let drained = self.connection.wait_timeout(0, SHUTDOWN_TIMEOUT, || {
+            let pending_replies = self.connection.pending_replies.disable_irq().lock();
+            pending_replies.waiting().is_empty().then_some(())
+        });
+        if drained.is_none() {
+            warn!("virtio-fs gives up on the requests in flight on shutdown");
+        }
+
+        if was_ready {
+            let destroyed = self
+                .submit_admitted(
+                    FuseCreds::default(),
+                    FuseOpcode::FuseDestroy,
+                    0,
+                    RequestParts::default(),
+                )
+                .and_then(|handle| handle.wait_timeout(SHUTDOWN_TIMEOUT));
+            if let Err(err) = destroyed {
+                warn!("virtio-fs failed to destroy the connection: {}", err);
             }
-            FuseOpcode::FuseRelease => {
-                let _datain = reader.read_val::<FuseReleaseIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                // let dataout = reader.read_val::<FuseReleaseOut>().unwrap();
-                early_print!(
-                    "Release response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
-                // early_print!("fh:{:?}\n", dataout.fh);
+        }
+        self.connection.abort();
+
+        let reset = self
+            .transport
+            .disable_irq()
+            .lock()
+            .write_device_status(DeviceStatus::empty());
+        if let Err(err) = reset {
+            warn!("virtio-fs failed to reset the device: {:?}", err);
+        }
+        // Waits for the handlers running on other CPUs to return.
+        let irq_target = self.irq_target.write().take();
+        drop(irq_target);
+        debug!("virtio-fs is shut down");
+    }
+
+    /// Opens directory `nodeid` and returns its handle.
+    ///
+    /// If the server does without `FUSE_OPENDIR`, `NO_OPENDIR_FH` is returned
+    /// right away, and is accepted as a handle by the other directory operations.
+    pub fn opendir_sync(&self, nodeid: u64, flags: u32) -> Result<u64, FilesystemError> {
+        let fh = opendir_fh(self.negotiated_flags(), || {
+            let openin = FuseOpenIn {
+                flags: flags,
+                open_flags: 0,
+            };
+            let (_, dataout) = self.send_sync(
+                FuseOpcode::FuseOpendir,
+                nodeid,
+                &[openin.as_bytes()],
+                size_of::<FuseOpenOut>(),
+            )?;
+            decode_reply::<FuseOpenOut>(&dataout)
+        })?;
+        self.open_handles
+            .disable_irq()
+            .lock()
+            .insert(nodeid, fh, FopenFlags::empty());
+        Ok(fh)
+    }
+
+    /// Opens file `nodeid` with the open flags `flags`.
+    ///
+    /// If the server does without `FUSE_OPEN`, the file is opened right away
+    /// under `NO_OPEN_FH`, which is accepted as a handle by the other file operations.
+    ///
+    /// With `O_TRUNC`, the file is truncated as part of the open once the server
+    /// agreed on `FUSE_ATOMIC_O_TRUNC`, so that nobody sees it opened but not
+    /// truncated yet. Otherwise the open is followed by a `setattr` to a size of
+    /// 0, and anything written to the file in between, e.g. through another
+    /// handle, is lost. If that `setattr` fails, the file is released again.
+    ///
+    /// The caller is taken to lack `CAP_FSETID`, see `WithCreds::with_cap_fsetid`.
+    pub fn open_sync(&self, nodeid: u64, flags: u32) -> Result<OpenFileState, FilesystemError> {
+        self.open_as(self.current_creds()?, false, nodeid, flags)
+    }
+
+    fn open_as(
+        &self,
+        creds: FuseCreds,
+        cap_fsetid: bool,
+        nodeid: u64,
+        flags: u32,
+    ) -> Result<OpenFileState, FilesystemError> {
+        let (open_flags, truncate) = open_trunc_flags(self.negotiated_flags(), flags);
+        let openout = zero_message_open(&self.no_open, || {
+            let openin = FuseOpenIn {
+                flags: open_flags,
+                open_flags: kill_suidgid_open_flags(
+                    self.negotiated_flags(),
+                    open_flags,
+                    cap_fsetid,
+                ),
+            };
+            let (_, dataout) = self.send_sync_as(
+                creds,
+                FuseOpcode::FuseOpen,
+                nodeid,
+                &[openin.as_bytes()],
+                0,
+                size_of::<FuseOpenOut>(),
+            )?;
+            decode_reply::<FuseOpenOut>(&dataout)
+        })?;
+        let file = OpenFileState::new(nodeid, flags, &openout, self.negotiated_flags());
+        self.record_open(nodeid, file.fh, file.open_flags);
+        if flags & O_TRUNC != 0 {
+            self.stored_data.disable_irq().lock().remove(nodeid);
+        }
+        if truncate {
+            let mut request = SetattrRequest::new().size(0).fh(file.fh);
+            if kill_suidgid_write_flags(self.negotiated_flags(), cap_fsetid) != 0 {
+                request = request.kill_suidgid();
             }
-            FuseOpcode::FuseWrite => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                early_print!(
-                    "Write response received: len={:?}, error={:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                if headerout.len > size_of::<FuseOutHeader>() as u32 {
-                    let writeout = reader.read_val::<FuseWriteOut>().unwrap();
-                    early_print!("Write response received: size={:?}\n", writeout.size);
+            if let Err(err) = self.setattr_as(creds, nodeid, &request) {
+                // The caller never gets the file, so it is released here.
+                if let Err(err) = self.release(nodeid, file.fh, flags, 0, false) {
+                    warn!("virtio-fs failed to release a file left open: {}", err);
                 }
+                return Err(err);
             }
-            FuseOpcode::FuseAccess => {
-                let _datain = reader.read_val::<FuseAccessIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                // let dataout = reader.read_val::<FuseAttrOut>().unwrap();
-                early_print!(
-                    "Access response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                // early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                // early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                // early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
-            }
-            FuseOpcode::FuseStatfs => {
-                let _datain = reader.read_val::<FuseInHeader>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseStatfsOut>().unwrap();
-                early_print!(
-                    "Statfs response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("blocks:{:?}\n", dataout.st.blocks);
-                early_print!("bfree:{:?}\n", dataout.st.bfree);
-                early_print!("bavail:{:?}\n", dataout.st.bavail);
-                early_print!("files:{:?}\n", dataout.st.files);
-                early_print!("ffree:{:?}\n", dataout.st.ffree);
-                early_print!("bsize:{:?}\n", dataout.st.bsize);
-                early_print!("namelen:{:?}\n", dataout.st.namelen);
-                early_print!("frsize:{:?}\n", dataout.st.frsize);
-                early_print!("padding:{:?}\n", dataout.st.padding);
-                early_print!("spare:{:?}\n", dataout.st.spare);
+        }
+        Ok(file)
+    }
 
-                early_println!();
-            }
-            FuseOpcode::FuseInterrupt => {
-                let _datain = reader.read_val::<FuseInterruptIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                early_print!(
-                    "Interrupt response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
-            }
-            FuseOpcode::FuseMkdir => {
-                let _datain = reader.read_val::<FuseMkdirIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
-                early_print!(
-                    "Mkdir response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
-            }
-            FuseOpcode::FuseCreate => {
-                let _datain = reader.read_val::<FuseCreateIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
-                early_print!(
-                    "Create response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
-            }
-            FuseOpcode::FuseDestroy => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                early_print!(
-                    "Destroy response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
-            }
-            FuseOpcode::FuseRename => {
-                let _datain = reader.read_val::<FuseRenameIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
-                early_print!(
-                    "Rename response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
-            }
+    /// Records that the server opened file `nodeid` under `fh` with `open_flags`.
+    ///
+    /// Unless the server asks to keep the cache of the file, the data stored
+    /// for it is dropped, since the file may have changed since.
+    fn record_open(&self, nodeid: u64, fh: u64, open_flags: FopenFlags) {
+        self.open_handles
+            .disable_irq()
+            .lock()
+            .insert(nodeid, fh, open_flags);
+        if !open_flags.contains(FopenFlags::FOPEN_KEEP_CACHE) {
+            self.stored_data.disable_irq().lock().remove(nodeid);
+        }
+    }
+
+    /// Returns whether the data read and written through `fh` is cached, which
+    /// it is not if the server opened the file with `FOPEN_DIRECT_IO`.
+    ///
+    /// The data cached by the device is the one stored with `FUSE_NOTIFY_STORE`.
+    fn is_cached(&self, nodeid: u64, fh: u64) -> bool {
+        self.open_handles
+            .disable_irq()
+            .lock()
+            .open_flags(nodeid, fh)
+            .is_none_or(|open_flags| !open_flags.contains(FopenFlags::FOPEN_DIRECT_IO))
+    }
+
+    /// Fails with `FilesystemError::InvalidOperation` unless `fh` is an open
+    /// handle of node `nodeid`, e.g. as it has been released already.
+    fn check_handle(&self, nodeid: u64, fh: u64) -> Result<(), FilesystemError> {
+        match self
+            .open_handles
+            .disable_irq()
+            .lock()
+            .open_flags(nodeid, fh)
+        {
+            Some(_) => Ok(()),
+            None => Err(FilesystemError::InvalidOperation),
+        }
+    }
+
+    /// Forgets an open of handle `fh` of node `nodeid`, which is about to be released.
+    fn release_handle(&self, nodeid: u64, fh: u64) -> Result<(), FilesystemError> {
+        if self.open_handles.disable_irq().lock().remove(nodeid, fh) {
+            Ok(())
+        } else {
+            Err(FilesystemError::InvalidOperation)
+        }
+    }
+
+    /// Reads one page of directory entries, starting after the entry whose `off` is `offset`.
+    pub fn readdir_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<FuseReaddirOut, FilesystemError> {
+        self.readdir_with(None, nodeid, fh, offset, size)
+    }
+
+    fn readdir_with(
+        &self,
+        cancel: Option<&CancelToken>,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<FuseReaddirOut, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        readdir_chunked(offset, size, self.max_transfer_size(), |offset, size| {
+            let readin = FuseReadIn {
+                fh: fh,
+                offset: offset,
+                size: size,
+                read_flags: 0,
+                lock_owner: 0,
+                flags: 0,
+                padding: 0,
+            };
+            let (headerout, dataout) = self.send_sync_with(
+                cancel,
+                FuseOpcode::FuseReaddir,
+                nodeid,
+                &[readin.as_bytes()],
+                size as usize,
+            )?;
+            FuseReaddirOut::read_dirent(&mut VmReader::from(dataout.as_slice()), headerout)
+        })
+    }
+
+    /// Reads one page of directory entries straight into the pages `out`, which
+    /// saves copying them out of the request buffer.
+    ///
+    /// Returns the number of bytes of entries at the start of `out`, to be parsed
+    /// with `FuseReaddirOut::read_dirent`. Since each page takes a descriptor,
+    /// `out` must be shorter than the request queues.
+    pub fn readdir_into(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        out: &[DmaStreamSlice<DmaStream>],
+    ) -> Result<usize, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let size = out.iter().map(|page| page.nbytes()).sum::<usize>();
+        let readin = FuseReadIn {
+            fh: fh,
+            offset: offset,
+            size: u32::try_from(size).map_err(|_| FilesystemError::InvalidOperation)?,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+        let creds = self.current_creds()?;
+        let reply = self.submit_sync(
+            creds,
+            FuseOpcode::FuseReaddir,
+            nodeid,
+            RequestParts {
+                datain: &[readin.as_bytes()],
+                out_pages: out,
+                ..Default::default()
+            },
+            None,
+        )?;
+        finish_reply_into(&reply, out)
+    }
+
+    /// Reads file data at `offset` straight into the pages `out`, e.g. the frames
+    /// of a page cache fill, which saves copying it out of the request buffer.
+    ///
+    /// Returns the number of bytes read to the start of `out`, which is less than
+    /// their size at the end of the file. As with `readdir_into`, `out` must be
+    /// shorter than the request queues.
+    pub fn read_into(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        out: &[DmaStreamSlice<DmaStream>],
+    ) -> Result<usize, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let size = out.iter().map(|page| page.nbytes()).sum::<usize>();
+        let readin = FuseReadIn {
+            fh: fh,
+            offset: offset,
+            size: u32::try_from(size).map_err(|_| FilesystemError::InvalidOperation)?,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+        let creds = self.current_creds()?;
+        let reply = self.submit_sync(
+            creds,
+            FuseOpcode::FuseRead,
+            nodeid,
+            RequestParts {
+                datain: &[readin.as_bytes()],
+                out_pages: out,
+                ..Default::default()
+            },
+            None,
+        )?;
+        finish_reply_into(&reply, out)
+    }
+
+    /// Reads up to `size` bytes at `offset` from a file opened with `open`.
+    ///
+    /// A read larger than fits in a request is split into several ones.
+    /// Returns the data along with whether the end of the file was reached,
+    /// after which there is no point in reading further.
+    pub fn read_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<(Vec<u8>, bool), FilesystemError> {
+        self.read_with(None, nodeid, fh, offset, size)
+    }
+
+    fn read_with(
+        &self,
+        cancel: Option<&CancelToken>,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<(Vec<u8>, bool), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let (data, eof) = read_chunked(offset, size, self.max_transfer_size(), |offset, size| {
+            let readin = FuseReadIn {
+                fh: fh,
+                offset: offset,
+                size: size,
+                read_flags: 0,
+                lock_owner: 0,
+                flags: 0,
+                padding: 0,
+            };
+            let (_, dataout) = self.send_sync_with(
+                cancel,
+                FuseOpcode::FuseRead,
+                nodeid,
+                &[readin.as_bytes()],
+                size as usize,
+            )?;
+            Ok(finish_read(dataout, size))
+        })?;
+        // A direct read goes to the server with the exact range of the caller,
+        // and leaves the stored data alone.
+        if self.is_cached(nodeid, fh) {
+            self.stored_data
+                .disable_irq()
+                .lock()
+                .update(nodeid, offset, &data);
+        }
+        Ok((data, eof))
+    }
+
+    /// Writes `data` at `offset` to a file opened with `open`.
+    ///
+    /// A write larger than the negotiated `max_write`, or than `max_pages` pages,
+    /// is split into several ones.
+    /// Returns the number of bytes written, which is less than the length of
+    /// `data` if the server wrote a chunk only in part.
+    ///
+    /// The caller is taken to lack `CAP_FSETID`, see `WithCreds::with_cap_fsetid`.
+    pub fn write_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FilesystemError> {
+        let write_flags = kill_suidgid_write_flags(self.negotiated_flags(), false);
+        self.write_with(self.current_creds()?, nodeid, fh, offset, data, write_flags)
+    }
+
+    /// Writes `data` at `offset` to a file opened with `open`, as flushed from
+    /// the page cache rather than written by a caller.
+    ///
+    /// Once `FUSE_WRITEBACK_CACHE` is negotiated, the write carries
+    /// `FUSE_WRITE_CACHE`, which tells the server that `fh` may be any handle
+    /// of the file opened for writing, and that there is no lock owner.
+    ///
+    /// Such a write never carries `FUSE_WRITE_KILL_SUIDGID`, as the caller it
+    /// stems from is long gone. Under `FUSE_HANDLE_KILLPRIV_V2`, the suid and
+    /// sgid bits are rather to be cleared when the data enters the page cache,
+    /// with a `setattr` carrying `FATTR_KILL_SUIDGID`.
+    pub fn write_cache(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FilesystemError> {
+        let write_flags = cache_write_flags(self.negotiated_flags());
+        self.write_with(self.current_creds()?, nodeid, fh, offset, data, write_flags)
+    }
+
+    fn write_with(
+        &self,
+        creds: FuseCreds,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+    ) -> Result<usize, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let written = write_chunked(offset, data, self.max_write_size(), |offset, chunk| {
+            let writein = FuseWriteIn {
+                fh: fh,
+                offset: offset,
+                size: chunk.len() as u32,
+                write_flags: write_flags,
+                lock_owner: 0,
+                flags: 0,
+                padding: 0,
+            };
+            let (_, dataout) = self.send_sync_as(
+                creds,
+                FuseOpcode::FuseWrite,
+                nodeid,
+                &[writein.as_bytes(), chunk],
+                0,
+                size_of::<FuseWriteOut>(),
+            )?;
+            Ok(decode_reply::<FuseWriteOut>(&dataout)?.size)
+        })?;
+        // The data stored for the file cannot be kept up to date past a direct
+        // write, which may be of a different size than asked for.
+        let mut stored_data = self.stored_data.disable_irq().lock();
+        if self.is_cached(nodeid, fh) {
+            stored_data.update(nodeid, offset, &data[..written]);
+        } else {
+            stored_data.remove(nodeid);
+        }
+        Ok(written)
+    }
+
+    /// Writes the pages `data` at `offset` to a file opened with `open`, which
+    /// the device reads in place, as flushed from the page cache.
+    ///
+    /// The write carries the same flags as one of `write_cache`.
+    /// Returns the number of bytes written. Unlike with `write_cache`, `data` is
+    /// never split, so it fails with `BufferTooLong` if it is larger than the
+    /// negotiated `max_write`, or has more pages than the request queues hold.
+    pub fn write_from(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[DmaStreamSlice<DmaStream>],
+    ) -> Result<usize, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let size = data.iter().map(|page| page.nbytes()).sum::<usize>();
+        let max_write = self.max_write_size() as usize;
+        if size > max_write {
+            return Err(FilesystemError::BufferTooLong(size, max_write));
+        }
+        // The staged headers and the reply take a descriptor each.
+        let max_pages =
+            (self.request_queues[0].disable_irq().lock().size() as usize).saturating_sub(2);
+        if data.len() > max_pages {
+            return Err(FilesystemError::BufferTooLong(data.len(), max_pages));
+        }
+        let writein = FuseWriteIn {
+            fh: fh,
+            offset: offset,
+            size: u32::try_from(size).map_err(|_| FilesystemError::InvalidOperation)?,
+            write_flags: cache_write_flags(self.negotiated_flags()),
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+        let creds = self.current_creds()?;
+        let reply = self.submit_sync(
+            creds,
+            FuseOpcode::FuseWrite,
+            nodeid,
+            RequestParts {
+                datain: &[writein.as_bytes()],
+                in_pages: data,
+                out_len: size_of::<FuseWriteOut>(),
+                ..Default::default()
+            },
+            None,
+        )?;
+        let (_, dataout) = parse_reply(&reply)?;
+        let written = (decode_reply::<FuseWriteOut>(&dataout)?.size as usize).min(size);
+        // As with `write_with`, the stored data is kept up to date only under
+        // the page cache.
+        let mut stored_data = self.stored_data.disable_irq().lock();
+        if self.is_cached(nodeid, fh) {
+            let mut written_data = vec![0u8; written];
+            let mut writer = VmWriter::from(written_data.as_mut_slice());
+            for page in data {
+                page.reader()?.read(&mut writer);
+            }
+            stored_data.update(nodeid, offset, &written_data);
+        } else {
+            stored_data.remove(nodeid);
+        }
+        Ok(written)
+    }
+
+    /// Writes `data` to `file` at `offset`, or at the end of the file if it was
+    /// opened with `O_APPEND`.
+    ///
+    /// An append is sent as `write_append` does, at the size of the file got
+    /// with `FUSE_GETATTR`. The size may change before the write arrives, e.g.
+    /// with appends of other clients. That is fine as long as the server opened
+    /// its file with `O_APPEND` as well: it writes an append to the end of the
+    /// file whatever the offset, so racing appends land one after the other.
+    ///
+    /// Under `FUSE_WRITEBACK_CACHE`, servers such as virtiofsd leave `O_APPEND`
+    /// out of their own open, expecting the client to append. The append is
+    /// then only emulated here, see `write_append`.
+    pub fn write_file_sync(
+        &self,
+        file: &OpenFileState,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FilesystemError> {
+        let offset = write_offset(file, offset, || Ok(self.getattr_sync(file.nodeid)?.size()))?;
+        self.write_sync(file.nodeid, file.fh, offset, data)
+    }
+
+    /// Writes `data` to the end of a file opened with `open`.
+    ///
+    /// The write is sent at the size of the file got with `FUSE_GETATTR`. That
+    /// leaves a window between the two requests: a write of another client
+    /// landing in it, e.g. another append, is overwritten. Prefer opening the
+    /// file with `O_APPEND` without `FUSE_WRITEBACK_CACHE`, and writing with
+    /// `write_file_sync`, so that the server appends on its side and the offset
+    /// sent does not matter.
+    pub fn write_append(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        data: &[u8],
+    ) -> Result<usize, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let offset = self.getattr_sync(nodeid)?.size();
+        self.write_sync(nodeid, fh, offset, data)
+    }
+
+    /// Returns which of `events` are ready on a file opened with `open`.
+    ///
+    /// The poll is scheduled with `FUSE_POLL_SCHEDULE_NOTIFY` under the handle
+    /// `fh`, so that the server sends `FUSE_NOTIFY_POLL` once the readiness of
+    /// the file changes. That wakes up `wait_poll`.
+    pub fn poll_sync(&self, nodeid: u64, fh: u64, events: u32) -> Result<u32, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        self.poll_waiters.schedule(fh);
+        let pollin = FusePollIn {
+            fh: fh,
+            kh: fh,
+            flags: FUSE_POLL_SCHEDULE_NOTIFY,
+            events: events,
+        };
+        let (_, dataout) = self.send_sync(
+            FuseOpcode::FusePoll,
+            nodeid,
+            &[pollin.as_bytes()],
+            size_of::<FusePollOut>(),
+        )?;
+        Ok(decode_reply::<FusePollOut>(&dataout)?.revents)
+    }
+
+    /// Blocks until some of `events` are ready on a file opened with `open`,
+    /// returning the ready ones.
+    pub fn wait_poll(&self, nodeid: u64, fh: u64, events: u32) -> Result<u32, FilesystemError> {
+        loop {
+            let revents = self.poll_sync(nodeid, fh, events)?;
+            if revents & events != 0 {
+                return Ok(revents);
+            }
+            self.poll_waiters.wait(fh);
+        }
+    }
+
+    /// Makes the ioctl `ioctlin.cmd` with argument `ioctlin.arg` on the file
+    /// handle `ioctlin.fh` opened with `open`, sending `in_data` and making room
+    /// for `ioctlin.out_size` bytes written back. The `in_size` of `ioctlin` is
+    /// that of `in_data`.
+    ///
+    /// With `FUSE_IOCTL_UNRESTRICTED` in `ioctlin.flags`, the server may not know
+    /// the buffers the ioctl takes up front. It then asks to retry the ioctl with
+    /// the data of the caller at the addresses it names, which `read_iovs`
+    /// reads, and with room for the data it writes back. The ioctl is made again
+    /// up to `FUSE_IOCTL_MAX_RETRIES` times, after which it fails with `EIO`.
+    pub fn ioctl_sync<F>(
+        &self,
+        nodeid: u64,
+        mut ioctlin: FuseIoctlIn,
+        in_data: &[u8],
+        mut read_iovs: F,
+    ) -> Result<IoctlReply, FilesystemError>
+    where
+        F: FnMut(&[FuseIoctlIovec]) -> Result<Vec<u8>, FilesystemError>,
+    {
+        self.check_handle(nodeid, ioctlin.fh)?;
+        let mut in_data = in_data.to_vec();
+        let mut out_iovs = Vec::new();
+        for _ in 0..=FUSE_IOCTL_MAX_RETRIES {
+            ioctlin.in_size = u32::try_from(in_data.len())
+                .map_err(|_| FilesystemError::Fuse { errno: EINVAL })?;
+            let (_, dataout) = self.send_sync(
+                FuseOpcode::FuseIoctl,
+                nodeid,
+                &[ioctlin.as_bytes(), &in_data],
+                size_of::<FuseIoctlOut>() + ioctlin.out_size as usize,
+            )?;
+            match decode_ioctl_reply(ioctlin.flags, &dataout)? {
+                IoctlStep::Done { result, out_data } => {
+                    return Ok(IoctlReply {
+                        result: result,
+                        out_data: out_data,
+                        out_iovs: out_iovs,
+                    });
+                }
+                IoctlStep::Retry {
+                    in_iovs,
+                    out_iovs: retry_out_iovs,
+                } => {
+                    in_data = read_iovs(&in_iovs)?;
+                    ioctlin.out_size = iovs_len(&retry_out_iovs)?;
+                    out_iovs = retry_out_iovs;
+                }
+            }
+        }
+        Err(FilesystemError::Fuse { errno: EIO })
+    }
+
+    /// Returns the offset `offset` is moved to with `whence` in a file opened
+    /// with `open`, e.g. the start of the next hole with `SEEK_HOLE`.
+    ///
+    /// A `SEEK_DATA` with no data at or after `offset` fails with `ENXIO`.
+    pub fn lseek_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<u64, FilesystemError> {
+        check_whence(whence)?;
+        self.check_handle(nodeid, fh)?;
+        let lseekin = FuseLseekIn {
+            fh: fh,
+            offset: offset,
+            whence: whence,
+            padding: 0,
+        };
+        let (_, dataout) = self.send_sync(
+            FuseOpcode::FuseLseek,
+            nodeid,
+            &[lseekin.as_bytes()],
+            size_of::<FuseLseekOut>(),
+        )?;
+        Ok(decode_reply::<FuseLseekOut>(&dataout)?.offset)
+    }
+
+    /// Returns the block of the device backing the file `nodeid` that holds its
+    /// block `index`, in blocks of `blocksize` bytes, as FIBMAP does.
+    ///
+    /// A server without block mapping, e.g. one not backed by a block device,
+    /// fails with `ENOSYS`.
+    pub fn bmap_sync(
+        &self,
+        nodeid: u64,
+        blocksize: u32,
+        index: u64,
+    ) -> Result<u64, FilesystemError> {
+        let bmapin = FuseBmapIn {
+            block: index,
+            blocksize: blocksize,
+            padding: 0,
+        };
+        let (_, dataout) = self.send_sync(
+            FuseOpcode::FuseBmap,
+            nodeid,
+            &[bmapin.as_bytes()],
+            size_of::<FuseBmapOut>(),
+        )?;
+        Ok(decode_reply::<FuseBmapOut>(&dataout)?.block)
+    }
+
+    /// Reads one page of directory entries along with their attributes.
+    ///
+    /// The entries count as lookups of their nodes, so they are handed over to
+    /// the cache. A `stat` of the entries can then be answered without `FUSE_GETATTR`.
+    pub fn readdirplus_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<FuseReaddirplusOut, FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let readin = FuseReadIn {
+            fh: fh,
+            offset: offset,
+            size: size,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        };
+        let (headerout, dataout) = self.send_sync(
+            FuseOpcode::FuseReaddirplus,
+            nodeid,
+            &[readin.as_bytes()],
+            size as usize,
+        )?;
+        let readdirplus_out = FuseReaddirplusOut::read_direntplus(
+            &mut VmReader::from(dataout.as_slice()),
+            headerout,
+        )?;
+        readdirplus_out
+            .dirents
+            .iter()
+            .filter(|dirent| dirent.name != b"." && dirent.name != b"..")
+            .for_each(|dirent| self.count_lookup(dirent.direntplus.entry_out.nodeid, 1));
+        let forgets = cache_direntplus(
+            &mut self.cache.disable_irq().lock(),
+            nodeid,
+            &readdirplus_out,
+            now(),
+        );
+        self.forget_evicted(forgets);
+        Ok(readdirplus_out)
+    }
+
+    /// Reads all entries of a directory opened with `opendir`.
+    ///
+    /// Each page is read into a buffer of `READDIR_BUFFER_SIZE` bytes, which
+    /// is enlarged up to the largest request for an entry that does not fit.
+    pub fn readdir_all(
+        &self,
+        nodeid: u64,
+        fh: u64,
+    ) -> Result<Vec<FuseDirentWithName>, FilesystemError> {
+        collect_dirents(|offset| {
+            fetch_growing(READDIR_BUFFER_SIZE, self.max_io_size(), |size| {
+                self.readdir_sync(nodeid, fh, offset, size)
+            })
+        })
+    }
+
+    /// Flushes the file opened with `open` as `fh` to stable storage.
+    ///
+    /// With `datasync`, only the data and the metadata needed to read it back
+    /// are flushed, as `fdatasync` does.
+    pub fn fsync_sync(&self, nodeid: u64, fh: u64, datasync: bool) -> Result<(), FilesystemError> {
+        self.check_handle(nodeid, fh)?;
+        let fsyncin = fuse_fsync_in(fh, datasync);
+        self.send_sync(FuseOpcode::FuseFsync, nodeid, &[fsyncin.as_bytes()], 0)?;
+        Ok(())
+    }
+
+    /// Removes the file `name` from directory `nodeid`.
+    pub fn unlink_sync(&self, nodeid: u64, name: &[u8]) -> Result<(), FilesystemError> {
+        let prepared_name = fuse_pad_str(name, true);
+        self.send_sync(FuseOpcode::FuseUnlink, nodeid, &[&prepared_name], 0)?;
+        self.remove_entries(&[(nodeid, name)]);
+        Ok(())
+    }
+
+    /// Removes the empty directory `name` from directory `nodeid`.
+    pub fn rmdir_sync(&self, nodeid: u64, name: &[u8]) -> Result<(), FilesystemError> {
+        let prepared_name = fuse_pad_str(name, true);
+        self.send_sync(FuseOpcode::FuseRmdir, nodeid, &[&prepared_name], 0)?;
+        self.remove_entries(&[(nodeid, name)]);
+        Ok(())
+    }
+
+    /// Renames `name` in directory `nodeid` to `newname` in directory `newdir`.
+    ///
+    /// `flags` is a combination of `RENAME_NOREPLACE`, `RENAME_EXCHANGE` and
+    /// `RENAME_WHITEOUT`. A server without `FUSE_RENAME2` only supports empty flags.
+    pub fn rename2_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        newdir: u64,
+        newname: &[u8],
+        flags: u32,
+    ) -> Result<(), FilesystemError> {
+        let names = [name, b"\0", newname, b"\0"].concat();
+        match rename_opcode(flags, self.protocol_minor())? {
+            FuseOpcode::FuseRename => {
+                let renamein = FuseRenameIn { newdir: newdir };
+                self.send_sync(
+                    FuseOpcode::FuseRename,
+                    nodeid,
+                    &[renamein.as_bytes(), &names],
+                    0,
+                )?;
+            }
+            opcode => {
+                let rename2in = FuseRename2In {
+                    newdir: newdir,
+                    flags: flags,
+                    padding: 0,
+                };
+                self.send_sync(opcode, nodeid, &[rename2in.as_bytes(), &names], 0)?;
+            }
+        }
+        // Both names may now refer to other nodes, or to none.
+        self.remove_entries(&[(nodeid, name), (newdir, newname)]);
+        Ok(())
+    }
+
+    /// Renames without replacing the target, failing with `EEXIST` if it exists.
+    pub fn rename_noreplace(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        newdir: u64,
+        newname: &[u8],
+    ) -> Result<(), FilesystemError> {
+        self.rename2_sync(nodeid, name, newdir, newname, RENAME_NOREPLACE)
+    }
+
+    /// Atomically exchanges the source and the target, which must both exist.
+    pub fn rename_exchange(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        newdir: u64,
+        newname: &[u8],
+    ) -> Result<(), FilesystemError> {
+        self.rename2_sync(nodeid, name, newdir, newname, RENAME_EXCHANGE)
+    }
+
+    /// Returns a handle that makes the next requests with the given credentials.
+    ///
+    /// This lets the server apply the semantics that depend on the caller,
+    /// such as setgid directories. The default credentials of the device
+    /// are left untouched.
+    pub fn with_creds(&self, uid: u32, gid: u32, pid: u32) -> WithCreds<'_> {
+        WithCreds {
+            device: self,
+            creds: FuseCreds {
+                uid: uid,
+                gid: gid,
+                pid: pid,
+            },
+            cap_fsetid: false,
+        }
+    }
+
+    /// Returns a handle that makes the next requests cancellable with `cancel`.
+    pub fn with_cancel<'a>(&'a self, cancel: &'a CancelToken) -> WithCancel<'a> {
+        WithCancel {
+            device: self,
+            cancel: cancel,
+        }
+    }
+
+    /// Cancels the requests made with `cancel`, waking up their callers.
+    pub fn cancel(&self, cancel: &CancelToken) {
+        cancel.cancel();
+        self.connection.reply_wait_queue.wake_all();
+    }
+
+    /// Creates the directory `name` in directory `nodeid`.
+    ///
+    /// The security context `secctx` and the supplementary groups `supp_groups`
+    /// of the caller are passed on if the server supports them.
+    pub fn mkdir_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        mode: u32,
+        umask: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<FuseEntryOut, FilesystemError> {
+        let creds = self.current_creds()?;
+        self.with_creds(creds.uid, creds.gid, creds.pid).mkdir_sync(
+            nodeid,
+            name,
+            mode,
+            umask,
+            secctx,
+            supp_groups,
+        )
+    }
+
+    /// Creates and opens the regular file `name` in directory `nodeid`, with
+    /// the mode, umask and open flags in `createin`.
+    ///
+    /// Returns the entry of the file along with its open handle. The security
+    /// context `secctx` and the supplementary groups `supp_groups` of the
+    /// caller are passed on if the server supports them.
+    pub fn create_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        createin: FuseCreateIn,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(FuseEntryOut, FuseOpenOut), FilesystemError> {
+        let creds = self.current_creds()?;
+        self.with_creds(creds.uid, creds.gid, creds.pid)
+            .create_sync(nodeid, name, createin, secctx, supp_groups)
+    }
+
+    /// Creates the file node `name` in directory `nodeid`.
+    ///
+    /// The security context `secctx` and the supplementary groups `supp_groups`
+    /// of the caller are passed on if the server supports them.
+    pub fn mknod_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<FuseEntryOut, FilesystemError> {
+        let creds = self.current_creds()?;
+        self.with_creds(creds.uid, creds.gid, creds.pid).mknod_sync(
+            nodeid,
+            name,
+            mode,
+            rdev,
+            umask,
+            secctx,
+            supp_groups,
+        )
+    }
+
+    /// Looks up `name` in directory `nodeid`, returning its entry along with
+    /// the metadata of the node.
+    ///
+    /// A valid cached entry is returned without asking the server.
+    pub fn lookup_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+    ) -> Result<(FuseEntryOut, Metadata), FilesystemError> {
+        if let Some(entry) = self.cache.disable_irq().lock().entry(nodeid, name, now()) {
+            self.node_refs.disable_irq().lock().get(entry.nodeid);
+            return Ok((entry, Metadata::from(entry.attr)));
+        }
+
+        let prepared_name = fuse_pad_str(name, true);
+        let (_, dataout) = self.send_sync(
+            FuseOpcode::FuseLookup,
+            nodeid,
+            &[&prepared_name],
+            size_of::<FuseEntryOut>(),
+        )?;
+        let entry = decode_reply::<FuseEntryOut>(&dataout)?;
+        self.check_submount(entry.nodeid, &entry.attr);
+        // The reference of the caller, and the one of the cached entry.
+        self.count_lookup(entry.nodeid, 2);
+        let forget = self
+            .cache
+            .disable_irq()
+            .lock()
+            .insert_entry(nodeid, name, &entry, now());
+        self.forget_evicted(forget);
+        Ok((entry, Metadata::from(entry.attr)))
+    }
+
+    /// Looks up `path` from directory `parent` one component at a time, and
+    /// returns the entry of the last one.
+    ///
+    /// `path` is relative to `parent`, so it must not start with `/`, and it must
+    /// name at least one entry. Empty and `.` components are skipped, while `..`
+    /// is looked up on the server like any other name. Symlinks are not followed.
+    ///
+    /// Fails with the error of the first component that cannot be looked up,
+    /// e.g. `ENOENT`. The caller holds a reference to the last entry only, as
+    /// with `lookup_sync`.
+    pub fn lookup_path(&self, parent: u64, path: &[u8]) -> Result<FuseEntryOut, FilesystemError> {
+        let components = path_components(path)?;
+        let mut entry: Option<FuseEntryOut> = None;
+        for name in components {
+            let nodeid = entry.map_or(parent, |entry| entry.nodeid);
+            let result = self.lookup_sync(nodeid, name);
+            // The directories walked through are referenced by the cache at most.
+            if let Some(entry) = entry {
+                self.put_nodes([(entry.nodeid, 1)]);
+            }
+            entry = Some(result?.0);
+        }
+        Ok(entry.unwrap())
+    }
+
+    /// Checks whether the caller may access node `nodeid` as `mask`, a
+    /// combination of `R_OK`, `W_OK` and `X_OK`.
+    pub fn access_sync(&self, nodeid: u64, mask: u32) -> Result<bool, FilesystemError> {
+        let accessin = FuseAccessIn {
+            mask: mask,
+            padding: 0,
+        };
+        let result = self.send_sync(FuseOpcode::FuseAccess, nodeid, &[accessin.as_bytes()], 0);
+        access_granted(result.map(|_| ()))
+    }
+
+    /// Returns the target of symlink `nodeid`.
+    ///
+    /// If the server agreed on `FUSE_CACHE_SYMLINKS`, the target is cached and
+    /// later calls are answered without `FUSE_READLINK`.
+    pub fn readlink_sync(&self, nodeid: u64) -> Result<Vec<u8>, FilesystemError> {
+        let cache_symlinks = self.negotiated_flags() & FUSE_CACHE_SYMLINKS != 0;
+        if cache_symlinks {
+            if let Some(target) = self.cache.disable_irq().lock().symlink(nodeid) {
+                return Ok(target);
+            }
+        }
+        let (_, target) = self.send_sync(FuseOpcode::FuseReadlink, nodeid, &[], FUSE_PATH_MAX)?;
+        if cache_symlinks {
+            self.cache
+                .disable_irq()
+                .lock()
+                .insert_symlink(nodeid, &target);
+        }
+        Ok(target)
+    }
+
+    /// Returns the statistics of the filesystem containing node `nodeid`, as
+    /// `statvfs` does.
+    pub fn statfs_sync(&self, nodeid: u64) -> Result<FuseKstatfs, FilesystemError> {
+        let (_, dataout) = self.send_sync(
+            FuseOpcode::FuseStatfs,
+            nodeid,
+            &[],
+            size_of::<FuseStatfsOut>(),
+        )?;
+        Ok(decode_statfs(&dataout)?.st)
+    }
+
+    /// Gets the attributes of node `nodeid`.
+    pub fn getattr_sync(&self, nodeid: u64) -> Result<Metadata, FilesystemError> {
+        let getattrin = FuseGetattrIn::new_zeroed();
+        let (_, dataout) = self.send_sync(
+            FuseOpcode::FuseGetattr,
+            nodeid,
+            &[getattrin.as_bytes()],
+            size_of::<FuseAttrOut>(),
+        )?;
+        let attr_out = decode_reply::<FuseAttrOut>(&dataout)?;
+        self.check_submount(nodeid, &attr_out.attr);
+        let valid = valid_duration(attr_out.attr_valid, attr_out.attr_valid_nsec);
+        self.cache
+            .disable_irq()
+            .lock()
+            .insert_attr(nodeid, attr_out.attr, valid, now());
+        Ok(Metadata::from(attr_out.attr))
+    }
+
+    /// Changes the attributes of node `nodeid` as set in `request`, returning
+    /// the metadata of the node afterwards.
+    pub fn setattr_with(
+        &self,
+        nodeid: u64,
+        request: &SetattrRequest,
+    ) -> Result<Metadata, FilesystemError> {
+        self.setattr_as(self.current_creds()?, nodeid, request)
+    }
+
+    fn setattr_as(
+        &self,
+        creds: FuseCreds,
+        nodeid: u64,
+        request: &SetattrRequest,
+    ) -> Result<Metadata, FilesystemError> {
+        let setattrin = request.setattr_in();
+        let (_, dataout) = self.send_sync_as(
+            creds,
+            FuseOpcode::FuseSetattr,
+            nodeid,
+            &[setattrin.as_bytes()],
+            0,
+            size_of::<FuseAttrOut>(),
+        )?;
+        let attr_out = decode_reply::<FuseAttrOut>(&dataout)?;
+        let valid = valid_duration(attr_out.attr_valid, attr_out.attr_valid_nsec);
+        self.cache
+            .disable_irq()
+            .lock()
+            .insert_attr(nodeid, attr_out.attr, valid, now());
+        Ok(Metadata::from(attr_out.attr))
+    }
+
+    /// Truncates or extends the file `nodeid` to `size` bytes.
+    pub fn truncate(&self, nodeid: u64, size: u64) -> Result<Metadata, FilesystemError> {
+        self.setattr_with(nodeid, &SetattrRequest::new().size(size))
+    }
+
+    /// Changes the permission bits of node `nodeid` to those of `mode`.
+    pub fn chmod(&self, nodeid: u64, mode: u32) -> Result<Metadata, FilesystemError> {
+        self.setattr_with(nodeid, &SetattrRequest::new().mode(mode))
+    }
+
+    /// Changes the owner of node `nodeid` to `uid` and its group to `gid`.
+    pub fn chown(&self, nodeid: u64, uid: u32, gid: u32) -> Result<Metadata, FilesystemError> {
+        self.setattr_with(nodeid, &SetattrRequest::new().uid(uid).gid(gid))
+    }
+
+    /// Returns the attributes of node `nodeid`, from the cache while they are valid.
+    pub fn stat_sync(&self, nodeid: u64) -> Result<FuseAttr, FilesystemError> {
+        if let Some(attr) = self.cache.disable_irq().lock().attr(nodeid, now()) {
+            return Ok(attr);
+        }
+        Ok(*self.getattr_sync(nodeid)?.attr())
+    }
+
+    /// Registers a callback receiving the invalidations sent by the server.
+    ///
+    /// They only arrive if `VIRTIO_FS_F_NOTIFICATION` is negotiated.
+    pub fn register_callback(&self, callback: Arc<dyn FilesystemCallback>) {
+        self.callbacks.write().push(callback);
+    }
+
+    /// Registers a callback invoked with the nodeid of every submount met by a
+    /// lookup or getattr.
+    ///
+    /// Submounts are only reported if the server agreed on `FUSE_SUBMOUNTS`.
+    /// The callback may run in interrupt context and must not block.
+    pub fn register_automount_callback(&self, callback: &'static AutomountCallback) {
+        self.automount_callbacks.write().push(Arc::new(callback));
+    }
+
+    /// Registers the provider of the credentials that requests are made with.
+    ///
+    /// Until one is registered, requests are made as root. The provider may
+    /// run with interrupts disabled and must not block.
+    pub fn register_creds_provider(&self, provider: &'static CredsProvider) {
+        *self.creds_provider.write() = Some(Arc::new(provider));
+    }
+
+    /// Returns the extensions of a request creating a node, along with their `total_extlen`.
+    fn create_extensions(
+        &self,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(Vec<u8>, u16), FilesystemError> {
+        let secctx = self
+            .has_negotiated(FUSE_SECURITY_CTX)
+            .then(|| secctx_extension(secctx));
+        let supp_groups = (self.has_negotiated(FUSE_CREATE_SUPP_GROUP) && !supp_groups.is_empty())
+            .then(|| supp_groups_extension(supp_groups));
+        let exts: Vec<_> = secctx
+            .iter()
+            .chain(supp_groups.iter())
+            .map(|(ext_type, data)| (*ext_type, data.as_slice()))
+            .collect();
+        build_extensions(&exts)
+    }
+
+    /// Returns the credentials of the current task to put in a `FuseInHeader`.
+    fn current_creds(&self) -> Result<FuseCreds, FilesystemError> {
+        let provider = self.creds_provider.read().clone();
+        provided_creds(provider.as_deref(), self.negotiated_flags())
+    }
+
+    /// Invokes the automount callbacks if `attr` marks node `nodeid` as a submount.
+    fn check_submount(&self, nodeid: u64, attr: &FuseAttr) {
+        let callbacks = self.automount_callbacks.read();
+        dispatch_submount(&callbacks, self.negotiated_flags(), nodeid, attr);
+    }
+
+    /// Sets how requests fail once the connection is aborted with `abort`.
+    pub fn on_abort(&self, policy: AbortPolicy) {
+        self.connection.abort_state.disable_irq().lock().policy = policy;
+    }
+
+    /// Aborts the connection, e.g. once the server is known to be gone.
+    ///
+    /// The requests awaiting a reply fail right away, and so do the ones made
+    /// afterwards, unless the `AbortPolicy` holds them until `reconnect`. They
+    /// fail with `ECONNABORTED` once `FUSE_ABORT_ERROR` is negotiated, or as
+    /// the policy asks for, and with `EIO` otherwise.
+    ///
+    /// Replies the device still returns are dropped undecoded.
+    pub fn abort(&self) {
+        self.connection.abort();
+    }
+
+    /// Lets requests through again after `abort`, once the connection is reestablished.
+    pub fn reconnect(&self) {
+        self.connection.reconnect();
+    }
+
+    /// Returns whether the `FUSE_INIT` handshake has completed, so that other
+    /// requests may be sent.
+    pub fn is_ready(&self) -> bool {
+        self.connection.is_ready()
+    }
+
+    /// Returns the parameters agreed on in `FUSE_INIT`, or `None` before it is answered.
+    pub fn negotiated(&self) -> Option<NegotiatedInit> {
+        self.connection.negotiated()
+    }
+
+    /// Returns the negotiated init flags, with `flags2` in the upper 32 bits.
+    ///
+    /// No flag is set before `FUSE_INIT` is answered.
+    pub fn negotiated_flags(&self) -> u64 {
+        self.negotiated()
+            .map_or(0, |negotiated| negotiated.fuse_flags())
+    }
+
+    /// Returns whether the server agreed on the init flag `flag`.
+    fn has_negotiated(&self, flag: u64) -> bool {
+        self.negotiated_flags() & flag != 0
+    }
+
+    /// Returns the minor version of the FUSE protocol, or 0 before `FUSE_INIT` is answered.
+    fn protocol_minor(&self) -> u32 {
+        self.negotiated().map_or(0, |negotiated| negotiated.minor)
+    }
+
+    /// Sends a request on one of the request queues and blocks until its reply arrives.
+    ///
+    /// `datain` is the request body following the `FuseInHeader`, and `out_len` is
+    /// the size reserved for the reply body following the `FuseOutHeader`.
+    fn send_sync(
+        &self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+        self.send_sync_with(None, opcode, nodeid, datain, out_len)
+    }
+
+    /// Same as `send_sync`, but gives up on the request once `cancel` is cancelled.
+    fn send_sync_with(
+        &self,
+        cancel: Option<&CancelToken>,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+        let creds = self.current_creds()?;
+        let reply = self.submit_sync(
+            creds,
+            opcode,
+            nodeid,
+            RequestParts {
+                datain: datain,
+                out_len: out_len,
+                ..Default::default()
+            },
+            cancel,
+        )?;
+        parse_reply(&reply)
+    }
+
+    /// Sends a request on one of the request queues without waiting for its reply.
+    ///
+    /// `datain` is the request body following the `FuseInHeader`, and `out_len` is
+    /// the size reserved for the reply body. The reply is taken with the returned
+    /// handle, which may also give up on it, e.g. once the device stops answering.
+    pub fn send_request(
+        &self,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<RequestHandle<'_>, FilesystemError> {
+        let creds = self.current_creds()?;
+        self.submit(
+            creds,
+            opcode,
+            nodeid,
+            RequestParts {
+                datain: datain,
+                out_len: out_len,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Same as `send_sync`, but makes the request with the credentials `creds`.
+    ///
+    /// `datain` ends with extensions of `total_extlen` units of 8 bytes, if any.
+    fn send_sync_as(
+        &self,
+        creds: FuseCreds,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        datain: &[&[u8]],
+        total_extlen: u16,
+        out_len: usize,
+    ) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+        let reply = self.submit_sync(
+            creds,
+            opcode,
+            nodeid,
+            RequestParts {
+                datain: datain,
+                total_extlen: total_extlen,
+                out_len: out_len,
+                ..Default::default()
+            },
+            None,
+        )?;
+        parse_reply(&reply)
+    }
+
+    /// Sends a request and blocks until its reply arrives, which is returned from
+    /// the `FuseOutHeader` on.
+    ///
+    /// The request is staged in a slot of the pool of its queue, waiting for one
+    /// to be free if needed. Its body is `parts.datain`, staged in the slot,
+    /// followed by `parts.in_pages`, which the device reads in place. The reply
+    /// body goes to the `parts.out_len` bytes following the `FuseOutHeader` in the
+    /// slot, then to `parts.out_pages`. Only the part in the slot is returned.
+    ///
+    /// The request and its reply each have a slot of their own, at the same
+    /// offset of `RequestPool::requests` and `RequestPool::replies`, so the
+    /// pages the device reads in place take no room in either.
+    ///
+    /// If `cancel` is cancelled before the reply arrives, the request fails with
+    /// `EINTR` and its reply is ignored.
+    fn submit_sync(
+        &self,
+        creds: FuseCreds,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        parts: RequestParts<'_>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<u8>, FilesystemError> {
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(FilesystemError::Fuse { errno: EINTR });
+        }
+        self.submit(creds, opcode, nodeid, parts)?
+            .wait_reply(cancel)
+    }
+
+    /// Sends a request on one of the request queues, returning the handle its
+    /// reply is waited for with.
+    ///
+    /// Once the slot the request is staged in is returned by the device, it is
+    /// freed by `handle_recv_irq`, whether or not the request is still waited for.
+    fn submit(
+        &self,
+        creds: FuseCreds,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        parts: RequestParts<'_>,
+    ) -> Result<RequestHandle<'_>, FilesystemError> {
+        self.connection
+            .reply_wait_queue
+            .wait_until(|| self.connection.admit())?;
+        self.submit_admitted(creds, opcode, nodeid, parts)
+    }
+
+    /// Same as `submit`, but without waiting for the request to be let through,
+    /// e.g. for the `FUSE_DESTROY` of `shutdown`.
+    fn submit_admitted(
+        &self,
+        creds: FuseCreds,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        parts: RequestParts<'_>,
+    ) -> Result<RequestHandle<'_>, FilesystemError> {
+        let queue_idx = self.select_queue();
+        let pool = &self.request_pools[queue_idx];
+        let len_staged =
+            size_of::<FuseInHeader>() + parts.datain.iter().map(|data| data.len()).sum::<usize>();
+        let len_in = len_staged
+            + parts
+                .in_pages
+                .iter()
+                .map(|page| page.nbytes())
+                .sum::<usize>();
+        let slot_size = pool.disable_irq().lock().slot_size;
+        check_slot_room(
+            len_staged,
+            size_of::<FuseOutHeader>() + parts.out_len,
+            slot_size,
+        )?;
+
+        let unique = self.connection.next_unique();
+        let mut headerin = fuse_in_header(opcode, unique, nodeid, len_in, creds);
+        headerin.total_extlen = parts.total_extlen;
+
+        // The slot is only given back once the device returns it, even if the
+        // request is cancelled in the meantime.
+        let offset = self
+            .connection
+            .reply_wait_queue
+            .wait_until(|| pool.disable_irq().lock().alloc());
+        let (requests, replies) = {
+            let pool = pool.disable_irq().lock();
+            (pool.requests.clone(), pool.replies.clone())
+        };
+
+        let start = now();
+        let post = || -> Result<(), FilesystemError> {
+            let mut request_queue = self.request_queues[queue_idx].disable_irq().lock();
+            {
+                // An abort since the check above has no more chance to fail the request.
+                let mut pending_replies = self.connection.pending_replies.disable_irq().lock();
+                let abort_state = self.connection.abort_state.disable_irq().lock();
+                if abort_state.aborted {
+                    return Err(abort_state.error());
+                }
+                pending_replies.insert(unique);
+            }
+            let posted = post_request(
+                &mut *request_queue,
+                &requests,
+                &replies,
+                offset,
+                &headerin,
+                &parts,
+            );
+            let token = match posted {
+                Ok(token) => token,
+                Err(err) => {
+                    self.connection
+                        .pending_replies
+                        .disable_irq()
+                        .lock()
+                        .remove(unique);
+                    return Err(err);
+                }
+            };
+            // The reply cannot be handled before this, as the queue is still locked.
+            pool.disable_irq().lock().track(token, offset, true);
+
+            if request_queue.should_notify() {
+                request_queue.notify();
+            }
+            Ok(())
+        };
+        if let Err(err) = post() {
+            pool.disable_irq().lock().free(offset);
+            return Err(err);
+        }
+        self.stats.submitted(headerin.opcode);
+
+        Ok(RequestHandle {
+            device: self,
+            unique: unique,
+            opcode: headerin.opcode,
+            start: start,
+        })
+    }
+
+    /// Hands the reply of a request sent by `send_sync`, written in the slot at
+    /// `offset` of `pool`, over to its waiter.
+    ///
+    /// Returns `false` if nobody is waiting for the request.
+    fn complete_reply(
+        &self,
+        pool: &RequestPool,
+        offset: usize,
+        headerin: &FuseInHeader,
+        len: usize,
+    ) -> bool {
+        // A `FUSE_NOTIFY_REPLY` carries the server's `notify_unique`, not one of ours.
+        if headerin.opcode == FuseOpcode::FuseNotifyReply as u32 {
+            return false;
+        }
+        let mut pending_replies = self.connection.pending_replies.disable_irq().lock();
+        // A duplicate reply, or one to a request failed by `abort`, is dropped
+        // without being copied.
+        if !pending_replies.is_waiting(headerin.unique) {
+            return false;
+        }
+
+        // A reply that cannot be read fails its request rather than the handler.
+        let reply = read_reply(&pool.replies, offset, len, pool.slot_size);
+        pending_replies.complete(headerin.unique, reply)
+    }
+
+    /// Sends the request `headerin` on the request queue `queue_idx` without
+    /// waiting for its reply.
+    ///
+    /// The request body is `datain`, staged in the slot right after the header,
+    /// and `out_len` bytes are left for the reply body. The request takes a
+    /// slot of the pool of the queue, under a unique of its own, and
+    /// `handle_reply` decodes its reply once the device returns the slot.
+    /// Waits for a free slot if all slots are occupied, so this must not be
+    /// called from an interrupt handler.
+    fn send_unawaited(
+        &self,
+        queue_idx: usize,
+        headerin: FuseInHeader,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<(), FilesystemError> {
+        self.check_unawaited(&headerin, datain, out_len)?;
+        let pool = &self.request_pools[queue_idx];
+        let offset = self
+            .connection
+            .reply_wait_queue
+            .wait_until(|| pool.disable_irq().lock().alloc());
+        self.post_unawaited(queue_idx, offset, headerin, datain, out_len)
+    }
+
+    /// Like `send_unawaited`, but fails with `EAGAIN` instead of waiting if all
+    /// slots are occupied.
+    fn try_send_unawaited(
+        &self,
+        queue_idx: usize,
+        headerin: FuseInHeader,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<(), FilesystemError> {
+        self.check_unawaited(&headerin, datain, out_len)?;
+        let offset = self.request_pools[queue_idx]
+            .disable_irq()
+            .lock()
+            .alloc()
+            .ok_or(FilesystemError::Fuse { errno: EAGAIN })?;
+        self.post_unawaited(queue_idx, offset, headerin, datain, out_len)
+    }
+
+    /// Checks that `headerin` covers `datain`, and that the request and
+    /// `out_len` bytes of reply body fit in a slot.
+    fn check_unawaited(
+        &self,
+        headerin: &FuseInHeader,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<(), FilesystemError> {
+        let len_in =
+            size_of::<FuseInHeader>() + datain.iter().map(|data| data.len()).sum::<usize>();
+        if headerin.len as usize != len_in {
+            return Err(FilesystemError::BufferTooShort);
+        }
+        check_slot_room(
+            len_in,
+            size_of::<FuseOutHeader>() + out_len,
+            self.slot_size(),
+        )
+    }
+
+    /// Posts a request sent by `send_unawaited` in the slot at `offset`, which
+    /// is freed again if the request cannot be posted.
+    fn post_unawaited(
+        &self,
+        queue_idx: usize,
+        offset: usize,
+        mut headerin: FuseInHeader,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Result<(), FilesystemError> {
+        // A `FUSE_NOTIFY_REPLY` carries the server's `notify_unique`, not one of ours.
+        if headerin.opcode != FuseOpcode::FuseNotifyReply as u32 {
+            headerin.unique = self.connection.next_unique();
+        }
+
+        let mut request_queue = self.request_queues[queue_idx].disable_irq().lock();
+        let mut pool = self.request_pools[queue_idx].disable_irq().lock();
+        let posted = post_request(
+            &mut *request_queue,
+            &pool.requests,
+            &pool.replies,
+            offset,
+            &headerin,
+            &RequestParts {
+                datain: datain,
+                out_len: out_len,
+                ..Default::default()
+            },
+        );
+        let token = match posted {
+            Ok(token) => token,
+            Err(err) => {
+                pool.free(offset);
+                return Err(err);
+            }
+        };
+        pool.track(token, offset, false);
+        drop(pool);
+        self.stats.submitted(headerin.opcode);
+
+        if request_queue.should_notify() {
+            request_queue.notify();
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the slots of the request pools.
+    fn slot_size(&self) -> usize {
+        self.request_pools[0].disable_irq().lock().slot_size
+    }
+
+    /// Waits for the reply to `FUSE_INIT`, then resizes the request pools to
+    /// the negotiated `max_write` and `max_pages`.
+    ///
+    /// This runs in process context before the device is handed out, so no
+    /// other request is in flight. If the server takes longer than `INIT_TIMEOUT`
+    /// to answer, the buffers are left at their default size, which
+    /// `max_io_size` keeps the reads and writes within.
+    fn wait_init(&self) -> Result<(), FilesystemError> {
+        let admitted = self
+            .connection
+            .wait_timeout(0, INIT_TIMEOUT, || self.connection.admit());
+        let Some(admitted) = admitted else {
+            warn!("virtio-fs FUSE_INIT is not answered, keeping the default buffer size");
+            return Ok(());
+        };
+        admitted?;
+        if let Some(negotiated) = self.negotiated() {
+            self.resize_request_buffers(&negotiated)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the request pools with ones whose slots fit the negotiated
+    /// `max_write` and `max_pages`.
+    ///
+    /// Fails if a pool cannot be allocated, in which case it keeps its size.
+    fn resize_request_buffers(&self, negotiated: &NegotiatedInit) -> Result<(), FilesystemError> {
+        let buffer_size = request_buffer_size(negotiated);
+        for pool in self.request_pools.iter() {
+            pool.disable_irq().lock().resize(buffer_size)?;
+        }
+        debug!("virtio-fs request slots resized to {} bytes", buffer_size);
+        Ok(())
+    }
+
+    /// Returns the largest payload of a single read or write, in whole pages.
+    ///
+    /// This follows the negotiated `max_write` and `max_pages`, which the
+    /// slots of the request pools are sized from.
+    fn max_io_size(&self) -> u32 {
+        let headers_len = size_of::<FuseInHeader>()
+            + size_of::<FuseWriteIn>().max(size_of::<FuseReadIn>())
+            + size_of::<FuseOutHeader>();
+        let max_io_size = (self.slot_size() - headers_len).align_down(PAGE_SIZE);
+        u32::try_from(max_io_size).unwrap_or(u32::MAX)
+    }
+
+    /// Returns the largest payload of a single read or write, which fits both
+    /// the request buffers and the negotiated `max_pages`.
+    fn max_transfer_size(&self) -> u32 {
+        self.negotiated().map_or(self.max_io_size(), |negotiated| {
+            self.max_io_size().min(negotiated.max_transfer_size())
+        })
+    }
+
+    /// Returns the largest payload of a single write, which also stays within
+    /// the negotiated `max_write`.
+    fn max_write_size(&self) -> u32 {
+        self.negotiated()
+            .map_or(self.max_transfer_size(), |negotiated| {
+                self.max_transfer_size().min(negotiated.max_write)
+            })
+    }
+
+    /// Picks the request queue for the next request in a round-robin manner.
+    fn select_queue(&self) -> usize {
+        self.next_queue.fetch_add(1, Ordering::Relaxed) % self.request_queues.len()
+    }
+
+    /// Sends a request made of `parts` on the hiprio queue, with its
+    /// first `len_in` bytes readable by the device.
+    ///
+    /// The request is staged in `hiprio_buffer`, never in a request pool,
+    /// and the device is waited for at most `HIPRIO_TIMEOUT` to return it.
+    ///
+    /// Returns the header of the reply, if the device wrote one.
+    fn submit_hiprio(
+        &self,
+        opcode: FuseOpcode,
+        parts: &[&[u8]],
+        len_in: usize,
+    ) -> Result<Option<FuseOutHeader>, FilesystemError> {
+        let opcode = opcode as u32;
+        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+        let used_len = post_hiprio(
+            &mut *hiprio_queue,
+            &self.hiprio_buffer,
+            parts,
+            len_in,
+            HIPRIO_TIMEOUT,
+        )
+        .inspect_err(|err| warn!("hiprio request {} failed: {:?}", opcode, err))?;
+        self.stats.submitted(opcode);
+        self.stats.completed(opcode);
+
+        if (used_len as usize) < size_of::<FuseOutHeader>() {
+            return Ok(None);
+        }
+        self.hiprio_buffer
+            .sync(len_in..len_in + size_of::<FuseOutHeader>())?;
+        let mut reader = self.hiprio_buffer.reader()?.skip(len_in);
+        Ok(Some(reader.read_val::<FuseOutHeader>()?))
+    }
+
+    /// Sends a `FUSE_INTERRUPT` for request `unique`, returning what the server
+    /// answered of it.
+    ///
+    /// The interrupt carries a unique of its own, so that a reply to it is never
+    /// taken for the reply of the interrupted request.
+    fn send_interrupt(&self, unique: u64) -> Result<InterruptStatus, FilesystemError> {
+        let len_in = size_of::<FuseInterruptIn>() + size_of::<FuseInHeader>();
+        let headerin = fuse_in_header(
+            FuseOpcode::FuseInterrupt,
+            self.connection.next_unique(),
+            0,
+            len_in,
+            FuseCreds::default(),
+        );
+        let interruptin = FuseInterruptIn { unique: unique };
+
+        let headerin_bytes = headerin.as_bytes();
+        let interruptin_bytes = interruptin.as_bytes();
+        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
+
+        let headerout = self.submit_hiprio(
+            FuseOpcode::FuseInterrupt,
+            &[headerin_bytes, interruptin_bytes, &headerout_buffer],
+            len_in,
+        )?;
+        interrupt_status(headerout.as_ref())
+    }
+
+    /// Interrupts request `unique`, which must be one sent by `send_sync` that
+    /// still awaits its reply.
+    ///
+    /// The interrupt is sent again for as long as the server asks to retry it
+    /// and the request is pending. Fails with `FilesystemError::InvalidOperation`
+    /// if there is no such request.
+    pub fn interrupt_request(&self, unique: u64) -> Result<InterruptStatus, FilesystemError> {
+        interrupt_until_settled(
+            || {
+                self.connection
+                    .pending_replies
+                    .disable_irq()
+                    .lock()
+                    .is_waiting(unique)
+            },
+            || self.send_interrupt(unique),
+        )
+    }
+
+    /// Selects the request queue of a request that is not waited for, failing
+    /// unless the `FUSE_INIT` handshake has completed.
+    ///
+    /// Such a request is not held until `reconnect` after an abort, as nobody
+    /// waits for it.
+    fn ready_queue(&self) -> Result<usize, FilesystemError> {
+        if let Some(err) = self.connection.abort_error() {
+            return Err(err);
+        }
+        if !self.is_ready() {
+            return Err(FilesystemError::InvalidOperation);
+        }
+        Ok(self.select_queue())
+    }
+
+    /// Handles the notifications the device has written to the notification queue.
+    fn handle_notify_irq(&self) {
+        let Some(notify_queue) = &self.notify_queue else {
+            return;
+        };
+        let mut notify_queue = notify_queue.disable_irq().lock();
+        while let Ok((token, len)) = notify_queue.queue.pop_used() {
+            let Some(offset) = notify_queue.slots.remove(&token) else {
+                warn!(
+                    "virtio-fs notification completed an unknown token {}",
+                    token
+                );
+                continue;
+            };
+
+            let len = (len as usize).min(notify_queue.slot_size);
+            // A notification that cannot be read is dropped, but its buffer is
+            // still posted again.
+            let buffer = &notify_queue.buffer;
+            match buffer
+                .sync(offset..offset + len)
+                .and_then(|()| buffer.reader())
+            {
+                Ok(reader) => {
+                    let mut reader = reader.skip(offset).limit(len);
+                    match reader.read_val::<FuseOutHeader>() {
+                        Ok(headerout) => self.dispatch_notify(&headerout, &mut reader),
+                        Err(_) => warn!("virtio-fs notification is shorter than its header"),
+                    }
+                }
+                Err(err) => warn!("virtio-fs failed to read a notification: {:?}", err),
+            }
+
+            if let Err(err) = notify_queue.post(offset) {
+                warn!(
+                    "virtio-fs failed to repost a notification buffer: {:?}",
+                    err
+                );
+            }
+        }
+        if notify_queue.queue.should_notify() {
+            notify_queue.queue.notify();
+        }
+    }
+
+    /// Decodes the notification following `headerout`.
+    ///
+    /// A notification is a reply with `unique` 0, whose `error` field holds
+    /// the `FuseNotifyCode`.
+    fn dispatch_notify(&self, headerout: &FuseOutHeader, reader: &mut VmReader<'_, Infallible>) {
+        let Ok(code) = FuseNotifyCode::try_from(headerout.error as u32) else {
+            warn!("virtio-fs unknown notification code {}", headerout.error);
+            return;
+        };
+        let result = match code {
+            FuseNotifyCode::FuseNotifyPoll
+            | FuseNotifyCode::FuseNotifyInvalInode
+            | FuseNotifyCode::FuseNotifyInvalEntry
+            | FuseNotifyCode::FuseNotifyDelete => {
+                let driver_caches = DriverCaches(self);
+                let registered = self.callbacks.read();
+                let callbacks: Vec<&dyn FilesystemCallback> =
+                    core::iter::once(&driver_caches as &dyn FilesystemCallback)
+                        .chain(registered.iter().map(|callback| callback.as_ref()))
+                        .collect();
+                notify_callbacks(&callbacks, code, reader)
+            }
+            FuseNotifyCode::FuseNotifyStore => self.handle_notify_store(reader),
+            FuseNotifyCode::FuseNotifyRetrieve => self.handle_notify_retrieve(reader),
+            FuseNotifyCode::FuseNotifyResend | FuseNotifyCode::FuseNotifyCodeMax => {
+                debug!("virtio-fs ignores notification code {}", headerout.error);
+                Ok(())
+            }
+        };
+        if result.is_err() {
+            warn!(
+                "virtio-fs notification {} is shorter than its body",
+                headerout.error
+            );
+        }
+    }
+
+    /// Keeps the data following `FuseNotifyStoreOut`, which the server pushes for a node.
+    fn handle_notify_store(&self, reader: &mut VmReader<'_, Infallible>) -> ostd::Result<()> {
+        let out = reader.read_val::<FuseNotifyStoreOut>()?;
+        if reader.remain() < out.size as usize {
+            return Err(ostd::Error::InvalidArgs);
+        }
+        let mut data = vec![0u8; out.size as usize];
+        reader.read(&mut VmWriter::from(data.as_mut_slice()));
+        let stored = self
+            .stored_data
+            .disable_irq()
+            .lock()
+            .store(out.nodeid, out.offset, &data);
+        if !stored {
+            debug!(
+                "virtio-fs drops {} bytes stored at {} in {}",
+                out.size, out.offset, out.nodeid
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends back the data asked for by `FuseNotifyRetrieveOut`.
+    ///
+    /// Only the data stored earlier is known, so the reply may be short.
+    fn handle_notify_retrieve(&self, reader: &mut VmReader<'_, Infallible>) -> ostd::Result<()> {
+        let out = reader.read_val::<FuseNotifyRetrieveOut>()?;
+        let data = self
+            .stored_data
+            .disable_irq()
+            .lock()
+            .retrieve(out.nodeid, out.offset, out.size)
+            .to_vec();
+        if let Err(err) = self.notify_reply(out.notify_unique, out.nodeid, out.offset, &data) {
+            warn!(
+                "virtio-fs failed to reply to retrieve {}: {}",
+                out.notify_unique, err
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_recv_irq(&self, queue_idx: usize) {
+        // The device may have completed several requests since the last interrupt.
+        let mut completed = false;
+        loop {
+            let mut request_queue = self.request_queues[queue_idx].disable_irq().lock();
+            let Ok((token, len)) = request_queue.pop_used() else {
+                break;
+            };
+            // Once the connection is aborted, the waiters are failed already and
+            // the replies are not to be trusted, so only the buffers are taken back.
+            let aborted = self.connection.abort_error().is_some();
+            let mut pool = self.request_pools[queue_idx].disable_irq().lock();
+            let Some(request) = pool.complete(token) else {
+                warn!(
+                    "virtio-fs queue {} returned unknown token {}",
+                    queue_idx, token
+                );
+                continue;
+            };
+            // A request may be waiting for the slot, if not for the reply.
+            completed = true;
+            if aborted {
+                pool.free(request.offset);
+            } else if request.awaited {
+                self.complete_pooled_reply(&pool, request.offset, len as usize);
+                pool.free(request.offset);
+            } else {
+                // The reply is copied out, so that it is decoded with the slot given back.
+                let exchange = read_exchange(&pool, request.offset, len as usize);
+                pool.free(request.offset);
+                drop(pool);
+                drop(request_queue);
+                let handled =
+                    exchange.and_then(|(request, reply)| self.handle_reply(&request, &reply));
+                if let Err(err) = handled {
+                    warn!("virtio-fs failed to handle a reply: {}", err);
+                }
+            }
+        }
+        if completed {
+            self.connection.reply_wait_queue.wake_all();
+        }
+    }
+
+    /// Hands the reply of `len` bytes to the request in the slot at `offset` of
+    /// `pool` over to its waiter.
+    ///
+    /// A reply nobody waits for anymore, e.g. as the request was cancelled, is dropped.
+    fn complete_pooled_reply(&self, pool: &RequestPool, offset: usize, len: usize) {
+        // The device only reads the request, so it is read back as staged.
+        let headerin = pool
+            .requests
+            .reader()
+            .and_then(|reader| reader.skip(offset).read_val::<FuseInHeader>());
+        match headerin {
+            Ok(headerin) => {
+                self.stats.completed(headerin.opcode);
+                self.complete_reply(pool, offset, &headerin, len);
+            }
+            Err(err) => warn!("virtio-fs failed to read a staged request: {:?}", err),
+        }
+    }
+
+    /// Decodes the `reply` to the `request` sent with `send_unawaited`.
+    ///
+    /// A reply too short for what it answers fails, rather than be read past.
+    fn handle_reply(&self, request: &[u8], reply: &[u8]) -> Result<(), FilesystemError> {
+        // The reply is decoded right after the request, as if they were one buffer.
+        let exchange = [request, reply].concat();
+        let mut reader = CountingReader::new(VmReader::from(exchange.as_slice()));
+        let headerin = reader.read_val::<FuseInHeader>()?;
+        self.stats.completed(headerin.opcode);
+
+        // Requests without a reply, e.g. `FUSE_FORGET`, leave nothing to check.
+        let mut headerout = VmReader::from(reply).read_val::<FuseOutHeader>().ok();
+        let error = headerout.as_ref().map_or(Ok(()), check_error);
+
+        let opcode = FuseOpcode::try_from(headerin.opcode)
+            .map_err(|_| FilesystemError::UnexpectedDataInPacket)?;
+        match opcode {
+            // The error is the answer of an access check, which has no reply body.
+            FuseOpcode::FuseAccess => {
+                let _datain = reader.read_val::<FuseAccessIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                match access_granted(check_error(&headerout)) {
+                    Ok(granted) => debug!("virtio-fs access reply: granted = {:?}", granted),
+                    Err(err) => debug!("virtio-fs access reply: {}", err),
+                }
+            }
+            // An interrupt is answered with an error for anything but success.
+            FuseOpcode::FuseInterrupt => {
+                let _datain = reader.read_val::<FuseInterruptIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                match interrupt_status(Some(&headerout)) {
+                    Ok(status) => debug!("virtio-fs interrupt reply: {:?}", status),
+                    Err(err) => debug!("virtio-fs interrupt reply: {}", err),
+                }
+            }
+            // A mode the server does not support is answered with `EOPNOTSUPP`.
+            FuseOpcode::FuseFallocate => {
+                let datain = reader.read_val::<FuseFallocateIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                match check_error(&headerout) {
+                    Ok(()) => debug!("virtio-fs fallocate reply: success"),
+                    Err(FilesystemError::Fuse { errno: EOPNOTSUPP }) => debug!(
+                        "virtio-fs fallocate mode {:?} is not supported",
+                        FallocFlags::from_bits_truncate(datain.mode)
+                    ),
+                    Err(err) => debug!("virtio-fs fallocate reply: {}", err),
+                }
+            }
+            // A failed request has no reply body to decode.
+            _ if error.is_err() => {
+                debug!(
+                    "virtio-fs request with opcode {} failed: {}",
+                    headerin.opcode,
+                    error.unwrap_err()
+                );
+                headerout = None;
+            }
+            FuseOpcode::FuseInit => {
+                let _datain = reader.read_val::<FuseInitIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let mut bytes = vec![0u8; reply_body_len(&headerout).min(reader.remain())];
+                reader.read(&mut VmWriter::from(bytes.as_mut_slice()));
+                let dataout = match parse_init_reply(&headerout, &bytes) {
+                    Ok(dataout) => dataout,
+                    Err(err) => {
+                        warn!("virtio-fs init failed: {}", err);
+                        self.connection.finish_init(None);
+                        return Ok(());
+                    }
+                };
+                let negotiated = NegotiatedInit::new(&dataout);
+                if negotiated.has(FUSE_NO_OPEN_SUPPORT) {
+                    self.no_open.store(true, Ordering::Relaxed);
+                }
+                self.connection.finish_init(Some(negotiated));
+                debug!(
+                    "virtio-fs init reply: version {}.{}, flags {:?}",
+                    negotiated.major,
+                    negotiated.minor,
+                    negotiated.fuse_flags()
+                );
+            }
+            FuseOpcode::FuseReaddir => {
+                // 这里的datain千万不要注释，注释掉会出bug！！！！
+                let _datain = reader.read_val::<FuseReadIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout)
+                    .unwrap_or_else(|err| {
+                        warn!("virtio-fs readdir reply is malformed: {}", err);
+                        FuseReaddirOut {
+                            dirents: Vec::new(),
+                        }
+                    });
+
+                early_print!(
+                    "Readdir response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                for dirent_name in readdir_out.dirents {
+                    let dirent = dirent_name.dirent;
+                    let name = String::from_utf8_lossy(&dirent_name.name);
+                    early_print!("Readdir response received: inode={:?}, off={:?}, namelen={:?}, type:{:?}, filename={:?}\n", 
+                        dirent.ino, dirent.off, dirent.namelen, dirent.type_, name);
+                }
+                early_println!();
+            }
+            FuseOpcode::FuseOpendir => {
+                let _datain = reader.read_val::<FuseOpenIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let mut bytes = vec![0u8; reply_body_len(&headerout).min(reader.remain())];
+                reader.read(&mut VmWriter::from(bytes.as_mut_slice()));
+                early_print!(
+                    "Opendir response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                let dataout = match decode_open_reply(&headerout, &bytes) {
+                    Ok(dataout) => dataout,
+                    Err(err) => {
+                        warn!("virtio-fs opendir reply cannot be decoded: {}", err);
+                        return Ok(());
+                    }
+                };
+                early_print!("fh:{:?}\n", dataout.fh);
+                early_print!(
+                    "open_flags:{:?}\n",
+                    FopenFlags::from_bits_truncate(dataout.open_flags)
+                );
+                early_print!("backing_id:{:?}\n", dataout.backing_id);
+                early_println!();
+                self.open_handles.disable_irq().lock().insert(
+                    headerin.nodeid,
+                    dataout.fh,
+                    FopenFlags::empty(),
+                );
+            }
+            FuseOpcode::FuseOpen => {
+                let _datain = reader.read_val::<FuseOpenIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let mut bytes = vec![0u8; reply_body_len(&headerout).min(reader.remain())];
+                reader.read(&mut VmWriter::from(bytes.as_mut_slice()));
+                early_print!(
+                    "Open response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                let dataout = match decode_open_reply(&headerout, &bytes) {
+                    Ok(dataout) => dataout,
+                    Err(err) => {
+                        warn!("virtio-fs open reply cannot be decoded: {}", err);
+                        return Ok(());
+                    }
+                };
+                early_print!("fh:{:?}\n", dataout.fh);
+                early_print!(
+                    "open_flags:{:?}\n",
+                    FopenFlags::from_bits_truncate(dataout.open_flags)
+                );
+                early_print!("backing_id:{:?}\n", dataout.backing_id);
+                self.open_handles.disable_irq().lock().insert(
+                    headerin.nodeid,
+                    dataout.fh,
+                    FopenFlags::from_bits_truncate(dataout.open_flags),
+                );
+            }
+            FuseOpcode::FuseRead => {
+                let _datain = reader.read_val::<FuseReadIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                //The requested action is to read up to size bytes of the file or directory, starting at offset. The bytes should be returned directly following the usual reply header.
+                // let dataout = reader.read_val::<Vec<u8>>()?;
+                early_print!(
+                    "Read response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                // early_println!();
+                // if the file is not empty
+                if headerout.len > size_of::<FuseOutHeader>() as u32 {
+                    let data_len = headerout.len - size_of::<FuseOutHeader>() as u32;
+                    let mut dataout_buf = vec![0u8; (data_len as usize).min(reader.remain())];
+                    let mut writer = VmWriter::from(dataout_buf.as_mut_slice());
+                    writer.write(&mut reader);
+                    let data_utf8 = String::from_utf8_lossy(&dataout_buf);
+                    early_print!("Read response received: data={:?}\n", data_utf8);
+                }
+                // early_print!("Read data: {:?}", dataout);
+            }
+            FuseOpcode::FuseFlush => {
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "Flush response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseReleasedir => {
+                let _datain = reader.read_val::<FuseReleaseIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                // let dataout = reader.read_val::<FuseReleaseOut>()?;
+                early_print!(
+                    "Releasedir response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+                // early_print!("fh:{:?}\n", dataout.fh);
+            }
+            FuseOpcode::FuseGetattr => {
+                let _datain = reader.read_val::<FuseGetattrIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseAttrOut>()?;
+                self.check_submount(headerin.nodeid, &dataout.attr);
+                early_print!(
+                    "Getattr response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
+                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
+                early_print!("attr:{:?}\n", dataout.attr);
+                early_println!();
+            }
+            FuseOpcode::FuseSetattr => {
+                let _datain = reader.read_val::<FuseSetattrIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseAttrOut>()?;
+                early_print!(
+                    "Setattr response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
+                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
+                early_print!("attr:{:?}\n", dataout.attr);
+                early_println!();
+            }
+            FuseOpcode::FuseLookup => {
+                // Skip the name.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseEntryOut>()?;
+                self.check_submount(dataout.nodeid, &dataout.attr);
+                early_print!(
+                    "Lookup response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!("test for lookup");
+                early_print!("nodeid:{:?}\n", dataout.nodeid);
+                early_print!("generation:{:?}\n", dataout.generation);
+                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
+                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
+                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
+                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
+                early_print!("attr:{:?}\n", dataout.attr);
+                early_println!();
+            }
+            FuseOpcode::FuseReadlink => {
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let mut target = vec![0u8; reply_body_len(&headerout).min(reader.remain())];
+                reader.read(&mut VmWriter::from(target.as_mut_slice()));
+                debug!(
+                    "virtio-fs readlink reply: {:?}",
+                    String::from_utf8_lossy(&target)
+                );
+            }
+            FuseOpcode::FuseRelease => {
+                let _datain = reader.read_val::<FuseReleaseIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                // let dataout = reader.read_val::<FuseReleaseOut>()?;
+                early_print!(
+                    "Release response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+                // early_print!("fh:{:?}\n", dataout.fh);
+            }
+            // The server may write less than asked for, e.g. as the disk is full.
+            FuseOpcode::FuseWrite => {
+                let datain = reader.read_val::<FuseWriteIn>()?;
+                reader.skip(datain.size as usize)?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "Write response received: len={:?}, error={:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                if headerout.len > size_of::<FuseOutHeader>() as u32 {
+                    let writeout = reader.read_val::<FuseWriteOut>()?;
+                    early_print!("Write response received: size={:?}\n", writeout.size);
+                    if writeout.size < datain.size {
+                        debug!(
+                            "virtio-fs short write: {} of {} bytes at offset {}",
+                            writeout.size, datain.size, datain.offset
+                        );
+                    }
+                }
+            }
+            FuseOpcode::FuseStatfs => {
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let mut bytes = vec![0u8; reply_body_len(&headerout).min(reader.remain())];
+                reader.read(&mut VmWriter::from(bytes.as_mut_slice()));
+                let Ok(dataout) = decode_statfs(&bytes) else {
+                    warn!("virtio-fs statfs reply is too short");
+                    return Ok(());
+                };
+                early_print!(
+                    "Statfs response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("blocks:{:?}\n", dataout.st.blocks);
+                early_print!("bfree:{:?}\n", dataout.st.bfree);
+                early_print!("bavail:{:?}\n", dataout.st.bavail);
+                early_print!("files:{:?}\n", dataout.st.files);
+                early_print!("ffree:{:?}\n", dataout.st.ffree);
+                early_print!("bsize:{:?}\n", dataout.st.bsize);
+                early_print!("namelen:{:?}\n", dataout.st.namelen);
+                early_print!("frsize:{:?}\n", dataout.st.frsize);
+                early_print!("padding:{:?}\n", dataout.st.padding);
+                early_print!("spare:{:?}\n", dataout.st.spare);
+
+                early_println!();
+            }
+            FuseOpcode::FuseMkdir => {
+                // Skip the `FuseMkdirIn`, the name and the extensions.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseEntryOut>()?;
+                early_print!(
+                    "Mkdir response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("nodeid:{:?}\n", dataout.nodeid);
+                early_print!("generation:{:?}\n", dataout.generation);
+                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
+                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
+                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
+                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
+                early_print!("attr:{:?}\n", dataout.attr);
+                early_println!();
+            }
+            FuseOpcode::FuseCreate => {
+                // Skip the `FuseCreateIn`, the name and the extensions.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseEntryOut>()?;
+                let openout = reader.read_val::<FuseOpenOut>()?;
+                early_print!(
+                    "Create response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                debug!("virtio-fs create reply: fh = {}", openout.fh);
+                early_print!("nodeid:{:?}\n", dataout.nodeid);
+                early_print!("generation:{:?}\n", dataout.generation);
+                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
+                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
+                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
+                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
+                early_print!("attr:{:?}\n", dataout.attr);
+                early_println!();
+                if headerout.error == 0 {
+                    self.open_handles.disable_irq().lock().insert(
+                        dataout.nodeid,
+                        openout.fh,
+                        FopenFlags::from_bits_truncate(openout.open_flags),
+                    );
+                }
+            }
+            FuseOpcode::FuseDestroy => {
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "Destroy response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseRename => {
+                // Skip the `FuseRenameIn` and the two names.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "Rename response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
             FuseOpcode::FuseRename2 => {
-                let _datain = reader.read_val::<FuseRename2In>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                // Skip the `FuseRename2In` and the two names.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
                 early_print!(
                     "Rename2 response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
+                early_println!();
+            }
+            FuseOpcode::FuseForget => {
+                let _datain = reader.read_val::<FuseForgetIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "Forget response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseNotifyReply => {
+                let datain = reader.read_val::<FuseNotifyRetrieveIn>()?;
+                reader.skip(datain.size as usize)?;
+                let _headerout = reader.read_val::<FuseOutHeader>()?;
+                debug!("virtio-fs retrieve reply of {} bytes is taken", datain.size);
+            }
+            FuseOpcode::FuseBatchForget => {
+                let _datain = reader.read_val::<FuseBatchForgetIn>()?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "BatchForget response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseLink => {
+                // Skip the `FuseLinkIn` and the name.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseEntryOut>()?;
+                early_print!(
+                    "Link response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
                 early_print!("nodeid:{:?}\n", dataout.nodeid);
                 early_print!("generation:{:?}\n", dataout.generation);
                 early_print!("entry_valid:{:?}\n", dataout.entry_valid);
@@ -2573,160 +5178,4281 @@ impl FilesystemDevice {
                 early_print!("attr:{:?}\n", dataout.attr);
                 early_println!();
             }
-            FuseOpcode::FuseForget => {
-                let _datain = reader.read_val::<FuseForgetIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                early_print!(
-                    "Forget response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
+            FuseOpcode::FuseUnlink | FuseOpcode::FuseRmdir => {
+                // Skip the name.
+                reader.skip((headerin.len as usize).saturating_sub(size_of::<FuseInHeader>()))?;
+                let headerout = reader.read_val::<FuseOutHeader>()?;
+                early_print!(
+                    "Unlink response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseGetlk => {
+                let _datain = reader.read_val::<FuseLkIn>()?;
+                let _headerout = reader.read_val::<FuseOutHeader>()?;
+                // The returned lock is the one conflicting with the request,
+                // or has type `F_UNLCK` if the lock could be placed.
+                let dataout = reader.read_val::<FuseLkOut>()?;
+                debug!("virtio-fs getlk reply: {:?}", dataout.lk);
+            }
+            FuseOpcode::FuseBmap => {
+                let _datain = reader.read_val::<FuseBmapIn>()?;
+                let _headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseBmapOut>()?;
+                debug!("virtio-fs bmap reply: block = {}", dataout.block);
+            }
+            FuseOpcode::FuseLseek => {
+                let _datain = reader.read_val::<FuseLseekIn>()?;
+                let _headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FuseLseekOut>()?;
+                debug!("virtio-fs lseek reply: offset = {}", dataout.offset);
+            }
+            FuseOpcode::FusePoll => {
+                let _datain = reader.read_val::<FusePollIn>()?;
+                let _headerout = reader.read_val::<FuseOutHeader>()?;
+                let dataout = reader.read_val::<FusePollOut>()?;
+                debug!("virtio-fs poll reply: revents = {:#x}", dataout.revents);
+            }
+            FuseOpcode::FuseSetlk | FuseOpcode::FuseSetlkw => {
+                let _datain = reader.read_val::<FuseLkIn>()?;
+                let _headerout = reader.read_val::<FuseOutHeader>()?;
+                debug!("virtio-fs setlk reply: success");
+            }
+            // Replies that are not decoded here leave nothing to check.
+            _ => headerout = None,
+        }
+        if let Some(headerout) = headerout {
+            let expected = headerin.len as usize + headerout.len as usize;
+            if let Err(err) = reader.check_consumed(expected) {
+                warn!(
+                    "virtio-fs reply to opcode {} was not decoded exactly: {}",
+                    headerin.opcode, err
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A request sent on one of the request queues, whose reply is yet to be taken.
+///
+/// It is obtained with `FilesystemDevice::send_request`. The request is given up
+/// on with `cancel`, on the timeout of `wait_timeout`, or once the handle is
+/// dropped, after which its reply is dropped if it still arrives.
+///
+/// The slot the request is staged in is only freed once the device returns it,
+/// so that a late reply never lands in the slot of another request. A device
+/// that never answers thus keeps the slot.
+pub struct RequestHandle<'a> {
+    device: &'a FilesystemDevice,
+    unique: u64,
+    opcode: u32,
+    /// The time the request was sent at, for `RequestStats`.
+    start: Duration,
+}
+
+impl RequestHandle<'_> {
+    /// Returns the unique of the request, as `interrupt_request` takes.
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Blocks until the reply arrives, returning its header and body.
+    pub fn wait(self) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+        parse_reply(&self.wait_reply(None)?)
+    }
+
+    /// Same as `wait`, but gives up on the request with `ETIMEDOUT` if it is not
+    /// answered within `timeout`.
+    ///
+    /// The server is not sent a `FUSE_INTERRUPT` on the timeout, since a device
+    /// that does not answer would not return the interrupt either.
+    pub fn wait_timeout(
+        self,
+        timeout: Duration,
+    ) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+        let slot = self
+            .device
+            .connection
+            .wait_timeout(self.unique, timeout, || self.take(None))
+            // Dropping the handle forgets the request.
+            .ok_or(FilesystemError::Fuse { errno: ETIMEDOUT })?;
+        parse_reply(&self.finish(slot)?)
+    }
+
+    /// Gives up on the request, and sends the server a `FUSE_INTERRUPT` for it.
+    pub fn cancel(self) -> Result<(), FilesystemError> {
+        self.forget();
+        self.device.interrupt(self.unique)
+    }
+
+    /// Blocks until the reply arrives, or until `cancel` is cancelled.
+    fn wait_reply(self, cancel: Option<&CancelToken>) -> Result<Vec<u8>, FilesystemError> {
+        let slot = self
+            .device
+            .connection
+            .reply_wait_queue
+            .wait_until(|| self.take(cancel));
+        self.finish(slot)
+    }
+
+    fn take(&self, cancel: Option<&CancelToken>) -> Option<ReplySlot> {
+        self.device
+            .connection
+            .pending_replies
+            .disable_irq()
+            .lock()
+            .take(self.unique, cancel)
+    }
+
+    fn finish(&self, slot: ReplySlot) -> Result<Vec<u8>, FilesystemError> {
+        if slot.is_some() {
+            self.device.stats.timed(self.opcode, now() - self.start);
+        }
+        finish_wait(slot, self.unique, |unique| self.device.interrupt(unique))
+    }
+
+    fn forget(&self) {
+        self.device
+            .connection
+            .pending_replies
+            .disable_irq()
+            .lock()
+            .remove(self.unique);
+    }
+}
+
+impl Drop for RequestHandle<'_> {
+    fn drop(&mut self) {
+        // A request whose reply is taken is already forgotten.
+        self.forget();
+    }
+}
+
+/// A handle to a `FilesystemDevice` that makes requests which can be cancelled.
+///
+/// It is obtained with `FilesystemDevice::with_cancel`. Once the token is
+/// cancelled, the calls made through the handle fail with `EINTR`.
+pub struct WithCancel<'a> {
+    device: &'a FilesystemDevice,
+    cancel: &'a CancelToken,
+}
+
+impl WithCancel<'_> {
+    /// Same as `FilesystemDevice::read_sync`.
+    pub fn read_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<(Vec<u8>, bool), FilesystemError> {
+        self.device
+            .read_with(Some(self.cancel), nodeid, fh, offset, size)
+    }
+
+    /// Same as `FilesystemDevice::readdir_sync`.
+    pub fn readdir_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<FuseReaddirOut, FilesystemError> {
+        self.device
+            .readdir_with(Some(self.cancel), nodeid, fh, offset, size)
+    }
+}
+
+/// A handle to a `FilesystemDevice` that makes requests with specific credentials.
+///
+/// It is obtained with `FilesystemDevice::with_creds` and lasts for the calls
+/// made through it.
+pub struct WithCreds<'a> {
+    device: &'a FilesystemDevice,
+    creds: FuseCreds,
+    /// Whether the caller has `CAP_FSETID`.
+    cap_fsetid: bool,
+}
+
+impl WithCreds<'_> {
+    /// Tells whether the caller has `CAP_FSETID`, which it lacks by default.
+    ///
+    /// Once `FUSE_HANDLE_KILLPRIV_V2` is negotiated, the server clears the suid
+    /// and sgid bits of a file written to, or opened with `O_TRUNC`, by a caller
+    /// lacking it.
+    pub fn with_cap_fsetid(mut self, cap_fsetid: bool) -> Self {
+        self.cap_fsetid = cap_fsetid;
+        self
+    }
+
+    /// Opens file `nodeid` with the open flags `flags`, as `FilesystemDevice::open_sync`.
+    pub fn open_sync(&self, nodeid: u64, flags: u32) -> Result<OpenFileState, FilesystemError> {
+        self.device
+            .open_as(self.creds, self.cap_fsetid, nodeid, flags)
+    }
+
+    /// Writes `data` at `offset` to a file opened with `open`, as
+    /// `FilesystemDevice::write_sync`.
+    pub fn write_sync(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FilesystemError> {
+        let write_flags = kill_suidgid_write_flags(self.device.negotiated_flags(), self.cap_fsetid);
+        self.device
+            .write_with(self.creds, nodeid, fh, offset, data, write_flags)
+    }
+
+    /// Creates the directory `name` in directory `nodeid`.
+    pub fn mkdir_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        mode: u32,
+        umask: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<FuseEntryOut, FilesystemError> {
+        let mkdirin = FuseMkdirIn {
+            mode: mode,
+            umask: umask,
+        };
+        let prepared_name = fuse_pad_str(name, true);
+        let (extensions, total_extlen) = self.device.create_extensions(secctx, supp_groups)?;
+        let (_, dataout) = self.device.send_sync_as(
+            self.creds,
+            FuseOpcode::FuseMkdir,
+            nodeid,
+            &[mkdirin.as_bytes(), &prepared_name, &extensions],
+            total_extlen,
+            size_of::<FuseEntryOut>(),
+        )?;
+        let entry = decode_reply::<FuseEntryOut>(&dataout)?;
+        self.device.count_lookup(entry.nodeid, 1);
+        Ok(entry)
+    }
+
+    /// Creates the file node `name` in directory `nodeid`.
+    pub fn mknod_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<FuseEntryOut, FilesystemError> {
+        let mknodin = FuseMknodIn {
+            mode: mode,
+            rdev: rdev,
+            umask: umask,
+            padding: 0,
+        };
+        let prepared_name = fuse_pad_str(name, true);
+        let (extensions, total_extlen) = self.device.create_extensions(secctx, supp_groups)?;
+        let (_, dataout) = self.device.send_sync_as(
+            self.creds,
+            FuseOpcode::FuseMknod,
+            nodeid,
+            &[mknodin.as_bytes(), &prepared_name, &extensions],
+            total_extlen,
+            size_of::<FuseEntryOut>(),
+        )?;
+        let entry = decode_reply::<FuseEntryOut>(&dataout)?;
+        self.device.count_lookup(entry.nodeid, 1);
+        Ok(entry)
+    }
+
+    /// Creates and opens the regular file `name` in directory `nodeid`.
+    pub fn create_sync(
+        &self,
+        nodeid: u64,
+        name: &[u8],
+        createin: FuseCreateIn,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(FuseEntryOut, FuseOpenOut), FilesystemError> {
+        let prepared_name = fuse_pad_str(name, true);
+        let (extensions, total_extlen) = self.device.create_extensions(secctx, supp_groups)?;
+        let (_, dataout) = self.device.send_sync_as(
+            self.creds,
+            FuseOpcode::FuseCreate,
+            nodeid,
+            &[createin.as_bytes(), &prepared_name, &extensions],
+            total_extlen,
+            size_of::<FuseEntryOut>() + size_of::<FuseOpenOut>(),
+        )?;
+        let (entry, openout) = decode_create_reply(&dataout)?;
+        self.device.count_lookup(entry.nodeid, 1);
+        self.device.record_open(
+            entry.nodeid,
+            openout.fh,
+            FopenFlags::from_bits_truncate(openout.open_flags),
+        );
+        Ok((entry, openout))
+    }
+}
+
+/// Registers the interrupt handlers of the request queues, the notification
+/// queue and configuration changes of `device` with `transport`.
+fn register_callbacks(
+    device: &FilesystemDevice,
+    transport: &mut dyn VirtioTransport,
+    request_queue_base: u16,
+) -> Result<(), VirtioTransportError> {
+    for queue_idx in 0..device.request_queues.len() {
+        let handle_request = irq_handler(&device.irq_target, move |device| {
+            device.handle_recv_irq(queue_idx)
+        });
+        transport.register_queue_callback(
+            request_queue_base + queue_idx as u16,
+            handle_request,
+            false,
+        )?;
+    }
+    if device.notify_queue.is_some() {
+        let handle_notify = irq_handler(&device.irq_target, FilesystemDevice::handle_notify_irq);
+        transport.register_queue_callback(NOTIFICATION_QUEUE_INDEX, handle_notify, false)?;
+    }
+    let handle_config_change =
+        irq_handler(&device.irq_target, FilesystemDevice::handle_config_change);
+    transport.register_cfg_callback(handle_config_change)
+}
+
+/// Returns an interrupt handler that calls `handle` on the device in `target`,
+/// and does nothing once `FilesystemDevice::shutdown` took the device out.
+fn irq_handler<F>(target: &Arc<IrqTarget>, handle: F) -> Box<IrqCallbackFunction>
+where
+    F: Fn(&FilesystemDevice) + Send + Sync + 'static,
+{
+    let target = target.clone();
+    Box::new(move |_: &TrapFrame| {
+        if let Some(device) = target.read().as_ref() {
+            handle(device);
+        }
+    })
+}
+
+/// Creates the request queues with `new_queue`, each with its own pool.
+///
+/// Only as many queues are created as the transport provides past
+/// `request_queue_base`, even if the config reports more.
+fn setup_request_queues<Q>(
+    config_queues: u32,
+    transport_queues: u16,
+    request_queue_base: u16,
+    mut new_queue: impl FnMut(u16) -> Result<Q, QueueError>,
+) -> Result<(Vec<SpinLock<Q>>, Vec<SpinLock<RequestPool>>), VirtioDeviceError> {
+    let num_request_queues =
+        usable_request_queues(config_queues, transport_queues, request_queue_base);
+    if num_request_queues as u32 != config_queues {
+        warn!(
+            "virtio-fs config reports {} request queues, but the transport only has {}",
+            config_queues, num_request_queues
+        );
+    }
+    if num_request_queues == 0 {
+        return Err(VirtioDeviceError::QueuesAmountDoNotMatch(
+            transport_queues,
+            request_queue_base + 1,
+        ));
+    }
+
+    let mut request_queues = Vec::new();
+    for i in 0..num_request_queues {
+        request_queues.push(SpinLock::new(new_queue(request_queue_base + i)?));
+    }
+    let request_pools = (0..num_request_queues)
+        .map(|_| RequestPool::new(REQUEST_POOL_SLOTS, 3 * PAGE_SIZE).map(SpinLock::new))
+        .collect::<Result<_, _>>()?;
+    Ok((request_queues, request_pools))
+}
+
+/// Allocates a buffer of 3 pages to stage a request and its reply in.
+///
+/// The device both reads and writes the buffer, as the request and its reply
+/// are placed one after the other in it.
+fn alloc_request_buffer() -> Result<DmaStream, ostd::Error> {
+    alloc_dma_stream(3, DmaDirection::Bidirectional)
+}
+
+/// Allocates a buffer of `nframes` pages that the device accesses in `direction`.
+fn alloc_dma_stream(nframes: usize, direction: DmaDirection) -> Result<DmaStream, ostd::Error> {
+    let vm_segment = FrameAllocOptions::new().alloc_segment(nframes)?;
+    DmaStream::map(vm_segment.into(), direction, false)
+}
+
+/// Returns the index of the first request queue.
+fn request_queue_base_index(features: FilesystemFeatures) -> u16 {
+    if features.contains(FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION) {
+        NOTIFICATION_QUEUE_INDEX + 1
+    } else {
+        NOTIFICATION_QUEUE_INDEX
+    }
+}
+
+/// The part of a virtqueue that requests are posted on, which tests stand in for.
+trait RequestQueue {
+    fn add_dma_buf(
+        &mut self,
+        inputs: &[&DmaStreamSlice<DmaStream>],
+        outputs: &[&DmaStreamSlice<DmaStream>],
+    ) -> Result<u16, QueueError>;
+}
+
+impl RequestQueue for VirtQueue {
+    fn add_dma_buf(
+        &mut self,
+        inputs: &[&DmaStreamSlice<DmaStream>],
+        outputs: &[&DmaStreamSlice<DmaStream>],
+    ) -> Result<u16, QueueError> {
+        VirtQueue::add_dma_buf(self, inputs, outputs)
+    }
+}
+
+/// The part of the hiprio queue that its requests wait on, which tests stand in for.
+trait HiprioQueue: RequestQueue {
+    fn can_pop(&self) -> bool;
+
+    fn pop_used(&mut self) -> Result<(u16, u32), QueueError>;
+
+    /// Returns whether the device owns none of the buffers posted on the queue.
+    fn is_idle(&self) -> bool;
+
+    fn kick(&mut self);
+}
+
+impl HiprioQueue for VirtQueue {
+    fn can_pop(&self) -> bool {
+        VirtQueue::can_pop(self)
+    }
+
+    fn pop_used(&mut self) -> Result<(u16, u32), QueueError> {
+        VirtQueue::pop_used(self)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.available_desc() == self.size() as usize
+    }
+
+    fn kick(&mut self) {
+        if self.should_notify() {
+            self.notify();
+        }
+    }
+}
+
+/// Stages the request made of `parts` in `buffer` and posts it on the hiprio
+/// `queue`, with its first `len_in` bytes readable by the device.
+///
+/// The hiprio queue has no interrupt handler, so this spins until the device
+/// returns the buffer, and returns the length it wrote. Interrupts are off
+/// meanwhile, so the deadline of `timeout` is kept with the TSC. Once it passes,
+/// the device still owns `buffer`; until it returns it, no request is staged
+/// there again and this fails with `QueueError::NotReady`.
+fn post_hiprio<Q: HiprioQueue>(
+    queue: &mut Q,
+    buffer: &DmaStream,
+    parts: &[&[u8]],
+    len_in: usize,
+    timeout: Duration,
+) -> Result<u32, FilesystemError> {
+    // A request given up on earlier may have been returned since.
+    if queue.can_pop() {
+        queue.pop_used()?;
+    }
+    if !queue.is_idle() {
+        return Err(QueueError::NotReady.into());
+    }
+
+    let len = stage_request(buffer, parts)?;
+    let (slice_in, slice_out) = split_request(buffer, len_in, len)?;
+    queue.add_dma_buf(&[&slice_in], &[&slice_out])?;
+    queue.kick();
+
+    let timeout_cycles = tsc_freq().saturating_mul(timeout.as_micros() as u64) / 1_000_000;
+    let deadline = read_tsc().saturating_add(timeout_cycles);
+    while !queue.can_pop() {
+        if read_tsc() >= deadline {
+            return Err(FilesystemError::Fuse { errno: ETIMEDOUT });
+        }
+        spin_loop();
+    }
+    let (_, used_len) = queue.pop_used()?;
+    Ok(used_len)
+}
+
+/// Stages the request `headerin` at `offset` in `requests` and posts it on
+/// `queue`, returning its token.
+///
+/// The request body is `parts.datain`, staged after the header, followed by
+/// `parts.in_pages`. The reply goes to the header and the `parts.out_len` bytes
+/// at `offset` in `replies`, then to `parts.out_pages`.
+fn post_request<Q: RequestQueue>(
+    queue: &mut Q,
+    requests: &DmaStream,
+    replies: &DmaStream,
+    offset: usize,
+    headerin: &FuseInHeader,
+    parts: &RequestParts<'_>,
+) -> Result<u16, FilesystemError> {
+    let len_staged =
+        size_of::<FuseInHeader>() + parts.datain.iter().map(|data| data.len()).sum::<usize>();
+    let len_in = headerin.len as usize;
+    let len_out = size_of::<FuseOutHeader>() + parts.out_len;
+    if len_staged > len_in
+        || offset + len_staged > requests.nbytes()
+        || offset + len_out > replies.nbytes()
+    {
+        return Err(FilesystemError::BufferTooShort);
+    }
+
+    let mut writer = requests.writer()?.skip(offset).limit(len_staged);
+    writer.write_val(headerin)?;
+    for data in parts.datain {
+        writer.write(&mut VmReader::from(*data));
+    }
+
+    requests.sync(offset..offset + len_staged)?;
+    for page in parts.in_pages {
+        page.sync()?;
+    }
+    let slice_in = DmaStreamSlice::new(requests.clone(), offset, len_staged);
+    let slice_out = DmaStreamSlice::new(replies.clone(), offset, len_out);
+    let inputs: Vec<_> = iter::once(&slice_in).chain(parts.in_pages.iter()).collect();
+    let outputs: Vec<_> = iter::once(&slice_out)
+        .chain(parts.out_pages.iter())
+        .collect();
+    Ok(queue.add_dma_buf(&inputs, &outputs)?)
+}
+
+/// Writes the request made of `parts` to `writer`, returning its length.
+///
+/// The parts are written one after the other in place, without being gathered
+/// in a buffer first. A request that does not fit fails before anything is
+/// written, rather than being sent truncated.
+fn write_request(
+    writer: &mut VmWriter<'_, Infallible>,
+    parts: &[&[u8]],
+) -> Result<usize, FilesystemError> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    if writer.avail() < len {
+        return Err(FilesystemError::OutputBufferTooShort(writer.avail(), len));
+    }
+    for part in parts {
+        writer.write(&mut VmReader::from(*part));
+    }
+    Ok(len)
+}
+
+/// Splits the request of `len` bytes staged at the start of `buffer` into the
+/// first `len_in` bytes, which the device reads, and the rest, which it writes.
+///
+/// A `len_in` miscomputed past the request, or a request past `buffer`, fails
+/// rather than hand the device slices of memory it is not meant to access.
+fn split_request(
+    buffer: &DmaStream,
+    len_in: usize,
+    len: usize,
+) -> Result<(DmaStreamSlice<&DmaStream>, DmaStreamSlice<&DmaStream>), FilesystemError> {
+    if len_in > len || len > buffer.nbytes() {
+        return Err(FilesystemError::BufferTooShort);
+    }
+    Ok((
+        DmaStreamSlice::new(buffer, 0, len_in),
+        DmaStreamSlice::new(buffer, len_in, len - len_in),
+    ))
+}
+
+/// Writes the request made of `parts` to the start of `buffer` and syncs it
+/// for the device, returning its length.
+fn stage_request(buffer: &DmaStream, parts: &[&[u8]]) -> Result<usize, FilesystemError> {
+    let len = write_request(&mut buffer.writer()?, parts)?;
+    buffer.sync(0..len)?;
+    Ok(len)
+}
+
+/// Returns the device-specific features out of the `negotiated` ones.
+///
+/// Fails if the features in `REQUIRED_FEATURES` were not negotiated.
+fn accepted_features(negotiated: u64) -> Result<FilesystemFeatures, VirtioDeviceError> {
+    let missing = REQUIRED_FEATURES & !negotiated;
+    if missing != 0 {
+        warn!("virtio-fs device lacks required features {:#x}", missing);
+        return Err(VirtioDeviceError::MissingFeatures(missing));
+    }
+    Ok(FilesystemFeatures::from_bits_truncate(negotiated))
+}
+
+/// Converts an error of the `FUSE_INIT` exchange into an error of the device
+/// setup, logging it first since the setup error cannot tell most of them apart.
+fn init_error(err: FilesystemError) -> VirtioDeviceError {
+    warn!("virtio-fs FUSE_INIT failed: {}", err);
+    match err {
+        FilesystemError::QueueError(err) => err.into(),
+        FilesystemError::DmaError(err) => VirtioDeviceError::ResourceAllocError(err),
+        _ => VirtioDeviceError::QueueUnknownError,
+    }
+}
+
+/// Returns the number of request queues that can actually be created.
+///
+/// The device config may over-report `num_request_queues`, so the count is
+/// clamped to the virtqueues left on the transport from `base_index` on.
+fn usable_request_queues(config_queues: u32, transport_queues: u16, base_index: u16) -> u16 {
+    let available = transport_queues.saturating_sub(base_index);
+    config_queues.min(available as u32) as u16
+}
+
+/// Returns the init flags asked for in `FUSE_INIT`.
+///
+/// `FUSE_BIG_WRITES` and `FUSE_MAX_PAGES` let reads and writes span more than
+/// a page, up to the `max_pages` the server answers with.
+fn init_flags() -> FuseInitFlags {
+    FuseInitFlags::FUSE_INIT_EXT
+        | FuseInitFlags::FUSE_ATOMIC_O_TRUNC
+        | FuseInitFlags::FUSE_ABORT_ERROR
+        | FuseInitFlags::FUSE_BIG_WRITES
+        | FuseInitFlags::FUSE_MAX_PAGES
+        | FuseInitFlags::FUSE_SETXATTR_EXT
+        | FuseInitFlags::FUSE_SUBMOUNTS
+        | FuseInitFlags::FUSE_NO_OPEN_SUPPORT
+        | FuseInitFlags::FUSE_NO_OPENDIR_SUPPORT
+        | FuseInitFlags::FUSE_SECURITY_CTX
+        | FuseInitFlags::FUSE_CREATE_SUPP_GROUP
+        | FuseInitFlags::FUSE_HANDLE_KILLPRIV_V2
+        | FuseInitFlags::FUSE_CACHE_SYMLINKS
+}
+
+/// Builds the `FUSE_INIT` request asking for the init flags `flags`, with
+/// `flags2` in the upper 32 bits.
+fn build_init_request(flags: u64, max_readahead: u32) -> (FuseInHeader, FuseInitIn) {
+    let len_in = size_of::<FuseInHeader>() + size_of::<FuseInitIn>();
+    let headerin = fuse_in_header(FuseOpcode::FuseInit, 0, 0, len_in, FuseCreds::default());
+    let initin = FuseInitIn {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        max_readahead: max_readahead,
+        flags: flags as u32,
+        flags2: (flags >> 32) as u32,
+        unused: [0u32; 11],
+    };
+    (headerin, initin)
+}
+
+/// Parses the reply to `FUSE_INIT`, whose body `bytes` follows `header`.
+///
+/// A server speaking an older minor version sends a shorter body, down to
+/// `FUSE_COMPAT_INIT_OUT_SIZE`. The fields it leaves out are zero.
+fn parse_init_reply(header: &FuseOutHeader, bytes: &[u8]) -> Result<FuseInitOut, FilesystemError> {
+    check_error(header)?;
+    if bytes.len() < FUSE_COMPAT_INIT_OUT_SIZE as usize {
+        return Err(FilesystemError::BufferTooShort);
+    }
+    let mut init_out = FuseInitOut::new_zeroed();
+    let len = bytes.len().min(size_of::<FuseInitOut>());
+    init_out.as_bytes_mut()[..len].copy_from_slice(&bytes[..len]);
+    if init_out.major != FUSE_KERNEL_VERSION {
+        return Err(FilesystemError::Fuse { errno: EPROTO });
+    }
+    Ok(init_out)
+}
+
+/// Decodes the reply body of `FUSE_CREATE`, a `FuseEntryOut` followed by a `FuseOpenOut`.
+fn decode_create_reply(dataout: &[u8]) -> Result<(FuseEntryOut, FuseOpenOut), FilesystemError> {
+    let entry = decode_reply::<FuseEntryOut>(dataout)?;
+    let openout = dataout
+        .get(size_of::<FuseEntryOut>()..)
+        .ok_or(FilesystemError::BufferTooShort)
+        .and_then(decode_reply::<FuseOpenOut>)?;
+    Ok((entry, openout))
+}
+
+/// Decodes the reply body of `FUSE_STATFS`.
+///
+/// A server older than 7.4 sends only `FUSE_COMPAT_STATFS_SIZE` bytes, without
+/// `frsize`, in which case the fragment size is the block size.
+fn decode_statfs(dataout: &[u8]) -> Result<FuseStatfsOut, FilesystemError> {
+    if dataout.len() < FUSE_COMPAT_STATFS_SIZE as usize {
+        return Err(FilesystemError::BufferTooShort);
+    }
+    let mut statfs_out = FuseStatfsOut::new_zeroed();
+    let len = dataout.len().min(size_of::<FuseStatfsOut>());
+    statfs_out.as_bytes_mut()[..len].copy_from_slice(&dataout[..len]);
+    if len < size_of::<FuseStatfsOut>() {
+        statfs_out.st.frsize = statfs_out.st.bsize;
+    }
+    Ok(statfs_out)
+}
+
+/// Decodes the reply body `dataout` of `FUSE_OPEN` or `FUSE_OPENDIR`.
+///
+/// A reply with an error, e.g. the `ENOSYS` of a server supporting
+/// zero-message opens, has no body to decode.
+fn decode_open_reply(
+    headerout: &FuseOutHeader,
+    dataout: &[u8],
+) -> Result<FuseOpenOut, FilesystemError> {
+    check_error(headerout)?;
+    decode_reply::<FuseOpenOut>(dataout)
+}
+
+/// Returns the length of the reply body following `header`.
+fn reply_body_len(header: &FuseOutHeader) -> usize {
+    (header.len as usize).saturating_sub(size_of::<FuseOutHeader>())
+}
+
+/// Returns the size of a request buffer that fits the largest read or write
+/// allowed by the `FUSE_INIT` reply, along with the headers.
+fn request_buffer_size(negotiated: &NegotiatedInit) -> usize {
+    let max_pages = if negotiated.max_pages != 0 {
+        negotiated.max_pages as usize
+    } else {
+        FUSE_DEFAULT_MAX_PAGES
+    };
+    let max_payload = (negotiated.max_write as usize)
+        .max(max_pages * PAGE_SIZE)
+        .min(FUSE_MAX_MAX_PAGES * PAGE_SIZE);
+    // The extra page holds the in and out headers and the fixed-size bodies.
+    max_payload.align_up(PAGE_SIZE) + PAGE_SIZE
+}
+
+/// Chooses the opcode of a rename with `flags` for a server speaking protocol 7.`minor`.
+///
+/// `FUSE_RENAME2` only exists since 7.23. Before that, a rename without flags
+/// falls back to `FUSE_RENAME`, while flags cannot be honored at all.
+///
+/// Unknown flags, and flags that contradict each other as for renameat2(2),
+/// are rejected before anything is sent.
+fn rename_opcode(flags: u32, minor: u32) -> Result<FuseOpcode, FilesystemError> {
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE | RENAME_WHITEOUT) != 0
+        || flags & RENAME_EXCHANGE != 0 && flags & (RENAME_NOREPLACE | RENAME_WHITEOUT) != 0
+    {
+        return Err(FilesystemError::InvalidOperation);
+    }
+    if minor >= FUSE_RENAME2_MINOR {
+        Ok(FuseOpcode::FuseRename2)
+    } else if flags == 0 {
+        Ok(FuseOpcode::FuseRename)
+    } else {
+        Err(FilesystemError::Fuse { errno: EINVAL })
+    }
+}
+
+/// Splits a reply into its `FuseOutHeader` and body, turning a server error into `Err`.
+fn parse_reply(reply: &[u8]) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+    let headerout = parse_reply_header(reply)?;
+    let dataout = reply
+        .get(size_of::<FuseOutHeader>()..headerout.len as usize)
+        .ok_or(FilesystemError::BufferTooShort)?;
+    Ok((headerout, dataout.to_vec()))
+}
+
+/// Decodes the `FuseOutHeader` starting `reply`, failing if it carries an error.
+fn parse_reply_header(reply: &[u8]) -> Result<FuseOutHeader, FilesystemError> {
+    let headerout = VmReader::from(reply)
+        .read_val::<FuseOutHeader>()
+        .map_err(|_| FilesystemError::BufferTooShort)?;
+    check_error(&headerout)?;
+    Ok(headerout)
+}
+
+/// Returns the error carried by `headerout`, as a negative errno, if any.
+///
+/// A reply with an error consists of the header alone, so its body must not
+/// be decoded.
+pub fn check_error(headerout: &FuseOutHeader) -> Result<(), FilesystemError> {
+    match headerout.error {
+        0 => Ok(()),
+        error => Err(FilesystemError::Fuse {
+            errno: error.saturating_neg(),
+        }),
+    }
+}
+
+/// Finishes a request whose reply body went to the pages `out`, returning its length.
+fn finish_reply_into(
+    reply: &[u8],
+    out: &[DmaStreamSlice<DmaStream>],
+) -> Result<usize, FilesystemError> {
+    let headerout = parse_reply_header(reply)?;
+    let size = out.iter().map(|page| page.nbytes()).sum::<usize>();
+    let len = (headerout.len as usize)
+        .checked_sub(size_of::<FuseOutHeader>())
+        .filter(|&len| len <= size)
+        .ok_or(FilesystemError::BufferTooShort)?;
+    for page in out {
+        page.sync()?;
+    }
+    Ok(len)
+}
+
+/// Builds the `FuseInHeader` of a request whose part read by the server is `len_in` bytes.
+fn fuse_in_header(
+    opcode: FuseOpcode,
+    unique: u64,
+    nodeid: u64,
+    len_in: usize,
+    creds: FuseCreds,
+) -> FuseInHeader {
+    FuseInHeader {
+        len: len_in as u32,
+        opcode: opcode as u32,
+        unique: unique,
+        nodeid: nodeid,
+        uid: creds.uid,
+        gid: creds.gid,
+        pid: creds.pid,
+        total_extlen: 0,
+        padding: 0,
+    }
+}
+
+/// Tells from the outcome of a `FUSE_ACCESS` whether the access is granted.
+///
+/// `EACCES` denies the access, while any other error is one of the request.
+fn access_granted(result: Result<(), FilesystemError>) -> Result<bool, FilesystemError> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(FilesystemError::Fuse { errno: EACCES }) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Builds the body of a `FUSE_FSYNC` or `FUSE_FSYNCDIR` of the handle `fh`.
+fn fuse_fsync_in(fh: u64, datasync: bool) -> FuseFsyncIn {
+    FuseFsyncIn {
+        fh: fh,
+        fsync_flags: if datasync { FUSE_FSYNC_FDATASYNC } else { 0 },
+        padding: 0,
+    }
+}
+
+/// Returns the time elapsed since boot, against which cached items expire.
+fn now() -> Duration {
+    Jiffies::elapsed().as_duration()
+}
+
+/// Decodes the fixed-size reply body `dataout`.
+fn decode_reply<T: Pod>(dataout: &[u8]) -> Result<T, FilesystemError> {
+    VmReader::from(dataout)
+        .read_val::<T>()
+        .map_err(|_| FilesystemError::BufferTooShort)
+}
+
+/// Returns the credentials of a request made by the task that `provider`
+/// reports, or as root if no provider is registered.
+fn provided_creds(
+    provider: Option<&CredsProvider>,
+    negotiated_flags: u64,
+) -> Result<FuseCreds, FilesystemError> {
+    let creds = match provider {
+        Some(provider) => provider(),
+        None => Some(FuseCreds::default()),
+    };
+    header_creds(creds, negotiated_flags)
+}
+
+/// Returns the credentials of a request made by a task with `creds`.
+///
+/// A task whose ids cannot be mapped is only let through if the server agreed
+/// on `FUSE_ALLOW_IDMAP`, with the ids set to `FUSE_INVALID_UIDGID`.
+fn header_creds(
+    creds: Option<FuseCreds>,
+    negotiated_flags: u64,
+) -> Result<FuseCreds, FilesystemError> {
+    match creds {
+        Some(creds) => Ok(creds),
+        None if negotiated_flags & FUSE_ALLOW_IDMAP != 0 => Ok(FuseCreds {
+            uid: FUSE_INVALID_UIDGID,
+            gid: FUSE_INVALID_UIDGID,
+            pid: 0,
+        }),
+        None => Err(FilesystemError::Fuse { errno: EOVERFLOW }),
+    }
+}
+
+/// Checks the `fallocate` mode `mode` against the combinations fallocate(2) allows.
+///
+/// A hole is only punched along with `FALLOC_FL_KEEP_SIZE`, and not along with
+/// zeroing. Collapsing and inserting a range take no other flag, while
+/// unsharing a range only goes with `FALLOC_FL_KEEP_SIZE`.
+fn check_fallocate_mode(mode: u32) -> Result<FallocFlags, FilesystemError> {
+    let mode = FallocFlags::from_bits(mode).ok_or(FilesystemError::InvalidOperation)?;
+    let is_valid = if mode.contains(FallocFlags::FALLOC_FL_PUNCH_HOLE) {
+        mode.contains(FallocFlags::FALLOC_FL_KEEP_SIZE)
+            && (mode - FallocFlags::FALLOC_FL_KEEP_SIZE - FallocFlags::FALLOC_FL_PUNCH_HOLE)
+                .is_empty()
+    } else if mode
+        .intersects(FallocFlags::FALLOC_FL_COLLAPSE_RANGE | FallocFlags::FALLOC_FL_INSERT_RANGE)
+    {
+        mode == FallocFlags::FALLOC_FL_COLLAPSE_RANGE || mode == FallocFlags::FALLOC_FL_INSERT_RANGE
+    } else if mode.contains(FallocFlags::FALLOC_FL_UNSHARE_RANGE) {
+        (mode - FallocFlags::FALLOC_FL_UNSHARE_RANGE - FallocFlags::FALLOC_FL_KEEP_SIZE).is_empty()
+    } else {
+        true
+    };
+    if is_valid {
+        Ok(mode)
+    } else {
+        Err(FilesystemError::InvalidOperation)
+    }
+}
+
+/// Decodes the reply body `dataout` of a `FUSE_IOCTL` made with `flags`.
+///
+/// A retry is only allowed with `FUSE_IOCTL_UNRESTRICTED`. Its iovecs follow
+/// the `FuseIoctlOut`, the `in_iovs` ones first, and there are at most
+/// `FUSE_IOCTL_MAX_IOV` of them in all.
+fn decode_ioctl_reply(flags: u32, dataout: &[u8]) -> Result<IoctlStep, FilesystemError> {
+    let ioctlout = decode_reply::<FuseIoctlOut>(dataout)?;
+    let body = &dataout[size_of::<FuseIoctlOut>()..];
+    if ioctlout.flags & FUSE_IOCTL_RETRY == 0 {
+        return Ok(IoctlStep::Done {
+            result: ioctlout.result,
+            out_data: body.to_vec(),
+        });
+    }
+    let num_iovs = ioctlout.in_iovs as usize + ioctlout.out_iovs as usize;
+    if flags & FUSE_IOCTL_UNRESTRICTED == 0 || num_iovs > FUSE_IOCTL_MAX_IOV as usize {
+        return Err(FilesystemError::UnexpectedDataInPacket);
+    }
+    let mut reader = VmReader::from(body);
+    let mut iovs = Vec::with_capacity(num_iovs);
+    for _ in 0..num_iovs {
+        let iov = reader
+            .read_val::<FuseIoctlIovec>()
+            .map_err(|_| FilesystemError::BufferTooShort)?;
+        iovs.push(iov);
+    }
+    let out_iovs = iovs.split_off(ioctlout.in_iovs as usize);
+    Ok(IoctlStep::Retry {
+        in_iovs: iovs,
+        out_iovs: out_iovs,
+    })
+}
+
+/// Returns the total length of `iovs`, which must fit in a request.
+fn iovs_len(iovs: &[FuseIoctlIovec]) -> Result<u32, FilesystemError> {
+    iovs.iter()
+        .try_fold(0u64, |len, iov| len.checked_add(iov.len))
+        .and_then(|len| u32::try_from(len).ok())
+        .ok_or(FilesystemError::Fuse { errno: EINVAL })
+}
+
+/// Splits the relative `path` into the names to look up one after the other.
+///
+/// Empty and `.` components name the directory they are in, so they are left
+/// out. A `path` starting with `/`, or naming no entry, is rejected.
+fn path_components(path: &[u8]) -> Result<Vec<&[u8]>, FilesystemError> {
+    if path.first() == Some(&b'/') {
+        return Err(FilesystemError::InvalidOperation);
+    }
+    let components: Vec<&[u8]> = path
+        .split(|&byte| byte == b'/')
+        .filter(|name| !name.is_empty() && *name != b".")
+        .collect();
+    if components.is_empty() {
+        return Err(FilesystemError::InvalidOperation);
+    }
+    Ok(components)
+}
+
+/// Returns the handle of a directory, which `opendir` obtains from the server
+/// unless `FUSE_NO_OPENDIR_SUPPORT` is among `negotiated_flags`.
+fn opendir_fh(
+    negotiated_flags: u64,
+    opendir: impl FnOnce() -> Result<FuseOpenOut, FilesystemError>,
+) -> Result<u64, FilesystemError> {
+    if negotiated_flags & FUSE_NO_OPENDIR_SUPPORT != 0 {
+        return Ok(NO_OPENDIR_FH);
+    }
+    opendir().map(|openout| openout.fh)
+}
+
+/// Returns the reply to `FUSE_OPEN`, which `open` obtains from the server
+/// unless `no_open` is set.
+///
+/// An open without a round-trip gets `NO_OPEN_FH` and keeps the cache of the
+/// file. A server that answers `FUSE_OPEN` with `ENOSYS` does without it from
+/// then on, so `no_open` is set and the open succeeds all the same.
+fn zero_message_open(
+    no_open: &AtomicBool,
+    open: impl FnOnce() -> Result<FuseOpenOut, FilesystemError>,
+) -> Result<FuseOpenOut, FilesystemError> {
+    let openout = FuseOpenOut {
+        fh: NO_OPEN_FH,
+        open_flags: FOPEN_KEEP_CACHE,
+        backing_id: -1,
+    };
+    if no_open.load(Ordering::Relaxed) {
+        return Ok(openout);
+    }
+    match open() {
+        Err(FilesystemError::Fuse { errno: ENOSYS }) => {
+            no_open.store(true, Ordering::Relaxed);
+            Ok(openout)
+        }
+        result => result,
+    }
+}
+
+/// Caches the entries, and their attributes, returned by `FUSE_READDIRPLUS` in
+/// directory `parent`.
+///
+/// Returns the entries to forget.
+fn cache_direntplus(
+    cache: &mut NodeCache,
+    parent: u64,
+    readdirplus_out: &FuseReaddirplusOut,
+    now: Duration,
+) -> Vec<(u64, u64)> {
+    readdirplus_out
+        .dirents
+        .iter()
+        // The server does not count the lookups of `.` and `..`.
+        .filter(|dirent| dirent.name != b"." && dirent.name != b"..")
+        .filter_map(|dirent| {
+            cache.insert_entry(parent, &dirent.name, &dirent.direntplus.entry_out, now)
+        })
+        .collect()
+}
+
+/// What the server answered of a `FUSE_INTERRUPT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptStatus {
+    /// The server took the interrupt, which it does not reply to on success.
+    Delivered,
+    /// The request had already finished, with nothing left to interrupt (`ENOENT`).
+    Finished,
+    /// The server has not seen the request yet, and asks to retry (`EAGAIN`).
+    Retry,
+}
+
+/// Decodes the reply to a `FUSE_INTERRUPT`, of which there may be none.
+fn interrupt_status(headerout: Option<&FuseOutHeader>) -> Result<InterruptStatus, FilesystemError> {
+    let Some(headerout) = headerout else {
+        return Ok(InterruptStatus::Delivered);
+    };
+    match check_error(headerout) {
+        Ok(()) => Ok(InterruptStatus::Delivered),
+        Err(FilesystemError::Fuse { errno: ENOENT }) => Ok(InterruptStatus::Finished),
+        Err(FilesystemError::Fuse { errno: EAGAIN }) => Ok(InterruptStatus::Retry),
+        Err(err) => Err(err),
+    }
+}
+
+/// Sends an interrupt with `send` while `is_pending`, until the server no
+/// longer asks to retry it.
+///
+/// Fails with `FilesystemError::InvalidOperation` if the request is not
+/// pending in the first place. Once it completes, a retry is moot and the
+/// request counts as finished.
+fn interrupt_until_settled<P, S>(
+    is_pending: P,
+    mut send: S,
+) -> Result<InterruptStatus, FilesystemError>
+where
+    P: Fn() -> bool,
+    S: FnMut() -> Result<InterruptStatus, FilesystemError>,
+{
+    if !is_pending() {
+        return Err(FilesystemError::InvalidOperation);
+    }
+    loop {
+        match send()? {
+            InterruptStatus::Retry if is_pending() => continue,
+            InterruptStatus::Retry => return Ok(InterruptStatus::Finished),
+            status => return Ok(status),
+        }
+    }
+}
+
+/// Fails with `BufferTooLong` unless the `len_staged` bytes of a request staged
+/// in a slot of `slot_size` bytes, and the `len_out` bytes of room for its reply,
+/// each fit in a slot.
+///
+/// Requests and replies have separate slots, so the two are not added up.
+fn check_slot_room(
+    len_staged: usize,
+    len_out: usize,
+    slot_size: usize,
+) -> Result<(), FilesystemError> {
+    for len in [len_staged, len_out] {
+        if len > slot_size {
+            return Err(FilesystemError::BufferTooLong(len, slot_size));
+        }
+    }
+    Ok(())
+}
+
+/// Copies out the reply of `len` bytes written at `reply_start` in `buffer`.
+///
+/// The buffer must be the `RequestPool::replies` of the queue the request was
+/// sent and completed on, the reply being at the offset of its slot. The
+/// reply is cut at the end of the slot of `slot_size` bytes: the length the
+/// device reports also counts what it wrote to pages of the caller, if any.
+fn read_reply(
+    buffer: &DmaStream,
+    reply_start: usize,
+    len: usize,
+    slot_size: usize,
+) -> Result<Vec<u8>, FilesystemError> {
+    let reply_len = len
+        .min(slot_size)
+        .min(buffer.nbytes().saturating_sub(reply_start));
+    buffer.sync(reply_start..reply_start + reply_len)?;
+
+    let mut reply = vec![0u8; reply_len];
+    let mut reader = buffer.reader()?.skip(reply_start).limit(reply_len);
+    reader.read(&mut VmWriter::from(reply.as_mut_slice()));
+    Ok(reply)
+}
+
+/// Copies out the request in the slot at `offset` of `pool`, along with its
+/// reply of `len` bytes.
+fn read_exchange(
+    pool: &RequestPool,
+    offset: usize,
+    len: usize,
+) -> Result<(Vec<u8>, Vec<u8>), FilesystemError> {
+    // The device only reads the request, so it is read back as staged.
+    let headerin = pool
+        .requests
+        .reader()?
+        .skip(offset)
+        .read_val::<FuseInHeader>()?;
+    let request_len = (headerin.len as usize).min(pool.slot_size);
+    let mut request = vec![0u8; request_len];
+    pool.requests
+        .reader()?
+        .skip(offset)
+        .limit(request_len)
+        .read(&mut VmWriter::from(request.as_mut_slice()));
+
+    let reply = read_reply(&pool.replies, offset, len, pool.slot_size)?;
+    Ok((request, reply))
+}
+
+/// Returns the reply in the slot taken by `InflightTable::take`.
+///
+/// An empty slot means the request was cancelled while the server has it, so
+/// `interrupt` is called to send it a `FUSE_INTERRUPT`.
+fn finish_wait<F>(slot: ReplySlot, unique: u64, interrupt: F) -> Result<Vec<u8>, FilesystemError>
+where
+    F: FnOnce(u64) -> Result<(), FilesystemError>,
+{
+    if let Some(reply) = slot {
+        return reply;
+    }
+    if let Err(err) = interrupt(unique) {
+        warn!(
+            "virtio-fs: failed to interrupt request {}: {:?}",
+            unique, err
+        );
+    }
+    Err(FilesystemError::Fuse { errno: EINTR })
+}
+
+/// Reads `size` bytes at `offset` with `read`, in chunks of at most `max_chunk` bytes.
+///
+/// The chunks are read in order, until one comes back short at the end of the file.
+fn read_chunked<F>(
+    offset: u64,
+    size: u32,
+    max_chunk: u32,
+    mut read: F,
+) -> Result<(Vec<u8>, bool), FilesystemError>
+where
+    F: FnMut(u64, u32) -> Result<(Vec<u8>, bool), FilesystemError>,
+{
+    let mut data = Vec::new();
+    while data.len() < size as usize {
+        let chunk_offset = offset
+            .checked_add(data.len() as u64)
+            .ok_or(FilesystemError::Fuse { errno: EINVAL })?;
+        let chunk_size = (size - data.len() as u32).min(max_chunk);
+        let (chunk, eof) = read(chunk_offset, chunk_size)?;
+        data.extend_from_slice(&chunk);
+        if eof {
+            return Ok((data, true));
+        }
+    }
+    Ok((data, false))
+}
+
+/// Checks that `whence` is one of the `SEEK_*` values of an lseek(2).
+fn check_whence(whence: u32) -> Result<(), FilesystemError> {
+    match whence {
+        SEEK_SET | SEEK_CUR | SEEK_END | SEEK_DATA | SEEK_HOLE => Ok(()),
+        _ => Err(FilesystemError::InvalidOperation),
+    }
+}
+
+/// Returns the offset a write at `offset` to `file` is sent at.
+///
+/// For a file opened with `O_APPEND`, that is the size of the file, which is
+/// only asked for with `file_size` then.
+fn write_offset<F>(file: &OpenFileState, offset: u64, file_size: F) -> Result<u64, FilesystemError>
+where
+    F: FnOnce() -> Result<u64, FilesystemError>,
+{
+    if file.is_append() {
+        file_size()
+    } else {
+        Ok(offset)
+    }
+}
+
+/// Returns the open flags to send for an open with `flags`, along with whether
+/// the file is to be truncated with a `setattr` afterwards.
+///
+/// `O_TRUNC` is only left to the server once `FUSE_ATOMIC_O_TRUNC` is among
+/// `negotiated_flags`. Otherwise it is taken out, as the server may not honor it.
+fn open_trunc_flags(negotiated_flags: u64, flags: u32) -> (u32, bool) {
+    if flags & O_TRUNC != 0 && negotiated_flags & FUSE_ATOMIC_O_TRUNC == 0 {
+        (flags & !O_TRUNC, true)
+    } else {
+        (flags, false)
+    }
+}
+
+/// Returns the write flags of a write by a caller, which asks the server to
+/// clear the suid and sgid bits of the file unless the caller has `CAP_FSETID`.
+///
+/// The server only takes care of them itself once `FUSE_HANDLE_KILLPRIV_V2`
+/// is among `negotiated_flags`.
+fn kill_suidgid_write_flags(negotiated_flags: u64, cap_fsetid: bool) -> u32 {
+    if negotiated_flags & FUSE_HANDLE_KILLPRIV_V2 != 0 && !cap_fsetid {
+        FUSE_WRITE_KILL_SUIDGID
+    } else {
+        0
+    }
+}
+
+/// Returns the `open_flags` of an open with `flags` by a caller, as with
+/// `kill_suidgid_write_flags`.
+///
+/// Only an open with `O_TRUNC` changes the file, and the server then clears
+/// the suid and sgid bits if the file is executable.
+fn kill_suidgid_open_flags(negotiated_flags: u64, flags: u32, cap_fsetid: bool) -> u32 {
+    if negotiated_flags & FUSE_HANDLE_KILLPRIV_V2 != 0 && flags & O_TRUNC != 0 && !cap_fsetid {
+        FUSE_OPEN_KILL_SUIDGID
+    } else {
+        0
+    }
+}
+
+/// Returns the write flags of a write flushed from the page cache, which is
+/// only told apart once `FUSE_WRITEBACK_CACHE` is among `negotiated_flags`.
+///
+/// `FUSE_WRITE_LOCKOWNER` is never set, as no lock owner is known.
+fn cache_write_flags(negotiated_flags: u64) -> u32 {
+    if negotiated_flags & FUSE_WRITEBACK_CACHE != 0 {
+        FUSE_WRITE_CACHE
+    } else {
+        0
+    }
+}
+
+/// Writes `data` at `offset` in chunks of up to `max_chunk` bytes, each one with
+/// `write`, which returns how many bytes of its chunk were written.
+///
+/// Returns the total number of bytes written, stopping at the first short write.
+fn write_chunked<F>(
+    offset: u64,
+    data: &[u8],
+    max_chunk: u32,
+    mut write: F,
+) -> Result<usize, FilesystemError>
+where
+    F: FnMut(u64, &[u8]) -> Result<u32, FilesystemError>,
+{
+    let mut written = 0;
+    for chunk in data.chunks(max_chunk.max(1) as usize) {
+        let chunk_offset = offset
+            .checked_add(written as u64)
+            .ok_or(FilesystemError::Fuse { errno: EINVAL })?;
+        let chunk_written = (write(chunk_offset, chunk)? as usize).min(chunk.len());
+        written += chunk_written;
+        if chunk_written < chunk.len() {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+/// Splits the data of a `FUSE_READ` of `size` bytes from the end-of-file indication.
+///
+/// A read only comes back short at the end of the file.
+fn finish_read(mut dataout: Vec<u8>, size: u32) -> (Vec<u8>, bool) {
+    dataout.truncate(size as usize);
+    let eof = dataout.len() < size as usize;
+    (dataout, eof)
+}
+
+/// Decodes an invalidation of `code` from `reader` and hands it to `callbacks`.
+fn notify_callbacks(
+    callbacks: &[&dyn FilesystemCallback],
+    code: FuseNotifyCode,
+    reader: &mut VmReader<'_, Infallible>,
+) -> ostd::Result<()> {
+    match code {
+        FuseNotifyCode::FuseNotifyPoll => {
+            let out = reader.read_val::<FuseNotifyPollWakeupOut>()?;
+            debug!("virtio-fs poll wakeup: kh = {:#x}", out.kh);
+            callbacks.iter().for_each(|cb| cb.poll_wakeup(out.kh));
+        }
+        FuseNotifyCode::FuseNotifyInvalInode => {
+            let out = reader.read_val::<FuseNotifyInvalInodeOut>()?;
+            debug!(
+                "virtio-fs invalidate inode {}: off = {}, len = {}",
+                out.ino, out.off, out.len
+            );
+            callbacks
+                .iter()
+                .for_each(|cb| cb.invalidate_inode(out.ino, out.off, out.len));
+        }
+        FuseNotifyCode::FuseNotifyInvalEntry => {
+            let out = reader.read_val::<FuseNotifyInvalEntryOut>()?;
+            let name = read_notify_name(reader, out.namelen)?;
+            debug!("virtio-fs invalidate entry in {}", out.parent);
+            callbacks
+                .iter()
+                .for_each(|cb| cb.invalidate_entry(out.parent, &name));
+        }
+        FuseNotifyCode::FuseNotifyDelete => {
+            let out = reader.read_val::<FuseNotifyDeleteOut>()?;
+            let name = read_notify_name(reader, out.namelen)?;
+            debug!("virtio-fs delete {} from {}", out.child, out.parent);
+            callbacks
+                .iter()
+                .for_each(|cb| cb.delete(out.parent, out.child, &name));
+        }
+        _ => return Err(ostd::Error::InvalidArgs),
+    }
+    Ok(())
+}
+
+/// Reads the name of `namelen` bytes following a notification, which ends with a NUL.
+fn read_notify_name(reader: &mut VmReader<'_, Infallible>, namelen: u32) -> ostd::Result<Vec<u8>> {
+    let namelen = namelen as usize;
+    if reader.remain() < namelen + 1 {
+        return Err(ostd::Error::InvalidArgs);
+    }
+    let mut name = vec![0u8; namelen];
+    reader.read(&mut VmWriter::from(name.as_mut_slice()));
+    reader.read_val::<u8>()?;
+    Ok(name)
+}
+
+/// Invokes `callbacks` with `nodeid` if its `attr` marks it as a submount.
+///
+/// The flag is only meaningful if `negotiated_flags` has `FUSE_SUBMOUNTS`.
+fn dispatch_submount(
+    callbacks: &[Arc<AutomountCallback>],
+    negotiated_flags: u64,
+    nodeid: u64,
+    attr: &FuseAttr,
+) {
+    if negotiated_flags & FUSE_SUBMOUNTS == 0 || attr.flags & FUSE_ATTR_SUBMOUNT == 0 {
+        return;
+    }
+    for callback in callbacks {
+        callback(nodeid);
+    }
+}
+
+/// Reads a whole directory by issuing `FUSE_READDIR` until the end of the stream.
+///
+/// The end is reached on a successful reply without any entry, which is how
+/// the server reports that nothing follows the given offset.
+///
+/// The offset of each request is the `off` of the last entry returned so far.
+/// It is an opaque cookie chosen by the server, not a byte or entry count, and
+/// may take any value up to `u64::MAX`. So it is passed on as is, and never
+/// computed from another offset. The read stops at a cookie that was already
+/// given, rather than going around in circles.
+fn collect_dirents<F>(mut fetch: F) -> Result<Vec<FuseDirentWithName>, FilesystemError>
+where
+    F: FnMut(u64) -> Result<FuseReaddirOut, FilesystemError>,
+{
+    let mut dirents = Vec::new();
+    let mut offset = 0;
+    let mut seen = BTreeSet::from([offset]);
+    loop {
+        let page = match fetch(offset) {
+            Ok(page) => page,
+            // The server no longer recognizes the cookie, e.g. because the
+            // directory changed in between. Keep what has been read so far.
+            Err(FilesystemError::Fuse { errno: EINVAL }) if offset != 0 => break,
+            Err(err) => return Err(err),
+        };
+        if page.is_end_of_stream() {
+            break;
+        }
+        let last = page.dirents.last().unwrap();
+        // A server that hands back a cookie it was given would loop forever.
+        if !seen.insert(last.dirent.off) {
+            warn!("virtio-fs readdir made no progress at offset {:#x}", offset);
+            break;
+        }
+        offset = last.dirent.off;
+        dirents.extend(page.dirents);
+    }
+    Ok(dirents)
+}
+
+/// Reads up to `size` bytes of directory entries after the entry whose `off`
+/// is `offset`, with `fetch` reading a reply of up to `max_chunk` bytes.
+///
+/// A `size` larger than `max_chunk` is read with several requests, each one
+/// after the last entry read so far, and the entries are put back together.
+/// The read stops early at the end of the directory, or at an entry that does
+/// not fit in the room left.
+fn readdir_chunked<F>(
+    mut offset: u64,
+    size: u32,
+    max_chunk: u32,
+    mut fetch: F,
+) -> Result<FuseReaddirOut, FilesystemError>
+where
+    F: FnMut(u64, u32) -> Result<FuseReaddirOut, FilesystemError>,
+{
+    let mut dirents = Vec::new();
+    let mut remaining = size;
+    loop {
+        let chunk = remaining.min(max_chunk.max(1));
+        let page = match fetch(offset, chunk) {
+            Err(FilesystemError::BufferTooShort) if !dirents.is_empty() => break,
+            result => result?,
+        };
+        let Some(last) = page.dirents.last() else {
+            break;
+        };
+        offset = last.dirent.off;
+        let len = page
+            .dirents
+            .iter()
+            .map(|dirent| fuse_dirent_size(&dirent.dirent))
+            .sum::<usize>();
+        dirents.extend(page.dirents);
+        // The whole rest was asked for, so the entries that did not fit are
+        // left to the next read.
+        if chunk == remaining {
+            break;
+        }
+        remaining = remaining.saturating_sub(len as u32);
+    }
+    Ok(FuseReaddirOut { dirents: dirents })
+}
+
+/// Reads one page of directory entries with `fetch`, into a buffer of `size` bytes.
+///
+/// As long as an entry does not fit, e.g. one with a long name, the read is
+/// retried with a buffer twice as large, up to `max_size` bytes.
+fn fetch_growing<F>(
+    mut size: u32,
+    max_size: u32,
+    mut fetch: F,
+) -> Result<FuseReaddirOut, FilesystemError>
+where
+    F: FnMut(u32) -> Result<FuseReaddirOut, FilesystemError>,
+{
+    loop {
+        match fetch(size) {
+            Err(FilesystemError::BufferTooShort) if size < max_size => {
+                size = size.saturating_mul(2).min(max_size);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Encodes a `FUSE_FORGET` request, from the `FuseInHeader` to the `FuseForgetIn`.
+fn encode_forget(nodeid: u64, nlookup: u64) -> Vec<u8> {
+    let headerin = FuseInHeader {
+        len: (size_of::<FuseForgetIn>() + size_of::<FuseInHeader>()) as u32,
+        opcode: FuseOpcode::FuseForget as u32,
+        unique: 0,
+        nodeid: nodeid,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        total_extlen: 0,
+        padding: 0,
+    };
+    let forgetin = FuseForgetIn { nlookup: nlookup };
+
+    [headerin.as_bytes(), forgetin.as_bytes()].concat()
+}
+
+/// Encodes a `FUSE_BATCH_FORGET` request, from the `FuseInHeader` to the last `FuseForgetOne`.
+fn encode_batch_forget(forget_list: &[(u64, u64)]) -> Vec<u8> {
+    let len = size_of::<FuseInHeader>()
+        + size_of::<FuseBatchForgetIn>()
+        + forget_list.len() * size_of::<FuseForgetOne>();
+
+    let headerin = FuseInHeader {
+        len: len as u32,
+        opcode: FuseOpcode::FuseBatchForget as u32,
+        unique: 0,
+        nodeid: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        total_extlen: 0,
+        padding: 0,
+    };
+    let batch_forget_in = FuseBatchForgetIn {
+        count: forget_list.len() as u32,
+        dummy: 0,
+    };
+
+    let mut request = Vec::with_capacity(len);
+    request.extend_from_slice(headerin.as_bytes());
+    request.extend_from_slice(batch_forget_in.as_bytes());
+    for (nodeid, nlookup) in forget_list {
+        let forget_one = FuseForgetOne {
+            nodeid: *nodeid,
+            nlookup: *nlookup,
+        };
+        request.extend_from_slice(forget_one.as_bytes());
+    }
+    request
+}
+
+/// Checks the name of an extended attribute, e.g. `user.foo`, which is sent
+/// as is along with its namespace prefix.
+///
+/// The name is taken as bytes, so it needs not be UTF-8. It must not be empty,
+/// nor hold a NUL, which would end it early on the server.
+fn check_xattr_name(name: &[u8]) -> Result<(), FilesystemError> {
+    if name.is_empty() || name.contains(&0) {
+        return Err(FilesystemError::InvalidOperation);
+    }
+    Ok(())
+}
+
+/// Returns the wire form of `setxattrin`.
+///
+/// Without `FUSE_SETXATTR_EXT`, the server expects the original 8-byte body
+/// that stops before `setxattr_flags`.
+fn setxattr_in_bytes(setxattrin: &FuseSetxattrIn, ext: bool) -> &[u8] {
+    let len = if ext {
+        size_of::<FuseSetxattrIn>()
+    } else {
+        FUSE_COMPAT_SETXATTR_IN_SIZE as usize
+    };
+    &setxattrin.as_bytes()[..len]
+}
+
+/// Encodes a `FUSE_NOTIFY_REPLY` to `FUSE_NOTIFY_RETRIEVE`, from the `FuseInHeader` to the data.
+///
+/// The reply is matched to the retrieve by `notify_unique`, carried as its `unique`.
+fn encode_notify_reply(notify_unique: u64, nodeid: u64, offset: u64, data: &[u8]) -> Vec<u8> {
+    let len = size_of::<FuseInHeader>() + size_of::<FuseNotifyRetrieveIn>() + data.len();
+    let headerin = fuse_in_header(
+        FuseOpcode::FuseNotifyReply,
+        notify_unique,
+        nodeid,
+        len,
+        FuseCreds::default(),
+    );
+    let retrievein = FuseNotifyRetrieveIn {
+        offset: offset,
+        size: data.len() as u32,
+        ..Default::default()
+    };
+
+    let mut request = Vec::with_capacity(len);
+    request.extend_from_slice(headerin.as_bytes());
+    request.extend_from_slice(retrievein.as_bytes());
+    request.extend_from_slice(data);
+    request
+}
+
+/// Builds the body shared by `FUSE_GETLK`, `FUSE_SETLK` and `FUSE_SETLKW`.
+///
+/// `flock` marks the lock as a BSD `flock(2)` lock rather than a POSIX record lock.
+fn fuse_lk_in(fh: u64, owner: u64, lk: FuseFileLock, flock: bool) -> FuseLkIn {
+    FuseLkIn {
+        fh: fh,
+        owner: owner,
+        lk: lk,
+        lk_flags: if flock { FUSE_LK_FLOCK } else { 0 },
+        padding: 0,
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn request_queues_clamped_to_transport() {
+        // The config claims 8 request queues, but the transport only has
+        // the hiprio queue plus 2 others.
+        assert_eq!(usable_request_queues(8, 3, 1), 2);
+        assert_eq!(usable_request_queues(1, 3, 1), 1);
+        assert_eq!(usable_request_queues(2, 1, 1), 0);
+        assert_eq!(usable_request_queues(2, 0, 1), 0);
+    }
+
+    #[ktest]
+    fn setup_creates_only_transport_queues() {
+        // The config claims 8 request queues, but the transport only has
+        // the hiprio queue plus 2 others.
+        let mut created = Vec::new();
+        let (request_queues, request_pools) = setup_request_queues(8, 3, 1, |idx| {
+            created.push(idx);
+            Ok(idx)
+        })
+        .unwrap();
+        assert_eq!(created, [1, 2]);
+        assert_eq!(request_queues.len(), 2);
+        assert_eq!(request_pools.len(), 2);
+        assert_eq!(*request_queues[1].lock(), 2);
+
+        // Without a single request queue the device cannot be used.
+        let result = setup_request_queues(2, 1, 1, |idx| Ok(idx));
+        assert!(matches!(
+            result,
+            Err(VirtioDeviceError::QueuesAmountDoNotMatch(1, 2))
+        ));
+    }
+
+    #[ktest]
+    fn completion_on_queue_2_reaches_its_waiter() {
+        let pools = (0..3)
+            .map(|_| RequestPool::new(1, PAGE_SIZE).unwrap())
+            .collect::<Vec<_>>();
+        let mut pending_replies = InflightTable::default();
+
+        // Requests 1 to 3 go out on queues 0 to 2, with a reply of their own.
+        let mut headers = Vec::new();
+        for (queue_idx, pool) in pools.iter().enumerate() {
+            let unique = queue_idx as u64 + 1;
+            let len_in = size_of::<FuseInHeader>();
+            let headerin = fuse_in_header(
+                FuseOpcode::FuseGetattr,
+                unique,
+                1,
+                len_in,
+                FuseCreds::default(),
+            );
+            let headerout = FuseOutHeader {
+                len: size_of::<FuseOutHeader>() as u32,
+                error: 0,
+                unique: unique,
+            };
+            stage_request(&pool.requests, &[headerin.as_bytes()]).unwrap();
+            pool.replies
+                .writer()
+                .unwrap()
+                .write(&mut VmReader::from(headerout.as_bytes()));
+            pending_replies.insert(unique);
+            headers.push(headerin);
+        }
+
+        // Only queue 2 completes.
+        let reply = read_reply(
+            &pools[2].replies,
+            0,
+            size_of::<FuseOutHeader>(),
+            pools[2].slot_size,
+        )
+        .unwrap();
+        assert!(pending_replies.complete(headers[2].unique, Ok(reply)));
+
+        let slot = pending_replies.take(3, None).unwrap();
+        let headerout = parse_reply_header(&slot.unwrap().unwrap()).unwrap();
+        assert_eq!(headerout.unique, 3);
+        assert!(pending_replies.is_waiting(1));
+        assert!(pending_replies.is_waiting(2));
+    }
+
+    #[ktest]
+    fn pooled_lookups_are_in_flight_together() {
+        let mut pool = RequestPool::new(REQUEST_POOL_SLOTS, 3 * PAGE_SIZE).unwrap();
+        let mut pending_replies = InflightTable::default();
+
+        // All lookups are staged before any of them completes.
+        let name = fuse_pad_str(b"file", true);
+        let len_in = size_of::<FuseInHeader>() + name.len();
+        for token in 0..REQUEST_POOL_SLOTS as u16 {
+            let unique = token as u64 + 1;
+            let offset = pool.alloc().unwrap();
+            let headerin = fuse_in_header(
+                FuseOpcode::FuseLookup,
+                unique,
+                1,
+                len_in,
+                FuseCreds::default(),
+            );
+            let headerout = FuseOutHeader {
+                len: size_of::<FuseOutHeader>() as u32,
+                error: 0,
+                unique: unique,
+            };
+            let request = [headerin.as_bytes(), &name].concat();
+            pool.requests
+                .writer()
+                .unwrap()
+                .skip(offset)
+                .write(&mut VmReader::from(request.as_slice()));
+            pool.replies
+                .writer()
+                .unwrap()
+                .skip(offset)
+                .write(&mut VmReader::from(headerout.as_bytes()));
+            pool.track(token, offset, true);
+            pending_replies.insert(unique);
+        }
+        assert!(pool.alloc().is_none());
+
+        // The replies come back in reverse order, each from its own slot.
+        for token in (0..REQUEST_POOL_SLOTS as u16).rev() {
+            let offset = pool.complete(token).unwrap().offset;
+            let headerin = pool
+                .requests
+                .reader()
+                .unwrap()
+                .skip(offset)
+                .read_val::<FuseInHeader>()
+                .unwrap();
+            let reply = read_reply(
+                &pool.replies,
+                offset,
+                size_of::<FuseOutHeader>(),
+                pool.slot_size,
+            )
+            .unwrap();
+            assert_eq!(parse_reply_header(&reply).unwrap().unique, headerin.unique);
+            assert!(pending_replies.complete(headerin.unique, Ok(reply)));
+            pool.free(offset);
+        }
+        assert!(pool.complete(0).is_none());
+        assert!(pool.alloc().is_some());
+    }
+
+    #[ktest]
+    fn reply_is_read_within_its_slot() {
+        let pool = RequestPool::new(2, PAGE_SIZE).unwrap();
+        pool.replies
+            .writer()
+            .unwrap()
+            .skip(PAGE_SIZE)
+            .write(&mut VmReader::from([0xffu8; 8].as_slice()));
+
+        // A reply that goes on in pages of the caller is not read on into the next slot.
+        let reply = read_reply(&pool.replies, 0, PAGE_SIZE + 8, pool.slot_size).unwrap();
+        assert_eq!(reply.len(), PAGE_SIZE);
+        assert!(!reply.contains(&0xff));
+    }
+
+    #[ktest]
+    fn request_and_reply_fit_their_slots_apart() {
+        // A request and its reply that both fill a slot fit, as each has its own.
+        assert!(check_slot_room(PAGE_SIZE, PAGE_SIZE, PAGE_SIZE).is_ok());
+        assert!(matches!(
+            check_slot_room(PAGE_SIZE + 1, 0, PAGE_SIZE),
+            Err(FilesystemError::BufferTooLong(len, PAGE_SIZE)) if len == PAGE_SIZE + 1
+        ));
+        assert!(matches!(
+            check_slot_room(0, PAGE_SIZE + 1, PAGE_SIZE),
+            Err(FilesystemError::BufferTooLong(len, PAGE_SIZE)) if len == PAGE_SIZE + 1
+        ));
+    }
+
+    #[ktest]
+    fn unawaited_requests_keep_their_own_exchange() {
+        let mut pool = RequestPool::new(2, PAGE_SIZE).unwrap();
+        let mut queue = MockQueue::default();
+
+        // Two requests go out without a waiter, each in a slot and under a unique of its own.
+        let mut offsets = Vec::new();
+        for unique in [1, 2] {
+            let offset = pool.alloc().unwrap();
+            let len_in = size_of::<FuseInHeader>();
+            let headerin = fuse_in_header(
+                FuseOpcode::FuseStatfs,
+                unique,
+                1,
+                len_in,
+                FuseCreds::default(),
+            );
+            let token = post_request(
+                &mut queue,
+                &pool.requests,
+                &pool.replies,
+                offset,
+                &headerin,
+                &RequestParts {
+                    out_len: size_of::<FuseStatfsOut>(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            pool.track(token, offset, false);
+            let headerout = FuseOutHeader {
+                len: size_of::<FuseOutHeader>() as u32,
+                error: 0,
+                unique: unique,
+            };
+            pool.replies
+                .writer()
+                .unwrap()
+                .skip(offset)
+                .write(&mut VmReader::from(headerout.as_bytes()));
+            offsets.push(offset);
+        }
+        assert_ne!(offsets[0], offsets[1]);
+
+        // The second one completes first, and is read from its own slot.
+        let request = pool.complete(1).unwrap();
+        assert!(!request.awaited);
+        let (request_bytes, reply) =
+            read_exchange(&pool, request.offset, size_of::<FuseOutHeader>()).unwrap();
+        assert_eq!(FuseInHeader::from_bytes(&request_bytes).unique, 2);
+        assert_eq!(parse_reply_header(&reply).unwrap().unique, 2);
+        pool.free(request.offset);
+        assert_eq!(pool.complete(0).unwrap().offset, offsets[0]);
+    }
+
+    #[ktest]
+    fn request_queues_follow_notification_queue() {
+        let base = request_queue_base_index(FilesystemFeatures::empty());
+        assert_eq!(base, 1);
+        assert_eq!(usable_request_queues(2, 3, base), 2);
+
+        // The notification queue takes index 1.
+        let base = request_queue_base_index(FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION);
+        assert_eq!(base, 2);
+        assert_eq!(usable_request_queues(2, 3, base), 1);
+        assert_eq!(usable_request_queues(1, 2, base), 0);
+    }
+
+    /// A hiprio queue that keeps the requests posted on it, and returns them
+    /// only while it `answers`.
+    #[derive(Default)]
+    struct MockHiprioQueue {
+        inner: MockQueue,
+        answers: bool,
+        in_flight: usize,
+    }
+
+    impl RequestQueue for MockHiprioQueue {
+        fn add_dma_buf(
+            &mut self,
+            inputs: &[&DmaStreamSlice<DmaStream>],
+            outputs: &[&DmaStreamSlice<DmaStream>],
+        ) -> Result<u16, QueueError> {
+            self.in_flight += 1;
+            self.inner.add_dma_buf(inputs, outputs)
+        }
+    }
+
+    impl HiprioQueue for MockHiprioQueue {
+        fn can_pop(&self) -> bool {
+            self.answers && self.in_flight > 0
+        }
+
+        fn pop_used(&mut self) -> Result<(u16, u32), QueueError> {
+            if !self.can_pop() {
+                return Err(QueueError::NotReady);
+            }
+            self.in_flight -= 1;
+            Ok((0, 0))
+        }
+
+        fn is_idle(&self) -> bool {
+            self.in_flight == 0
+        }
+
+        fn kick(&mut self) {}
+    }
+
+    fn post_forget(
+        queue: &mut MockHiprioQueue,
+        buffer: &DmaStream,
+        timeout: Duration,
+    ) -> Result<u32, FilesystemError> {
+        let forget = encode_forget(2, 1);
+        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
+        post_hiprio(
+            queue,
+            buffer,
+            &[&forget, &headerout_buffer],
+            forget.len(),
+            timeout,
+        )
+    }
+
+    #[ktest]
+    fn forget_does_not_clobber_pending_lookup() {
+        let pool = RequestPool::new(1, PAGE_SIZE).unwrap();
+        let hiprio_buffer = alloc_request_buffer().unwrap();
+
+        // A lookup is posted and awaits its reply.
+        let name = fuse_pad_str(b"file", true);
+        let len_in = size_of::<FuseInHeader>() + name.len();
+        let headerin = fuse_in_header(FuseOpcode::FuseLookup, 1, 1, len_in, FuseCreds::default());
+        let mut request_queue = MockQueue::default();
+        post_request(
+            &mut request_queue,
+            &pool.requests,
+            &pool.replies,
+            0,
+            &headerin,
+            &RequestParts {
+                datain: &[&name],
+                out_len: size_of::<FuseEntryOut>(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // A forget goes out meanwhile.
+        let mut hiprio_queue = MockHiprioQueue {
+            answers: true,
+            ..Default::default()
+        };
+        post_forget(&mut hiprio_queue, &hiprio_buffer, HIPRIO_TIMEOUT).unwrap();
+        let (readable, _) = hiprio_queue.inner.posted.pop().unwrap();
+        assert_eq!(readable, encode_forget(2, 1));
+
+        let mut staged = vec![0u8; len_in];
+        pool.requests
+            .reader()
+            .unwrap()
+            .read(&mut VmWriter::from(staged.as_mut_slice()));
+        assert_eq!(staged, [headerin.as_bytes(), &name].concat());
+    }
+
+    #[ktest]
+    fn unanswered_hiprio_request_times_out() {
+        let hiprio_buffer = alloc_request_buffer().unwrap();
+        let mut hiprio_queue = MockHiprioQueue::default();
+
+        let result = post_forget(&mut hiprio_queue, &hiprio_buffer, Duration::from_millis(10));
+        assert!(matches!(
+            result,
+            Err(FilesystemError::Fuse { errno: ETIMEDOUT })
+        ));
+
+        // The device still owns the buffer, so nothing is staged in it.
+        let result = post_forget(&mut hiprio_queue, &hiprio_buffer, Duration::from_millis(10));
+        assert!(matches!(
+            result,
+            Err(FilesystemError::QueueError(QueueError::NotReady))
+        ));
+        assert_eq!(hiprio_queue.inner.posted.len(), 1);
+
+        // Once it is returned, requests go out again.
+        hiprio_queue.answers = true;
+        post_forget(&mut hiprio_queue, &hiprio_buffer, HIPRIO_TIMEOUT).unwrap();
+        assert_eq!(hiprio_queue.inner.posted.len(), 2);
+    }
+
+    fn interrupt_reply(errno: i32) -> FuseOutHeader {
+        FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -errno,
+            unique: 9,
+        }
+    }
+
+    #[ktest]
+    fn interrupt_replies_are_decoded() {
+        assert_eq!(interrupt_status(None).unwrap(), InterruptStatus::Delivered);
+        assert_eq!(
+            interrupt_status(Some(&interrupt_reply(ENOENT))).unwrap(),
+            InterruptStatus::Finished
+        );
+        assert_eq!(
+            interrupt_status(Some(&interrupt_reply(EAGAIN))).unwrap(),
+            InterruptStatus::Retry
+        );
+        assert!(matches!(
+            interrupt_status(Some(&interrupt_reply(EINVAL))),
+            Err(FilesystemError::Fuse { errno: EINVAL })
+        ));
+    }
+
+    #[ktest]
+    fn interrupt_targets_pending_requests_only() {
+        let mut pending_replies = InflightTable::default();
+        pending_replies.insert(3);
+
+        let mut sent = 0;
+        let result = interrupt_until_settled(
+            || pending_replies.is_waiting(4),
+            || {
+                sent += 1;
+                Ok(InterruptStatus::Delivered)
+            },
+        );
+        assert!(matches!(result, Err(FilesystemError::InvalidOperation)));
+        assert_eq!(sent, 0);
+
+        // The server asks to retry until it has seen the request.
+        let mut replies = [
+            InterruptStatus::Retry,
+            InterruptStatus::Retry,
+            InterruptStatus::Delivered,
+        ]
+        .into_iter();
+        let status = interrupt_until_settled(
+            || pending_replies.is_waiting(3),
+            || {
+                sent += 1;
+                Ok(replies.next().unwrap())
+            },
+        )
+        .unwrap();
+        assert_eq!(status, InterruptStatus::Delivered);
+        assert_eq!(sent, 3);
+    }
+
+    #[ktest]
+    fn oversized_request_is_not_truncated() {
+        let parts: [&[u8]; 2] = [&[7u8; 16], &[8u8; 32]];
+        let mut buffer = [0u8; 48];
+
+        let len = write_request(&mut VmWriter::from(&mut buffer[..]), &parts).unwrap();
+        assert_eq!(len, 48);
+        assert_eq!(buffer[..16], [7u8; 16]);
+        assert_eq!(buffer[16..], [8u8; 32]);
+
+        // Nothing is written of a request that does not fit.
+        let mut buffer = [0u8; 32];
+        let result = write_request(&mut VmWriter::from(&mut buffer[..]), &parts);
+        assert!(matches!(
+            result,
+            Err(FilesystemError::OutputBufferTooShort(32, 48))
+        ));
+        assert_eq!(buffer, [0u8; 32]);
+    }
+
+    /// A request queue that keeps the bytes of the requests posted on it.
+    #[derive(Default)]
+    struct MockQueue {
+        /// The bytes readable by the device, along with the room left for the reply.
+        posted: Vec<(Vec<u8>, usize)>,
+    }
+
+    impl RequestQueue for MockQueue {
+        fn add_dma_buf(
+            &mut self,
+            inputs: &[&DmaStreamSlice<DmaStream>],
+            outputs: &[&DmaStreamSlice<DmaStream>],
+        ) -> Result<u16, QueueError> {
+            let mut readable = Vec::new();
+            for input in inputs {
+                let mut bytes = vec![0u8; input.nbytes()];
+                input
+                    .reader()
+                    .unwrap()
+                    .read(&mut VmWriter::from(bytes.as_mut_slice()));
+                readable.extend_from_slice(&bytes);
+            }
+            let writable = outputs.iter().map(|output| output.nbytes()).sum();
+            self.posted.push((readable, writable));
+            Ok(self.posted.len() as u16 - 1)
+        }
+    }
+
+    fn post_to_mock(
+        opcode: FuseOpcode,
+        datain: &[&[u8]],
+        in_pages: &[DmaStreamSlice<DmaStream>],
+        out_len: usize,
+    ) -> (Vec<u8>, usize) {
+        let creds = FuseCreds {
+            uid: 1000,
+            gid: 100,
+            pid: 42,
+        };
+        let len_in = size_of::<FuseInHeader>()
+            + datain.iter().map(|data| data.len()).sum::<usize>()
+            + in_pages.iter().map(|page| page.nbytes()).sum::<usize>();
+        let headerin = fuse_in_header(opcode, 5, 1, len_in, creds);
+        let mut queue = MockQueue::default();
+        let requests = alloc_request_buffer().unwrap();
+        let replies = alloc_request_buffer().unwrap();
+        // The request is staged past the start of the buffers, as in a pool slot.
+        let token = post_request(
+            &mut queue,
+            &requests,
+            &replies,
+            PAGE_SIZE,
+            &headerin,
+            &RequestParts {
+                datain: datain,
+                in_pages: in_pages,
+                out_len: out_len,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(token, 0);
+        queue.posted.pop().unwrap()
+    }
+
+    #[ktest]
+    fn lookup_is_encoded_exactly() {
+        let name = fuse_pad_str(b"foo", true);
+        let (readable, writable) = post_to_mock(
+            FuseOpcode::FuseLookup,
+            &[&name],
+            &[],
+            size_of::<FuseEntryOut>(),
+        );
+        #[rustfmt::skip]
+        let expected: [u8; 48] = [
+            48, 0, 0, 0,              // len
+            1, 0, 0, 0,               // opcode: FUSE_LOOKUP
+            5, 0, 0, 0, 0, 0, 0, 0,   // unique
+            1, 0, 0, 0, 0, 0, 0, 0,   // nodeid
+            0xe8, 3, 0, 0,            // uid: 1000
+            100, 0, 0, 0,             // gid
+            42, 0, 0, 0,              // pid
+            0, 0,                     // total_extlen
+            0, 0,                     // padding
+            b'f', b'o', b'o', 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(readable, expected);
+        assert_eq!(
+            writable,
+            size_of::<FuseOutHeader>() + size_of::<FuseEntryOut>()
+        );
+    }
+
+    #[ktest]
+    fn request_is_split_within_bounds() {
+        let vm_segment = FrameAllocOptions::new().alloc_segment(1).unwrap();
+        let stream = DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap();
+
+        let (slice_in, slice_out) = split_request(&stream, 64, 96).unwrap();
+        assert_eq!((slice_in.offset(), slice_in.nbytes()), (0, 64));
+        assert_eq!((slice_out.offset(), slice_out.nbytes()), (64, 32));
+
+        assert!(matches!(
+            split_request(&stream, 97, 96),
+            Err(FilesystemError::BufferTooShort)
+        ));
+        assert!(matches!(
+            split_request(&stream, 64, PAGE_SIZE + 1),
+            Err(FilesystemError::BufferTooShort)
+        ));
+    }
+
+    #[ktest]
+    fn write_from_pages_is_encoded_exactly() {
+        let page = {
+            let vm_segment = FrameAllocOptions::new().alloc_segment(1).unwrap();
+            let stream =
+                DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap();
+            stream.writer().unwrap().fill(0xabu8);
+            DmaStreamSlice::new(stream, 0, 16)
+        };
+        let writein = FuseWriteIn {
+            fh: 3,
+            offset: 4096,
+            size: 16,
+            ..Default::default()
+        };
+        let (readable, writable) = post_to_mock(
+            FuseOpcode::FuseWrite,
+            &[writein.as_bytes()],
+            core::slice::from_ref(&page),
+            size_of::<FuseWriteOut>(),
+        );
+
+        let len_in = size_of::<FuseInHeader>() + size_of::<FuseWriteIn>() + 16;
+        assert_eq!(readable.len(), len_in);
+        let mut reader = VmReader::from(readable.as_slice());
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        assert_eq!(headerin.len as usize, len_in);
+        assert_eq!(headerin.opcode, FuseOpcode::FuseWrite as u32);
+        assert_eq!(reader.read_val::<FuseWriteIn>().unwrap().offset, 4096);
+        // The data follows in place, from the page of the caller.
+        assert_eq!(readable[len_in - 16..], [0xab; 16]);
+        assert_eq!(
+            writable,
+            size_of::<FuseOutHeader>() + size_of::<FuseWriteOut>()
+        );
+    }
+
+    /// A server answering the requests posted through `post_request` one at a time.
+    struct FakeServer {
+        queue: MockQueue,
+        requests: DmaStream,
+        replies: DmaStream,
+        next_unique: u64,
+    }
+
+    impl FakeServer {
+        fn new() -> Self {
+            Self {
+                queue: MockQueue::default(),
+                requests: alloc_request_buffer().unwrap(),
+                replies: alloc_request_buffer().unwrap(),
+                next_unique: 1,
+            }
+        }
+
+        /// Sends a request and answers it with the reply body `body`, or with
+        /// `errno` if it is not 0, returning the reply as `send_sync` does.
+        fn exchange(
+            &mut self,
+            opcode: FuseOpcode,
+            nodeid: u64,
+            datain: &[&[u8]],
+            out_len: usize,
+            errno: i32,
+            body: &[u8],
+        ) -> Result<(FuseOutHeader, Vec<u8>), FilesystemError> {
+            let unique = self.next_unique;
+            self.next_unique += 1;
+            let len_in =
+                size_of::<FuseInHeader>() + datain.iter().map(|data| data.len()).sum::<usize>();
+            let headerin = fuse_in_header(opcode, unique, nodeid, len_in, FuseCreds::default());
+            post_request(
+                &mut self.queue,
+                &self.requests,
+                &self.replies,
+                0,
+                &headerin,
+                &RequestParts {
+                    datain: datain,
+                    out_len: out_len,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let (readable, writable) = self.queue.posted.last().unwrap();
+            assert_eq!(readable.len(), len_in);
+            assert_eq!(&readable[..size_of::<FuseInHeader>()], headerin.as_bytes());
+
+            // The reply goes to the room left for it in the reply buffer.
+            let headerout = FuseOutHeader {
+                len: (size_of::<FuseOutHeader>() + body.len()) as u32,
+                error: -errno,
+                unique: unique,
+            };
+            let reply = [headerout.as_bytes(), body].concat();
+            assert!(reply.len() <= *writable);
+            self.replies
+                .writer()
+                .unwrap()
+                .write(&mut VmReader::from(reply.as_slice()));
+            let slot_size = self.replies.nbytes();
+            parse_reply(&read_reply(&self.replies, 0, reply.len(), slot_size)?)
+        }
+    }
+
+    #[ktest]
+    fn lookup_open_read_flow() {
+        let mut server = FakeServer::new();
+        let name = fuse_pad_str(b"testf01", true);
+        let entry = FuseEntryOut {
+            nodeid: 2,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseLookup,
+                1,
+                &[&name],
+                size_of::<FuseEntryOut>(),
+                0,
+                entry.as_bytes(),
+            )
+            .unwrap();
+        let nodeid = decode_reply::<FuseEntryOut>(&dataout).unwrap().nodeid;
+        assert_eq!(nodeid, 2);
+
+        let openin = FuseOpenIn::default();
+        let openout = FuseOpenOut {
+            fh: 7,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseOpen,
+                nodeid,
+                &[openin.as_bytes()],
+                size_of::<FuseOpenOut>(),
+                0,
+                openout.as_bytes(),
+            )
+            .unwrap();
+        let fh = decode_reply::<FuseOpenOut>(&dataout).unwrap().fh;
+        assert_eq!(fh, 7);
+
+        let readin = FuseReadIn {
+            fh: fh,
+            size: 128,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseRead,
+                nodeid,
+                &[readin.as_bytes()],
+                128,
+                0,
+                b"hello world",
+            )
+            .unwrap();
+        assert_eq!(finish_read(dataout, 128), (b"hello world".to_vec(), true));
+    }
+
+    #[ktest]
+    fn lookup_opendir_readdir_flow() {
+        let mut server = FakeServer::new();
+        let name = fuse_pad_str(b"testdir", true);
+        let entry = FuseEntryOut {
+            nodeid: 3,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseLookup,
+                1,
+                &[&name],
+                size_of::<FuseEntryOut>(),
+                0,
+                entry.as_bytes(),
+            )
+            .unwrap();
+        let nodeid = decode_reply::<FuseEntryOut>(&dataout).unwrap().nodeid;
+
+        let openin = FuseOpenIn::default();
+        let openout = FuseOpenOut {
+            fh: 9,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseOpendir,
+                nodeid,
+                &[openin.as_bytes()],
+                size_of::<FuseOpenOut>(),
+                0,
+                openout.as_bytes(),
+            )
+            .unwrap();
+        let fh = decode_reply::<FuseOpenOut>(&dataout).unwrap().fh;
+
+        let mut dirents = Vec::new();
+        for (off, name) in [(1u64, &b"."[..]), (2, b".."), (3, b"testf01")] {
+            let dirent = FuseDirent {
+                ino: 10 + off,
+                off: off,
+                namelen: name.len() as u32,
+                ..Default::default()
+            };
+            dirents.extend_from_slice(dirent.as_bytes());
+            dirents.extend_from_slice(&fuse_pad_str(name, false));
+        }
+        let readin = FuseReadIn {
+            fh: fh,
+            size: 256,
+            ..Default::default()
+        };
+        let (headerout, dataout) = server
+            .exchange(
+                FuseOpcode::FuseReaddir,
+                nodeid,
+                &[readin.as_bytes()],
+                256,
+                0,
+                &dirents,
+            )
+            .unwrap();
+        let readdir_out =
+            FuseReaddirOut::read_dirent(&mut VmReader::from(dataout.as_slice()), headerout)
+                .unwrap();
+        let names: Vec<_> = readdir_out
+            .dirents
+            .iter()
+            .map(|d| d.name.as_slice())
+            .collect();
+        assert_eq!(names, [&b"."[..], &b".."[..], &b"testf01"[..]]);
+
+        let releasein = FuseReleaseIn {
+            fh: fh,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseReleasedir,
+                nodeid,
+                &[releasein.as_bytes()],
+                0,
+                0,
+                &[],
+            )
+            .unwrap();
+        assert!(dataout.is_empty());
+    }
+
+    #[ktest]
+    fn create_write_flush_release_flow() {
+        let mut server = FakeServer::new();
+        let createin = FuseCreateIn {
+            flags: 2,
+            mode: 0o100644,
+            umask: 0o022,
+            open_flags: 0,
+        };
+        let name = fuse_pad_str(b"test_create", true);
+        let entry = FuseEntryOut {
+            nodeid: 4,
+            ..Default::default()
+        };
+        let openout = FuseOpenOut {
+            fh: 11,
+            ..Default::default()
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseCreate,
+                1,
+                &[createin.as_bytes(), &name],
+                size_of::<FuseEntryOut>() + size_of::<FuseOpenOut>(),
+                0,
+                &[entry.as_bytes(), openout.as_bytes()].concat(),
+            )
+            .unwrap();
+        let (entry, openout) = decode_create_reply(&dataout).unwrap();
+        assert_eq!((entry.nodeid, openout.fh), (4, 11));
+
+        let data = b"Test write file";
+        let writein = FuseWriteIn {
+            fh: openout.fh,
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        let writeout = FuseWriteOut {
+            size: data.len() as u32,
+            padding: 0,
+        };
+        let (_, dataout) = server
+            .exchange(
+                FuseOpcode::FuseWrite,
+                entry.nodeid,
+                &[writein.as_bytes(), data],
+                size_of::<FuseWriteOut>(),
+                0,
+                writeout.as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            decode_reply::<FuseWriteOut>(&dataout).unwrap().size as usize,
+            data.len()
+        );
+
+        let flushin = FuseFlushIn {
+            fh: openout.fh,
+            ..Default::default()
+        };
+        let flushed = server.exchange(
+            FuseOpcode::FuseFlush,
+            entry.nodeid,
+            &[flushin.as_bytes()],
+            0,
+            0,
+            &[],
+        );
+        assert!(flushed.is_ok());
+
+        let releasein = FuseReleaseIn {
+            fh: openout.fh,
+            ..Default::default()
+        };
+        let released = server.exchange(
+            FuseOpcode::FuseRelease,
+            entry.nodeid,
+            &[releasein.as_bytes()],
+            0,
+            0,
+            &[],
+        );
+        assert!(released.is_ok());
+
+        // A failed request comes back as the errno of the server.
+        let missing = fuse_pad_str(b"testf02", true);
+        let result = server.exchange(
+            FuseOpcode::FuseLookup,
+            1,
+            &[&missing],
+            size_of::<FuseEntryOut>(),
+            ENOENT,
+            &[],
+        );
+        assert!(matches!(
+            result,
+            Err(FilesystemError::Fuse { errno: ENOENT })
+        ));
+    }
+
+    #[ktest]
+    fn device_without_version_1_is_rejected() {
+        let negotiate = |offered| driver_features(VirtioDeviceType::Filesystem, offered);
+        let offered = VIRTIO_F_VERSION_1 | FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION.bits();
+        let features = accepted_features(negotiate(offered)).unwrap();
+        assert_eq!(features, FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION);
+
+        let result = accepted_features(negotiate(
+            FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION.bits(),
+        ));
+        assert!(matches!(
+            result,
+            Err(VirtioDeviceError::MissingFeatures(VIRTIO_F_VERSION_1))
+        ));
+    }
+
+    fn fake_readdir_page(entries: &[(u64, &[u8])]) -> FuseReaddirOut {
+        let dirents = entries
+            .iter()
+            .map(|(off, name)| FuseDirentWithName {
+                dirent: FuseDirent {
+                    ino: 2,
+                    off: *off,
+                    namelen: name.len() as u32,
+                    ..Default::default()
+                },
+                name: name.to_vec(),
+            })
+            .collect();
+        FuseReaddirOut { dirents: dirents }
+    }
+
+    #[ktest]
+    fn large_readdir_is_reassembled() {
+        let names: [&[u8]; 10] = [b"a", b"b", b"c", b"d", b"e", b"f", b"g", b"h", b"i", b"j"];
+        let entries: Vec<(u64, &[u8])> = (1..).zip(names).collect();
+        // Each entry takes 32 bytes.
+        let mut requested = Vec::new();
+        let mut fetch = |offset: u64, size: u32| -> Result<FuseReaddirOut, FilesystemError> {
+            requested.push((offset, size));
+            let start = offset as usize;
+            let end = (start + size as usize / 32).min(entries.len());
+            Ok(fake_readdir_page(&entries[start..end]))
+        };
+
+        let readdir_out = readdir_chunked(0, 256, 96, &mut fetch).unwrap();
+        assert_eq!(readdir_out.dirents.len(), 8);
+        assert_eq!(readdir_out.dirents[7].name, b"h");
+
+        // The read stops at the end of the directory.
+        let readdir_out = readdir_chunked(0, 1024, 96, &mut fetch).unwrap();
+        assert_eq!(readdir_out.dirents.len(), 10);
+        assert_eq!(
+            requested,
+            [
+                (0, 96),
+                (3, 96),
+                (6, 64),
+                (0, 96),
+                (3, 96),
+                (6, 96),
+                (9, 96),
+                (10, 96)
+            ]
+        );
+    }
+
+    #[ktest]
+    fn readdir_into_leaves_dirents_in_pages() {
+        let out = {
+            let vm_segment = FrameAllocOptions::new().alloc_segment(1).unwrap();
+            let stream =
+                DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap();
+            [DmaStreamSlice::new(stream, 0, PAGE_SIZE)]
+        };
+
+        // The device writes the dirents to the page, and the header to the request buffer.
+        let entries: [(u64, &[u8]); 2] = [(1, b"a"), (2, b"longer_name")];
+        let mut dirents = Vec::new();
+        for (off, name) in entries {
+            let dirent = FuseDirent {
+                ino: 10 + off,
+                off: off,
+                namelen: name.len() as u32,
+                ..Default::default()
+            };
+            dirents.extend_from_slice(dirent.as_bytes());
+            dirents.extend_from_slice(&fuse_pad_str(name, false));
+        }
+        out[0]
+            .writer()
+            .unwrap()
+            .write(&mut VmReader::from(dirents.as_slice()));
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + dirents.len()) as u32,
+            error: 0,
+            unique: 1,
+        };
+
+        let len = finish_reply_into(headerout.as_bytes(), &out).unwrap();
+        assert_eq!(len, dirents.len());
+        let mut reader = out[0].reader().unwrap().limit(len);
+        let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout).unwrap();
+        let names: Vec<_> = readdir_out
+            .dirents
+            .iter()
+            .map(|d| d.name.as_slice())
+            .collect();
+        assert_eq!(names, [&b"a"[..], &b"longer_name"[..]]);
+
+        // A reply claiming more than the pages hold is rejected.
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + PAGE_SIZE + 8) as u32,
+            ..headerout
+        };
+        assert!(matches!(
+            finish_reply_into(headerout.as_bytes(), &out),
+            Err(FilesystemError::BufferTooShort)
+        ));
+    }
+
+    #[ktest]
+    fn readdir_all_resumes_from_last_off() {
+        // The offsets are opaque, neither contiguous nor monotonic.
+        let entries: [(u64, &[u8]); 5] = [
+            (0x9a3f, b"a"),
+            (0x11, b"bb"),
+            (0xdead_0000, b"c"),
+            (0x7, b"dddd"),
+            (0xffff_fff0, b"e"),
+        ];
+        let mut requested = Vec::new();
+        let dirents = collect_dirents(|offset| {
+            requested.push(offset);
+            let start = if offset == 0 {
+                0
+            } else {
+                entries
+                    .iter()
+                    .position(|(off, _)| *off == offset)
+                    .ok_or(FilesystemError::Fuse { errno: EINVAL })?
+                    + 1
+            };
+            let end = (start + 2).min(entries.len());
+            Ok(fake_readdir_page(&entries[start..end]))
+        })
+        .unwrap();
+
+        assert_eq!(requested, vec![0, 0x11, 0x7, 0xffff_fff0]);
+        let names: Vec<&[u8]> = dirents.iter().map(|d| d.name.as_slice()).collect();
+        assert_eq!(
+            names,
+            entries.iter().map(|(_, name)| *name).collect::<Vec<_>>()
+        );
+    }
+
+    #[ktest]
+    fn readdir_all_with_offsets_near_u64_max() {
+        let entries: [(u64, &[u8]); 3] =
+            [(u64::MAX - 2, b"a"), (u64::MAX - 1, b"b"), (u64::MAX, b"c")];
+        let mut requested = Vec::new();
+        let dirents = collect_dirents(|offset| {
+            requested.push(offset);
+            let page: &[(u64, &[u8])] = match offset {
+                0 => &entries[..2],
+                _ if offset == u64::MAX - 1 => &entries[2..],
+                _ => &[],
+            };
+            Ok(fake_readdir_page(page))
+        })
+        .unwrap();
+
+        // The read goes on from `u64::MAX - 1`, and ends after `u64::MAX`.
+        assert_eq!(requested, vec![0, u64::MAX - 1, u64::MAX]);
+        assert_eq!(dirents.len(), 3);
+        assert_eq!(dirents[2].dirent.off, u64::MAX);
+    }
+
+    #[ktest]
+    fn readdir_all_of_empty_directory() {
+        let entries: [(u64, &[u8]); 2] = [(1, b"."), (2, b"..")];
+        let mut requests = 0;
+        let dirents = collect_dirents(|offset| {
+            requests += 1;
+            match offset {
+                0 => Ok(fake_readdir_page(&entries)),
+                _ => Ok(fake_readdir_page(&[])),
+            }
+        })
+        .unwrap();
+        assert_eq!(requests, 2);
+        let names: Vec<&[u8]> = dirents.iter().map(|d| d.name.as_slice()).collect();
+        assert_eq!(names, vec![&b"."[..], &b".."[..]]);
+
+        // Not even "." and "..", e.g. when reading from a stale handle.
+        let dirents = collect_dirents(|_| Ok(fake_readdir_page(&[]))).unwrap();
+        assert!(dirents.is_empty());
+    }
+
+    #[ktest]
+    fn readdir_all_stops_on_stale_offset() {
+        let entries: [(u64, &[u8]); 2] = [(0x40, b"a"), (0x80, b"b")];
+        let dirents = collect_dirents(|offset| match offset {
+            0 => Ok(fake_readdir_page(&entries)),
+            _ => Err(FilesystemError::Fuse { errno: EINVAL }),
+        })
+        .unwrap();
+        assert_eq!(dirents.len(), 2);
+
+        // An EINVAL for the very first page is a real error.
+        let result = collect_dirents(|_| Err(FilesystemError::Fuse { errno: EINVAL }));
+        assert!(matches!(
+            result,
+            Err(FilesystemError::Fuse { errno: EINVAL })
+        ));
+    }
+
+    #[ktest]
+    fn readdir_all_stops_on_cycling_offsets() {
+        let entries: [(u64, &[u8]); 2] = [(0x40, b"a"), (0x80, b"b")];
+        let mut requests = 0;
+        let dirents = collect_dirents(|offset| {
+            requests += 1;
+            // The server goes back to the first page after the second one.
+            match offset {
+                0x40 => Ok(fake_readdir_page(&entries[1..])),
+                _ => Ok(fake_readdir_page(&entries[..1])),
+            }
+        })
+        .unwrap();
+        assert_eq!(requests, 3);
+        assert_eq!(dirents.len(), 2);
+    }
+
+    #[ktest]
+    fn readdir_buffer_grows_for_long_names() {
+        let mut sizes = Vec::new();
+        let page = fetch_growing(READDIR_BUFFER_SIZE, 3 * READDIR_BUFFER_SIZE, |size| {
+            sizes.push(size);
+            if size < 2 * READDIR_BUFFER_SIZE {
+                return Err(FilesystemError::BufferTooShort);
+            }
+            Ok(fake_readdir_page(&[(1, b"long")]))
+        })
+        .unwrap();
+        assert_eq!(page.dirents.len(), 1);
+        assert_eq!(sizes, [READDIR_BUFFER_SIZE, 2 * READDIR_BUFFER_SIZE]);
+
+        // The buffer does not grow past the largest request.
+        sizes.clear();
+        let result = fetch_growing(READDIR_BUFFER_SIZE, 3 * READDIR_BUFFER_SIZE, |size| {
+            sizes.push(size);
+            Err(FilesystemError::BufferTooShort)
+        });
+        assert!(matches!(result, Err(FilesystemError::BufferTooShort)));
+        assert_eq!(
+            sizes,
+            [
+                READDIR_BUFFER_SIZE,
+                2 * READDIR_BUFFER_SIZE,
+                3 * READDIR_BUFFER_SIZE
+            ]
+        );
+    }
+
+    #[ktest]
+    fn batch_forget_encodes_count_and_len() {
+        let request = encode_batch_forget(&[(2, 1), (3, 5), (7, 2)]);
+        assert_eq!(request.len(), 40 + 8 + 3 * 16);
+
+        let mut reader = VmReader::from(request.as_slice());
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        assert_eq!(headerin.len as usize, request.len());
+        assert_eq!(headerin.opcode, FuseOpcode::FuseBatchForget as u32);
+        let batch_forget_in = reader.read_val::<FuseBatchForgetIn>().unwrap();
+        assert_eq!(batch_forget_in.count, 3);
+        let last = reader.skip(2 * 16).read_val::<FuseForgetOne>().unwrap();
+        assert_eq!((last.nodeid, last.nlookup), (7, 2));
+    }
+
+    #[ktest]
+    fn lseek_whence_is_checked() {
+        for whence in [SEEK_SET, SEEK_CUR, SEEK_END, SEEK_DATA, SEEK_HOLE] {
+            assert!(check_whence(whence).is_ok());
+        }
+        assert!(matches!(
+            check_whence(5),
+            Err(FilesystemError::InvalidOperation)
+        ));
+
+        // A `SEEK_DATA` past the end of the file is an error of the server.
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -ENXIO,
+            unique: 1,
+        };
+        assert!(matches!(
+            check_error(&headerout),
+            Err(FilesystemError::Fuse { errno: ENXIO })
+        ));
+    }
+
+    #[ktest]
+    fn access_denied_by_eacces() {
+        assert!(matches!(access_granted(Ok(())), Ok(true)));
+        assert!(matches!(
+            access_granted(Err(FilesystemError::Fuse { errno: EACCES })),
+            Ok(false)
+        ));
+        assert!(matches!(
+            access_granted(Err(FilesystemError::Fuse { errno: EIO })),
+            Err(FilesystemError::Fuse { errno: EIO })
+        ));
+    }
+
+    #[ktest]
+    fn fdatasync_flag_is_set_for_datasync_only() {
+        let fsyncin = fuse_fsync_in(3, true);
+        assert_eq!(fsyncin.fh, 3);
+        assert_eq!(fsyncin.fsync_flags, FUSE_FSYNC_FDATASYNC);
+
+        let fsyncin = fuse_fsync_in(3, false);
+        assert_eq!(fsyncin.fsync_flags, 0);
+    }
+
+    #[ktest]
+    fn failed_lookup_has_no_entry() {
+        // A `FUSE_LOOKUP` of a missing name is answered with the header alone.
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -2,
+            unique: 1,
+        };
+        assert!(matches!(
+            check_error(&headerout),
+            Err(FilesystemError::Fuse { errno: 2 })
+        ));
+        let result = parse_reply(headerout.as_bytes())
+            .and_then(|(_, dataout)| decode_reply::<FuseEntryOut>(&dataout));
+        assert!(matches!(result, Err(FilesystemError::Fuse { errno: 2 })));
+
+        let headerout = FuseOutHeader {
+            error: 0,
+            ..headerout
+        };
+        assert!(check_error(&headerout).is_ok());
+    }
+
+    #[ktest]
+    fn create_reply_carries_open_handle() {
+        let entry = FuseEntryOut {
+            nodeid: 5,
+            generation: 1,
+            ..Default::default()
+        };
+        let openout = FuseOpenOut {
+            fh: 9,
+            open_flags: FOPEN_KEEP_CACHE,
+            backing_id: 0,
+        };
+        let dataout = [entry.as_bytes(), openout.as_bytes()].concat();
+        let (entry, openout) = decode_create_reply(&dataout).unwrap();
+        assert_eq!(entry.nodeid, 5);
+        assert_eq!(openout.fh, 9);
+        assert_eq!(openout.open_flags, FOPEN_KEEP_CACHE);
+
+        // A reply without the `FuseOpenOut` is malformed.
+        let result = decode_create_reply(&dataout[..size_of::<FuseEntryOut>()]);
+        assert!(matches!(result, Err(FilesystemError::BufferTooShort)));
+    }
+
+    #[ktest]
+    fn bmap_reply_is_decoded() {
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + size_of::<FuseBmapOut>()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let bmapout = FuseBmapOut { block: 0x1234 };
+        let reply = [headerout.as_bytes(), bmapout.as_bytes()].concat();
+        let (_, dataout) = parse_reply(&reply).unwrap();
+        assert_eq!(decode_reply::<FuseBmapOut>(&dataout).unwrap().block, 0x1234);
+
+        // The reserved reply body is not decoded when the server lacks bmap.
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -ENOSYS,
+            unique: 1,
+        };
+        let reply = [headerout.as_bytes(), &[0xff; size_of::<FuseBmapOut>()][..]].concat();
+        assert!(matches!(
+            parse_reply(&reply),
+            Err(FilesystemError::Fuse { errno: ENOSYS })
+        ));
+    }
+
+    #[ktest]
+    fn statfs_reply_is_decoded() {
+        let st = FuseKstatfs {
+            blocks: 1000,
+            bfree: 400,
+            bavail: 300,
+            files: 100,
+            ffree: 50,
+            bsize: 4096,
+            namelen: 255,
+            frsize: 1024,
+            spare: [7; 6],
+            ..Default::default()
+        };
+        let reply = FuseStatfsOut { st: st };
+        let decoded = decode_statfs(reply.as_bytes()).unwrap().st;
+        assert_eq!(decoded.frsize, 1024);
+        assert_eq!(decoded.spare, [7; 6]);
+        assert_eq!(decoded.namelen, 255);
+
+        // An old server stops after `namelen`.
+        let bytes = &reply.as_bytes()[..FUSE_COMPAT_STATFS_SIZE as usize];
+        let decoded = decode_statfs(bytes).unwrap().st;
+        assert_eq!(decoded.blocks, 1000);
+        assert_eq!(decoded.namelen, 255);
+        assert_eq!(decoded.frsize, 4096);
+        assert_eq!(decoded.spare, [0; 6]);
+
+        assert!(matches!(
+            decode_statfs(&bytes[..40]),
+            Err(FilesystemError::BufferTooShort)
+        ));
+    }
+
+    #[ktest]
+    fn init_request_carries_flags2() {
+        let flags = FUSE_ASYNC_READ | FUSE_INIT_EXT | FUSE_SECURITY_CTX;
+        let (headerin, initin) = build_init_request(flags, 0x2_0000);
+        assert_eq!(headerin.opcode, FuseOpcode::FuseInit as u32);
+        assert_eq!(
+            headerin.len as usize,
+            size_of::<FuseInHeader>() + size_of::<FuseInitIn>()
+        );
+        assert_eq!(initin.major, FUSE_KERNEL_VERSION);
+        assert_eq!(initin.max_readahead, 0x2_0000);
+        assert_eq!(initin.flags as u64 | (initin.flags2 as u64) << 32, flags);
+    }
+
+    #[ktest]
+    fn init_request_asks_for_large_transfers() {
+        let (_, initin) = build_init_request(init_flags().bits(), FUSE_MAX_READAHEAD);
+        let flags = initin.flags as u64 | (initin.flags2 as u64) << 32;
+        for flag in [FUSE_BIG_WRITES, FUSE_MAX_PAGES, FUSE_INIT_EXT] {
+            assert_ne!(flags & flag, 0);
+        }
+        assert_eq!(initin.max_readahead, FUSE_MAX_READAHEAD);
+    }
+
+    #[ktest]
+    fn transfers_are_capped_at_max_pages() {
+        let mut init_out = FuseInitOut {
+            flags: FUSE_MAX_PAGES as u32,
+            max_pages: 64,
+            ..Default::default()
+        };
+        let negotiated = NegotiatedInit::new(&init_out);
+        assert_eq!(negotiated.max_transfer_size(), (64 * PAGE_SIZE) as u32);
+
+        // `max_pages` is ignored unless `FUSE_MAX_PAGES` is agreed on.
+        init_out.flags = 0;
+        let negotiated = NegotiatedInit::new(&init_out);
+        assert_eq!(negotiated.max_pages, 0);
+        assert_eq!(
+            negotiated.max_transfer_size(),
+            (FUSE_DEFAULT_MAX_PAGES * PAGE_SIZE) as u32
+        );
+    }
+
+    fn init_reply_header(body_len: usize, error: i32) -> FuseOutHeader {
+        FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + body_len) as u32,
+            error: error,
+            unique: 0,
+        }
+    }
+
+    #[ktest]
+    fn init_reply_is_parsed() {
+        let reply = FuseInitOut {
+            major: FUSE_KERNEL_VERSION,
+            minor: 40,
+            flags: FUSE_MAX_PAGES as u32,
+            max_write: 1024 * 1024,
+            max_pages: 256,
+            flags2: (FUSE_SECURITY_CTX >> 32) as u32,
+            ..Default::default()
+        };
+        let bytes = reply.as_bytes();
+        let init_out = parse_init_reply(&init_reply_header(bytes.len(), 0), bytes).unwrap();
+        assert_eq!(init_out.minor, 40);
+        assert_eq!(init_out.max_pages, 256);
+        assert_eq!(init_out.flags2, reply.flags2);
+
+        let result = parse_init_reply(&init_reply_header(0, -EPROTO), &[]);
+        assert!(matches!(
+            result,
+            Err(FilesystemError::Fuse { errno: EPROTO })
+        ));
+        let mut other_major = reply;
+        other_major.major = 8;
+        let result = parse_init_reply(&init_reply_header(bytes.len(), 0), other_major.as_bytes());
+        assert!(matches!(
+            result,
+            Err(FilesystemError::Fuse { errno: EPROTO })
+        ));
+    }
+
+    #[ktest]
+    fn compat_init_reply_is_parsed() {
+        let reply = FuseInitOut {
+            major: FUSE_KERNEL_VERSION,
+            minor: 22,
+            max_readahead: 0x2_0000,
+            max_write: 128 * 1024,
+            ..Default::default()
+        };
+        // A 7.22 server stops after `max_write`.
+        let bytes = &reply.as_bytes()[..FUSE_COMPAT_22_INIT_OUT_SIZE as usize];
+        let init_out = parse_init_reply(&init_reply_header(bytes.len(), 0), bytes).unwrap();
+        assert_eq!(init_out.minor, 22);
+        assert_eq!(init_out.max_write, 128 * 1024);
+        assert_eq!(init_out.max_pages, 0);
+        assert_eq!(init_out.flags2, 0);
+
+        // A server older than 7.5 only sends its version.
+        let bytes = &reply.as_bytes()[..FUSE_COMPAT_INIT_OUT_SIZE as usize];
+        let init_out = parse_init_reply(&init_reply_header(bytes.len(), 0), bytes).unwrap();
+        assert_eq!(init_out.max_write, 0);
+
+        let result = parse_init_reply(&init_reply_header(4, 0), &bytes[..4]);
+        assert!(matches!(result, Err(FilesystemError::BufferTooShort)));
+    }
+
+    #[ktest]
+    fn request_buffer_sized_from_init_reply() {
+        let mut init_out = FuseInitOut {
+            max_write: 128 * 1024,
+            ..Default::default()
+        };
+        assert_eq!(
+            request_buffer_size(&NegotiatedInit::new(&init_out)),
+            128 * 1024 + PAGE_SIZE
+        );
+
+        init_out.flags = FUSE_MAX_PAGES as u32;
+        init_out.max_pages = 256;
+        init_out.max_write = 1000 * 1000;
+        assert_eq!(
+            request_buffer_size(&NegotiatedInit::new(&init_out)),
+            1024 * 1024 + PAGE_SIZE
+        );
+
+        // A huge `max_write` is capped.
+        init_out.max_write = 16 * 1024 * 1024;
+        assert_eq!(
+            request_buffer_size(&NegotiatedInit::new(&init_out)),
+            FUSE_MAX_MAX_PAGES * PAGE_SIZE + PAGE_SIZE
+        );
+    }
+
+    #[ktest]
+    fn rename_falls_back_without_rename2() {
+        // Since 7.23, every rename goes through FUSE_RENAME2.
+        assert!(matches!(rename_opcode(0, 23), Ok(FuseOpcode::FuseRename2)));
+        assert!(matches!(
+            rename_opcode(RENAME_EXCHANGE, 40),
+            Ok(FuseOpcode::FuseRename2)
+        ));
+
+        // Before, only a plain rename is possible.
+        assert!(matches!(rename_opcode(0, 22), Ok(FuseOpcode::FuseRename)));
+        for flags in [RENAME_NOREPLACE, RENAME_EXCHANGE] {
+            assert!(matches!(
+                rename_opcode(flags, 22),
+                Err(FilesystemError::Fuse { errno: EINVAL })
+            ));
+        }
+    }
+
+    #[ktest]
+    fn contradicting_rename_flags_are_rejected() {
+        assert!(matches!(
+            rename_opcode(RENAME_NOREPLACE | RENAME_WHITEOUT, 40),
+            Ok(FuseOpcode::FuseRename2)
+        ));
+        for flags in [
+            RENAME_NOREPLACE | RENAME_EXCHANGE,
+            RENAME_EXCHANGE | RENAME_WHITEOUT,
+            1 << 3,
+        ] {
+            assert!(matches!(
+                rename_opcode(flags, 40),
+                Err(FilesystemError::InvalidOperation)
+            ));
+        }
+    }
+
+    #[ktest]
+    fn opendir_is_skipped_without_server_support() {
+        let mut opcodes = Vec::new();
+        let opendir = |opcodes: &mut Vec<FuseOpcode>| -> Result<FuseOpenOut, FilesystemError> {
+            opcodes.push(FuseOpcode::FuseOpendir);
+            Ok(FuseOpenOut {
+                fh: 7,
+                ..Default::default()
+            })
+        };
+
+        let fh = opendir_fh(FUSE_INIT_EXT | FUSE_NO_OPENDIR_SUPPORT, || {
+            opendir(&mut opcodes)
+        })
+        .unwrap();
+        assert_eq!(fh, NO_OPENDIR_FH);
+        assert!(opcodes.is_empty());
+
+        let fh = opendir_fh(FUSE_INIT_EXT, || opendir(&mut opcodes)).unwrap();
+        assert_eq!(fh, 7);
+        assert_eq!(opcodes.len(), 1);
+    }
+
+    #[ktest]
+    fn o_trunc_is_left_to_server_once_atomic() {
+        let flags = O_TRUNC | 0o1;
+        assert_eq!(
+            open_trunc_flags(FUSE_INIT_EXT | FUSE_ATOMIC_O_TRUNC, flags),
+            (flags, false)
+        );
+        assert_eq!(open_trunc_flags(FUSE_INIT_EXT, flags), (0o1, true));
+        assert_eq!(open_trunc_flags(FUSE_INIT_EXT, 0o1), (0o1, false));
+    }
+
+    #[ktest]
+    fn unprivileged_writes_kill_suidgid() {
+        let negotiated_flags = FUSE_INIT_EXT | FUSE_HANDLE_KILLPRIV_V2;
+        let write_flags = kill_suidgid_write_flags(negotiated_flags, false);
+        let writein = FuseWriteIn {
+            fh: 1,
+            size: 4096,
+            write_flags: write_flags,
+            ..Default::default()
+        };
+        assert_ne!(writein.write_flags & FUSE_WRITE_KILL_SUIDGID, 0);
+        assert_eq!(kill_suidgid_write_flags(negotiated_flags, true), 0);
+        // Without `FUSE_HANDLE_KILLPRIV_V2`, the server clears the bits on its own.
+        assert_eq!(kill_suidgid_write_flags(FUSE_INIT_EXT, false), 0);
+
+        let flags = O_TRUNC | 0o1;
+        assert_eq!(
+            kill_suidgid_open_flags(negotiated_flags, flags, false),
+            FUSE_OPEN_KILL_SUIDGID
+        );
+        assert_eq!(kill_suidgid_open_flags(negotiated_flags, flags, true), 0);
+        assert_eq!(kill_suidgid_open_flags(negotiated_flags, 0o1, false), 0);
+    }
+
+    #[ktest]
+    fn cache_writes_are_flagged_under_writeback_cache() {
+        assert_eq!(cache_write_flags(FUSE_INIT_EXT), 0);
+        let write_flags = cache_write_flags(FUSE_INIT_EXT | FUSE_WRITEBACK_CACHE);
+        assert_eq!(write_flags, FUSE_WRITE_CACHE);
+        assert_eq!(write_flags & FUSE_WRITE_LOCKOWNER, 0);
+    }
+
+    #[ktest]
+    fn fallocate_modes_are_checked() {
+        for mode in [
+            0,
+            FALLOC_FL_KEEP_SIZE,
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+            FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE,
+            FALLOC_FL_COLLAPSE_RANGE,
+            FALLOC_FL_UNSHARE_RANGE | FALLOC_FL_KEEP_SIZE,
+        ] {
+            assert!(check_fallocate_mode(mode).is_ok());
+        }
+        for mode in [
+            FALLOC_FL_PUNCH_HOLE,
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE | FALLOC_FL_ZERO_RANGE,
+            FALLOC_FL_INSERT_RANGE | FALLOC_FL_KEEP_SIZE,
+            FALLOC_FL_UNSHARE_RANGE | FALLOC_FL_ZERO_RANGE,
+            1 << 2,
+        ] {
+            assert!(matches!(
+                check_fallocate_mode(mode),
+                Err(FilesystemError::InvalidOperation)
+            ));
+        }
+    }
+
+    #[ktest]
+    fn ioctl_retry_is_decoded() {
+        let ioctlout = FuseIoctlOut {
+            result: 3,
+            ..Default::default()
+        };
+        let mut dataout = ioctlout.as_bytes().to_vec();
+        dataout.extend_from_slice(b"out");
+        match decode_ioctl_reply(0, &dataout).unwrap() {
+            IoctlStep::Done { result, out_data } => {
+                assert_eq!(result, 3);
+                assert_eq!(out_data, b"out");
+            }
+            step => panic!("unexpected step {:?}", step),
+        }
+
+        let ioctlout = FuseIoctlOut {
+            flags: FUSE_IOCTL_RETRY,
+            in_iovs: 1,
+            out_iovs: 2,
+            ..Default::default()
+        };
+        let mut dataout = ioctlout.as_bytes().to_vec();
+        for (base, len) in [(0x1000, 8), (0x2000, 16), (0x3000, 4)] {
+            dataout.extend_from_slice(
+                FuseIoctlIovec {
+                    base: base,
+                    len: len,
+                }
+                .as_bytes(),
+            );
+        }
+        match decode_ioctl_reply(FUSE_IOCTL_UNRESTRICTED, &dataout).unwrap() {
+            IoctlStep::Retry { in_iovs, out_iovs } => {
+                assert_eq!(in_iovs.len(), 1);
+                assert_eq!(in_iovs[0].base, 0x1000);
+                assert_eq!(iovs_len(&out_iovs).unwrap(), 20);
             }
-            FuseOpcode::FuseBatchForget => {
-                let _datain = reader.read_val::<FuseBatchForgetIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                early_print!(
-                    "BatchForget response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_println!();
+            step => panic!("unexpected step {:?}", step),
+        }
+
+        // A retry is only allowed for an unrestricted ioctl.
+        assert!(matches!(
+            decode_ioctl_reply(0, &dataout),
+            Err(FilesystemError::UnexpectedDataInPacket)
+        ));
+        // The iovecs must all be there, and be few enough.
+        assert!(matches!(
+            decode_ioctl_reply(FUSE_IOCTL_UNRESTRICTED, &dataout[..dataout.len() - 1]),
+            Err(FilesystemError::BufferTooShort)
+        ));
+        let too_many = FuseIoctlOut {
+            out_iovs: FUSE_IOCTL_MAX_IOV,
+            ..ioctlout
+        };
+        assert!(matches!(
+            decode_ioctl_reply(FUSE_IOCTL_UNRESTRICTED, too_many.as_bytes()),
+            Err(FilesystemError::UnexpectedDataInPacket)
+        ));
+    }
+
+    #[ktest]
+    fn path_is_split_into_components() {
+        let components = path_components(b"a//./b/../c/").unwrap();
+        assert_eq!(components, [&b"a"[..], b"b", b"..", b"c"]);
+        assert_eq!(path_components(b"..").unwrap(), [&b".."[..]]);
+
+        for path in [&b"/a"[..], b"", b".", b"./"] {
+            assert!(matches!(
+                path_components(path),
+                Err(FilesystemError::InvalidOperation)
+            ));
+        }
+    }
+
+    #[ktest]
+    fn open_without_round_trip() {
+        let mut opens = 0;
+        let mut open = |error: i32| -> Result<FuseOpenOut, FilesystemError> {
+            opens += 1;
+            match error {
+                0 => Ok(FuseOpenOut {
+                    fh: 7,
+                    ..Default::default()
+                }),
+                error => Err(FilesystemError::Fuse { errno: error }),
             }
-            FuseOpcode::FuseLink => {
-                let _datain = reader.read_val::<FuseLinkIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
-                early_print!(
-                    "Link response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
+        };
+
+        let no_open = AtomicBool::new(false);
+        let openout = zero_message_open(&no_open, || open(0)).unwrap();
+        assert_eq!(openout.fh, 7);
+        assert!(matches!(
+            zero_message_open(&no_open, || open(EACCES)),
+            Err(FilesystemError::Fuse { errno: EACCES })
+        ));
+        assert!(!no_open.load(Ordering::Relaxed));
+
+        // `ENOSYS` opens the file all the same, and the later opens skip the server.
+        let openout = zero_message_open(&no_open, || open(ENOSYS)).unwrap();
+        assert_eq!(openout.fh, NO_OPEN_FH);
+        assert_eq!(openout.open_flags, FOPEN_KEEP_CACHE);
+        let openout = zero_message_open(&no_open, || open(0)).unwrap();
+        assert_eq!(openout.fh, NO_OPEN_FH);
+        assert_eq!(opens, 3);
+    }
+
+    #[ktest]
+    fn unlink_reply_is_header_only() {
+        // Whatever follows the header in the buffer is not part of the reply.
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: 0,
+            unique: 1,
+        };
+        let mut reply = headerout.as_bytes().to_vec();
+        reply.extend_from_slice(&[0xaa; size_of::<FuseEntryOut>()]);
+        let (_, dataout) = parse_reply(&reply).unwrap();
+        assert!(dataout.is_empty());
+
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -39, // ENOTEMPTY
+            unique: 2,
+        };
+        let result = parse_reply(headerout.as_bytes());
+        assert!(matches!(result, Err(FilesystemError::Fuse { errno: 39 })));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Invalidation {
+        Inode(u64, i64, i64),
+        Entry(u64, Vec<u8>),
+        Delete(u64, u64, Vec<u8>),
+        Poll(u64),
+    }
+
+    struct MockCallback {
+        received: SpinLock<Vec<Invalidation>>,
+    }
+
+    impl FilesystemCallback for MockCallback {
+        fn invalidate_inode(&self, ino: u64, off: i64, len: i64) {
+            self.received
+                .lock()
+                .push(Invalidation::Inode(ino, off, len));
+        }
+
+        fn invalidate_entry(&self, parent: u64, name: &[u8]) {
+            self.received
+                .lock()
+                .push(Invalidation::Entry(parent, name.to_vec()));
+        }
+
+        fn delete(&self, parent: u64, child: u64, name: &[u8]) {
+            self.received
+                .lock()
+                .push(Invalidation::Delete(parent, child, name.to_vec()));
+        }
+
+        fn poll_wakeup(&self, kh: u64) {
+            self.received.lock().push(Invalidation::Poll(kh));
+        }
+    }
+
+    #[ktest]
+    fn callbacks_receive_notifications() {
+        let mock = Arc::new(MockCallback {
+            received: SpinLock::new(Vec::new()),
+        });
+        let callbacks: [&dyn FilesystemCallback; 1] = [mock.as_ref()];
+        let notify = |code: FuseNotifyCode, body: &[&[u8]]| {
+            let body = body.concat();
+            notify_callbacks(&callbacks, code, &mut VmReader::from(body.as_slice()))
+        };
+
+        let inode = FuseNotifyInvalInodeOut {
+            ino: 2,
+            off: -1,
+            len: 0,
+        };
+        notify(FuseNotifyCode::FuseNotifyInvalInode, &[inode.as_bytes()]).unwrap();
+        let entry = FuseNotifyInvalEntryOut {
+            parent: 1,
+            namelen: 3,
+            flags: 0,
+        };
+        notify(
+            FuseNotifyCode::FuseNotifyInvalEntry,
+            &[entry.as_bytes(), b"foo\0"],
+        )
+        .unwrap();
+        let delete = FuseNotifyDeleteOut {
+            parent: 1,
+            child: 3,
+            namelen: 3,
+            padding: 0,
+        };
+        notify(
+            FuseNotifyCode::FuseNotifyDelete,
+            &[delete.as_bytes(), b"bar\0"],
+        )
+        .unwrap();
+        let poll = FuseNotifyPollWakeupOut { kh: 0x42 };
+        notify(FuseNotifyCode::FuseNotifyPoll, &[poll.as_bytes()]).unwrap();
+        // A name cut short is not handed out.
+        assert!(notify(
+            FuseNotifyCode::FuseNotifyInvalEntry,
+            &[entry.as_bytes(), b"fo"]
+        )
+        .is_err());
+
+        assert_eq!(
+            *mock.received.lock(),
+            [
+                Invalidation::Inode(2, -1, 0),
+                Invalidation::Entry(1, b"foo".to_vec()),
+                Invalidation::Delete(1, 3, b"bar".to_vec()),
+                Invalidation::Poll(0x42),
+            ]
+        );
+    }
+
+    #[ktest]
+    fn poll_notification_wakes_its_waiter() {
+        let poll_waiters = Arc::new(PollWaiters::new());
+        let callbacks: [&dyn FilesystemCallback; 1] = [poll_waiters.as_ref()];
+        let poll = FuseNotifyPollWakeupOut { kh: 7 };
+        let notify = || {
+            notify_callbacks(
+                &callbacks,
+                FuseNotifyCode::FuseNotifyPoll,
+                &mut VmReader::from(poll.as_bytes()),
+            )
+            .unwrap()
+        };
+
+        // A notification arriving before the wait is not missed.
+        poll_waiters.schedule(7);
+        notify();
+        poll_waiters.wait(7);
+        assert!(poll_waiters.woken.lock().is_empty());
+
+        // Scheduling the poll again drops the notifications of the last one.
+        notify();
+        poll_waiters.schedule(7);
+        assert!(!poll_waiters.woken.lock().contains(&7));
+    }
+
+    #[ktest]
+    fn notify_reply_echoes_notify_unique() {
+        let request = encode_notify_reply(0xabcd, 5, 4096, b"data");
+        let mut reader = VmReader::from(request.as_slice());
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        assert_eq!(headerin.opcode, FuseOpcode::FuseNotifyReply as u32);
+        assert_eq!(headerin.unique, 0xabcd);
+        assert_eq!(headerin.nodeid, 5);
+        assert_eq!(headerin.len as usize, request.len());
+
+        let retrievein = reader.read_val::<FuseNotifyRetrieveIn>().unwrap();
+        assert_eq!(retrievein.offset, 4096);
+        assert_eq!(retrievein.size, 4);
+        assert_eq!(&request[request.len() - 4..], b"data");
+    }
+
+    #[ktest]
+    fn lk_in_sets_flock_flag() {
+        let lk = FuseFileLock {
+            start: 0,
+            end: u64::MAX,
+            type_: 1,
+            pid: 42,
+        };
+
+        let posix = fuse_lk_in(3, 7, lk, false);
+        assert_eq!((posix.fh, posix.owner, posix.lk_flags), (3, 7, 0));
+        assert_eq!((posix.lk.end, posix.lk.pid), (u64::MAX, 42));
+
+        let flock = fuse_lk_in(3, 7, lk, true);
+        assert_eq!(flock.lk_flags, FUSE_LK_FLOCK);
+    }
+
+    #[ktest]
+    fn info_reflects_init_reply() {
+        let mut config = VirtioFilesystemConfig::new_zeroed();
+        config.tag[..6].copy_from_slice(b"myfs01");
+        config.num_request_queues = 1;
+        config.notify_buf_size = 4096;
+
+        let info =
+            FilesystemDeviceInfo::new(&config, FilesystemFeatures::empty(), None, 1, 3 * 4096, 16);
+        assert_eq!(info.tag, "myfs01");
+        assert_eq!((info.major, info.minor), (0, 0));
+
+        let init_out = FuseInitOut {
+            major: FUSE_KERNEL_VERSION,
+            minor: 38,
+            max_readahead: 128 * 1024,
+            flags: (FUSE_ASYNC_READ | FUSE_INIT_EXT) as u32,
+            max_write: 1024 * 1024,
+            flags2: 1,
+            ..Default::default()
+        };
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + size_of::<FuseInitOut>()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let init_out = parse_init_reply(&headerout, init_out.as_bytes()).unwrap();
+        let offered = VIRTIO_F_VERSION_1 | FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION.bits();
+        let features =
+            accepted_features(driver_features(VirtioDeviceType::Filesystem, offered)).unwrap();
+        let info = FilesystemDeviceInfo::new(
+            &config,
+            features,
+            Some(&NegotiatedInit::new(&init_out)),
+            1,
+            3 * 4096,
+            DEFAULT_CACHE_CAPACITY,
+        );
+        assert_eq!(info.tag, "myfs01");
+        assert_eq!(info.features, FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION);
+        assert_eq!(info.major, FUSE_KERNEL_VERSION);
+        assert_eq!(info.minor, 38);
+        assert_eq!(info.fuse_flags, FUSE_ASYNC_READ | FUSE_INIT_EXT | 1 << 32);
+        assert_eq!(info.max_write, 1024 * 1024);
+        assert_eq!(info.max_readahead, 128 * 1024);
+        assert_eq!(info.num_request_queues, 1);
+        assert_eq!(info.notify_buf_size, 4096);
+        assert_eq!(info.request_buffer_size, 3 * 4096);
+        assert_eq!(info.cache_capacity, DEFAULT_CACHE_CAPACITY);
+    }
+
+    #[ktest]
+    fn stat_after_readdirplus_needs_no_getattr() {
+        let names: [&[u8]; 4] = [b".", b"a", b"bb", b"ccc"];
+        let mut dataout = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let direntplus = FuseDirentplus {
+                entry_out: FuseEntryOut {
+                    nodeid: 2 + i as u64,
+                    entry_valid: 1,
+                    attr_valid: 1,
+                    attr: FuseAttr {
+                        ino: 100 + i as u64,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                dirent: FuseDirent {
+                    ino: 100 + i as u64,
+                    off: 1 + i as u64,
+                    namelen: name.len() as u32,
+                    ..Default::default()
+                },
+            };
+            dataout.extend_from_slice(direntplus.as_bytes());
+            dataout.extend_from_slice(name);
+            dataout.resize(fuse_rec_align(dataout.len()), 0);
+        }
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + dataout.len()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let readdirplus_out =
+            FuseReaddirplusOut::read_direntplus(&mut VmReader::from(dataout.as_slice()), headerout)
+                .unwrap();
+        assert_eq!(readdirplus_out.dirents.len(), 4);
+
+        let mut cache = NodeCache::new(NonZeroUsize::new(16).unwrap());
+        let now = Duration::ZERO;
+        let forgets = cache_direntplus(&mut cache, 1, &readdirplus_out, now);
+        assert!(forgets.is_empty());
+
+        // `ls -l`: stat each entry, going to the server only on a cache miss.
+        let mut getattr_calls = 0;
+        for dirent in readdirplus_out.dirents.iter().skip(1) {
+            let nodeid = dirent.direntplus.entry_out.nodeid;
+            match cache.attr(nodeid, now) {
+                Some(attr) => assert_eq!(attr.ino, dirent.direntplus.dirent.ino),
+                None => getattr_calls += 1,
             }
-            FuseOpcode::FuseUnlink => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
-                early_print!(
-                    "Unlink response received: len = {:?}, error = {:?}\n",
-                    headerout.len,
-                    headerout.error
-                );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
-                early_println!();
+            assert_eq!(cache.entry(1, &dirent.name, now).unwrap().nodeid, nodeid);
+        }
+        assert_eq!(getattr_calls, 0);
+        // `.` does not hold a lookup count, so it is not cached.
+        assert!(cache.entry(1, b".", now).is_none());
+    }
+
+    #[ktest]
+    fn large_read_is_split_into_chunks() {
+        const MAX_CHUNK: u32 = 4096;
+        let file: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let mut chunks = Vec::new();
+        // Serves a `FUSE_READ`, recording the chunk it asks for.
+        let read = |chunks: &mut Vec<(u64, u32)>, offset: u64, size: u32| {
+            chunks.push((offset, size));
+            let start = (offset as usize).min(file.len());
+            let end = (start + size as usize).min(file.len());
+            Ok(finish_read(file[start..end].to_vec(), size))
+        };
+
+        // The last chunk is partial, and the offset advances by what was read.
+        let (data, eof) = read_chunked(100, 9000, MAX_CHUNK, |offset, size| {
+            read(&mut chunks, offset, size)
+        })
+        .unwrap();
+        assert_eq!(data, file[100..9100]);
+        assert!(!eof);
+        assert_eq!(chunks, [(100, 4096), (4196, 4096), (8292, 808)]);
+
+        // A short chunk ends the read.
+        chunks.clear();
+        let (data, eof) = read_chunked(5000, 1 << 20, MAX_CHUNK, |offset, size| {
+            read(&mut chunks, offset, size)
+        })
+        .unwrap();
+        assert_eq!(data, file[5000..]);
+        assert!(eof);
+        assert_eq!(chunks.len(), 2);
+    }
+
+    #[ktest]
+    fn large_write_is_split_into_chunks() {
+        const MAX_CHUNK: u32 = 4096;
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let mut file = vec![0u8; 20000];
+        let mut chunks = Vec::new();
+
+        let written = write_chunked(100, &data, MAX_CHUNK, |offset, chunk| {
+            chunks.push((offset, chunk.len()));
+            file[offset as usize..offset as usize + chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len() as u32)
+        })
+        .unwrap();
+        assert_eq!(written, data.len());
+        assert_eq!(file[100..10100], data[..]);
+        assert_eq!(chunks, [(100, 4096), (4196, 4096), (8292, 1808)]);
+
+        // A short write ends the write.
+        chunks.clear();
+        let written = write_chunked(0, &data, MAX_CHUNK, |offset, chunk| {
+            chunks.push((offset, chunk.len()));
+            Ok(if offset == 0 {
+                chunk.len() as u32
+            } else {
+                1000
+            })
+        })
+        .unwrap();
+        assert_eq!(written, 4096 + 1000);
+        assert_eq!(chunks.len(), 2);
+    }
+
+    #[ktest]
+    fn write_size_is_taken_from_reply() {
+        let data = [7u8; 6000];
+
+        // A server claiming more than the chunk is taken to have written the chunk.
+        let written =
+            write_chunked(0, &data, 4096, |_, chunk| Ok(chunk.len() as u32 + 10)).unwrap();
+        assert_eq!(written, data.len());
+
+        // A write of nothing returns at once, with no further chunk sent.
+        let mut calls = 0;
+        let written = write_chunked(0, &data, 4096, |_, _| {
+            calls += 1;
+            Ok(0)
+        })
+        .unwrap();
+        assert_eq!((written, calls), (0, 1));
+
+        // A failed chunk fails the write, even after a chunk got written.
+        let result = write_chunked(0, &data, 4096, |offset, chunk| {
+            if offset == 0 {
+                Ok(chunk.len() as u32)
+            } else {
+                Err(FilesystemError::Fuse { errno: EIO })
             }
-            _ => {}
-        }
-        drop(request_queue);
-        test_device(&self);
-    }
-}
-
-static TEST_COUNTER: RwLock<u32> = RwLock::new(0);
-pub fn test_device(device: &FilesystemDevice) {
-    let mut test_counter = TEST_COUNTER.write();
-    *test_counter += 1;
-    drop(test_counter);
-    let test_counter = TEST_COUNTER.read();
-    match *test_counter {
-        // // test lookup
-        // 1 => device.lookup(1, Vec::from("testf01")),
-
-        // test opendir and readdir
-        // 1 => device.lookup(1, Vec::from("testdir")),
-        // 2 => device.opendir(2, 0),
-        // 3 => device.readdir(2, 0, 0, 256),
-
-        // // test open
-        // 1 => device.lookup(1, Vec::from("testf01")),
-        // 2 => device.open(2, 0),
-
-        // // test read
-        // 1 => device.lookup(1, Vec::from("testf01")),
-        // 2 => device.open(2, 0),
-        // 3 => device.read(2, 0, 0, 128),
-        // 4 => device.lookup(1, Vec::from("testf02")),
-        // 5 => device.open(3, 0),
-        // 6 => device.read(3, 1, 0, 128),
-
-        // test write
-        // 1 => device.lookup(1, Vec::from("testf_write")),
-        // 2 => device.open(2, 2),
-        // 3 => device.write(2, 0, 0, "Test write file".as_bytes()),
-
-        // test create
-        // 1 => device.lookup(1, "testdir".as_bytes().to_vec()),
-        // 2 => device.create(2, "test_create".as_bytes().to_vec(), 0o755, 0o777, 2),
-
-        // test flush
-        // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
-        // 2 => device.open(2, 0),
-        // 3 => device.flush(2, 0, 0),
-
-        // test releasedir
-        // 1 => device.lookup(1, "testdir".as_bytes().to_vec()),
-        // 2 => device.opendir(2, 0),
-        // 3 => device.readdir(2, 0, 0, 256),
-        // 4 => device.releasedir(2, 0, 0),
-        // 5 => device.readdir(2, 0, 0, 256),
-
-        // test getattr
-        // 1 => device.lookup(1, "testdir".as_bytes().to_vec()),
-        // 2 => device.getattr(2, 0, 0, 0),
-
-        // test release
-        // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
-        // 2 => device.open(2, 0),
-        // 3 => device.read(2, 0, 0, 128),
-        // 4 => device.release(2, 0, 0, 0, true),
-        // 5 => device.read(2, 0, 0, 128),
-
-        // test access
-        // 1 => device.lookup(1, "testdir".as_bytes().to_vec()),
-        // 2 => device.access(2, 0),
-
-        // test statfs
-        // 1 => device.statfs(1),
-
-        // test interrupt
-        // 1 => device.interrupt(0),
-
-        // test mkdir
-        // 1 => device.mkdir(1, 0o755, 0o777, "test_mkdir".as_bytes().to_vec()),
-
-        // test destroy
-        // 1 => device.destroy(),
-        // 2 => device.lookup(1, "testf01".as_bytes().to_vec()),
-
-        // test rename
-        // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
-        // 2 => device.rename(2, "testf01".as_bytes().to_vec(), 1, "testf01_rename".as_bytes().to_vec()),
-
-        // test rename2
-        // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
-        // 2 => device.rename2(2, "testf01".as_bytes().to_vec(), 1, "testf01_rename".as_bytes().to_vec(), 0),
-
-        // test forget
-        // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
-        // 2 => device.forget(2, 1),
-
-        // test batch_forget
-        // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
-        // 2 => device.lookup(1, "testf02".as_bytes().to_vec()),
-        // 3 => device.batch_forget(&[(2, 3)]),
-        _ => (),
-    };
+        });
+        assert!(matches!(result, Err(FilesystemError::Fuse { errno: EIO })));
+    }
+
+    #[ktest]
+    fn open_reply_without_body_is_not_decoded() {
+        // An error reply is the header alone.
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -ENOSYS,
+            unique: 0,
+        };
+        assert!(matches!(
+            decode_open_reply(&headerout, &[]),
+            Err(FilesystemError::Fuse { errno: ENOSYS })
+        ));
+
+        // A successful reply must carry the body.
+        let headerout = FuseOutHeader {
+            error: 0,
+            ..headerout
+        };
+        assert!(matches!(
+            decode_open_reply(&headerout, &[]),
+            Err(FilesystemError::BufferTooShort)
+        ));
+        let openout = FuseOpenOut {
+            fh: 7,
+            open_flags: FOPEN_KEEP_CACHE,
+            backing_id: -1,
+        };
+        let dataout = decode_open_reply(&headerout, openout.as_bytes()).unwrap();
+        assert_eq!(dataout.fh, 7);
+    }
+
+    #[ktest]
+    fn open_reply_carries_backing_id() {
+        let openout = FuseOpenOut {
+            fh: 3,
+            open_flags: FOPEN_PASSTHROUGH | FOPEN_KEEP_CACHE,
+            backing_id: 1,
+        };
+        let openout = decode_reply::<FuseOpenOut>(openout.as_bytes()).unwrap();
+        let file = OpenFileState::new(2, 0, &openout, FUSE_PASSTHROUGH);
+        assert_eq!(file.fh, 3);
+        assert_eq!(file.backing_id, Some(1));
+        assert!(file.open_flags.contains(FopenFlags::FOPEN_KEEP_CACHE));
+        assert!(!file.is_direct_io());
+
+        // Without `FUSE_PASSTHROUGH`, the id means nothing.
+        let file = OpenFileState::new(2, 0, &openout, 0);
+        assert_eq!(file.backing_id, None);
+        // Nor does a negative one.
+        let openout = FuseOpenOut {
+            backing_id: -1,
+            ..openout
+        };
+        let file = OpenFileState::new(2, 0, &openout, FUSE_PASSTHROUGH);
+        assert_eq!(file.backing_id, None);
+
+        let openout = FuseOpenOut {
+            open_flags: FOPEN_DIRECT_IO,
+            ..openout
+        };
+        assert!(OpenFileState::new(2, 0, &openout, 0).is_direct_io());
+    }
+
+    #[ktest]
+    fn forgets_are_coalesced_until_due() {
+        let mut batch = ForgetBatch::default();
+        assert!(!batch.push(2, 1));
+        assert!(!batch.push(3, 4));
+        assert!(!batch.push(2, 2));
+        assert_eq!(batch.forgets, [(2, 3), (3, 4)]);
+
+        for nodeid in 4..FORGET_FLUSH_THRESHOLD as u64 + 1 {
+            assert!(!batch.push(nodeid, 1));
+        }
+        assert!(batch.push(FORGET_FLUSH_THRESHOLD as u64 + 1, 1));
+        assert_eq!(batch.take().len(), FORGET_FLUSH_THRESHOLD);
+        assert!(batch.forgets.is_empty());
+    }
+
+    #[ktest]
+    fn released_handle_is_rejected() {
+        let mut handles = HandleTable::default();
+        handles.insert(2, 3, FopenFlags::FOPEN_DIRECT_IO);
+        assert_eq!(handles.open_flags(2, 3), Some(FopenFlags::FOPEN_DIRECT_IO));
+        // The handle is only good for the node it was opened on.
+        assert_eq!(handles.open_flags(4, 3), None);
+
+        // A server without handles returns the same one for each open.
+        handles.insert(2, 3, FopenFlags::empty());
+        assert!(handles.remove(2, 3));
+        assert_eq!(handles.open_flags(2, 3), Some(FopenFlags::empty()));
+        assert!(handles.remove(2, 3));
+        assert_eq!(handles.open_flags(2, 3), None);
+        assert!(!handles.remove(2, 3));
+    }
+
+    #[ktest]
+    fn append_write_goes_to_end_of_file() {
+        let mut file = OpenFileState {
+            nodeid: 2,
+            fh: 3,
+            flags: O_APPEND | 0o1,
+            open_flags: FopenFlags::empty(),
+            backing_id: None,
+        };
+        let mut contents = b"hello".to_vec();
+        let mut getattrs = 0;
+        for data in [&b" world"[..], b"!"] {
+            let offset = write_offset(&file, 0, || {
+                getattrs += 1;
+                Ok(contents.len() as u64)
+            })
+            .unwrap();
+            assert_eq!(offset, contents.len() as u64);
+            contents.extend_from_slice(data);
+        }
+        assert_eq!(contents, b"hello world!");
+        assert_eq!(getattrs, 2);
+
+        // Without `O_APPEND`, the offset is taken as is and the size is not asked for.
+        file.flags = 0o1;
+        let offset = write_offset(&file, 3, || panic!("the size is not needed")).unwrap();
+        assert_eq!(offset, 3);
+    }
+
+    #[ktest]
+    fn read_of_last_partial_block_reports_eof() {
+        const BLOCK_SIZE: u32 = 4096;
+        let file: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        // Serves a `FUSE_READ` the way the server does, short at the end of the file.
+        let read = |offset: u64, size: u32| {
+            let start = (offset as usize).min(file.len());
+            let end = (start + size as usize).min(file.len());
+            finish_read(file[start..end].to_vec(), size)
+        };
+
+        let (data, eof) = read(0, BLOCK_SIZE);
+        assert_eq!(data.len(), BLOCK_SIZE as usize);
+        assert!(!eof);
+
+        let (data, eof) = read(2 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        assert_eq!(data, file[8192..]);
+        assert!(eof);
+
+        let (data, eof) = read(file.len() as u64, BLOCK_SIZE);
+        assert!(data.is_empty());
+        assert!(eof);
+    }
+
+    #[ktest]
+    fn overridden_creds_reach_header() {
+        let creds = FuseCreds {
+            uid: 1000,
+            gid: 100,
+            pid: 4242,
+        };
+        let len_in = size_of::<FuseInHeader>() + size_of::<FuseMkdirIn>() + 8;
+        let headerin = fuse_in_header(FuseOpcode::FuseMkdir, 7, 1, len_in, creds);
+        assert_eq!(
+            (headerin.uid, headerin.gid, headerin.pid),
+            (1000, 100, 4242)
+        );
+        assert_eq!(headerin.opcode, FuseOpcode::FuseMkdir as u32);
+        assert_eq!(headerin.len as usize, len_in);
+
+        let headerin = fuse_in_header(FuseOpcode::FuseMkdir, 8, 1, len_in, FuseCreds::default());
+        assert_eq!((headerin.uid, headerin.gid, headerin.pid), (0, 0, 0));
+    }
+
+    #[ktest]
+    fn lookup_carries_caller_uid() {
+        fn caller() -> Option<FuseCreds> {
+            Some(FuseCreds {
+                uid: 1000,
+                gid: 100,
+                pid: 42,
+            })
+        }
+        fn unmapped() -> Option<FuseCreds> {
+            None
+        }
+        let post_lookup = |provider: &'static CredsProvider, flags| {
+            let creds = provided_creds(Some(provider), flags)?;
+            let prepared_name = fuse_pad_str(b"file", true);
+            let len_in = size_of::<FuseInHeader>() + prepared_name.len();
+            let headerin = fuse_in_header(FuseOpcode::FuseLookup, 3, 1, len_in, creds);
+            let mut queue = MockQueue::default();
+            let requests = alloc_request_buffer().unwrap();
+            let replies = alloc_request_buffer().unwrap();
+            post_request(
+                &mut queue,
+                &requests,
+                &replies,
+                0,
+                &headerin,
+                &RequestParts {
+                    datain: &[&prepared_name],
+                    out_len: size_of::<FuseEntryOut>(),
+                    ..Default::default()
+                },
+            )?;
+            let (readable, _) = queue.posted.pop().unwrap();
+            Ok::<_, FilesystemError>(decode_reply::<FuseInHeader>(&readable).unwrap())
+        };
+
+        let headerin = post_lookup(&caller, FUSE_INIT_EXT).unwrap();
+        assert_eq!(headerin.opcode, FuseOpcode::FuseLookup as u32);
+        assert_eq!((headerin.uid, headerin.gid, headerin.pid), (1000, 100, 42));
+
+        // Unmapped ids are only sent to a server that allows idmapped mounts.
+        let headerin = post_lookup(&unmapped, FUSE_INIT_EXT | FUSE_ALLOW_IDMAP).unwrap();
+        assert_eq!(headerin.uid, FUSE_INVALID_UIDGID);
+        assert_eq!(headerin.gid, FUSE_INVALID_UIDGID);
+        assert!(matches!(
+            post_lookup(&unmapped, FUSE_INIT_EXT),
+            Err(FilesystemError::Fuse { errno: EOVERFLOW })
+        ));
+    }
+
+    #[ktest]
+    fn cancelled_read_is_interrupted() {
+        let cancel = CancelToken::new();
+        let mut pending_replies = InflightTable::default();
+        // A `FUSE_READ` sent with unique 7 is awaiting its reply.
+        pending_replies.insert(7);
+        assert!(pending_replies.take(7, Some(&cancel)).is_none());
+
+        cancel.cancel();
+        let slot = pending_replies.take(7, Some(&cancel)).unwrap();
+        assert!(pending_replies.slots.is_empty());
+        // The reply coming after all is dropped.
+        assert!(!pending_replies.complete(7, Ok(vec![0u8; 16])));
+
+        let mut interrupted = None;
+        let result = finish_wait(slot, 7, |unique| {
+            interrupted = Some(unique);
+            Ok(())
+        });
+        assert_eq!(interrupted, Some(7));
+        assert!(matches!(
+            result,
+            Err(FilesystemError::Fuse { errno: EINTR })
+        ));
+    }
+
+    #[ktest]
+    fn reply_wins_over_late_cancel() {
+        let cancel = CancelToken::new();
+        let mut pending_replies = InflightTable::default();
+        pending_replies.insert(7);
+        assert!(pending_replies.complete(7, Ok(vec![0u8; 16])));
+        cancel.cancel();
+
+        let slot = pending_replies.take(7, Some(&cancel)).unwrap();
+        let result = finish_wait(slot, 7, |_| panic!("the request is not interrupted"));
+        assert_eq!(result.unwrap().len(), 16);
+    }
+
+    #[ktest]
+    fn duplicate_reply_is_dropped() {
+        let mut pending_replies = InflightTable::default();
+        pending_replies.insert(5);
+        pending_replies.insert(6);
+        assert!(pending_replies.is_waiting(5));
+
+        assert!(pending_replies.complete(5, Ok(vec![1u8; 16])));
+        assert!(!pending_replies.is_waiting(5));
+        // The second reply to unique 5 neither replaces the first one nor
+        // completes another request.
+        assert!(!pending_replies.complete(5, Ok(vec![2u8; 24])));
+        assert!(pending_replies.is_waiting(6));
+
+        let reply = pending_replies.take(5, None).unwrap().unwrap().unwrap();
+        assert_eq!(reply, vec![1u8; 16]);
+        // Nor does it once the waiter is gone.
+        assert!(!pending_replies.complete(5, Ok(vec![2u8; 24])));
+        assert!(pending_replies.take(5, None).is_none());
+        assert_eq!(pending_replies.slots.len(), 1);
+    }
+
+    #[ktest]
+    fn timed_out_request_keeps_its_slot() {
+        let connection = FuseConnection::new();
+        let mut pool = RequestPool::new(1, PAGE_SIZE).unwrap();
+        let offset = pool.alloc().unwrap();
+        pool.track(3, offset, true);
+        connection.pending_replies.disable_irq().lock().insert(9);
+
+        // The deadline of the waiter passes before the device answers.
+        let deadline = (Duration::ZERO, 9);
+        connection.deadlines.disable_irq().lock().insert(deadline);
+        connection.wake_timed_out();
+        connection.deadlines.disable_irq().lock().remove(&deadline);
+        connection.pending_replies.disable_irq().lock().remove(9);
+        // The device may still write the slot, so it is not handed out again.
+        assert!(pool.alloc().is_none());
+
+        // The late reply is dropped, and only then is the slot freed.
+        let mut pending_replies = connection.pending_replies.disable_irq().lock();
+        assert!(!pending_replies.complete(9, Ok(vec![0u8; 16])));
+        assert!(pending_replies.slots.is_empty());
+        drop(pending_replies);
+        pool.free(pool.complete(3).unwrap().offset);
+        assert_eq!(pool.alloc(), Some(offset));
+    }
+
+    #[ktest]
+    fn requests_wait_for_init_reply() {
+        assert!(matches!(
+            InitState::Unsent.admit(),
+            Some(Err(FilesystemError::InvalidOperation))
+        ));
+        // Requests made while `FUSE_INIT` is in flight are held.
+        assert!(InitState::Sent.admit().is_none());
+        assert!(matches!(InitState::Ready.admit(), Some(Ok(()))));
+        assert!(matches!(
+            InitState::Failed.admit(),
+            Some(Err(FilesystemError::InvalidOperation))
+        ));
+    }
+
+    #[ktest]
+    fn shut_down_connection_lets_nothing_through() {
+        let connection = FuseConnection::new();
+        connection.finish_init(Some(NegotiatedInit::default()));
+        connection.pending_replies.disable_irq().lock().insert(5);
+        connection.pending_replies.disable_irq().lock().insert(6);
+        connection
+            .pending_replies
+            .disable_irq()
+            .lock()
+            .complete(6, Ok(Vec::new()));
+
+        assert!(connection.shut_down());
+        assert!(matches!(
+            connection.admit(),
+            Some(Err(FilesystemError::InvalidOperation))
+        ));
+        // A late reply to `FUSE_INIT` does not bring the connection back.
+        connection.finish_init(Some(NegotiatedInit::default()));
+        assert!(!connection.is_ready());
+        assert!(!connection.shut_down());
+
+        // Only the request still waiting is given time to finish.
+        let waiting = connection.pending_replies.disable_irq().lock().waiting();
+        assert_eq!(waiting, vec![5]);
+        let drained = connection.wait_timeout(0, Duration::ZERO, || {
+            let pending_replies = connection.pending_replies.disable_irq().lock();
+            pending_replies.waiting().is_empty().then_some(())
+        });
+        assert!(drained.is_none());
+        assert!(connection.deadlines.disable_irq().lock().is_empty());
+    }
+
+    #[ktest]
+    fn connection_is_ready_once_init_is_answered() {
+        let connection = FuseConnection::new();
+        assert_eq!(connection.next_unique(), 1);
+        assert_eq!(connection.next_unique(), 2);
+
+        *connection.init_state.disable_irq().lock() = InitState::Sent;
+        assert!(connection.admit().is_none());
+        connection.finish_init(Some(NegotiatedInit::default()));
+        assert!(connection.is_ready());
+        assert!(matches!(connection.admit(), Some(Ok(()))));
+
+        connection.finish_init(None);
+        assert!(!connection.is_ready());
+        assert!(connection.negotiated().is_none());
+    }
+
+    fn abort_with(policy: AbortPolicy) -> (AbortState, InflightTable) {
+        let mut state = AbortState {
+            policy: policy,
+            ..Default::default()
+        };
+        assert!(matches!(state.admit(), Some(Ok(()))));
+
+        let mut pending_replies = InflightTable::default();
+        pending_replies.insert(1);
+        pending_replies.insert(2);
+        pending_replies.complete(2, Ok(vec![0u8; 16]));
+        state.abort(&mut pending_replies);
+        // A reply that already arrived is left to its waiter.
+        assert!(matches!(pending_replies.slots[&2], Some(Ok(_))));
+        (state, pending_replies)
+    }
+
+    #[ktest]
+    fn abort_fails_requests_with_eio() {
+        let (state, pending_replies) = abort_with(AbortPolicy::default());
+        assert!(matches!(
+            pending_replies.slots[&1],
+            Some(Err(FilesystemError::Fuse { errno: EIO }))
+        ));
+        assert!(matches!(
+            state.admit(),
+            Some(Err(FilesystemError::Fuse { errno: EIO }))
+        ));
+    }
+
+    #[ktest]
+    fn abort_fails_requests_with_econnaborted() {
+        let policy = AbortPolicy {
+            conn_aborted: true,
+            ..Default::default()
+        };
+        let (state, pending_replies) = abort_with(policy);
+        assert!(matches!(
+            pending_replies.slots[&1],
+            Some(Err(FilesystemError::Fuse {
+                errno: ECONNABORTED
+            }))
+        ));
+        assert!(matches!(
+            state.admit(),
+            Some(Err(FilesystemError::Fuse {
+                errno: ECONNABORTED
+            }))
+        ));
+    }
+
+    #[ktest]
+    fn negotiated_abort_error_fails_with_econnaborted() {
+        let connection = FuseConnection::new();
+        assert!(connection.abort_error().is_none());
+        let negotiated = NegotiatedInit {
+            flags: FUSE_ABORT_ERROR as u32,
+            ..Default::default()
+        };
+        connection.finish_init(Some(negotiated));
+        connection.pending_replies.disable_irq().lock().insert(1);
+
+        connection.abort();
+        assert!(matches!(
+            connection.pending_replies.disable_irq().lock().slots[&1],
+            Some(Err(FilesystemError::Fuse {
+                errno: ECONNABORTED
+            }))
+        ));
+        assert!(matches!(
+            connection.abort_error(),
+            Some(FilesystemError::Fuse {
+                errno: ECONNABORTED
+            })
+        ));
+        assert!(matches!(
+            connection.admit(),
+            Some(Err(FilesystemError::Fuse {
+                errno: ECONNABORTED
+            }))
+        ));
+
+        connection.reconnect();
+        assert!(connection.abort_error().is_none());
+        assert!(matches!(connection.admit(), Some(Ok(()))));
+    }
+
+    #[ktest]
+    fn abort_holds_requests_until_reconnect() {
+        let policy = AbortPolicy {
+            block_until_reconnect: true,
+            ..Default::default()
+        };
+        let (mut state, pending_replies) = abort_with(policy);
+        // The requests in flight still fail, since their replies are lost.
+        assert!(matches!(
+            pending_replies.slots[&1],
+            Some(Err(FilesystemError::Fuse { errno: EIO }))
+        ));
+        assert!(state.admit().is_none());
+
+        state.reconnect();
+        assert!(matches!(state.admit(), Some(Ok(()))));
+    }
+
+    #[ktest]
+    fn tag_stops_at_first_nul() {
+        let mut config = VirtioFilesystemConfig::new_zeroed();
+        assert_eq!(config.tag(), "");
+
+        config.tag[..4].copy_from_slice(b"home");
+        config.tag[5..8].copy_from_slice(b"xyz");
+        assert_eq!(config.tag(), "home");
+
+        // A tag that fills the whole array has no terminator.
+        config.tag = [b'a'; 36];
+        assert_eq!(config.tag(), "a".repeat(36));
+    }
+
+    #[ktest]
+    fn negotiated_init_keeps_reply_parameters() {
+        let init_out = FuseInitOut {
+            major: FUSE_KERNEL_VERSION,
+            minor: 38,
+            max_readahead: 128 * 1024,
+            flags: (FUSE_SETXATTR_EXT | FUSE_INIT_EXT) as u32,
+            max_background: 16,
+            max_write: 1024 * 1024,
+            time_gran: 1,
+            flags2: (FUSE_SECURITY_CTX >> 32) as u32,
+            ..Default::default()
+        };
+        let negotiated = NegotiatedInit::new(&init_out);
+        assert_eq!(negotiated.max_background, 16);
+        assert_eq!(negotiated.time_gran, 1);
+        assert!(negotiated.has(FUSE_SETXATTR_EXT));
+        assert!(negotiated.has(FUSE_SECURITY_CTX));
+        assert!(!negotiated.has(FUSE_POSIX_ACL));
+    }
+
+    #[ktest]
+    fn submount_in_lookup_reply_fires_automount() {
+        let mounted = Arc::new(AtomicU64::new(0));
+        let callback: Arc<AutomountCallback> = {
+            let mounted = mounted.clone();
+            Arc::new(move |nodeid| mounted.store(nodeid, Ordering::Relaxed))
+        };
+        let callbacks = [callback];
+
+        let mut entry = FuseEntryOut::new_zeroed();
+        entry.nodeid = 42;
+        entry.attr.flags = FUSE_ATTR_SUBMOUNT;
+        let mut entry = decode_reply::<FuseEntryOut>(entry.as_bytes()).unwrap();
+
+        // Without `FUSE_SUBMOUNTS`, the attribute flag is not to be trusted.
+        dispatch_submount(&callbacks, FUSE_INIT_EXT, entry.nodeid, &entry.attr);
+        assert_eq!(mounted.load(Ordering::Relaxed), 0);
+
+        dispatch_submount(&callbacks, FUSE_SUBMOUNTS, entry.nodeid, &entry.attr);
+        assert_eq!(mounted.load(Ordering::Relaxed), 42);
+
+        // A regular node does not fire.
+        mounted.store(0, Ordering::Relaxed);
+        entry.attr.flags = 0;
+        dispatch_submount(&callbacks, FUSE_SUBMOUNTS, entry.nodeid, &entry.attr);
+        assert_eq!(mounted.load(Ordering::Relaxed), 0);
+    }
+
+    #[ktest]
+    fn xattr_names_are_checked() {
+        assert!(check_xattr_name(b"user.foo").is_ok());
+        assert!(check_xattr_name(b"security.\xff\xfe").is_ok());
+        assert!(matches!(
+            check_xattr_name(b""),
+            Err(FilesystemError::InvalidOperation)
+        ));
+        assert!(matches!(
+            check_xattr_name(b"user.f\0o"),
+            Err(FilesystemError::InvalidOperation)
+        ));
+    }
+
+    #[ktest]
+    fn setxattr_in_extended_only_when_negotiated() {
+        let setxattrin = FuseSetxattrIn {
+            size: 5,
+            flags: 1,
+            setxattr_flags: FUSE_SETXATTR_ACL_KILL_SGID,
+            padding: 0,
+        };
+        assert_eq!(setxattr_in_bytes(&setxattrin, false).len(), 8);
+        assert_eq!(setxattr_in_bytes(&setxattrin, true).len(), 16);
+        assert_eq!(
+            setxattr_in_bytes(&setxattrin, false),
+            &setxattrin.as_bytes()[..8]
+        );
+    }
 }
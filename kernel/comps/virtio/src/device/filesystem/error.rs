@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::{fmt, result};
+
+use crate::queue::QueueError;
+
+/// `ENOENT`, as the server answers an interrupt of a finished request with.
+pub const ENOENT: i32 = 2;
+/// `EINTR`, for requests given up on with a `CancelToken`.
+pub const EINTR: i32 = 4;
+/// `EIO`, for requests failed by an aborted connection.
+pub const EIO: i32 = 5;
+/// `ENXIO`, as the server answers a `SEEK_DATA` past the end of the file with.
+pub const ENXIO: i32 = 6;
+/// `EAGAIN`, as the server answers an interrupt of a request it has not seen yet with.
+pub const EAGAIN: i32 = 11;
+/// `EACCES`, as the server denies an access check with.
+pub const EACCES: i32 = 13;
+/// `EINVAL`, as returned negated in `FuseOutHeader::error`.
+pub const EINVAL: i32 = 22;
+/// `EPROTO`, for a server speaking another major version of the protocol.
+pub const EPROTO: i32 = 71;
+/// `ERANGE`, as the server answers with when a value does not fit the buffer given.
+pub const ERANGE: i32 = 34;
+/// `ENOSYS`, as the server answers an operation it does not implement with.
+pub const ENOSYS: i32 = 38;
+/// `EOVERFLOW`, for ids of the caller that the server cannot be given.
+pub const EOVERFLOW: i32 = 75;
+/// `EOPNOTSUPP`, as the server answers a `fallocate` mode it does not support with.
+pub const EOPNOTSUPP: i32 = 95;
+/// `ECONNABORTED`, for requests failed by an aborted connection with `FUSE_ABORT_ERROR`.
+pub const ECONNABORTED: i32 = 103;
+/// `ETIMEDOUT`, for requests given up on with `RequestHandle::wait_timeout`.
+pub const ETIMEDOUT: i32 = 110;
+
+/// The error type of VirtIO filesystem driver.
+#[derive(Debug)]
+pub enum FilesystemError {
+    /// The server replied with an error, carrying the positive errno.
+    ///
+    /// Unlike the other variants, which are failures of the driver or of the
+    /// transport, this is the outcome of the operation itself, e.g. `ENOENT`
+    /// for a name that does not exist.
+    Fuse { errno: i32 },
+    /// The reply is shorter than expected.
+    BufferTooShort,
+    /// The request has exceeded the maximum buffer size.
+    BufferTooLong(usize, usize),
+    /// Only part of the request fits in the request buffer.
+    OutputBufferTooShort(usize, usize),
+    /// Queue Error
+    QueueError(QueueError),
+    /// Failed to access the DMA buffers.
+    DmaError(ostd::Error),
+    /// The bytes decoded from an exchange differ from its length.
+    ReplyLengthMismatch(usize, usize),
+    /// The operation cannot be made, e.g. as `FUSE_INIT` is unsent or failed,
+    /// or as it is given flags that contradict each other.
+    InvalidOperation,
+    /// The reply holds data that cannot be decoded, e.g. an entry with an empty name.
+    UnexpectedDataInPacket,
+}
+
+impl FilesystemError {
+    /// The server has no such entry.
+    pub const fn enoent() -> Self {
+        Self::Fuse { errno: ENOENT }
+    }
+
+    /// The server denied the caller the operation.
+    pub const fn eacces() -> Self {
+        Self::Fuse { errno: EACCES }
+    }
+
+    /// The server does not implement the operation.
+    pub const fn enosys() -> Self {
+        Self::Fuse { errno: ENOSYS }
+    }
+
+    /// The value asked for does not fit the size given, e.g. that of an extended attribute.
+    pub const fn erange() -> Self {
+        Self::Fuse { errno: ERANGE }
+    }
+
+    /// There is no such device or address, e.g. no data past an offset.
+    pub const fn enxio() -> Self {
+        Self::Fuse { errno: ENXIO }
+    }
+
+    /// Returns the positive errno the server replied with, or `None` if the
+    /// operation failed short of a reply, e.g. as the queue is full.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Self::Fuse { errno } => Some(*errno),
+            _ => None,
+        }
+    }
+}
+
+impl From<QueueError> for FilesystemError {
+    fn from(value: QueueError) -> Self {
+        Self::QueueError(value)
+    }
+}
+
+impl From<ostd::Error> for FilesystemError {
+    fn from(value: ostd::Error) -> Self {
+        Self::DmaError(value)
+    }
+}
+
+impl fmt::Display for FilesystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fuse { errno } => write!(f, "The server replied with errno '{errno}'"),
+            Self::BufferTooShort => write!(f, "The reply is shorter than expected"),
+            Self::BufferTooLong(actual, max) => {
+                write!(f, "The request length '{actual}' has exceeded the maximum allowed buffer length '{max}'")
+            }
+            Self::OutputBufferTooShort(written, len) => {
+                write!(f, "Wrote '{written}' bytes of a request of '{len}' bytes")
+            }
+            Self::QueueError(_) => write!(f, "Error encountered out of virtio-fs itself!"),
+            Self::DmaError(err) => write!(f, "Failed to access the DMA buffers: {err:?}"),
+            Self::ReplyLengthMismatch(consumed, expected) => {
+                write!(
+                    f,
+                    "Decoded '{consumed}' bytes of an exchange of '{expected}' bytes"
+                )
+            }
+            Self::InvalidOperation => write!(f, "The operation cannot be made"),
+            Self::UnexpectedDataInPacket => write!(f, "The reply holds malformed data"),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, FilesystemError>;
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn errno_tells_server_errors_apart() {
+        assert_eq!(FilesystemError::enoent().errno(), Some(ENOENT));
+        assert_eq!(FilesystemError::erange().errno(), Some(ERANGE));
+        assert!(matches!(
+            FilesystemError::enosys(),
+            FilesystemError::Fuse { errno: ENOSYS }
+        ));
+        assert_eq!(FilesystemError::BufferTooShort.errno(), None);
+        assert_eq!(FilesystemError::InvalidOperation.errno(), None);
+    }
+}
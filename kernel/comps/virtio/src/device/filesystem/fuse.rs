@@ -227,7 +227,7 @@
 
 use core::convert::TryFrom;
 
-use ostd::Pod;
+use ostd::{mm::PAGE_SIZE, Pod};
 
 /** Version number of this interface */
 pub const FUSE_KERNEL_VERSION: u32 = 7;
@@ -339,7 +339,22 @@ pub const FATTR_LOCKOWNER: u32 = 1 << 9;
 pub const FATTR_CTIME: u32 = 1 << 10;
 pub const FATTR_KILL_SUIDGID: u32 = 1 << 11;
 
-//TODO: bitflags
+bitflags::bitflags! {
+    pub struct FattrFlags: u32 {
+        const FATTR_MODE = FATTR_MODE;
+        const FATTR_UID = FATTR_UID;
+        const FATTR_GID = FATTR_GID;
+        const FATTR_SIZE = FATTR_SIZE;
+        const FATTR_ATIME = FATTR_ATIME;
+        const FATTR_MTIME = FATTR_MTIME;
+        const FATTR_FH = FATTR_FH;
+        const FATTR_ATIME_NOW = FATTR_ATIME_NOW;
+        const FATTR_MTIME_NOW = FATTR_MTIME_NOW;
+        const FATTR_LOCKOWNER = FATTR_LOCKOWNER;
+        const FATTR_CTIME = FATTR_CTIME;
+        const FATTR_KILL_SUIDGID = FATTR_KILL_SUIDGID;
+    }
+}
 
 /**
  * Flags returned by the OPEN request
@@ -363,6 +378,19 @@ pub const FOPEN_NOFLUSH: u32 = 1 << 5;
 pub const FOPEN_PARALLEL_DIRECT_WRITES: u32 = 1 << 6;
 pub const FOPEN_PASSTHROUGH: u32 = 1 << 7;
 
+bitflags::bitflags! {
+    pub struct FopenFlags: u32 {
+        const FOPEN_DIRECT_IO = FOPEN_DIRECT_IO;
+        const FOPEN_KEEP_CACHE = FOPEN_KEEP_CACHE;
+        const FOPEN_NONSEEKABLE = FOPEN_NONSEEKABLE;
+        const FOPEN_CACHE_DIR = FOPEN_CACHE_DIR;
+        const FOPEN_STREAM = FOPEN_STREAM;
+        const FOPEN_NOFLUSH = FOPEN_NOFLUSH;
+        const FOPEN_PARALLEL_DIRECT_WRITES = FOPEN_PARALLEL_DIRECT_WRITES;
+        const FOPEN_PASSTHROUGH = FOPEN_PASSTHROUGH;
+    }
+}
+
 /**
  * INIT request/reply flags
  *
@@ -461,7 +489,19 @@ pub const FUSE_HAS_RESEND: u64 = 1u64 << 39;
 
 bitflags::bitflags! {
     pub struct FuseInitFlags: u64 {
+        const FUSE_ATOMIC_O_TRUNC = FUSE_ATOMIC_O_TRUNC;
+        const FUSE_ABORT_ERROR = FUSE_ABORT_ERROR;
+        const FUSE_BIG_WRITES = FUSE_BIG_WRITES;
+        const FUSE_MAX_PAGES = FUSE_MAX_PAGES;
+        const FUSE_NO_OPEN_SUPPORT = FUSE_NO_OPEN_SUPPORT;
+        const FUSE_HANDLE_KILLPRIV_V2 = FUSE_HANDLE_KILLPRIV_V2;
+        const FUSE_CACHE_SYMLINKS = FUSE_CACHE_SYMLINKS;
+        const FUSE_NO_OPENDIR_SUPPORT = FUSE_NO_OPENDIR_SUPPORT;
+        const FUSE_SUBMOUNTS = FUSE_SUBMOUNTS;
+        const FUSE_SETXATTR_EXT = FUSE_SETXATTR_EXT;
         const FUSE_INIT_EXT = FUSE_INIT_EXT;
+        const FUSE_SECURITY_CTX = FUSE_SECURITY_CTX;
+        const FUSE_CREATE_SUPP_GROUP = FUSE_CREATE_SUPP_GROUP;
     }
 }
 
@@ -487,11 +527,59 @@ pub const FUSE_RELEASE_FLOCK_UNLOCK: u32 = 1 << 1;
  */
 pub const FUSE_GETATTR_FH: u32 = 1 << 0;
 
+/**
+ * fuse_attr flags
+ *
+ * FUSE_ATTR_SUBMOUNT: Object is a submount root
+ * FUSE_ATTR_DAX: Enable DAX for this file in per inode DAX mode
+ */
+pub const FUSE_ATTR_SUBMOUNT: u32 = 1 << 0;
+pub const FUSE_ATTR_DAX: u32 = 1 << 1;
+
+/**
+ * Fallocate modes, as for fallocate(2)
+ *
+ * FALLOC_FL_KEEP_SIZE: don't extend the file size
+ * FALLOC_FL_PUNCH_HOLE: deallocate the range, along with FALLOC_FL_KEEP_SIZE
+ * FALLOC_FL_COLLAPSE_RANGE: remove the range without leaving a hole
+ * FALLOC_FL_ZERO_RANGE: zero the range
+ * FALLOC_FL_INSERT_RANGE: insert a hole at the offset, shifting the data after it
+ * FALLOC_FL_UNSHARE_RANGE: unshare the blocks of the range shared with other files
+ */
+pub const FALLOC_FL_KEEP_SIZE: u32 = 1 << 0;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 1 << 1;
+pub const FALLOC_FL_COLLAPSE_RANGE: u32 = 1 << 3;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 1 << 4;
+pub const FALLOC_FL_INSERT_RANGE: u32 = 1 << 5;
+pub const FALLOC_FL_UNSHARE_RANGE: u32 = 1 << 6;
+
+bitflags::bitflags! {
+    pub struct FallocFlags: u32 {
+        const FALLOC_FL_KEEP_SIZE = FALLOC_FL_KEEP_SIZE;
+        const FALLOC_FL_PUNCH_HOLE = FALLOC_FL_PUNCH_HOLE;
+        const FALLOC_FL_COLLAPSE_RANGE = FALLOC_FL_COLLAPSE_RANGE;
+        const FALLOC_FL_ZERO_RANGE = FALLOC_FL_ZERO_RANGE;
+        const FALLOC_FL_INSERT_RANGE = FALLOC_FL_INSERT_RANGE;
+        const FALLOC_FL_UNSHARE_RANGE = FALLOC_FL_UNSHARE_RANGE;
+    }
+}
+
 /**
  * Lock flags
  */
 pub const FUSE_LK_FLOCK: u32 = 1 << 0;
 
+/**
+ * Rename2 flags, as for renameat2(2)
+ *
+ * RENAME_NOREPLACE: don't overwrite the target
+ * RENAME_EXCHANGE: exchange the source and the target
+ * RENAME_WHITEOUT: leave a whiteout object at the source
+ */
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+pub const RENAME_EXCHANGE: u32 = 1 << 1;
+pub const RENAME_WHITEOUT: u32 = 1 << 2;
+
 /**
  * WRITE flags
  *
@@ -578,11 +666,23 @@ pub const FUSE_EXPIRE_ONLY: u32 = 1 << 0;
  * FUSE_MAX_NR_SECCTX: maximum value of &fuse_secctx_header.nr_secctx
  * FUSE_EXT_GROUPS: &fuse_supp_groups extension
  */
-#[repr(u32)]
+pub const FUSE_MAX_NR_SECCTX: u32 = 31;
+pub const FUSE_EXT_GROUPS: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FuseExtType {
-    /* Types 0..31 are reserved for fuse_secctx_header */
-    FuseMaxNrSecctx = 31,
-    FuseExtGroups = 32,
+    /* Types 0..31 are reserved for fuse_secctx_header, whose nr_secctx is the type */
+    FuseSecctx(u32),
+    FuseExtGroups,
+}
+
+impl From<FuseExtType> for u32 {
+    fn from(value: FuseExtType) -> Self {
+        match value {
+            FuseExtType::FuseSecctx(nr_secctx) => nr_secctx,
+            FuseExtType::FuseExtGroups => FUSE_EXT_GROUPS,
+        }
+    }
 }
 
 #[repr(u32)]
@@ -725,6 +825,27 @@ pub enum FuseNotifyCode {
     FuseNotifyCodeMax,
 }
 
+/// Invalid notification code error.
+#[derive(Debug)]
+pub struct InvalidNotifyCodeError;
+
+impl TryFrom<u32> for FuseNotifyCode {
+    type Error = InvalidNotifyCodeError;
+
+    fn try_from(n: u32) -> Result<Self, Self::Error> {
+        match n {
+            1 => Ok(FuseNotifyCode::FuseNotifyPoll),
+            2 => Ok(FuseNotifyCode::FuseNotifyInvalInode),
+            3 => Ok(FuseNotifyCode::FuseNotifyInvalEntry),
+            4 => Ok(FuseNotifyCode::FuseNotifyStore),
+            5 => Ok(FuseNotifyCode::FuseNotifyRetrieve),
+            6 => Ok(FuseNotifyCode::FuseNotifyDelete),
+            7 => Ok(FuseNotifyCode::FuseNotifyResend),
+            _ => Err(InvalidNotifyCodeError),
+        }
+    }
+}
+
 /* The read buffer is required to be at least 8k, but may be much larger */
 pub const FUSE_MIN_READ_BUFFER: u32 = 8192;
 
@@ -1186,10 +1307,49 @@ pub struct FuseDirent {
     pub ino: u64,
     pub off: u64,
     pub namelen: u32,
+    /// The `DT_*` type of the entry, as decoded by `file_type`.
     pub type_: u32,
     pub name: [u8; 0],
 }
 
+impl FuseDirent {
+    /// Returns the type of the file the entry refers to.
+    pub fn file_type(&self) -> DirentType {
+        DirentType::from(self.type_)
+    }
+}
+
+/// The type of a directory entry, as the `DT_*` values of `readdir(3)`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirentType {
+    Unknown = 0,
+    Fifo = 1,
+    Chr = 2,
+    Dir = 4,
+    Blk = 6,
+    Reg = 8,
+    Lnk = 10,
+    Sock = 12,
+}
+
+impl From<u32> for DirentType {
+    /// Maps a raw type to a `DirentType`, where values the protocol does not
+    /// define, e.g. `DT_WHT`, become `Unknown`.
+    fn from(n: u32) -> Self {
+        match n {
+            1 => DirentType::Fifo,
+            2 => DirentType::Chr,
+            4 => DirentType::Dir,
+            6 => DirentType::Blk,
+            8 => DirentType::Reg,
+            10 => DirentType::Lnk,
+            12 => DirentType::Sock,
+            _ => DirentType::Unknown,
+        }
+    }
+}
+
 /* Align variable length records to 64bit boundary */
 pub const fn fuse_rec_align(x: usize) -> usize {
     (x + core::mem::size_of::<u64>() - 1) & !(core::mem::size_of::<u64>() - 1)
@@ -1338,8 +1498,9 @@ pub struct FuseRemoveMappingOne {
     pub len: u64,     /* Length of mapping required */
 }
 
-// pub const FUSE_REMOVEMAPPING_MAX_ENTRY: usize =
-//     PAGE_SIZE / core::mem::size_of::<FuseRemoveMappingOne>();
+/* The number of fuse_removemapping_one that fit in a single page */
+pub const FUSE_REMOVEMAPPING_MAX_ENTRY: usize =
+    PAGE_SIZE / core::mem::size_of::<FuseRemoveMappingOne>();
 
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod)]
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use super::fuse::{DirentType, FuseAttr};
+
+/// The bits of `mode` that hold the file type, as `S_IFMT`.
+const S_IFMT: u32 = 0o170000;
+
+/// The attributes of a node, as `stat(2)` reports them.
+///
+/// This wraps the `FuseAttr` returned by the server, of which it decodes the
+/// mode and pairs up the timestamps.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    attr: FuseAttr,
+}
+
+impl Metadata {
+    pub fn ino(&self) -> u64 {
+        self.attr.ino
+    }
+
+    /// Returns the type of the file, from the `S_IFMT` bits of its mode.
+    pub fn file_type(&self) -> DirentType {
+        DirentType::from((self.attr.mode & S_IFMT) >> 12)
+    }
+
+    /// Returns the permission bits, along with the setuid, setgid and sticky bits.
+    pub fn permissions(&self) -> u32 {
+        self.attr.mode & !S_IFMT
+    }
+
+    pub fn size(&self) -> u64 {
+        self.attr.size
+    }
+
+    /// Returns the number of 512-byte blocks allocated to the file.
+    pub fn blocks(&self) -> u64 {
+        self.attr.blocks
+    }
+
+    pub fn blksize(&self) -> u32 {
+        self.attr.blksize
+    }
+
+    pub fn nlink(&self) -> u32 {
+        self.attr.nlink
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.attr.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.attr.gid
+    }
+
+    pub fn rdev(&self) -> u32 {
+        self.attr.rdev
+    }
+
+    /// Returns the time of last access, as seconds and nanoseconds.
+    pub fn atime(&self) -> (u64, u32) {
+        (self.attr.atime, self.attr.atimensec)
+    }
+
+    /// Returns the time of last modification, as seconds and nanoseconds.
+    pub fn mtime(&self) -> (u64, u32) {
+        (self.attr.mtime, self.attr.mtimensec)
+    }
+
+    /// Returns the time of last status change, as seconds and nanoseconds.
+    pub fn ctime(&self) -> (u64, u32) {
+        (self.attr.ctime, self.attr.ctimensec)
+    }
+
+    /// Returns the raw attributes, e.g. for the fields not decoded here.
+    pub fn attr(&self) -> &FuseAttr {
+        &self.attr
+    }
+}
+
+impl From<FuseAttr> for Metadata {
+    fn from(attr: FuseAttr) -> Self {
+        Self { attr: attr }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn mode_and_times_are_decoded() {
+        let metadata = Metadata::from(FuseAttr {
+            ino: 2,
+            size: 4096,
+            mtime: 1_700_000_000,
+            mtimensec: 5,
+            mode: 0o040755,
+            ..Default::default()
+        });
+        assert_eq!(metadata.file_type(), DirentType::Dir);
+        assert_eq!(metadata.permissions(), 0o755);
+        assert_eq!(metadata.size(), 4096);
+        assert_eq!(metadata.mtime(), (1_700_000_000, 5));
+
+        let metadata = Metadata::from(FuseAttr {
+            mode: 0o104755,
+            ..Default::default()
+        });
+        assert_eq!(metadata.file_type(), DirentType::Reg);
+        // The setuid bit is kept along with the permissions.
+        assert_eq!(metadata.permissions(), 0o4755);
+    }
+}
@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: MPL-2.0
 
+pub mod cache;
 pub mod config;
 pub mod device;
+pub mod error;
 pub mod fuse;
+pub mod metadata;
 pub mod request;
+pub mod stats;
 
 pub static DEVICE_NAME: &str = "Virtio-fs";
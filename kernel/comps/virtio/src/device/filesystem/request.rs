@@ -1,30 +1,45 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use alloc::{vec, vec::Vec};
-use core::fmt::Debug;
+use core::{
+    fmt::Debug,
+    mem,
+    ops::{Deref, DerefMut},
+};
 
 use ostd::{
     early_print,
-    mm::{VmReader, VmWriter},
+    mm::{Infallible, VmReader, VmWriter},
     Pod,
 };
 
-use super::fuse::*;
+use super::{
+    error::{FilesystemError, EINVAL},
+    fuse::*,
+};
+
+/// The credentials a request is made with, carried in its `FuseInHeader`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FuseCreds {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
 
 pub trait AnyFuseDevice {
     // Send Init Request to Device.
-    fn init(&self);
-    fn readdir(&self, nodeid: u64, fh: u64, offset: u64, size: u32);
-    fn opendir(&self, nodeid: u64, flags: u32);
-    fn open(&self, nodeid: u64, flags: u32);
-    fn read(&self, nodeid: u64, fh: u64, offset: u64, size: u32);
-    fn flush(&self, nodeid: u64, fh: u64, lock_owner: u64);
-    fn releasedir(&self, nodeid: u64, fh: u64, flags: u32);
-    fn getattr(&self, nodeid: u64, fh: u64, flags: u32, dummy: u32);
+    fn init(&self) -> Result<(), FilesystemError>;
+    fn readdir(&self, nodeid: u64, fh: u64, offset: u64, size: u32) -> Result<(), FilesystemError>;
+    fn opendir(&self, nodeid: u64, flags: u32) -> Result<(), FilesystemError>;
+    fn open(&self, nodeid: u64, flags: u32) -> Result<(), FilesystemError>;
+    fn read(&self, nodeid: u64, fh: u64, offset: u64, size: u32) -> Result<(), FilesystemError>;
+    fn flush(&self, nodeid: u64, fh: u64, lock_owner: u64) -> Result<(), FilesystemError>;
+    fn releasedir(&self, nodeid: u64, fh: u64, flags: u32) -> Result<(), FilesystemError>;
+    fn getattr(&self, nodeid: u64, fh: u64, flags: u32, dummy: u32) -> Result<(), FilesystemError>;
     fn setattr(
         &self,
         nodeid: u64,
-        valid: u32,
+        valid: FattrFlags,
         fh: u64,
         size: u64,
         lock_owner: u64,
@@ -37,28 +52,79 @@ pub trait AnyFuseDevice {
         mode: u32,
         uid: u32,
         gid: u32,
-    );
-    fn lookup(&self, nodeid: u64, name: Vec<u8>);
-    fn release(&self, nodeid: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool);
-    fn access(&self, nodeid: u64, mask: u32);
-    fn statfs(&self, nodeid: u64);
-    fn interrupt(&self, unique: u64);
-    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]);
+    ) -> Result<(), FilesystemError>;
+    fn lookup(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError>;
+    fn release(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<(), FilesystemError>;
+    fn access(&self, nodeid: u64, mask: u32) -> Result<(), FilesystemError>;
+    fn statfs(&self, nodeid: u64) -> Result<(), FilesystemError>;
+    fn interrupt(&self, unique: u64) -> Result<(), FilesystemError>;
+    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]) -> Result<(), FilesystemError>;
     // fn interrupt(&self, nodeid: u64, fh: u64, lock_owner: u64, unique: u64);
-    fn mkdir(&self, nodeid: u64, mode: u32, umask: u32, name: Vec<u8>);
-    fn create(&self, nodeid: u64, name: Vec<u8>, mode: u32, umask: u32, flags: u32);
-    fn destroy(&self);
-    fn rename(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>);
-    fn rename2(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>, flags: u32);
-    fn forget(&self, nodeid: u64, nlookup: u64);
-    fn batch_forget(&self, forget_list: &[(u64, u64)]);
-    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>);
-    fn unlink(&self, nodeid: u64, name: Vec<u8>);
-
-    fn bmap(&self, nodeid: u64, blocksize: u32, index: u64);
-    fn fallocate(&self, nodeid: u64, fh: u64, offset: u64, length: u64, mode: u32);
-    fn fsync(&self, nodeid: u64, fh: u64, datasync: u32);
-    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: u32);
+    fn mkdir(
+        &self,
+        nodeid: u64,
+        mode: u32,
+        umask: u32,
+        name: Vec<u8>,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError>;
+    fn create(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError>;
+    fn destroy(&self) -> Result<(), FilesystemError>;
+    fn rename(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+    ) -> Result<(), FilesystemError>;
+    fn rename2(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+        flags: u32,
+    ) -> Result<(), FilesystemError>;
+    fn forget(&self, nodeid: u64, nlookup: u64) -> Result<(), FilesystemError>;
+    fn batch_forget(&self, forget_list: &[(u64, u64)]) -> Result<(), FilesystemError>;
+    fn notify_reply(
+        &self,
+        notify_unique: u64,
+        nodeid: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FilesystemError>;
+    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError>;
+    fn unlink(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError>;
+
+    fn bmap(&self, nodeid: u64, blocksize: u32, index: u64) -> Result<(), FilesystemError>;
+    fn fallocate(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> Result<(), FilesystemError>;
+    fn fsync(&self, nodeid: u64, fh: u64, datasync: u32) -> Result<(), FilesystemError>;
+    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: u32) -> Result<(), FilesystemError>;
     fn getlk(
         &self,
         nodeid: u64,
@@ -68,17 +134,39 @@ pub trait AnyFuseDevice {
         end: u64,
         typ: u32,
         pid: u32,
-    );
-    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32);
-    fn ioctl(&self, nodeid: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8]);
-    fn listxattr(&self, nodeid: u64, size: u32);
-    fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32);
-    fn mknod(&self, nodeid: u64, name: Vec<u8>, mode: u32, rdev: u32);
-    fn poll(&self, nodeid: u64, fh: u64, events: u32);
+    ) -> Result<(), FilesystemError>;
+    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32) -> Result<(), FilesystemError>;
+    fn ioctl(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+    ) -> Result<(), FilesystemError>;
+    fn listxattr(&self, nodeid: u64, size: u32) -> Result<(), FilesystemError>;
+    fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32) -> Result<(), FilesystemError>;
+    fn mknod(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        rdev: u32,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError>;
+    fn poll(&self, nodeid: u64, fh: u64, events: u32) -> Result<(), FilesystemError>;
     // fn readdirplus(&self, nodeid: u64, fh: u64, offset: u64, size: u32);
-    fn readlink(&self, nodeid: u64);
-    fn removexattr(&self, nodeid: u64, name: Vec<u8>);
-    fn rmdir(&self, nodeid: u64, name: Vec<u8>);
+    fn readlink(&self, nodeid: u64) -> Result<(), FilesystemError>;
+    fn removexattr(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError>;
+    fn setxattr(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), FilesystemError>;
+    fn rmdir(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FilesystemError>;
     fn setlk(
         &self,
         nodeid: u64,
@@ -88,8 +176,8 @@ pub trait AnyFuseDevice {
         end: u64,
         typ: u32,
         pid: u32,
-        sleep: u32,
-    );
+        flock: bool,
+    ) -> Result<(), FilesystemError>;
     fn setlkw(
         &self,
         nodeid: u64,
@@ -99,19 +187,193 @@ pub trait AnyFuseDevice {
         end: u64,
         typ: u32,
         pid: u32,
-        sleep: u32,
-    );
-    fn symlink(&self, nodeid: u64, name: Vec<u8>, link: Vec<u8>);
+        flock: bool,
+    ) -> Result<(), FilesystemError>;
+    fn symlink(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        link: Vec<u8>,
+        secctx: Option<SecurityContext<'_>>,
+        supp_groups: &[u32],
+    ) -> Result<(), FilesystemError>;
 }
 
-pub fn fuse_pad_str(name: &str, repr_c: bool) -> Vec<u8> {
+/// Pads a name to a multiple of 8 bytes, NUL-terminating it first if `repr_c`.
+///
+/// Names are raw bytes, which need not be valid UTF-8.
+pub fn fuse_pad_str(name: &[u8], repr_c: bool) -> Vec<u8> {
     let name_len = name.len() as u32 + if repr_c { 1 } else { 0 };
     let name_pad_len = name_len + ((8 - (name_len & 0x7)) & 0x7); //Pad to multiple of 8 bytes
-    let mut prepared_name: Vec<u8> = name.as_bytes().to_vec();
+    let mut prepared_name: Vec<u8> = name.to_vec();
     prepared_name.resize(name_pad_len as usize, 0);
     prepared_name
 }
 
+/// Serializes the request extensions `exts`, each being its type and the
+/// bytes following its `FuseExtHeader`.
+///
+/// Each extension is padded to 8 bytes. Returns the extensions along with the
+/// `total_extlen` of the `FuseInHeader`, which counts them in units of 8 bytes.
+pub fn build_extensions(exts: &[(FuseExtType, &[u8])]) -> Result<(Vec<u8>, u16), FilesystemError> {
+    let mut extensions = Vec::new();
+    for (ext_type, data) in exts {
+        if let FuseExtType::FuseSecctx(nr_secctx) = ext_type {
+            if *nr_secctx > FUSE_MAX_NR_SECCTX {
+                return Err(FilesystemError::Fuse { errno: EINVAL });
+            }
+        }
+        let size = fuse_rec_align(mem::size_of::<FuseExtHeader>() + data.len());
+        let extheader = FuseExtHeader {
+            size: u32::try_from(size).map_err(|_| FilesystemError::Fuse { errno: EINVAL })?,
+            type_: (*ext_type).into(),
+        };
+        let start = extensions.len();
+        extensions.extend_from_slice(extheader.as_bytes());
+        extensions.extend_from_slice(data);
+        extensions.resize(start + size, 0);
+    }
+    let total_extlen =
+        u16::try_from(extensions.len() / 8).map_err(|_| FilesystemError::Fuse { errno: EINVAL })?;
+    Ok((extensions, total_extlen))
+}
+
+/// The attributes to change with `FUSE_SETATTR`.
+///
+/// Each setter also sets the `FATTR_*` bit telling the server which field is
+/// valid, so that the two cannot go out of sync.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetattrRequest {
+    setattrin: FuseSetattrIn,
+}
+
+impl SetattrRequest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.setattrin.mode = mode;
+        self.with(FATTR_MODE)
+    }
+
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.setattrin.uid = uid;
+        self.with(FATTR_UID)
+    }
+
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.setattrin.gid = gid;
+        self.with(FATTR_GID)
+    }
+
+    /// Truncates or extends the file to `size` bytes.
+    pub fn size(mut self, size: u64) -> Self {
+        self.setattrin.size = size;
+        self.with(FATTR_SIZE)
+    }
+
+    /// Makes the change through the open file `fh`, e.g. an `ftruncate(2)`.
+    pub fn fh(mut self, fh: u64) -> Self {
+        self.setattrin.fh = fh;
+        self.with(FATTR_FH)
+    }
+
+    pub fn lock_owner(mut self, lock_owner: u64) -> Self {
+        self.setattrin.lock_owner = lock_owner;
+        self.with(FATTR_LOCKOWNER)
+    }
+
+    pub fn atime(mut self, secs: u64, nsecs: u32) -> Self {
+        self.setattrin.atime = secs;
+        self.setattrin.atimensec = nsecs;
+        self.with(FATTR_ATIME)
+    }
+
+    /// Sets the access time to the current time of the server.
+    pub fn atime_now(self) -> Self {
+        self.with(FATTR_ATIME | FATTR_ATIME_NOW)
+    }
+
+    pub fn mtime(mut self, secs: u64, nsecs: u32) -> Self {
+        self.setattrin.mtime = secs;
+        self.setattrin.mtimensec = nsecs;
+        self.with(FATTR_MTIME)
+    }
+
+    /// Sets the modification time to the current time of the server.
+    pub fn mtime_now(self) -> Self {
+        self.with(FATTR_MTIME | FATTR_MTIME_NOW)
+    }
+
+    pub fn ctime(mut self, secs: u64, nsecs: u32) -> Self {
+        self.setattrin.ctime = secs;
+        self.setattrin.ctimensec = nsecs;
+        self.with(FATTR_CTIME)
+    }
+
+    /// Asks the server to clear the suid and sgid bits, as it does once
+    /// `FUSE_HANDLE_KILLPRIV_V2` is negotiated.
+    pub fn kill_suidgid(self) -> Self {
+        self.with(FATTR_KILL_SUIDGID)
+    }
+
+    /// Returns the `FATTR_*` bits of the attributes set so far.
+    pub fn valid(&self) -> FattrFlags {
+        FattrFlags::from_bits_truncate(self.setattrin.valid)
+    }
+
+    /// Returns the body of the `FUSE_SETATTR` request.
+    pub fn setattr_in(&self) -> FuseSetattrIn {
+        self.setattrin
+    }
+
+    fn with(mut self, valid: u32) -> Self {
+        self.setattrin.valid |= valid;
+        self
+    }
+}
+
+/// The security context of a node being created, e.g. its SELinux label.
+#[derive(Debug, Clone, Copy)]
+pub struct SecurityContext<'a> {
+    /// The name of the context, e.g. `security.selinux`.
+    pub name: &'a [u8],
+    pub context: &'a [u8],
+}
+
+/// Builds the security context extension sent once `FUSE_SECURITY_CTX` is
+/// negotiated, which holds no context if `secctx` is `None`.
+///
+/// The extension is to be serialized by `build_extensions`, whose `FuseExtHeader`
+/// doubles as the `FuseSecctxHeader`.
+pub fn secctx_extension(secctx: Option<SecurityContext<'_>>) -> (FuseExtType, Vec<u8>) {
+    let Some(secctx) = secctx else {
+        return (FuseExtType::FuseSecctx(0), Vec::new());
+    };
+    let fuse_secctx = FuseSecctx {
+        size: secctx.context.len() as u32,
+        padding: 0,
+    };
+    let data = [fuse_secctx.as_bytes(), secctx.name, b"\0", secctx.context].concat();
+    (FuseExtType::FuseSecctx(1), data)
+}
+
+/// Builds the extension carrying the supplementary groups `groups`, sent once
+/// `FUSE_CREATE_SUPP_GROUP` is negotiated.
+pub fn supp_groups_extension(groups: &[u32]) -> (FuseExtType, Vec<u8>) {
+    let supp_groups = FuseSuppGroups {
+        nr_groups: groups.len() as u32,
+        groups: [],
+    };
+    let mut data = supp_groups.as_bytes().to_vec();
+    for group in groups {
+        data.extend_from_slice(group.as_bytes());
+    }
+    (FuseExtType::FuseExtGroups, data)
+}
+
+/// A request along with the room for its reply, as laid out in a request buffer.
 #[derive(Debug)]
 #[repr(C)]
 pub struct VirtioFsReq {
@@ -125,20 +387,20 @@ pub struct VirtioFsReq {
 }
 
 impl VirtioFsReq {
-    pub fn into_bytes(&self) -> Vec<u8> {
-        let fuse_in_header = self.headerin.as_bytes();
-        let datain = self.datain.as_slice();
-        let fuse_out_header = self.headerout.as_bytes();
-        let dataout = self.dataout.as_slice();
-
-        let total_len = fuse_in_header.len() + datain.len() + fuse_out_header.len() + dataout.len();
-
-        let mut concat_req = vec![0u8; total_len];
-        concat_req[0..fuse_in_header.len()].copy_from_slice(fuse_in_header);
-        concat_req[fuse_in_header.len()..(fuse_in_header.len() + datain.len())]
-            .copy_from_slice(datain);
+    /// Returns the length of the device-readable part, which the device-writable part follows.
+    pub fn len_in(&self) -> usize {
+        mem::size_of::<FuseInHeader>() + self.datain.len()
+    }
 
-        concat_req
+    /// Lays out the four parts of the request back to back.
+    pub fn into_bytes(&self) -> Vec<u8> {
+        [
+            self.headerin.as_bytes(),
+            self.datain.as_slice(),
+            self.headerout.as_bytes(),
+            self.dataout.as_slice(),
+        ]
+        .concat()
     }
 }
 
@@ -148,22 +410,70 @@ pub struct FuseDirentWithName {
     pub name: Vec<u8>,
 }
 
+impl FuseDirentWithName {
+    /// Returns the type of the file, which tells e.g. subdirectories apart
+    /// without a `FUSE_GETATTR`.
+    pub fn file_type(&self) -> DirentType {
+        self.dirent.file_type()
+    }
+}
+
+/// The longest name a directory entry may have, as the Linux client accepts.
+const FUSE_NAME_MAX: usize = 1024;
+
+/// Returns the length of the directory entries in a reply with `out_header`,
+/// of which the reader holds `remain` bytes.
+fn dirents_len(out_header: &FuseOutHeader, remain: usize) -> Result<usize, FilesystemError> {
+    let len = (out_header.len as usize)
+        .checked_sub(size_of::<FuseOutHeader>())
+        .ok_or(FilesystemError::UnexpectedDataInPacket)?;
+    if len > remain {
+        return Err(FilesystemError::UnexpectedDataInPacket);
+    }
+    Ok(len)
+}
+
+/// Checks the name length of a directory entry, which must be neither empty
+/// nor longer than `FUSE_NAME_MAX`.
+fn check_namelen(namelen: u32) -> Result<(), FilesystemError> {
+    if namelen == 0 || namelen as usize > FUSE_NAME_MAX {
+        return Err(FilesystemError::UnexpectedDataInPacket);
+    }
+    Ok(())
+}
+
 ///Contain all directory entries for one directory
 pub struct FuseReaddirOut {
     pub dirents: Vec<FuseDirentWithName>,
 }
 impl FuseReaddirOut {
     /// Read all directory entries from the buffer
+    ///
+    /// Returns `FilesystemError::BufferTooShort` if the first entry does not fit
+    /// in the reply, e.g. because its name is longer than the whole buffer. The
+    /// caller may then retry with a larger buffer. A later entry straddling the
+    /// end of the reply is left out, to be read again from the `off` of the
+    /// entry before it.
+    ///
+    /// Returns `FilesystemError::UnexpectedDataInPacket` if the reply claims more
+    /// bytes than the reader holds, or if an entry has an invalid name length.
     pub fn read_dirent(
         reader: &mut VmReader<'_, ostd::mm::Infallible>,
         out_header: FuseOutHeader,
-    ) -> FuseReaddirOut {
-        let mut len = out_header.len as i32 - size_of::<FuseOutHeader>() as i32;
+    ) -> Result<FuseReaddirOut, FilesystemError> {
+        let mut len = dirents_len(&out_header, reader.remain())?;
         let mut dirents: Vec<FuseDirentWithName> = Vec::new();
         // For paddings between dirents
         let mut padding: Vec<u8> = vec![0 as u8; 8];
         while len > 0 {
-            let dirent = reader.read_val::<FuseDirent>().unwrap();
+            if len < FUSE_NAME_OFFSET {
+                break;
+            }
+            let dirent = reader.read_val::<FuseDirent>()?;
+            check_namelen(dirent.namelen)?;
+            if fuse_dirent_size(&dirent) > len {
+                break;
+            }
             let mut file_name: Vec<u8>;
 
             file_name = vec![0 as u8; dirent.namelen as usize];
@@ -182,8 +492,573 @@ impl FuseReaddirOut {
                 size_of::<FuseDirent>() as u32 + dirent.namelen,
                 dirent.namelen
             );
-            len -= size_of::<FuseDirent>() as i32 + dirent.namelen as i32 + pad_len as i32;
+            len = len.saturating_sub(fuse_dirent_size(&dirent));
+        }
+        if len > 0 && dirents.is_empty() {
+            return Err(FilesystemError::BufferTooShort);
         }
-        FuseReaddirOut { dirents: dirents }
+        Ok(FuseReaddirOut { dirents: dirents })
+    }
+
+    /// Returns whether this is the end of the directory stream.
+    ///
+    /// The server signals the end of a directory with a successful reply that
+    /// consists of the `FuseOutHeader` alone. Error replies never reach here.
+    pub fn is_end_of_stream(&self) -> bool {
+        self.dirents.is_empty()
+    }
+}
+
+///FuseDirentplus with the file name
+pub struct FuseDirentplusWithName {
+    pub direntplus: FuseDirentplus,
+    pub name: Vec<u8>,
+}
+
+impl FuseDirentplusWithName {
+    /// Returns the type of the file, as `FuseDirentWithName::file_type`.
+    pub fn file_type(&self) -> DirentType {
+        self.direntplus.dirent.file_type()
+    }
+}
+
+///Contain all directory entries, with their attributes, for one directory
+pub struct FuseReaddirplusOut {
+    pub dirents: Vec<FuseDirentplusWithName>,
+}
+impl FuseReaddirplusOut {
+    /// Read all directory entries from the buffer
+    ///
+    /// Returns `FilesystemError::BufferTooShort` if an entry does not fit in the reply,
+    /// and `FilesystemError::UnexpectedDataInPacket` if the reply is malformed as
+    /// for `FuseReaddirOut::read_dirent`.
+    pub fn read_direntplus(
+        reader: &mut VmReader<'_, Infallible>,
+        out_header: FuseOutHeader,
+    ) -> Result<FuseReaddirplusOut, FilesystemError> {
+        let mut len = dirents_len(&out_header, reader.remain())?;
+        let mut dirents = Vec::new();
+        // For paddings between dirents
+        let mut padding = [0u8; 8];
+        while len > 0 {
+            if len < FUSE_NAME_OFFSET_DIRENTPLUS {
+                break;
+            }
+            let direntplus = reader.read_val::<FuseDirentplus>()?;
+            check_namelen(direntplus.dirent.namelen)?;
+            let entry_len = fuse_direntplus_size(&direntplus);
+            if entry_len > len {
+                break;
+            }
+
+            let mut name = vec![0u8; direntplus.dirent.namelen as usize];
+            VmWriter::from(name.as_mut_slice()).write(reader);
+            let pad_len = entry_len - FUSE_NAME_OFFSET_DIRENTPLUS - name.len();
+            VmWriter::from(&mut padding[..pad_len]).write(reader);
+            dirents.push(FuseDirentplusWithName {
+                direntplus: direntplus,
+                name: name,
+            });
+            len = len.saturating_sub(entry_len);
+        }
+        if len > 0 && dirents.is_empty() {
+            return Err(FilesystemError::BufferTooShort);
+        }
+        Ok(FuseReaddirplusOut { dirents: dirents })
+    }
+}
+
+/// A `VmReader` over a completed request that counts the bytes consumed.
+///
+/// Decoding a request and its reply should consume exactly the length of the
+/// request plus `FuseOutHeader::len`. Anything else points to a body decoded
+/// with the wrong type, or to a malformed reply.
+pub struct CountingReader<'a> {
+    reader: VmReader<'a, Infallible>,
+    len: usize,
+}
+
+impl<'a> CountingReader<'a> {
+    pub fn new(reader: VmReader<'a, Infallible>) -> Self {
+        let len = reader.remain();
+        Self {
+            reader: reader,
+            len: len,
+        }
+    }
+
+    /// Skips the next `nbytes` bytes, counting them as consumed.
+    ///
+    /// Fails without skipping anything if fewer bytes remain.
+    pub fn skip(&mut self, nbytes: usize) -> Result<(), FilesystemError> {
+        if nbytes > self.reader.remain() {
+            return Err(FilesystemError::BufferTooShort);
+        }
+        let reader = mem::replace(&mut self.reader, VmReader::from(&[][..]));
+        self.reader = reader.skip(nbytes);
+        Ok(())
+    }
+
+    /// Returns the number of bytes consumed so far.
+    pub fn consumed(&self) -> usize {
+        self.len - self.reader.remain()
+    }
+
+    /// Checks that exactly `expected` bytes have been consumed.
+    pub fn check_consumed(&self, expected: usize) -> Result<(), FilesystemError> {
+        let consumed = self.consumed();
+        if consumed == expected {
+            Ok(())
+        } else {
+            Err(FilesystemError::ReplyLengthMismatch(consumed, expected))
+        }
+    }
+}
+
+impl<'a> Deref for CountingReader<'a> {
+    type Target = VmReader<'a, Infallible>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
+
+impl DerefMut for CountingReader<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.reader
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn extensions_are_padded_and_counted() {
+        let groups = [1u32, 2, 3];
+        let groups_ext = [&3u32.to_ne_bytes()[..], groups.as_bytes()].concat();
+        let (extensions, total_extlen) = build_extensions(&[
+            (FuseExtType::FuseSecctx(1), b"ctx"),
+            (FuseExtType::FuseExtGroups, &groups_ext),
+        ])
+        .unwrap();
+
+        // 8 + 3 bytes padded to 16, then 8 + 16 bytes.
+        assert_eq!(extensions.len(), 40);
+        assert_eq!(total_extlen, 5);
+
+        let mut reader = VmReader::from(extensions.as_slice());
+        let secctx = reader.read_val::<FuseExtHeader>().unwrap();
+        assert_eq!((secctx.size, secctx.type_), (16, 1));
+        assert_eq!(&extensions[8..16], b"ctx\0\0\0\0\0");
+        let mut reader = VmReader::from(&extensions[16..]);
+        let groups = reader.read_val::<FuseExtHeader>().unwrap();
+        assert_eq!((groups.size, groups.type_), (24, FUSE_EXT_GROUPS));
+    }
+
+    #[ktest]
+    fn extensions_are_validated() {
+        assert_eq!(build_extensions(&[]).unwrap(), (Vec::new(), 0));
+        assert!(matches!(
+            build_extensions(&[(FuseExtType::FuseSecctx(FUSE_MAX_NR_SECCTX + 1), b"")]),
+            Err(FilesystemError::Fuse { errno: EINVAL })
+        ));
+        // The extensions must be countable by `total_extlen`.
+        let huge = vec![0u8; 8 * u16::MAX as usize];
+        assert!(matches!(
+            build_extensions(&[(FuseExtType::FuseExtGroups, &huge)]),
+            Err(FilesystemError::Fuse { errno: EINVAL })
+        ));
+    }
+
+    #[ktest]
+    fn secctx_extension_layout() {
+        let secctx = SecurityContext {
+            name: b"security.selinux",
+            context: b"system_u:object_r:tmp_t:s0",
+        };
+        let (ext_type, data) = secctx_extension(Some(secctx));
+        let (extensions, total_extlen) = build_extensions(&[(ext_type, &data)]).unwrap();
+
+        // The header, the `FuseSecctx`, the NUL-terminated name and the context.
+        let size = fuse_rec_align(8 + 8 + 17 + 27);
+        assert_eq!(extensions.len(), size);
+        assert_eq!(total_extlen as usize * 8, size);
+
+        let mut reader = VmReader::from(extensions.as_slice());
+        let header = reader.read_val::<FuseSecctxHeader>().unwrap();
+        assert_eq!((header.size as usize, header.nr_secctx), (size, 1));
+        let fuse_secctx = reader.read_val::<FuseSecctx>().unwrap();
+        assert_eq!(fuse_secctx.size, 27);
+        assert_eq!(&extensions[16..33], b"security.selinux\0");
+        assert_eq!(&extensions[33..60], secctx.context);
+        assert!(extensions[60..].iter().all(|&byte| byte == 0));
+
+        // Without a context, the extension is a bare header.
+        let (ext_type, data) = secctx_extension(None);
+        let (extensions, total_extlen) = build_extensions(&[(ext_type, &data)]).unwrap();
+        assert_eq!(
+            extensions,
+            [8u32.to_ne_bytes(), 0u32.to_ne_bytes()].concat()
+        );
+        assert_eq!(total_extlen, 1);
+    }
+
+    #[ktest]
+    fn supp_groups_extension_layout() {
+        let (ext_type, data) = supp_groups_extension(&[100, 200]);
+        let (extensions, total_extlen) = build_extensions(&[(ext_type, &data)]).unwrap();
+
+        // The header, `nr_groups` and two groups, padded from 20 to 24 bytes.
+        assert_eq!(extensions.len(), 24);
+        assert_eq!(total_extlen, 3);
+
+        let mut reader = VmReader::from(extensions.as_slice());
+        let extheader = reader.read_val::<FuseExtHeader>().unwrap();
+        assert_eq!((extheader.size, extheader.type_), (24, FUSE_EXT_GROUPS));
+        assert_eq!(reader.read_val::<u32>().unwrap(), 2);
+        assert_eq!(reader.read_val::<u32>().unwrap(), 100);
+        assert_eq!(reader.read_val::<u32>().unwrap(), 200);
+        assert_eq!(reader.read_val::<u32>().unwrap(), 0);
+    }
+
+    #[ktest]
+    fn pad_str_keeps_non_utf8_bytes() {
+        let name = [b'a', 0xff, 0xfe, b'b'];
+        assert_eq!(
+            fuse_pad_str(&name, true),
+            [b'a', 0xff, 0xfe, b'b', 0, 0, 0, 0]
+        );
+        assert_eq!(
+            fuse_pad_str(&name, false),
+            [b'a', 0xff, 0xfe, b'b', 0, 0, 0, 0]
+        );
+
+        // The NUL terminator may require another 8 bytes.
+        let name = [0xffu8; 8];
+        let padded = fuse_pad_str(&name, true);
+        assert_eq!(padded.len(), 16);
+        assert_eq!(padded[..8], name);
+        assert!(padded[8..].iter().all(|&b| b == 0));
+    }
+
+    #[ktest]
+    fn header_only_readdir_reply_is_end_of_stream() {
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: 0,
+            unique: 1,
+        };
+        let mut reader = VmReader::from(&[0u8; 0][..]);
+        let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout).unwrap();
+        assert!(readdir_out.is_end_of_stream());
+    }
+
+    #[ktest]
+    fn dirent_longer_than_buffer_is_rejected() {
+        // The reply buffer only has room for 256 bytes, while the entry
+        // claims a 300-byte name.
+        const BUFFER_SIZE: usize = 256;
+        let dirent = FuseDirent {
+            ino: 2,
+            off: 1,
+            namelen: 300,
+            type_: 8,
+            name: [],
+        };
+        let mut dataout = dirent.as_bytes().to_vec();
+        dataout.resize(BUFFER_SIZE, b'x');
+
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + BUFFER_SIZE) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let mut reader = VmReader::from(dataout.as_slice());
+        let result = FuseReaddirOut::read_dirent(&mut reader, headerout);
+        assert!(matches!(result, Err(FilesystemError::BufferTooShort)));
+    }
+
+    #[ktest]
+    fn dirent_straddling_end_of_reply_is_left_out() {
+        let mut dataout = Vec::new();
+        for (off, name) in [(1, &b"first"[..]), (2, b"second")] {
+            let dirent = FuseDirent {
+                ino: 2,
+                off: off,
+                namelen: name.len() as u32,
+                type_: 8,
+                name: [],
+            };
+            dataout.extend_from_slice(dirent.as_bytes());
+            dataout.extend_from_slice(&fuse_pad_str(name, false));
+        }
+        // The reply ends within the name of the second entry.
+        dataout.truncate(dataout.len() - 4);
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + dataout.len()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let mut reader = VmReader::from(dataout.as_slice());
+        let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout).unwrap();
+        assert_eq!(readdir_out.dirents.len(), 1);
+        assert_eq!(readdir_out.dirents[0].name, b"first");
+    }
+
+    #[ktest]
+    fn dirent_types_are_decoded() {
+        let mut dataout = Vec::new();
+        for (name, type_) in [(&b"dir"[..], 4), (&b"file"[..], 8), (&b"odd"[..], 14)] {
+            let dirent = FuseDirent {
+                ino: 2,
+                off: dataout.len() as u64 + 1,
+                namelen: name.len() as u32,
+                type_: type_,
+                name: [],
+            };
+            dataout.extend_from_slice(dirent.as_bytes());
+            dataout.extend_from_slice(&fuse_pad_str(name, false));
+        }
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + dataout.len()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let mut reader = VmReader::from(dataout.as_slice());
+        let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout).unwrap();
+        let types: Vec<_> = readdir_out
+            .dirents
+            .iter()
+            .map(|dirent| dirent.file_type())
+            .collect();
+        assert_eq!(
+            types,
+            [DirentType::Dir, DirentType::Reg, DirentType::Unknown]
+        );
+        // The raw type is kept as is.
+        assert_eq!(readdir_out.dirents[2].dirent.type_, 14);
+    }
+
+    #[ktest]
+    fn setattr_request_sets_valid_bits() {
+        let request = SetattrRequest::new().mode(0o644).size(0);
+        assert_eq!(
+            request.valid(),
+            FattrFlags::FATTR_MODE | FattrFlags::FATTR_SIZE
+        );
+        assert_eq!(request.setattr_in().mode, 0o644);
+
+        let request = SetattrRequest::new().atime(10, 20).mtime_now();
+        let setattrin = request.setattr_in();
+        assert_eq!((setattrin.atime, setattrin.atimensec), (10, 20));
+        assert_eq!(
+            request.valid(),
+            FattrFlags::FATTR_ATIME | FattrFlags::FATTR_MTIME | FattrFlags::FATTR_MTIME_NOW
+        );
+    }
+
+    #[ktest]
+    fn malformed_readdir_reply_is_rejected() {
+        let dirent = FuseDirent {
+            ino: 2,
+            off: 1,
+            namelen: 0,
+            type_: 8,
+            name: [],
+        };
+        let dataout = dirent.as_bytes().to_vec();
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + dataout.len()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        // An entry with an empty name would make no progress.
+        let mut reader = VmReader::from(dataout.as_slice());
+        let result = FuseReaddirOut::read_dirent(&mut reader, headerout);
+        assert!(matches!(
+            result,
+            Err(FilesystemError::UnexpectedDataInPacket)
+        ));
+
+        // The reply claims more bytes than the buffer holds.
+        let truncated = FuseOutHeader {
+            len: 4096,
+            ..headerout
+        };
+        let mut reader = VmReader::from(dataout.as_slice());
+        let result = FuseReaddirOut::read_dirent(&mut reader, truncated);
+        assert!(matches!(
+            result,
+            Err(FilesystemError::UnexpectedDataInPacket)
+        ));
+
+        // So does a length shorter than the header itself.
+        let garbage = FuseOutHeader {
+            len: 3,
+            ..headerout
+        };
+        let mut reader = VmReader::from(dataout.as_slice());
+        let result = FuseReaddirplusOut::read_direntplus(&mut reader, garbage);
+        assert!(matches!(
+            result,
+            Err(FilesystemError::UnexpectedDataInPacket)
+        ));
+    }
+
+    /// Lays out a request and its reply as they sit in a request buffer,
+    /// returning the buffer and the length of the whole exchange.
+    fn fake_exchange(opcode: FuseOpcode, datain: &[u8], dataout: &[u8]) -> (Vec<u8>, usize) {
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseInHeader>() + datain.len()) as u32,
+            opcode: opcode as u32,
+            unique: 1,
+            ..Default::default()
+        };
+        let headerout = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + dataout.len()) as u32,
+            error: 0,
+            unique: 1,
+        };
+        let exchange = VirtioFsReq {
+            headerin: headerin,
+            datain: datain.to_vec(),
+            headerout: headerout,
+            dataout: dataout.to_vec(),
+        }
+        .into_bytes();
+        let len = exchange.len();
+        // The buffer is larger than any single exchange.
+        let mut buffer = exchange;
+        buffer.resize(len + 64, 0xaa);
+        (buffer, len)
+    }
+
+    #[ktest]
+    fn request_is_laid_out_in_full() {
+        let req = VirtioFsReq {
+            headerin: FuseInHeader {
+                len: (size_of::<FuseInHeader>() + 3) as u32,
+                ..Default::default()
+            },
+            datain: vec![1, 2, 3],
+            headerout: FuseOutHeader {
+                len: 0,
+                error: 0,
+                unique: 7,
+            },
+            dataout: vec![4, 5],
+        };
+        let bytes = req.into_bytes();
+        let len_in = req.len_in();
+        assert_eq!(len_in, size_of::<FuseInHeader>() + 3);
+        assert_eq!(bytes.len(), len_in + size_of::<FuseOutHeader>() + 2);
+        assert_eq!(&bytes[len_in - 3..len_in], &[1, 2, 3]);
+
+        // The output regions are not dropped.
+        let headerout = FuseOutHeader::from_bytes(&bytes[len_in..]);
+        assert_eq!(headerout.unique, 7);
+        assert_eq!(&bytes[bytes.len() - 2..], &[4, 5]);
+    }
+
+    #[ktest]
+    fn fixed_size_replies_are_fully_consumed() {
+        let (buffer, len) = fake_exchange(
+            FuseOpcode::FuseInit,
+            FuseInitIn::new_zeroed().as_bytes(),
+            FuseInitOut::new_zeroed().as_bytes(),
+        );
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        reader.read_val::<FuseInHeader>().unwrap();
+        reader.read_val::<FuseInitIn>().unwrap();
+        reader.read_val::<FuseOutHeader>().unwrap();
+        reader.read_val::<FuseInitOut>().unwrap();
+        reader.check_consumed(len).unwrap();
+
+        let (buffer, len) = fake_exchange(
+            FuseOpcode::FuseGetattr,
+            FuseGetattrIn::new_zeroed().as_bytes(),
+            FuseAttrOut::new_zeroed().as_bytes(),
+        );
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        reader.read_val::<FuseInHeader>().unwrap();
+        reader.read_val::<FuseGetattrIn>().unwrap();
+        reader.read_val::<FuseOutHeader>().unwrap();
+        reader.read_val::<FuseAttrOut>().unwrap();
+        reader.check_consumed(len).unwrap();
+
+        let (buffer, len) = fake_exchange(
+            FuseOpcode::FuseOpen,
+            FuseOpenIn::new_zeroed().as_bytes(),
+            FuseOpenOut::new_zeroed().as_bytes(),
+        );
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        reader.read_val::<FuseInHeader>().unwrap();
+        reader.read_val::<FuseOpenIn>().unwrap();
+        reader.read_val::<FuseOutHeader>().unwrap();
+        reader.read_val::<FuseOpenOut>().unwrap();
+        reader.check_consumed(len).unwrap();
+    }
+
+    #[ktest]
+    fn name_and_header_only_replies_are_fully_consumed() {
+        let name = fuse_pad_str(b"testf01", true);
+        let (buffer, len) = fake_exchange(
+            FuseOpcode::FuseLookup,
+            &name,
+            FuseEntryOut::new_zeroed().as_bytes(),
+        );
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        reader
+            .skip(headerin.len as usize - size_of::<FuseInHeader>())
+            .unwrap();
+        reader.read_val::<FuseOutHeader>().unwrap();
+        reader.read_val::<FuseEntryOut>().unwrap();
+        reader.check_consumed(len).unwrap();
+
+        let (buffer, len) = fake_exchange(FuseOpcode::FuseUnlink, &name, &[]);
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        reader
+            .skip(headerin.len as usize - size_of::<FuseInHeader>())
+            .unwrap();
+        reader.read_val::<FuseOutHeader>().unwrap();
+        reader.check_consumed(len).unwrap();
+    }
+
+    #[ktest]
+    fn over_read_is_reported() {
+        // Decoding an entry from a header-only reply reads past its end.
+        let name = fuse_pad_str(b"testf01", true);
+        let (buffer, len) = fake_exchange(FuseOpcode::FuseUnlink, &name, &[]);
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        reader
+            .skip(headerin.len as usize - size_of::<FuseInHeader>())
+            .unwrap();
+        reader.read_val::<FuseOutHeader>().unwrap();
+        reader.read_val::<FuseEntryOut>().unwrap();
+        let result = reader.check_consumed(len);
+        assert!(matches!(
+            result,
+            Err(FilesystemError::ReplyLengthMismatch(consumed, expected))
+                if consumed == len + size_of::<FuseEntryOut>() && expected == len
+        ));
+    }
+
+    #[ktest]
+    fn skip_past_the_end_fails() {
+        // A request cut short cannot be skipped past, as the reply would be read from nowhere.
+        let name = fuse_pad_str(b"testf01", true);
+        let (buffer, _) = fake_exchange(FuseOpcode::FuseUnlink, &name, &[]);
+        let mut reader = CountingReader::new(VmReader::from(buffer.as_slice()));
+        assert!(matches!(
+            reader.skip(buffer.len() + 1),
+            Err(FilesystemError::BufferTooShort)
+        ));
+        assert_eq!(reader.consumed(), 0);
     }
 }
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::collections::BTreeMap;
+use core::{
+    array,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::fuse::FuseOpcode;
+
+/// The number of opcodes counted, which are all those of FUSE but not of CUSE.
+const NUM_OPCODES: usize = FuseOpcode::FuseStatx as usize + 1;
+
+/// The counters of the requests of each opcode, updated without locking.
+pub struct RequestStats {
+    submitted: [AtomicU64; NUM_OPCODES],
+    completed: [AtomicU64; NUM_OPCODES],
+    /// The number of requests whose latency is added to `latency_nsecs`.
+    timed: [AtomicU64; NUM_OPCODES],
+    latency_nsecs: [AtomicU64; NUM_OPCODES],
+}
+
+impl RequestStats {
+    pub fn new() -> Self {
+        Self {
+            submitted: array::from_fn(|_| AtomicU64::new(0)),
+            completed: array::from_fn(|_| AtomicU64::new(0)),
+            timed: array::from_fn(|_| AtomicU64::new(0)),
+            latency_nsecs: array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Counts a request of `opcode` handed over to the device.
+    ///
+    /// Opcodes out of range, e.g. those of CUSE, are not counted.
+    pub fn submitted(&self, opcode: u32) {
+        if let Some(counter) = self.submitted.get(opcode as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a request of `opcode` returned by the device.
+    pub fn completed(&self, opcode: u32) {
+        if let Some(counter) = self.completed.get(opcode as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds `latency`, the time a request of `opcode` took to be answered.
+    pub fn timed(&self, opcode: u32, latency: Duration) {
+        let opcode = opcode as usize;
+        if opcode >= NUM_OPCODES {
+            return;
+        }
+        let nsecs = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.timed[opcode].fetch_add(1, Ordering::Relaxed);
+        self.latency_nsecs[opcode].fetch_add(nsecs, Ordering::Relaxed);
+    }
+
+    /// Returns the counters of the opcodes that requests were made with so far.
+    ///
+    /// The counters are read one by one, so they may be slightly inconsistent
+    /// with each other while requests are made.
+    pub fn snapshot(&self) -> FuseStats {
+        let mut ops = BTreeMap::new();
+        for opcode in 0..NUM_OPCODES {
+            let stats = OpcodeStats {
+                submitted: self.submitted[opcode].load(Ordering::Relaxed),
+                completed: self.completed[opcode].load(Ordering::Relaxed),
+                timed: self.timed[opcode].load(Ordering::Relaxed),
+                total_latency: Duration::from_nanos(
+                    self.latency_nsecs[opcode].load(Ordering::Relaxed),
+                ),
+            };
+            if stats != OpcodeStats::default() {
+                ops.insert(opcode as u32, stats);
+            }
+        }
+        FuseStats { ops: ops }
+    }
+}
+
+/// The counters of the requests of one opcode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpcodeStats {
+    /// The number of requests handed over to the device.
+    pub submitted: u64,
+    /// The number of requests returned by the device.
+    pub completed: u64,
+    /// The number of requests waited for by a caller, which are timed.
+    pub timed: u64,
+    /// The time the timed requests took in total, in steps of the system tick.
+    pub total_latency: Duration,
+}
+
+impl OpcodeStats {
+    /// Returns the mean latency of the timed requests, if any.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let timed = u32::try_from(self.timed).ok().filter(|&timed| timed > 0)?;
+        Some(self.total_latency / timed)
+    }
+}
+
+/// A snapshot of the requests made by a device, as returned by `FilesystemDevice::stats`.
+#[derive(Debug, Clone, Default)]
+pub struct FuseStats {
+    ops: BTreeMap<u32, OpcodeStats>,
+}
+
+impl FuseStats {
+    /// Returns the counters of `opcode`, which are zero if it was never used.
+    pub fn get(&self, opcode: FuseOpcode) -> OpcodeStats {
+        self.ops.get(&(opcode as u32)).copied().unwrap_or_default()
+    }
+
+    /// Iterates over the opcodes used so far along with their counters.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &OpcodeStats)> {
+        self.ops.iter().map(|(opcode, stats)| (*opcode, stats))
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn requests_are_counted_per_opcode() {
+        let stats = RequestStats::new();
+        stats.submitted(FuseOpcode::FuseLookup as u32);
+        stats.submitted(FuseOpcode::FuseLookup as u32);
+        stats.completed(FuseOpcode::FuseLookup as u32);
+        stats.timed(FuseOpcode::FuseLookup as u32, Duration::from_millis(2));
+        stats.timed(FuseOpcode::FuseLookup as u32, Duration::from_millis(4));
+        // CUSE opcodes are out of range.
+        stats.submitted(FuseOpcode::CuseInit as u32);
+
+        let snapshot = stats.snapshot();
+        let lookup = snapshot.get(FuseOpcode::FuseLookup);
+        assert_eq!((lookup.submitted, lookup.completed), (2, 1));
+        assert_eq!(lookup.mean_latency(), Some(Duration::from_millis(3)));
+        assert_eq!(snapshot.get(FuseOpcode::FuseRead), OpcodeStats::default());
+        assert_eq!(snapshot.get(FuseOpcode::FuseRead).mean_latency(), None);
+        assert_eq!(snapshot.iter().count(), 1);
+    }
+}
@@ -2,7 +2,7 @@
 
 use int_to_c_enum::TryFromInt;
 
-use crate::queue::QueueError;
+use crate::{queue::QueueError, transport::VirtioTransportError};
 
 pub mod block;
 pub mod console;
@@ -50,6 +50,12 @@ pub enum VirtioDeviceError {
     QueueUnknownError,
     /// The input virtio capability list contains invalid element
     CapabilityListError,
+    /// The device lacks features the driver requires, given as their bits
+    MissingFeatures(u64),
+    /// Failed to allocate the memory the device needs, e.g. its DMA buffers
+    ResourceAllocError(ostd::Error),
+    /// The transport failed to set the device up, e.g. to register an interrupt callback
+    TransportError(VirtioTransportError),
 }
 
 impl From<QueueError> for VirtioDeviceError {
@@ -57,3 +63,15 @@ impl From<QueueError> for VirtioDeviceError {
         VirtioDeviceError::QueueUnknownError
     }
 }
+
+impl From<VirtioTransportError> for VirtioDeviceError {
+    fn from(value: VirtioTransportError) -> Self {
+        VirtioDeviceError::TransportError(value)
+    }
+}
+
+impl From<ostd::Error> for VirtioDeviceError {
+    fn from(value: ostd::Error) -> Self {
+        VirtioDeviceError::ResourceAllocError(value)
+    }
+}
@@ -97,10 +97,16 @@ fn pop_device_transport() -> Option<Box<dyn VirtioTransport>> {
 }
 
 fn negotiate_features(transport: &mut Box<dyn VirtioTransport>) {
-    let features = transport.read_device_features();
+    let features = driver_features(transport.device_type(), transport.read_device_features());
+    transport.write_driver_features(features).unwrap();
+}
+
+/// Returns the features a driver of `device_type` accepts out of the `features`
+/// its device offers, which are written back to the device.
+pub(crate) fn driver_features(device_type: VirtioDeviceType, features: u64) -> u64 {
     let mask = ((1u64 << 24) - 1) | (((1u64 << 24) - 1) << 50);
     let device_specified_features = features & mask;
-    let device_support_features = match transport.device_type() {
+    let device_support_features = match device_type {
         VirtioDeviceType::Network => NetworkDevice::negotiate_features(device_specified_features),
         VirtioDeviceType::Block => BlockDevice::negotiate_features(device_specified_features),
         VirtioDeviceType::Input => InputDevice::negotiate_features(device_specified_features),
@@ -113,9 +119,7 @@ fn negotiate_features(transport: &mut Box<dyn VirtioTransport>) {
     };
     let mut support_feature = Feature::from_bits_truncate(features);
     support_feature.remove(Feature::RING_EVENT_IDX);
-    transport
-        .write_driver_features(features & (support_feature.bits | device_support_features))
-        .unwrap();
+    features & (support_feature.bits | device_support_features)
 }
 
 bitflags! {